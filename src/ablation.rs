@@ -0,0 +1,139 @@
+use std::error::Error;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use smartcore::metrics::accuracy;
+use crate::dataset::Dataset;
+use crate::metrics::FeatureImportance;
+use crate::model::{FittedModel, ModelConfig};
+
+#[derive(Debug)]
+pub struct AblationResult {
+    pub feature: String,
+    pub accuracy: f64,
+    pub delta: f64, // accuracy without the feature minus the full model's accuracy
+}
+
+fn fit_accuracy(config: &ModelConfig, train: &Dataset, test: &Dataset) -> Result<f64, Box<dyn Error>> {
+    let model = FittedModel::fit(config, train)?;
+    let y_pred = model.predict(&test.to_matrix())?;
+    Ok(accuracy(&test.labels, &y_pred))
+}
+
+/// Leave-one-feature-out ablation: retrains once per feature with that column
+/// removed from both splits. `features` restricts the run to the named columns;
+/// an empty slice ablates every column. Returns the full model's accuracy and
+/// one result per ablated feature, in column order.
+pub fn ablation(
+    config: &ModelConfig,
+    train: &Dataset,
+    test: &Dataset,
+    features: &[String],
+) -> Result<(f64, Vec<AblationResult>), Box<dyn Error>> {
+    let indices: Vec<usize> = if features.is_empty() {
+        (0..train.feature_names.len()).collect()
+    } else {
+        features
+            .iter()
+            .map(|name| {
+                train
+                    .feature_index(name)
+                    .ok_or_else(|| format!("unknown feature `{}`", name))
+            })
+            .collect::<Result<_, _>>()?
+    };
+
+    let baseline = fit_accuracy(config, train, test)?;
+    let mut results = Vec::new();
+    for index in indices {
+        let acc = fit_accuracy(config, &train.without_feature(index), &test.without_feature(index))?;
+        results.push(AblationResult {
+            feature: train.feature_names[index].clone(),
+            accuracy: acc,
+            delta: acc - baseline,
+        });
+    }
+    Ok((baseline, results))
+}
+
+/// Permutation importance of every feature of an already fitted model: the test
+/// accuracy minus the accuracy with that column shuffled (seed `seed + column`),
+/// so no retraining is needed. Sorted largest first, ties in column order.
+pub fn permutation_importance(
+    model: &FittedModel,
+    test: &Dataset,
+    seed: u64,
+) -> Result<Vec<FeatureImportance>, Box<dyn Error>> {
+    let full = accuracy(&test.labels, &model.predict(&test.to_matrix())?);
+    let mut importances = Vec::with_capacity(test.n_features());
+    for (j, feature) in test.feature_names.iter().enumerate() {
+        let mut column: Vec<f64> = test.column(j).collect();
+        column.shuffle(&mut StdRng::seed_from_u64(seed + j as u64));
+        let mut shuffled = test.clone();
+        for (i, value) in column.into_iter().enumerate() {
+            shuffled.row_mut(i)[j] = value;
+        }
+        importances.push(FeatureImportance {
+            feature: feature.clone(),
+            importance: full - accuracy(&test.labels, &model.predict(&shuffled.to_matrix())?),
+        });
+    }
+    importances.sort_by(|a, b| b.importance.total_cmp(&a.importance));
+    Ok(importances)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::dataset::RowId;
+    use crate::model::{ForestConfig, ModelKind};
+
+    #[test]
+    fn test_removing_signal_feature_drops_accuracy_most() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut features = Vec::new();
+        let mut labels = Vec::new();
+        for _ in 0..200 {
+            let signal: f64 = rng.gen_range(-1.0..1.0);
+            features.push(vec![rng.gen_range(-1.0..1.0), signal, rng.gen_range(-1.0..1.0)]);
+            labels.push(if signal < 0.0 { 1 } else { 2 });
+        }
+        let dataset = Dataset::from_rows(
+            vec!["noise_a".into(), "signal".into(), "noise_b".into()],
+            &features,
+            labels,
+            vec![RowId::default(); 200],
+        );
+        let (train, test) = dataset.train_test_split(0.25, 1);
+        let config = ModelConfig {
+            kind: ModelKind::RandomForest,
+            tree_depth: 3,
+            forest: ForestConfig {
+                m: None,
+                ..Default::default()
+            },
+            seed: 1,
+        };
+
+        let (baseline, results) = ablation(&config, &train, &test, &[]).unwrap();
+        assert!(baseline > 0.9);
+        let worst = results
+            .iter()
+            .min_by(|a, b| a.delta.total_cmp(&b.delta))
+            .unwrap();
+        assert_eq!(worst.feature, "signal");
+
+        let only_noise = ablation(&config, &train, &test, &["noise_b".to_string()]).unwrap().1;
+        assert_eq!(only_noise.len(), 1);
+        assert!(ablation(&config, &train, &test, &["missing".to_string()]).is_err());
+
+        let model = FittedModel::fit(&config, &train).unwrap();
+        let importances = permutation_importance(&model, &test, 3).unwrap();
+        assert_eq!(importances.len(), 3);
+        assert_eq!(importances[0].feature, "signal");
+        assert!(importances[0].importance > 0.2);
+        assert_eq!(importances, permutation_importance(&model, &test, 3).unwrap());
+    }
+}
@@ -0,0 +1,287 @@
+//! Disk cache of the processed records for `--cache`. When a new year arrives,
+//! only the ticker-years the cache lacks are built from the source files; the
+//! records before them keep their values, the first new record's changes are
+//! computed against the last cached one, and every record's price change is
+//! refreshed, since new price rows can complete the previous year or extend a
+//! multi-year horizon. Restated values for cached years are not picked up;
+//! delete the cache to rebuild from scratch.
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use crate::stock_data::{
+    cash_flow_metrics, combine_stock_data, fill_changes, horizon_price_change, load_financial_files,
+    load_price_files, mark_split_adjusted, process_stock_data_with_report, LoadOptions, LoadReport, StockData,
+    StockDataError, YearlyValues,
+};
+
+/// Processed records by ticker, each ticker's in year order.
+pub type CachedRecords = HashMap<String, Vec<StockData>>;
+
+/// What `load_or_update` did with the cache.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheReport {
+    pub rebuilt: Option<String>, // why the records were built from scratch; None when the cache was used
+    pub cached_records: usize,
+    pub new_records: usize,
+    pub refreshed_prices: usize, // cached records whose price change or volatility changed
+}
+
+// Bumped when `StockData` gains a field, so older caches are rebuilt rather than read with it empty
+const RECORD_VERSION: u32 = 2;
+
+// The inputs and options the records depend on; a cache written under other ones is rebuilt
+fn cache_key(financial_files: &[(&str, &str)], price_files: &[&str], options: &LoadOptions) -> String {
+    let mut base_years: Vec<(&String, &u32)> = options.base_years.iter().collect();
+    base_years.sort();
+    format!(
+        "records={} files={:?} prices={:?} skip_missing_files={} base_years={:?} gap_policy={:?} sheet={:?} horizon={} \
+         min_volatility_months={} dividend_file={:?} split_file={:?} price_conflict={:?} join_policy={:?} \
+         price_reference={} year_range={} currency_file={:?} fx_rates_file={:?} missing_fx_rate={:?} \
+         ratio_epsilon={} input_layout={:?}",
+        RECORD_VERSION,
+        financial_files,
+        price_files,
+        options.skip_missing_files,
+        base_years,
+        options.gap_policy,
+        options.sheet,
+        options.horizon,
+        options.min_volatility_months,
+        options.dividend_file,
+        options.split_file,
+        options.price_conflict,
+        options.join_policy,
+        options.price_reference,
+        options.year_range,
+        options.currency_file,
+        options.fx_rates_file,
+        options.missing_fx_rate,
+        options.ratio_epsilon,
+        options.input_layout
+    )
+}
+
+fn key_path(path: &str) -> String {
+    format!("{}.key", path)
+}
+
+fn cache_error(path: &str) -> impl Fn(csv::Error) -> StockDataError + '_ {
+    move |source| StockDataError::Csv {
+        path: path.to_string(),
+        source,
+    }
+}
+
+/// Writes the records as CSV, one row per ticker-year.
+pub fn save_cache(path: &str, stock_data: &CachedRecords) -> Result<(), StockDataError> {
+    let mut tickers: Vec<&String> = stock_data.keys().collect();
+    tickers.sort();
+    let mut writer = csv::Writer::from_path(path).map_err(cache_error(path))?;
+    for record in tickers.into_iter().flat_map(|ticker| &stock_data[ticker]) {
+        writer.serialize(record).map_err(cache_error(path))?;
+    }
+    writer.flush().map_err(|source| StockDataError::Io {
+        path: path.to_string(),
+        source,
+    })
+}
+
+/// Reads records written by `save_cache`, grouped by ticker in year order.
+pub fn load_cache(path: &str) -> Result<CachedRecords, StockDataError> {
+    let mut reader = csv::Reader::from_path(path).map_err(cache_error(path))?;
+    let mut stock_data = CachedRecords::new();
+    for record in reader.deserialize() {
+        let record: StockData = record.map_err(cache_error(path))?;
+        stock_data.entry(record.ticker.clone()).or_default().push(record);
+    }
+    for records in stock_data.values_mut() {
+        records.sort_by_key(|record| record.year);
+    }
+    Ok(stock_data)
+}
+
+// `values` without the ticker-years the cache has. Tickers stay listed even
+// when none of their years is new, so the join sees the same ticker sets.
+fn new_years(values: &YearlyValues, cached: &HashMap<String, HashSet<u32>>) -> YearlyValues {
+    values
+        .iter()
+        .map(|(ticker, years)| {
+            let known = cached.get(ticker);
+            let years = years
+                .iter()
+                .filter(|(year, _)| !known.is_some_and(|known| known.contains(year)))
+                .map(|(&year, &value)| (year, value))
+                .collect();
+            (ticker.clone(), years)
+        })
+        .collect()
+}
+
+/// Adds the ticker-years the source files have and `cached` lacks, and
+/// refreshes the price change and volatility of every record. The load report
+/// covers the join of the added ticker-years.
+pub fn update_stock_data(
+    mut cached: CachedRecords,
+    financial_files: &[(&str, &str)],
+    price_files: &[&str],
+    options: &LoadOptions,
+) -> Result<(CachedRecords, LoadReport, CacheReport), StockDataError> {
+    let cached_years: HashMap<String, HashSet<u32>> = cached
+        .iter()
+        .map(|(ticker, records)| (ticker.clone(), records.iter().map(|record| record.year).collect()))
+        .collect();
+    let (price_changes, volatilities, split_adjusted) = load_price_files(price_files, options)?;
+    let (metrics, unavailable) = load_financial_files(financial_files, options)?;
+    let metrics: Vec<YearlyValues> = metrics.iter().map(|values| new_years(values, &cached_years)).collect();
+    let (fresh, load_report) = combine_stock_data(
+        [&metrics[0], &metrics[1], &metrics[2], &metrics[3], &metrics[4]],
+        cash_flow_metrics(&metrics),
+        &unavailable,
+        &price_changes,
+        Some(&volatilities),
+        options,
+    )?;
+
+    let mut report = CacheReport {
+        cached_records: cached.values().map(Vec::len).sum(),
+        ..Default::default()
+    };
+    for records in cached.values_mut() {
+        for record in records.iter_mut() {
+            let price_change = horizon_price_change(&price_changes, &record.ticker, record.year, options.horizon);
+            let volatility = volatilities.get(&record.ticker).and_then(|years| years.get(&record.year)).copied();
+            let changed = price_change.to_bits() != record.price_change.to_bits()
+                || volatility.map(f64::to_bits) != record.price_volatility.map(f64::to_bits);
+            report.refreshed_prices += usize::from(changed);
+            record.price_change = price_change;
+            record.price_volatility = volatility;
+        }
+    }
+    for (ticker, new_records) in fresh {
+        if new_records.is_empty() {
+            continue;
+        }
+        report.new_records += new_records.len();
+        let records = cached.entry(ticker).or_default();
+        let first_new_year = new_records[0].year;
+        records.extend(new_records);
+        records.sort_by_key(|record| record.year);
+        let first_new = records.iter().position(|record| record.year == first_new_year).unwrap_or(0);
+        fill_changes(records, first_new, options.gap_policy);
+    }
+    mark_split_adjusted(&mut cached, &split_adjusted);
+    Ok((cached, load_report, report))
+}
+
+/// The records for these files: updated from the cache at `path` when it was
+/// written for the same files and options, built from scratch otherwise. The
+/// result is written back to the cache either way.
+pub fn load_or_update(
+    path: &str,
+    financial_files: &[(&str, &str)],
+    price_files: &[&str],
+    options: &LoadOptions,
+) -> Result<(CachedRecords, LoadReport, CacheReport), StockDataError> {
+    let key = cache_key(financial_files, price_files, options);
+    let stored_key = std::fs::read_to_string(key_path(path)).ok();
+    let (stock_data, load_report, report) = if !Path::new(path).exists() {
+        let (stock_data, load_report) = process_stock_data_with_report(financial_files, price_files, options)?;
+        (stock_data, load_report, CacheReport { rebuilt: Some("no cache yet".to_string()), ..Default::default() })
+    } else if stored_key.as_deref() != Some(key.as_str()) {
+        let (stock_data, load_report) = process_stock_data_with_report(financial_files, price_files, options)?;
+        let reason = "the cache was written for other files or options".to_string();
+        (stock_data, load_report, CacheReport { rebuilt: Some(reason), ..Default::default() })
+    } else {
+        update_stock_data(load_cache(path)?, financial_files, price_files, options)?
+    };
+    save_cache(path, &stock_data)?;
+    std::fs::write(key_path(path), key).map_err(|source| StockDataError::Io {
+        path: key_path(path),
+        source,
+    })?;
+    Ok((stock_data, load_report, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stock_data::process_stock_data;
+    use crate::test_util::{fixture_path, write_fixture};
+
+    // One wide file per metric for the years listed, newest first as the exports are
+    fn financial_files(name: &str, years: &[u32]) -> Vec<(String, &'static str)> {
+        let header: Vec<String> = years.iter().map(u32::to_string).collect();
+        let metrics = [("assets", 400.0), ("cash", 40.0), ("equity", 200.0), ("profit", 30.0), ("revenue", 100.0)];
+        metrics
+            .iter()
+            .map(|(metric, base)| {
+                let mut contents = format!("Ticker,{}\n", header.join(","));
+                for (t, ticker) in ["AAA", "BBB", "CCC"].iter().enumerate() {
+                    let value = |year: u32| base * (1.0 + t as f64) + ((year * 7 + t as u32 * 3) % 11) as f64;
+                    let values: Vec<String> = years.iter().map(|&year| value(year).to_string()).collect();
+                    contents.push_str(&format!("{},{}\n", ticker, values.join(",")));
+                }
+                (write_fixture(&format!("cache_{}_{}.csv", name, metric), &contents), *metric)
+            })
+            .collect()
+    }
+
+    // Monthly prices through December of `last_year`; the last month is missing
+    // when `partial` so the new file completes it
+    fn price_file(name: &str, last_year: u32, partial: bool) -> String {
+        let mut contents = String::from(",Date,AAA,BBB,CCC\n");
+        let mut row = 0;
+        for year in 2018..=last_year {
+            for month in 1..=12 {
+                if partial && year == last_year && month == 12 {
+                    continue;
+                }
+                let step = ((year - 2018) * 12 + month) as f64;
+                let prices = (10.0 + step, 50.0 - step * 0.3, 20.0 + (step * 1.7) % 9.0);
+                let (a, b, c) = prices;
+                contents.push_str(&format!("{},{}-{:02}-15,{},{},{}\n", row, year, month, a, b, c));
+                row += 1;
+            }
+        }
+        write_fixture(&format!("cache_{}_prices.csv", name), &contents)
+    }
+
+    #[test]
+    fn test_incremental_update_matches_full_rebuild() {
+        let options = LoadOptions { horizon: 2, ..Default::default() };
+        let pairs = |files: &[(String, &'static str)]| -> Vec<(String, String)> {
+            files.iter().map(|(path, metric)| (path.clone(), metric.to_string())).collect()
+        };
+        let old = pairs(&financial_files("old", &[2021, 2020, 2019, 2018]));
+        let old_files: Vec<(&str, &str)> = old.iter().map(|(path, metric)| (path.as_str(), metric.as_str())).collect();
+        let old_prices = price_file("old", 2021, true);
+        let cached = process_stock_data(&old_files, &[&old_prices], &options).unwrap();
+
+        let path = fixture_path("cache_records.csv");
+        let path = path.to_str().unwrap();
+        save_cache(path, &cached).unwrap();
+        let reloaded = load_cache(path).unwrap();
+
+        // One more year of fundamentals, and prices through the end of 2022
+        let new = pairs(&financial_files("new", &[2022, 2021, 2020, 2019, 2018]));
+        let new_files: Vec<(&str, &str)> = new.iter().map(|(path, metric)| (path.as_str(), metric.as_str())).collect();
+        let new_prices = price_file("new", 2022, false);
+        let (updated, _, report) = update_stock_data(reloaded, &new_files, &[&new_prices], &options).unwrap();
+        let rebuilt = process_stock_data(&new_files, &[&new_prices], &options).unwrap();
+
+        assert_eq!(report.new_records, 3);
+        assert_eq!(report.cached_records, 12);
+        // 2020 (horizon into 2021) and 2021 (its December, and its horizon into 2022) per ticker
+        assert_eq!(report.refreshed_prices, 6);
+        let rows = |stock_data: &HashMap<String, Vec<StockData>>| {
+            let mut tickers: Vec<&String> = stock_data.keys().collect();
+            tickers.sort();
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for record in tickers.into_iter().flat_map(|ticker| &stock_data[ticker]) {
+                writer.serialize(record).unwrap();
+            }
+            String::from_utf8(writer.into_inner().unwrap()).unwrap()
+        };
+        assert_eq!(rows(&updated), rows(&rebuilt));
+        assert!(updated["AAA"].last().unwrap().change_in_revenue.is_some());
+    }
+}
@@ -0,0 +1,169 @@
+//! Conversion of the financial files' values to USD for universes that mix
+//! listings reporting in different currencies. Every metric is monetary, so
+//! each ticker-year value is multiplied by its currency's rate for that year
+//! before any ratio or year-over-year change is computed from it.
+use std::collections::HashMap;
+use crate::stock_data::{normalize_ticker, open_csv, StockDataError, YearlyValues};
+
+/// Currency tickers not listed in the currencies file report in.
+pub const BASE_CURRENCY: &str = "USD";
+
+/// What to do with a ticker-year whose currency has no rate for that year.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingRate {
+    /// Stop loading with an error naming the currency and year
+    #[default]
+    Error,
+    /// Leave the ticker-year out of every metric and warn
+    Exclude,
+}
+
+/// Currency code per ticker from a `ticker,currency` file.
+pub fn read_currencies(path: &str) -> Result<HashMap<String, String>, StockDataError> {
+    let mut reader = open_csv(path)?;
+    let mut currencies = HashMap::new();
+    for result in reader.records() {
+        let record = result.map_err(|source| StockDataError::Csv {
+            path: path.to_string(),
+            source,
+        })?;
+        let ticker = normalize_ticker(record.get(0).unwrap_or(""));
+        let currency = record.get(1).unwrap_or("").trim().to_ascii_uppercase();
+        if !ticker.is_empty() && !currency.is_empty() {
+            currencies.insert(ticker, currency);
+        }
+    }
+    Ok(currencies)
+}
+
+/// USD per unit of each currency by year, from a `currency,year,rate` file.
+pub fn read_fx_rates(path: &str) -> Result<HashMap<(String, u32), f64>, StockDataError> {
+    let mut reader = open_csv(path)?;
+    let mut rates = HashMap::new();
+    for result in reader.records() {
+        let record = result.map_err(|source| StockDataError::Csv {
+            path: path.to_string(),
+            source,
+        })?;
+        let currency = record.get(0).unwrap_or("").trim().to_ascii_uppercase();
+        let year = record.get(1).and_then(|year| year.trim().parse().ok());
+        let rate = record.get(2).and_then(|rate| rate.trim().parse::<f64>().ok());
+        match (year, rate) {
+            (Some(year), Some(rate)) if rate.is_finite() && rate > 0.0 => {
+                rates.insert((currency, year), rate);
+            }
+            _ => {
+                let fields: Vec<&str> = record.iter().collect();
+                return Err(StockDataError::ColumnType {
+                    path: path.to_string(),
+                    column: "rate".to_string(),
+                    message: format!("`{}` is not a currency, year and positive rate", fields.join(",")),
+                })
+            }
+        }
+    }
+    Ok(rates)
+}
+
+/// Multiplies every value of a non-USD ticker by its currency's rate for the
+/// value's year. Ticker-years without a rate are an error, or under
+/// `MissingRate::Exclude` are removed from every metric and returned sorted.
+pub fn convert_to_usd(
+    metrics: &mut [YearlyValues],
+    currencies: &HashMap<String, String>,
+    rates: &HashMap<(String, u32), f64>,
+    missing: MissingRate,
+) -> Result<Vec<(String, u32)>, StockDataError> {
+    let mut excluded = Vec::new();
+    for (ticker, currency) in currencies {
+        if currency == BASE_CURRENCY {
+            continue;
+        }
+        let mut years: Vec<u32> =
+            metrics.iter().filter_map(|metric| metric.get(ticker)).flat_map(|years| years.keys().copied()).collect();
+        years.sort();
+        years.dedup();
+        for year in years {
+            match (rates.get(&(currency.clone(), year)), missing) {
+                (Some(rate), _) => {
+                    for value in metrics.iter_mut().filter_map(|metric| metric.get_mut(ticker)?.get_mut(&year)) {
+                        *value *= rate;
+                    }
+                }
+                (None, MissingRate::Error) => {
+                    return Err(StockDataError::MissingFxRate {
+                        ticker: ticker.clone(),
+                        currency: currency.clone(),
+                        year,
+                    })
+                }
+                (None, MissingRate::Exclude) => {
+                    for years in metrics.iter_mut().filter_map(|metric| metric.get_mut(ticker)) {
+                        years.remove(&year);
+                    }
+                    excluded.push((ticker.clone(), year));
+                }
+            }
+        }
+    }
+    excluded.sort();
+    Ok(excluded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stock_data::{process_stock_data, LoadOptions};
+    use crate::test_util::write_fixture;
+
+    #[test]
+    fn test_eur_ticker_deltas_in_usd() {
+        // SAP reports in EUR, AAPL in USD; the same numbers for both
+        let metric = |name: &str| {
+            let contents = "Ticker,2022,2021,2020\nAAPL,120,110,100\nSAP,120,110,100\n";
+            (write_fixture(&format!("fx_{}.csv", name), contents), name.to_string())
+        };
+        let files: Vec<(String, String)> = ["assets", "cash", "equity", "profit", "revenue"].map(metric).to_vec();
+        let files: Vec<(&str, &str)> = files.iter().map(|(path, name)| (path.as_str(), name.as_str())).collect();
+        let prices = write_fixture("fx_prices.csv", ",Date,AAPL,SAP\n0,2021-01-15,10,10\n1,2021-12-15,11,11\n");
+        let currencies = write_fixture("fx_currencies.csv", "ticker,currency\nSAP,eur\nAAPL,USD\n");
+        let rates = write_fixture("fx_rates.csv", "currency,year,rate\nEUR,2020,1.25\nEUR,2021,1.5\nEUR,2022,1.0\n");
+        let options = LoadOptions {
+            currency_file: Some(currencies.clone()),
+            fx_rates_file: Some(rates),
+            ..Default::default()
+        };
+        let stock_data = process_stock_data(&files, &[&prices], &options).unwrap();
+        let mut sap = stock_data["SAP"].clone();
+        sap.sort_by_key(|record| record.year);
+
+        // 100 EUR at 1.25 is 125 USD, 110 at 1.5 is 165, 120 at 1.0 is 120
+        let revenues: Vec<f64> = sap.iter().map(|record| record.revenue).collect();
+        assert_eq!(revenues, [125.0, 165.0, 120.0]);
+        assert_eq!(sap[1].change_in_revenue, Some(40.0));
+        assert_eq!(sap[2].change_in_revenue, Some(-45.0));
+        assert_eq!((sap[0].assets, sap[0].cash, sap[0].equity, sap[0].profit), (125.0, 125.0, 125.0, 125.0));
+        // Ratios of two converted values do not move
+        assert_eq!(sap[1].profit_margin, Some(1.0));
+        let aapl = stock_data["AAPL"].iter().find(|record| record.year == 2021).unwrap();
+        assert_eq!(aapl.change_in_revenue, Some(10.0));
+
+        let partial = write_fixture("fx_rates_partial.csv", "currency,year,rate\nEUR,2021,1.5\nEUR,2022,1.0\n");
+        let missing = LoadOptions {
+            fx_rates_file: Some(partial),
+            ..options.clone()
+        };
+        let err = process_stock_data(&files, &[&prices], &missing).unwrap_err();
+        assert!(matches!(err, StockDataError::MissingFxRate { ref currency, year: 2020, .. } if currency == "EUR"));
+        let excluded = LoadOptions {
+            missing_fx_rate: MissingRate::Exclude,
+            ..missing
+        };
+        let stock_data = process_stock_data(&files, &[&prices], &excluded).unwrap();
+        let mut years: Vec<u32> = stock_data["SAP"].iter().map(|record| record.year).collect();
+        years.sort();
+        assert_eq!(years, [2021, 2022]);
+        assert_eq!(stock_data["AAPL"].len(), 3);
+    }
+}
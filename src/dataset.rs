@@ -0,0 +1,1210 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use smartcore::linalg::basic::matrix::DenseMatrix;
+use crate::metrics::ColumnStats;
+use crate::stock_data::{open_csv, StockData, StockDataError, CASH_FLOW_METRICS, PRICE_VOLATILITY};
+
+pub const N_CLASSES: usize = 4;
+
+// Column order of the rows built by `prepare_dataset`
+pub const FEATURE_NAMES: [&str; 15] = [
+    "delta_revenue",
+    "delta_profit_margin",
+    "delta_roa",
+    "delta_cash_to_assets",
+    "delta_equity_to_assets",
+    "cash_to_assets",
+    "equity_to_assets",
+    "delta_revenue*delta_profit_margin",
+    "cash_to_revenue",
+    "delta_cash_to_revenue",
+    "delta_roe",
+    "free_cash_flow",
+    "fcf_margin",
+    "delta_fcf_margin",
+    "prior_price_volatility",
+];
+
+/// Datasets a model cannot be trained on, each with a hint on what to relax.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DatasetError {
+    EmptyDataset,
+    SingleClassTraining { class: u8 },
+    TooFewRows { got: usize, needed: usize },
+    BelowMinRows { got: usize, min_rows: usize },
+}
+
+impl fmt::Display for DatasetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatasetError::EmptyDataset => write!(
+                f,
+                "no feature rows survived loading and filtering; relax --sanity-filters, --outlier drop \
+                 or --gap-policy skip, or add years of data"
+            ),
+            DatasetError::SingleClassTraining { class } => write!(
+                f,
+                "every training row has class {}, so there is nothing to tell apart; \
+                 move the label thresholds or train on more tickers and years",
+                class
+            ),
+            DatasetError::TooFewRows { got, needed } => write!(
+                f,
+                "{} training rows, but a split needs at least {} (min_samples_split); \
+                 lower --min-samples-split, hold out fewer rows or relax the filters",
+                got, needed
+            ),
+            DatasetError::BelowMinRows { got, min_rows } => write!(
+                f,
+                "only {} usable rows after joining and filtering (--min-rows {}); check that the tickers \
+                 and years match across the financial and price files, or relax the filters",
+                got, min_rows
+            ),
+        }
+    }
+}
+
+impl Error for DatasetError {}
+
+/// The ticker-year a feature row was built from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RowId {
+    pub ticker: String,
+    pub year: u32,
+    pub price_change: f64, // the percent change the label was derived from
+    // The record before's price change when it is the previous year's and has
+    // one; it spans the same horizon, so past one year it overlaps this row's
+    pub prior_price_change: Option<f64>,
+}
+
+/// Prepared feature rows, kept in one row-major buffer until a model needs a
+/// matrix so that columns can still be dropped or rows subset. The number of
+/// columns is always `feature_names.len()`.
+#[derive(Debug, Clone)]
+pub struct Dataset {
+    pub feature_names: Vec<String>,
+    pub values: Vec<f64>, // `len() * n_features()` values, one row after another
+    pub labels: Vec<u8>,
+    pub rows: Vec<RowId>, // aligned with the rows of `values` and with `labels`
+}
+
+impl Dataset {
+    /// Builds a dataset from one vector per row; every row must have one value per name.
+    pub fn from_rows(feature_names: Vec<String>, features: &[Vec<f64>], labels: Vec<u8>, rows: Vec<RowId>) -> Dataset {
+        assert!(features.iter().all(|row| row.len() == feature_names.len()), "row width differs from feature names");
+        Dataset {
+            feature_names,
+            values: features.concat(),
+            labels,
+            rows,
+        }
+    }
+
+    pub fn n_features(&self) -> usize {
+        self.feature_names.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    pub fn row(&self, i: usize) -> &[f64] {
+        let n = self.n_features();
+        &self.values[i * n..(i + 1) * n]
+    }
+
+    pub fn row_mut(&mut self, i: usize) -> &mut [f64] {
+        let n = self.n_features();
+        &mut self.values[i * n..(i + 1) * n]
+    }
+
+    /// The feature rows in order, each `n_features()` long.
+    pub fn feature_rows(&self) -> impl Iterator<Item = &[f64]> + Clone {
+        (0..self.len()).map(|i| self.row(i))
+    }
+
+    /// Every row's value of feature `j`.
+    pub fn column(&self, j: usize) -> impl Iterator<Item = f64> + Clone + '_ {
+        self.feature_rows().map(move |row| row[j])
+    }
+
+    pub fn column_stats(&self) -> Vec<ColumnStats> {
+        self.feature_names.iter().enumerate().map(|(j, name)| ColumnStats::of(name, self.column(j))).collect()
+    }
+
+    /// Checks that a model fit on these rows can split at all: there are rows,
+    /// at least `min_samples_split` of them, and more than one class.
+    pub fn check_trainable(&self, min_samples_split: usize) -> Result<(), DatasetError> {
+        if self.is_empty() {
+            return Err(DatasetError::EmptyDataset);
+        }
+        if self.len() < min_samples_split {
+            return Err(DatasetError::TooFewRows {
+                got: self.len(),
+                needed: min_samples_split,
+            });
+        }
+        if self.labels.iter().all(|&label| label == self.labels[0]) {
+            return Err(DatasetError::SingleClassTraining { class: self.labels[0] });
+        }
+        Ok(())
+    }
+
+    /// The feature matrix, built from the row-major buffer in one copy.
+    pub fn to_matrix(&self) -> DenseMatrix<f64> {
+        DenseMatrix::new(self.len(), self.n_features(), self.values.clone(), false)
+    }
+
+    pub fn feature_index(&self, name: &str) -> Option<usize> {
+        self.feature_names.iter().position(|n| n == name)
+    }
+
+    /// Copy of the dataset with one column removed from every row and from the names.
+    pub fn without_feature(&self, index: usize) -> Dataset {
+        let kept: Vec<usize> = (0..self.n_features()).filter(|&j| j != index).collect();
+        self.select_columns(&kept)
+    }
+
+    /// Copy of the dataset without the named columns; names it does not have are ignored.
+    pub fn without_features(&self, names: &[String]) -> Dataset {
+        let kept: Vec<usize> = (0..self.n_features())
+            .filter(|&j| !names.contains(&self.feature_names[j]))
+            .collect();
+        self.select_columns(&kept)
+    }
+
+    /// Copy of the dataset with only the given columns, in the given order.
+    pub fn select_columns(&self, columns: &[usize]) -> Dataset {
+        Dataset {
+            feature_names: columns.iter().map(|&j| self.feature_names[j].clone()).collect(),
+            values: self.feature_rows().flat_map(|row| columns.iter().map(|&j| row[j])).collect(),
+            labels: self.labels.clone(),
+            rows: self.rows.clone(),
+        }
+    }
+
+    pub fn subset(&self, indices: &[usize]) -> Dataset {
+        Dataset {
+            feature_names: self.feature_names.clone(),
+            values: indices.iter().flat_map(|&i| self.row(i).iter().copied()).collect(),
+            labels: indices.iter().map(|&i| self.labels[i]).collect(),
+            rows: indices.iter().map(|&i| self.rows[i].clone()).collect(),
+        }
+    }
+
+    /// The rows in an order drawn from `seed`; the same seed always gives the
+    /// same order of the same rows.
+    pub fn shuffled(&self, seed: u64) -> Dataset {
+        let mut indices: Vec<usize> = (0..self.len()).collect();
+        indices.shuffle(&mut StdRng::seed_from_u64(seed));
+        self.subset(&indices)
+    }
+
+    /// Shuffles the rows with `seed` and returns `(train, test)`, the first
+    /// `test_size` of the shuffled rows held out.
+    pub fn train_test_split(&self, test_size: f64, seed: u64) -> (Dataset, Dataset) {
+        let shuffled = self.shuffled(seed);
+        let n_test = (self.len() as f64 * test_size) as usize;
+        let indices: Vec<usize> = (0..self.len()).collect();
+        (shuffled.subset(&indices[n_test..]), shuffled.subset(&indices[..n_test]))
+    }
+
+    /// Adds `other`'s rows after these; both must have the same columns.
+    pub fn append(&mut self, other: Dataset) {
+        assert_eq!(self.feature_names, other.feature_names, "appended rows have other columns");
+        self.values.extend(other.values);
+        self.labels.extend(other.labels);
+        self.rows.extend(other.rows);
+    }
+
+    /// Reads rows written by `export_features` back. Their price changes are
+    /// not in the file and are left NaN.
+    pub fn read_features(path: &str) -> Result<Dataset, StockDataError> {
+        let mut reader = open_csv(path)?;
+        let csv_error = |source| StockDataError::Csv {
+            path: path.to_string(),
+            source,
+        };
+        let error = |column: &str, message: String| StockDataError::ColumnType {
+            path: path.to_string(),
+            column: column.to_string(),
+            message,
+        };
+        let header: Vec<String> = reader.headers().map_err(csv_error)?.iter().map(str::to_string).collect();
+        let n = header.len();
+        if n < 3 || header[0] != "ticker" || header[1] != "year" || header[n - 1] != "label" {
+            return Err(error("label", format!("`{}` is not ticker,year,<features>,label", header.join(","))));
+        }
+        let mut dataset = Dataset {
+            feature_names: header[2..n - 1].to_vec(),
+            values: Vec::new(),
+            labels: Vec::new(),
+            rows: Vec::new(),
+        };
+        for result in reader.records() {
+            let record = result.map_err(csv_error)?;
+            let year = record[1].trim().parse().map_err(|_| error("year", format!("`{}` is not a year", &record[1])))?;
+            for (name, value) in header[2..n - 1].iter().zip(record.iter().skip(2)) {
+                let value = value.trim().parse().map_err(|_| error(name, format!("`{}` is not a number", value)))?;
+                dataset.values.push(value);
+            }
+            let label = &record[n - 1];
+            let label = label.trim().parse().map_err(|_| error("label", format!("`{}` is not a class", label)))?;
+            dataset.labels.push(label);
+            dataset.rows.push(RowId {
+                ticker: record[0].to_string(),
+                year,
+                price_change: f64::NAN,
+                prior_price_change: None,
+            });
+        }
+        Ok(dataset)
+    }
+
+    /// Writes `ticker,year,<feature names...>,label`, one line per row, exactly as the model sees it.
+    pub fn export_features(&self, path: &str) -> Result<(), csv::Error> {
+        let mut writer = csv::Writer::from_path(path)?;
+        let mut header = vec!["ticker", "year"];
+        header.extend(self.feature_names.iter().map(String::as_str));
+        header.push("label");
+        writer.write_record(&header)?;
+
+        for ((row, values), label) in self.rows.iter().zip(self.feature_rows()).zip(&self.labels) {
+            let mut record = vec![row.ticker.clone(), row.year.to_string()];
+            record.extend(values.iter().map(|value| value.to_string()));
+            record.push(label.to_string());
+            writer.write_record(&record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the rows in svmlight format (`label index:value ...`, 1-based
+    /// indices, zeros left out) to `path`, the feature name of each index to
+    /// `<path>.features` and the ticker and year of each line to `<path>.meta`.
+    /// Values are written in the shortest form that parses back to the same f64.
+    pub fn export_libsvm(&self, path: &str) -> Result<(), csv::Error> {
+        let mut lines = String::new();
+        for (values, label) in self.feature_rows().zip(&self.labels) {
+            lines.push_str(&label.to_string());
+            for (j, value) in values.iter().enumerate().filter(|(_, value)| **value != 0.0) {
+                lines.push_str(&format!(" {}:{}", j + 1, value));
+            }
+            lines.push('\n');
+        }
+        std::fs::write(path, lines)?;
+
+        let mut features = csv::Writer::from_path(format!("{}.features", path))?;
+        features.write_record(["index", "feature"])?;
+        for (j, name) in self.feature_names.iter().enumerate() {
+            features.write_record([(j + 1).to_string(), name.clone()])?;
+        }
+        features.flush()?;
+
+        let mut meta = csv::Writer::from_path(format!("{}.meta", path))?;
+        meta.write_record(["row", "ticker", "year"])?;
+        for (i, row) in self.rows.iter().enumerate() {
+            meta.write_record([(i + 1).to_string(), row.ticker.clone(), row.year.to_string()])?;
+        }
+        meta.flush()?;
+        Ok(())
+    }
+}
+
+/// One feature column, computed from a record and the record before it.
+/// `prepare_dataset_with` takes a list of these, so features can be added
+/// without touching the built-in ones.
+pub trait FeatureExtractor {
+    /// The column name.
+    fn name(&self) -> &str;
+
+    /// Financial metrics the value is computed from. When the loader marked one
+    /// of them unavailable the column is left out of the dataset.
+    fn metrics(&self) -> &[&str] {
+        &[]
+    }
+
+    /// The value for `current`, or `None` when it is undefined for this pair of
+    /// records, which leaves the row out.
+    fn extract(&self, current: &StockData, previous: &StockData) -> Option<f64>;
+}
+
+// A built-in `FEATURE_NAMES` column
+#[derive(Clone, Copy)]
+struct BuiltinFeature {
+    name: &'static str,
+    metrics: &'static [&'static str],
+    extract: fn(&StockData, &StockData) -> Option<f64>,
+}
+
+impl FeatureExtractor for BuiltinFeature {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn metrics(&self) -> &[&str] {
+        self.metrics
+    }
+
+    fn extract(&self, current: &StockData, previous: &StockData) -> Option<f64> {
+        (self.extract)(current, previous)
+    }
+}
+
+// Ratio changes follow the loader's gap policy: per year when it normalized
+// the changes, otherwise over the whole span
+fn years_elapsed(current: &StockData, previous: &StockData) -> f64 {
+    if current.changes_normalized {
+        (current.year - previous.year) as f64
+    } else {
+        1.0
+    }
+}
+
+// A ratio or ratio change the loader left undefined (`None`, see `Ratio`)
+// becomes NaN, so the non-finite policy drops or imputes the row like any other
+// missing value rather than the row being skipped here or the value read as 0
+fn undefined_as_nan(value: Option<f64>) -> f64 {
+    value.unwrap_or(f64::NAN)
+}
+
+fn unavailable(record: &StockData, metrics: &[&str]) -> bool {
+    record.unavailable.iter().any(|metric| metrics.contains(&metric.as_str()))
+}
+
+// Cash-burn proxy; 0 when the feature is dropped anyway for a missing file
+fn cash_to_revenue(record: &StockData) -> f64 {
+    if unavailable(record, &["cash", "revenue"]) {
+        return 0.0;
+    }
+    undefined_as_nan(record.cash_to_revenue)
+}
+
+// Free cash flow, only when both cash-flow files were supplied; a year missing
+// from them or with an undefined margin is missing like an undefined ratio
+fn cash_flow_value(record: &StockData, value: Option<f64>) -> f64 {
+    if unavailable(record, &CASH_FLOW_METRICS) {
+        return 0.0;
+    }
+    undefined_as_nan(value)
+}
+
+// In `FEATURE_NAMES` order
+const BUILTIN_FEATURES: [BuiltinFeature; FEATURE_NAMES.len()] = [
+    BuiltinFeature {
+        name: FEATURE_NAMES[0],
+        metrics: &["revenue"],
+        extract: |current, _| current.change_in_revenue,
+    },
+    BuiltinFeature {
+        name: FEATURE_NAMES[1],
+        metrics: &["profit", "revenue"],
+        extract: |current, _| Some(undefined_as_nan(current.change_in_profit_margin)),
+    },
+    BuiltinFeature {
+        name: FEATURE_NAMES[2],
+        metrics: &["profit", "revenue", "assets"],
+        extract: |current, _| Some(undefined_as_nan(current.change_in_roa)),
+    },
+    BuiltinFeature {
+        name: FEATURE_NAMES[3],
+        metrics: &["cash", "assets"],
+        extract: |current, previous| {
+            let change = undefined_as_nan(current.cash_to_assets) - undefined_as_nan(previous.cash_to_assets);
+            Some(change / years_elapsed(current, previous))
+        },
+    },
+    BuiltinFeature {
+        name: FEATURE_NAMES[4],
+        metrics: &["equity", "assets"],
+        extract: |current, previous| {
+            let change = undefined_as_nan(current.equity_to_assets) - undefined_as_nan(previous.equity_to_assets);
+            Some(change / years_elapsed(current, previous))
+        },
+    },
+    // Levels, so 5% -> 10% and 50% -> 55% differ
+    BuiltinFeature {
+        name: FEATURE_NAMES[5],
+        metrics: &["cash", "assets"],
+        extract: |current, _| Some(undefined_as_nan(current.cash_to_assets)),
+    },
+    BuiltinFeature {
+        name: FEATURE_NAMES[6],
+        metrics: &["equity", "assets"],
+        extract: |current, _| Some(undefined_as_nan(current.equity_to_assets)),
+    },
+    // Interaction
+    BuiltinFeature {
+        name: FEATURE_NAMES[7],
+        metrics: &["profit", "revenue"],
+        extract: |current, _| Some(current.change_in_revenue? * undefined_as_nan(current.change_in_profit_margin)),
+    },
+    BuiltinFeature {
+        name: FEATURE_NAMES[8],
+        metrics: &["cash", "revenue"],
+        extract: |current, _| Some(cash_to_revenue(current)),
+    },
+    BuiltinFeature {
+        name: FEATURE_NAMES[9],
+        metrics: &["cash", "revenue"],
+        extract: |current, previous| {
+            let change = cash_to_revenue(current) - cash_to_revenue(previous);
+            Some(change / years_elapsed(current, previous))
+        },
+    },
+    BuiltinFeature {
+        name: FEATURE_NAMES[10],
+        metrics: &["profit", "equity"],
+        extract: |current, _| Some(undefined_as_nan(current.change_in_roe)),
+    },
+    BuiltinFeature {
+        name: FEATURE_NAMES[11],
+        metrics: &["operating_cashflow", "capex"],
+        extract: |current, _| Some(cash_flow_value(current, current.free_cash_flow)),
+    },
+    BuiltinFeature {
+        name: FEATURE_NAMES[12],
+        metrics: &["operating_cashflow", "capex", "revenue"],
+        extract: |current, _| Some(cash_flow_value(current, current.fcf_margin)),
+    },
+    BuiltinFeature {
+        name: FEATURE_NAMES[13],
+        metrics: &["operating_cashflow", "capex", "revenue"],
+        extract: |current, _| Some(cash_flow_value(current, current.change_in_fcf_margin)),
+    },
+    // The previous year's volatility, so nothing from the label year leaks in.
+    // NaN when that year had too few months of prices; the non-finite policy
+    // then drops or imputes the row.
+    BuiltinFeature {
+        name: FEATURE_NAMES[14],
+        metrics: &[PRICE_VOLATILITY],
+        extract: |_, previous| Some(undefined_as_nan(previous.price_volatility)),
+    },
+];
+
+/// Column `GapPolicy::Annotate` adds: the years a row's changes span, so the
+/// model can tell a change over a missing year from a year-over-year one.
+pub const GAP_YEARS: &str = "gap_years";
+
+const GAP_YEARS_FEATURE: BuiltinFeature = BuiltinFeature {
+    name: GAP_YEARS,
+    metrics: &[],
+    extract: |current, _| Some(current.gap_years as f64),
+};
+
+/// The `FEATURE_NAMES` columns as extractors, in that order.
+pub fn builtin_extractors() -> Vec<Box<dyn FeatureExtractor>> {
+    BUILTIN_FEATURES.iter().map(|feature| Box::new(*feature) as Box<dyn FeatureExtractor>).collect()
+}
+
+fn builtin_feature(name: &str) -> Option<BuiltinFeature> {
+    BUILTIN_FEATURES.iter().find(|feature| feature.name == name).copied()
+}
+
+// The product of two built-in features, named `first*second`
+struct Interaction {
+    name: String,
+    factors: [BuiltinFeature; 2],
+    metrics: Vec<&'static str>,
+}
+
+impl Interaction {
+    fn new(first: BuiltinFeature, second: BuiltinFeature) -> Interaction {
+        let mut metrics = first.metrics.to_vec();
+        metrics.extend(second.metrics.iter().filter(|metric| !first.metrics.contains(metric)));
+        Interaction {
+            name: format!("{}*{}", first.name, second.name),
+            factors: [first, second],
+            metrics,
+        }
+    }
+}
+
+impl FeatureExtractor for Interaction {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn metrics(&self) -> &[&str] {
+        &self.metrics
+    }
+
+    fn extract(&self, current: &StockData, previous: &StockData) -> Option<f64> {
+        let [first, second] = &self.factors;
+        Some(first.extract(current, previous)? * second.extract(current, previous)?)
+    }
+}
+
+/// One `first*second` column per pair of the named built-in features, in list
+/// order. A feature is paired with itself only when `squares` is set, and pairs
+/// that already are a built-in column are skipped. Unknown names are ignored;
+/// the pipeline rejects them when it is built.
+pub fn interaction_extractors(features: &[String], squares: bool) -> Vec<Box<dyn FeatureExtractor>> {
+    let known: Vec<BuiltinFeature> = features.iter().filter_map(|name| builtin_feature(name)).collect();
+    let mut extractors: Vec<Box<dyn FeatureExtractor>> = Vec::new();
+    for (i, &first) in known.iter().enumerate() {
+        let start = if squares { i } else { i + 1 };
+        for &second in &known[start..] {
+            let interaction = Interaction::new(first, second);
+            if builtin_feature(&interaction.name).is_none() {
+                extractors.push(Box::new(interaction));
+            }
+        }
+    }
+    extractors
+}
+
+/// The extractor of a column `prepare_dataset_with` can produce: a built-in
+/// feature, `GAP_YEARS`, or an interaction of two built-in features.
+pub fn extractor_by_name(name: &str) -> Option<Box<dyn FeatureExtractor>> {
+    if name == GAP_YEARS {
+        return Some(Box::new(GAP_YEARS_FEATURE));
+    }
+    if let Some(feature) = builtin_feature(name) {
+        return Some(Box::new(feature));
+    }
+    let (first, second) = name.split_once('*')?;
+    Some(Box::new(Interaction::new(builtin_feature(first)?, builtin_feature(second)?)))
+}
+
+/// What `compute_feature_row` builds and asks of a record.
+pub struct FeatureConfig {
+    pub require_label: bool, // skip records whose price change has no class; forecast rows have none yet
+    pub extractors: Vec<Box<dyn FeatureExtractor>>, // one column each, in order
+}
+
+impl Default for FeatureConfig {
+    /// The built-in features, without requiring a label.
+    fn default() -> Self {
+        FeatureConfig {
+            require_label: false,
+            extractors: builtin_extractors(),
+        }
+    }
+}
+
+/// One ticker-year's values of `FeatureConfig::extractors`, in that order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureRow {
+    pub id: RowId,
+    pub values: Vec<f64>,
+    pub label: Option<u8>,
+}
+
+/// The feature row of `current`, with `previous` the record before it, or `None`
+/// when either is excluded, either lacks the year-over-year changes (no usable
+/// year before it), or `cfg` requires a label and the price change has none.
+/// Shared by training rows and forecast rows so both are built the same way.
+/// Undefined ratios come out as NaN and, like other NaN or infinite values, are
+/// kept for the non-finite policy.
+pub fn compute_feature_row(current: &StockData, previous: &StockData, cfg: &FeatureConfig) -> Option<FeatureRow> {
+    let label = categorize_price_change(current.price_change);
+    if (cfg.require_label && label.is_none())
+        || current.excluded
+        || previous.excluded
+        || previous.change_in_revenue.is_none()
+        || current.change_in_revenue.is_none()
+    {
+        return None;
+    }
+    let values = cfg.extractors.iter().map(|extractor| extractor.extract(current, previous)).collect::<Option<_>>()?;
+    Some(FeatureRow {
+        id: RowId {
+            ticker: current.ticker.clone(),
+            year: current.year,
+            price_change: current.price_change,
+            prior_price_change: (previous.year + 1 == current.year && previous.price_change.is_finite())
+                .then_some(previous.price_change),
+        },
+        values,
+        label,
+    })
+}
+
+/// What building the feature rows left out, for the caller to report.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DatasetReport {
+    pub unlabelled: usize, // rows whose price change is NaN or infinite (or lacks prices for the whole horizon)
+    pub volatility_dropped: bool, // no year has prices in enough months, so prior_price_volatility is left out
+    pub without_external_label: Vec<(String, u32)>, // (ticker, year) rows the labels file has no class for
+}
+
+/// Builds one row of the built-in features per record that has two years of
+/// history, ordered by ticker and then year. Features computed from a metric
+/// the loader marked unavailable are left out entirely.
+pub fn prepare_dataset(stock_data: &HashMap<String, Vec<StockData>>) -> Dataset {
+    prepare_dataset_with(stock_data, builtin_extractors()).0
+}
+
+/// `prepare_dataset` with one column per extractor instead of the built-in
+/// features, and what it left out.
+pub fn prepare_dataset_with(
+    stock_data: &HashMap<String, Vec<StockData>>,
+    extractors: Vec<Box<dyn FeatureExtractor>>,
+) -> (Dataset, DatasetReport) {
+    let cfg = FeatureConfig {
+        require_label: true,
+        extractors,
+    };
+    let mut values = Vec::new();
+    let mut labels = Vec::new();
+    let mut rows = Vec::new();
+    let mut unavailable: HashSet<&str> = HashSet::new();
+    let mut unlabelled = 0;
+    let mut volatility_seen = false;
+
+    // Tickers by name and each ticker's years ascending, so the rows come out in
+    // the same order on every run whatever the map's iteration order
+    let mut tickers: Vec<&String> = stock_data.keys().collect();
+    tickers.sort();
+    for ticker in tickers {
+        let mut records: Vec<&StockData> = stock_data[ticker].iter().collect();
+        records.sort_by_key(|record| record.year);
+        for i in 2..records.len() {
+            let (current, previous) = (records[i], records[i - 1]);
+            let Some(row) = compute_feature_row(current, previous, &cfg) else {
+                unlabelled += usize::from(categorize_price_change(current.price_change).is_none());
+                continue;
+            };
+
+            unavailable.extend(current.unavailable.iter().map(String::as_str));
+            volatility_seen |= previous.price_volatility.is_some();
+            values.extend_from_slice(&row.values);
+            labels.extend(row.label);
+            rows.push(row.id);
+        }
+    }
+
+    let uses_volatility = cfg.extractors.iter().any(|extractor| extractor.metrics().contains(&PRICE_VOLATILITY));
+    let volatility_dropped =
+        uses_volatility && !volatility_seen && !labels.is_empty() && !unavailable.contains(PRICE_VOLATILITY);
+    if volatility_dropped {
+        unavailable.insert(PRICE_VOLATILITY);
+    }
+
+    let dataset = Dataset {
+        feature_names: cfg.extractors.iter().map(|extractor| extractor.name().to_string()).collect(),
+        values,
+        labels,
+        rows,
+    };
+    let kept: Vec<usize> = (0..cfg.extractors.len())
+        .filter(|&j| !cfg.extractors[j].metrics().iter().any(|metric| unavailable.contains(metric)))
+        .collect();
+    let report = DatasetReport {
+        unlabelled,
+        volatility_dropped,
+        ..Default::default()
+    };
+    (dataset.select_columns(&kept), report)
+}
+
+/// Feature rows built from each ticker's latest record, to predict the year after it.
+#[derive(Debug, Clone)]
+pub struct ForecastRows {
+    pub feature_names: Vec<String>,
+    pub values: Vec<f64>,            // one row per entry of `rows`, laid out like `Dataset::values`
+    pub rows: Vec<RowId>,            // the latest record's ticker and year; its price change is not used
+    pub unforecastable: Vec<String>, // tickers whose latest record lacks the history or a finite feature
+}
+
+impl ForecastRows {
+    pub fn to_matrix(&self) -> DenseMatrix<f64> {
+        DenseMatrix::new(self.rows.len(), self.feature_names.len(), self.values.clone(), false)
+    }
+}
+
+/// Builds, per ticker sorted by name, the row its latest record would have as a
+/// training row, restricted to `feature_names` (the columns a model was fit on).
+pub fn prepare_forecast_rows(stock_data: &HashMap<String, Vec<StockData>>, feature_names: &[String]) -> ForecastRows {
+    let cfg = FeatureConfig {
+        require_label: false,
+        extractors: feature_names
+            .iter()
+            .map(|name| extractor_by_name(name).expect("a prepare_dataset feature"))
+            .collect(),
+    };
+    let mut tickers: Vec<&String> = stock_data.keys().collect();
+    tickers.sort();
+
+    let mut forecast = ForecastRows {
+        feature_names: feature_names.to_vec(),
+        values: Vec::new(),
+        rows: Vec::new(),
+        unforecastable: Vec::new(),
+    };
+    for ticker in tickers {
+        let row = match stock_data[ticker].as_slice() {
+            [.., previous, current] => compute_feature_row(current, previous, &cfg),
+            _ => None,
+        };
+        let selected = row
+            .map(|row| (row.values, row.id))
+            .filter(|(values, _)| values.iter().all(|value| value.is_finite()));
+        match selected {
+            Some((values, id)) => {
+                forecast.values.extend(values);
+                forecast.rows.push(id);
+            }
+            None => forecast.unforecastable.push(ticker.clone()),
+        }
+    }
+    forecast
+}
+
+/// Class of a percent price change over half-open buckets: `[-inf, -50)` is 0,
+/// `[-50, 0)` is 1, `[0, 50)` is 2 and `[50, inf)` is 3, so each boundary belongs
+/// to the class above it. `None` for NaN or infinite changes, which have no class.
+pub fn categorize_price_change(price_change: f64) -> Option<u8> {
+    if !price_change.is_finite() {
+        return None;
+    }
+    Some(match price_change {
+        pc if pc < -50.0 => 0,
+        pc if pc < 0.0 => 1,
+        pc if pc < 50.0 => 2,
+        _ => 3,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use smartcore::linalg::basic::arrays::Array;
+    use crate::model::{FittedModel, ForestConfig, ModelConfig, ModelKind};
+    use crate::nonfinite::{sanitize_features, NonFinitePolicy};
+    use crate::stock_data::{process_stock_data, LoadOptions};
+    use crate::synthetic::{ticker_records, SyntheticConfig};
+    use crate::test_util::fixture_path;
+
+    // How the matrix was built before the flat buffer: one vector per row, copied again
+    fn matrix_from_row_vectors(dataset: &Dataset) -> DenseMatrix<f64> {
+        let rows: Vec<Vec<f64>> = dataset.feature_rows().map(<[f64]>::to_vec).collect();
+        DenseMatrix::from_2d_vec(&rows)
+    }
+
+    #[test]
+    fn test_skip_missing_file_drops_its_features() {
+        let data = SyntheticConfig {
+            n_tickers: 1,
+            n_years: 4,
+            ..Default::default()
+        }
+        .generate();
+        let written = data.write_csvs(&fixture_path("skip_missing")).unwrap();
+        let prices = &written.price_file;
+        let mut files = written.financial_file_pairs();
+        files[1] = ("no_such_cash_file.csv", "cash");
+
+        assert!(process_stock_data(&files, &[prices], &LoadOptions::default()).is_err());
+
+        let options = LoadOptions {
+            skip_missing_files: true,
+            ..Default::default()
+        };
+        let stock_data = process_stock_data(&files, &[prices], &options).unwrap();
+        let dataset = prepare_dataset(&stock_data);
+
+        assert_eq!(dataset.len(), 2);
+        assert!(!dataset.feature_names.contains(&"delta_cash_to_assets".to_string()));
+        assert!(dataset.feature_names.contains(&"delta_equity_to_assets".to_string()));
+        assert_eq!(dataset.values.len(), dataset.len() * dataset.feature_names.len());
+    }
+
+    #[test]
+    fn test_change_calculations() {
+        let rows = [
+            (2020, [100.0, 10.0, 50.0, 5.0, 100.0], 0.0),
+            (2021, [200.0, 10.0, 60.0, 8.0, 100.0], 0.0),
+            (2022, [200.0, 50.0, 110.0, 12.0, 150.0], 20.0),
+        ];
+        let records = ticker_records("AAA", &rows);
+        let labelled = FeatureConfig {
+            require_label: true,
+            ..Default::default()
+        };
+        let row = compute_feature_row(&records[2], &records[1], &labelled).unwrap();
+        assert_eq!((row.id.ticker.as_str(), row.id.year, row.label), ("AAA", 2022, Some(2)));
+        assert_eq!(row.id.prior_price_change, Some(0.0));
+        let value = |name: &str| row.values[FEATURE_NAMES.iter().position(|known| *known == name).unwrap()];
+
+        assert_eq!(value("delta_revenue"), 50.0);
+        assert!((value("delta_profit_margin") - (12.0 / 150.0 - 8.0 / 100.0)).abs() < 1e-12);
+        assert!((value("delta_roa") - (12.0 / 200.0 - 8.0 / 200.0)).abs() < 1e-12);
+        assert!((value("delta_cash_to_assets") - (0.25 - 0.05)).abs() < 1e-12);
+        assert!((value("delta_equity_to_assets") - (0.55 - 0.3)).abs() < 1e-12);
+        assert_eq!(value("cash_to_assets"), 0.25);
+        assert_eq!(value("equity_to_assets"), 0.55);
+
+        let dataset = prepare_dataset(&HashMap::from([("AAA".to_string(), records)]));
+        assert_eq!(dataset.len(), 1);
+        assert_eq!(dataset.row(0)[dataset.feature_index("delta_revenue").unwrap()], 50.0);
+    }
+
+    #[test]
+    fn test_feature_row_skip_conditions() {
+        let rows = [
+            (2020, [100.0, 10.0, 50.0, 5.0, 100.0], 0.0),
+            (2021, [200.0, 10.0, 60.0, 8.0, 100.0], 0.0),
+            (2022, [200.0, 50.0, 110.0, 12.0, 150.0], 20.0),
+        ];
+        let records = ticker_records("AAA", &rows);
+        let (previous, current) = (&records[1], &records[2]);
+        let labelled = FeatureConfig {
+            require_label: true,
+            ..Default::default()
+        };
+        let index = |name: &str| FEATURE_NAMES.iter().position(|known| *known == name).unwrap();
+
+        // Zero assets leave the asset ratios NaN for the non-finite policy, not 0
+        let no_assets = ticker_records("AAA", &[rows[0], rows[1], (2022, [0.0, 50.0, 110.0, 12.0, 150.0], 20.0)]);
+        let row = compute_feature_row(&no_assets[2], &no_assets[1], &labelled).unwrap();
+        assert!(row.values[index("cash_to_assets")].is_nan());
+        assert!(row.values[index("delta_cash_to_assets")].is_nan());
+        assert!(row.values[index("delta_roa")].is_nan());
+        let no_previous_assets = ticker_records("AAA", &[rows[0], (2021, [0.0, 10.0, 60.0, 8.0, 100.0], 0.0), rows[2]]);
+        let row = compute_feature_row(&no_previous_assets[2], &no_previous_assets[1], &labelled).unwrap();
+        assert_eq!(row.values[index("cash_to_assets")], 0.25);
+        assert!(row.values[index("delta_cash_to_assets")].is_nan());
+        assert!(row.values[index("delta_equity_to_assets")].is_nan());
+
+        // No year-over-year changes on either record: no usable year before it
+        let mut missing = current.clone();
+        missing.change_in_revenue = None;
+        assert_eq!(compute_feature_row(&missing, previous, &labelled), None);
+        let mut missing = previous.clone();
+        missing.change_in_revenue = None;
+        assert_eq!(compute_feature_row(current, &missing, &labelled), None);
+        assert_eq!(compute_feature_row(&records[1], &records[0], &labelled), None);
+
+        // Undefined ratios and ratio changes, cash flow included, are NaN like the asset ratios
+        let mut undefined = current.clone();
+        undefined.change_in_roe = None;
+        undefined.cash_to_revenue = None;
+        undefined.unavailable.clear(); // as if the cash-flow files were given, without this year
+        let row = compute_feature_row(&undefined, previous, &labelled).unwrap();
+        for name in ["delta_roe", "cash_to_revenue", "delta_cash_to_revenue", "free_cash_flow", "fcf_margin"] {
+            assert!(row.values[index(name)].is_nan(), "{}", name);
+        }
+        assert!(row.values[index("delta_revenue")].is_finite());
+
+        // A price change without a class only matters when a label is required
+        let mut unlabelled = current.clone();
+        unlabelled.price_change = f64::NAN;
+        assert_eq!(compute_feature_row(&unlabelled, previous, &labelled), None);
+        let row = compute_feature_row(&unlabelled, previous, &FeatureConfig::default()).unwrap();
+        assert_eq!(row.label, None);
+    }
+
+    #[test]
+    fn test_custom_extractor_becomes_a_column() {
+        struct Constant;
+        impl FeatureExtractor for Constant {
+            fn name(&self) -> &str {
+                "constant"
+            }
+
+            fn extract(&self, _: &StockData, _: &StockData) -> Option<f64> {
+                Some(7.0)
+            }
+        }
+
+        let rows = [
+            (2020, [100.0, 10.0, 50.0, 5.0, 100.0], 0.0),
+            (2021, [200.0, 10.0, 60.0, 8.0, 100.0], 0.0),
+            (2022, [200.0, 50.0, 110.0, 12.0, 150.0], 20.0),
+            (2023, [220.0, 40.0, 120.0, 15.0, 160.0], -10.0),
+        ];
+        let stock_data = HashMap::from([("AAA".to_string(), ticker_records("AAA", &rows))]);
+        let mut extractors = builtin_extractors();
+        extractors.push(Box::new(Constant));
+        let (dataset, _) = prepare_dataset_with(&stock_data, extractors);
+
+        let builtin = prepare_dataset(&stock_data);
+        assert_eq!(dataset.feature_names[..builtin.n_features()], builtin.feature_names[..]);
+        assert_eq!(dataset.feature_names.last().unwrap(), "constant");
+        assert_eq!(dataset.column(dataset.n_features() - 1).collect::<Vec<f64>>(), vec![7.0; 2]);
+
+        let (only_constant, _) = prepare_dataset_with(&stock_data, vec![Box::new(Constant)]);
+        assert_eq!(only_constant.feature_names, vec!["constant".to_string()]);
+        assert_eq!(only_constant.labels, builtin.labels);
+    }
+
+    #[test]
+    fn test_interaction_columns() {
+        let rows = [
+            (2020, [100.0, 10.0, 50.0, 5.0, 100.0], 0.0),
+            (2021, [200.0, 10.0, 60.0, 8.0, 100.0], 0.0),
+            (2022, [200.0, 50.0, 110.0, 12.0, 150.0], 20.0),
+            (2023, [220.0, 40.0, 120.0, 15.0, 160.0], -10.0),
+        ];
+        let stock_data = HashMap::from([("AAA".to_string(), ticker_records("AAA", &rows))]);
+        let listed: Vec<String> = ["delta_roa", "delta_cash_to_assets", "cash_to_assets", "equity_to_assets"]
+            .map(String::from)
+            .to_vec();
+        let interactions = interaction_extractors(&listed, false);
+        assert_eq!(interactions.len(), 4 * 3 / 2);
+        assert_eq!(interactions[0].name(), "delta_roa*delta_cash_to_assets");
+        assert_eq!(interactions[5].name(), "cash_to_assets*equity_to_assets");
+        assert_eq!(interaction_extractors(&listed, true).len(), 4 * 5 / 2);
+
+        let (dataset, _) = prepare_dataset_with(&stock_data, interactions);
+        let builtin = prepare_dataset(&stock_data);
+        assert_eq!(dataset.len(), builtin.len());
+        for (j, name) in dataset.feature_names.iter().enumerate() {
+            let (first, second) = name.split_once('*').unwrap();
+            let (first, second) = (builtin.feature_index(first).unwrap(), builtin.feature_index(second).unwrap());
+            for i in 0..dataset.len() {
+                assert_eq!(dataset.row(i)[j], builtin.row(i)[first] * builtin.row(i)[second]);
+            }
+        }
+
+        // The built-in product is not generated a second time
+        let pair: Vec<String> = ["delta_revenue", "delta_profit_margin"].map(String::from).to_vec();
+        assert!(interaction_extractors(&pair, false).is_empty());
+        assert!(extractor_by_name("delta_roa*cash_to_assets").is_some());
+        assert!(extractor_by_name("delta_roa*unknown").is_none());
+    }
+
+    #[test]
+    fn test_cash_to_revenue_feature() {
+        let stock_data = |previous_revenue: f64| {
+            let rows = [
+                (2020, [100.0, 10.0, 50.0, 5.0, 100.0], 0.0),
+                (2021, [100.0, 30.0, 50.0, 5.0, previous_revenue], 0.0),
+                (2022, [100.0, 60.0, 50.0, 5.0, 200.0], 0.0),
+            ];
+            HashMap::from([("AAA".to_string(), ticker_records("AAA", &rows))])
+        };
+        let dataset = prepare_dataset(&stock_data(200.0));
+        let level = dataset.feature_index("cash_to_revenue").unwrap();
+        let delta = dataset.feature_index("delta_cash_to_revenue").unwrap();
+
+        // 2020 and 2021 only provide history
+        assert_eq!(dataset.len(), 1);
+        assert!((dataset.row(0)[level] - 0.3).abs() < 1e-12);
+        assert!((dataset.row(0)[delta] - 0.15).abs() < 1e-12);
+
+        // Zero, near-zero or negative revenue in the previous year leaves the
+        // ratio undefined: NaN, so the default non-finite policy drops the row
+        for revenue in [0.0, 1e-12, -50.0] {
+            let dataset = prepare_dataset(&stock_data(revenue));
+            assert!(dataset.row(0)[delta].is_nan(), "revenue {}", revenue);
+            assert!(sanitize_features(&dataset, NonFinitePolicy::Drop).0.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_categorize_boundaries() {
+        let cases = [
+            (f64::NEG_INFINITY, None),
+            (-50.000001, Some(0)),
+            (-50.0, Some(1)),
+            (-0.000001, Some(1)),
+            (0.0, Some(2)),
+            (-0.0, Some(2)),
+            (49.999999, Some(2)),
+            (50.0, Some(3)),
+            (1e6, Some(3)),
+            (f64::INFINITY, None),
+            (f64::NAN, None),
+        ];
+        for (change, expected) in cases {
+            assert_eq!(categorize_price_change(change), expected, "{}", change);
+        }
+    }
+
+    #[test]
+    fn test_non_finite_price_changes_are_dropped() {
+        let rows = |change_2022: f64| {
+            [
+                (2020, [100.0, 10.0, 50.0, 5.0, 100.0], 0.0),
+                (2021, [110.0, 12.0, 55.0, 6.0, 110.0], 10.0),
+                (2022, [120.0, 14.0, 60.0, 8.0, 130.0], change_2022),
+                (2023, [130.0, 15.0, 65.0, 9.0, 140.0], 50.0),
+            ]
+        };
+        let finite = HashMap::from([("AAA".to_string(), ticker_records("AAA", &rows(-50.0)))]);
+        let dataset = prepare_dataset(&finite);
+        assert_eq!(dataset.labels, vec![1, 3]);
+
+        for change in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let stock_data = HashMap::from([("AAA".to_string(), ticker_records("AAA", &rows(change)))]);
+            let dataset = prepare_dataset(&stock_data);
+            assert_eq!(dataset.labels, vec![3]);
+            assert_eq!(dataset.rows[0].year, 2023);
+        }
+    }
+
+    #[test]
+    fn test_export_features() {
+        let dataset = Dataset::from_rows(
+            vec!["delta_revenue".to_string(), "delta_roa".to_string()],
+            &[vec![1.5, -0.25], vec![2.0, 0.125], vec![-3.0, 0.0]],
+            vec![2, 1, 0],
+            vec![
+                RowId { ticker: "AAA".to_string(), year: 2021, ..Default::default() },
+                RowId { ticker: "AAA".to_string(), year: 2022, ..Default::default() },
+                RowId { ticker: "BBB".to_string(), year: 2022, ..Default::default() },
+            ],
+        );
+        let path = fixture_path("export_features.csv");
+        dataset.export_features(path.to_str().unwrap()).unwrap();
+
+        let mut reader = csv::Reader::from_path(&path).unwrap();
+        let header: Vec<String> = reader.headers().unwrap().iter().map(String::from).collect();
+        assert_eq!(header, vec!["ticker", "year", "delta_revenue", "delta_roa", "label"]);
+
+        let records: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(records.len(), 3);
+        assert!(records.iter().all(|record| record.len() == 5));
+        assert_eq!(&records[1][0], "AAA");
+        assert_eq!(records[1][1].parse::<u32>().unwrap(), 2022);
+        assert_eq!(records[1][3].parse::<f64>().unwrap(), 0.125);
+        assert_eq!(&records[2][4], "0");
+
+        let read = Dataset::read_features(path.to_str().unwrap()).unwrap();
+        assert_eq!((&read.feature_names, &read.values), (&dataset.feature_names, &dataset.values));
+        assert_eq!(read.labels, dataset.labels);
+        assert_eq!((read.rows[2].ticker.as_str(), read.rows[2].year), ("BBB", 2022));
+        let mut doubled = dataset.clone();
+        doubled.append(read);
+        assert_eq!((doubled.len(), doubled.row(4)), (6, dataset.row(1)));
+    }
+
+    #[test]
+    fn test_row_order_is_canonical() {
+        let stock_data = SyntheticConfig { n_tickers: 40, seed: 6, ..Default::default() }.generate().stock_data();
+        // A map built separately iterates in another order; its records are also newest first
+        let rebuilt: HashMap<String, Vec<StockData>> = stock_data
+            .iter()
+            .map(|(ticker, records)| (ticker.clone(), records.iter().rev().cloned().collect()))
+            .collect();
+        let export = |dataset: &Dataset, name: &str| {
+            let path = fixture_path(&format!("canonical_{}", name));
+            let path = path.to_str().unwrap();
+            dataset.export_features(&format!("{}.csv", path)).unwrap();
+            dataset.export_libsvm(&format!("{}.svm", path)).unwrap();
+            ["csv", "svm", "svm.meta"].map(|extension| std::fs::read(format!("{}.{}", path, extension)).unwrap())
+        };
+        let first = prepare_dataset(&stock_data);
+        let second = prepare_dataset(&rebuilt);
+        assert_eq!(first.values, second.values);
+        assert_eq!(first.rows, second.rows);
+        assert_eq!(export(&first, "first"), export(&second, "second"));
+        let ids: Vec<(&str, u32)> = first.rows.iter().map(|row| (row.ticker.as_str(), row.year)).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_shuffle_is_reproducible() {
+        let dataset = prepare_dataset(&SyntheticConfig::default().generate().stock_data());
+        let shuffled = dataset.shuffled(9);
+        assert_eq!(shuffled.rows, dataset.shuffled(9).rows);
+        assert_eq!(shuffled.values, dataset.shuffled(9).values);
+        assert_ne!(shuffled.rows, dataset.rows);
+        assert_ne!(shuffled.rows, dataset.shuffled(10).rows);
+        let mut restored = shuffled.rows.clone();
+        restored.sort_by(|a, b| (&a.ticker, a.year).cmp(&(&b.ticker, b.year)));
+        assert_eq!(restored, dataset.rows);
+
+        // The split holds out the first rows of the same shuffle
+        let (train, test) = dataset.train_test_split(0.25, 9);
+        assert_eq!(test.rows, shuffled.rows[..test.len()]);
+        assert_eq!(train.rows, shuffled.rows[test.len()..]);
+    }
+
+    #[test]
+    fn test_export_libsvm_round_trips() {
+        let dataset = prepare_dataset(&SyntheticConfig::default().generate().stock_data());
+        let (train, _) = dataset.train_test_split(0.5, 3);
+        let path = fixture_path("export.svm");
+        let path = path.to_str().unwrap();
+        train.export_libsvm(path).unwrap();
+
+        let mut values = vec![0.0f64; train.len() * train.n_features()];
+        let mut labels = Vec::new();
+        for (i, line) in std::fs::read_to_string(path).unwrap().lines().enumerate() {
+            let mut fields = line.split(' ');
+            labels.push(fields.next().unwrap().parse::<u8>().unwrap());
+            for field in fields {
+                let (index, value) = field.split_once(':').unwrap();
+                let j = index.parse::<usize>().unwrap() - 1;
+                values[i * train.n_features() + j] = value.parse().unwrap();
+            }
+        }
+        assert_eq!(labels, train.labels);
+        // Bit patterns, so NaN values compare equal too
+        let bits = |values: &[f64]| values.iter().map(|value| value.to_bits()).collect::<Vec<u64>>();
+        assert_eq!(bits(&values), bits(&train.values));
+
+        let features: Vec<csv::StringRecord> =
+            csv::Reader::from_path(format!("{}.features", path)).unwrap().records().map(Result::unwrap).collect();
+        let names: Vec<&str> = features.iter().map(|record| record.get(1).unwrap()).collect();
+        assert_eq!(names, train.feature_names);
+        assert_eq!(&features[0][0], "1");
+
+        let meta: Vec<csv::StringRecord> =
+            csv::Reader::from_path(format!("{}.meta", path)).unwrap().records().map(Result::unwrap).collect();
+        assert_eq!(meta.len(), train.len());
+        for (record, row) in meta.iter().zip(&train.rows) {
+            assert_eq!((&record[1], record[2].parse::<u32>().unwrap()), (row.ticker.as_str(), row.year));
+        }
+    }
+
+    #[test]
+    fn test_flat_matrix_matches_row_vectors() {
+        let dataset = prepare_dataset(&SyntheticConfig::default().generate().stock_data());
+        let (flat, nested) = (dataset.to_matrix(), matrix_from_row_vectors(&dataset));
+        assert_eq!(flat.shape(), nested.shape());
+        assert_eq!(flat.shape(), (dataset.len(), dataset.feature_names.len()));
+        for i in 0..dataset.len() {
+            for j in 0..dataset.feature_names.len() {
+                assert_eq!(flat.get((i, j)).to_bits(), nested.get((i, j)).to_bits(), "({}, {})", i, j);
+            }
+        }
+
+        let config = ModelConfig {
+            kind: ModelKind::RandomForest,
+            tree_depth: 3,
+            forest: ForestConfig::default(),
+            seed: 3,
+        };
+        let model = FittedModel::fit(&config, &dataset).unwrap();
+        assert_eq!(model.predict(&flat).unwrap(), model.predict(&nested).unwrap());
+    }
+
+    #[test]
+    #[ignore = "timing only; run with --ignored --nocapture"]
+    fn bench_flat_matrix_construction() {
+        let (n_rows, n_cols) = (500_000, FEATURE_NAMES.len());
+        let dataset = Dataset {
+            feature_names: FEATURE_NAMES.iter().map(|name| name.to_string()).collect(),
+            values: (0..n_rows * n_cols).map(|i| (i % 997) as f64 * 0.01).collect(),
+            labels: vec![0; n_rows],
+            rows: vec![RowId::default(); n_rows],
+        };
+
+        let start = Instant::now();
+        let nested = matrix_from_row_vectors(&dataset);
+        let nested_time = start.elapsed();
+        let start = Instant::now();
+        let flat = dataset.to_matrix();
+        let flat_time = start.elapsed();
+
+        assert_eq!(flat.shape(), nested.shape());
+        // The row vectors cost one allocation per row plus the outer vector; the flat
+        // buffer is a single allocation that smartcore takes over without reshaping
+        println!("row vectors: {:?} ({} allocations)", nested_time, n_rows + 2);
+        println!("flat buffer: {:?} (1 allocation)", flat_time);
+    }
+}
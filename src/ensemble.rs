@@ -0,0 +1,171 @@
+use std::error::Error;
+use smartcore::linalg::basic::arrays::Array;
+use smartcore::linalg::basic::matrix::DenseMatrix;
+use smartcore::linear::logistic_regression::{LogisticRegression, LogisticRegressionParameters};
+use crate::dataset::{Dataset, N_CLASSES};
+use crate::model::{FittedModel, ModelConfig, ModelKind};
+
+type Logistic = LogisticRegression<f64, u8, DenseMatrix<f64>, Vec<u8>>;
+
+pub struct MemberPrediction {
+    pub name: &'static str,
+    pub y_pred: Vec<u8>,
+}
+
+pub struct EnsemblePrediction {
+    pub members: Vec<MemberPrediction>,
+    pub y_pred: Vec<u8>,
+    pub scores: Vec<Vec<f64>>, // averaged member probabilities
+}
+
+fn argmax(scores: &[f64]) -> u8 {
+    let mut best = 0;
+    for (class, &score) in scores.iter().enumerate() {
+        if score > scores[best] {
+            best = class;
+        }
+    }
+    best as u8
+}
+
+// Logistic regression is fit on features standardized with the training split's
+// mean and standard deviation; the raw deltas span a dozen orders of magnitude.
+fn standardize(train: &Dataset, rows: &Dataset) -> DenseMatrix<f64> {
+    let stats = train.column_stats();
+    let scaled: Vec<f64> = rows
+        .feature_rows()
+        .flat_map(|row| {
+            row.iter()
+                .zip(&stats)
+                .map(|(value, column)| if column.std > 0.0 { (value - column.mean) / column.std } else { 0.0 })
+        })
+        .collect();
+    DenseMatrix::new(rows.len(), stats.len(), scaled, false)
+}
+
+fn logistic_probabilities(model: &Logistic, x: &DenseMatrix<f64>) -> Vec<Vec<f64>> {
+    let coefficients = model.coefficients();
+    let intercept = model.intercept();
+    let classes = model.classes();
+    let (n_rows, n_cols) = x.shape();
+    let linear = |i: usize, k: usize| -> f64 {
+        (0..n_cols).map(|j| x.get((i, j)) * coefficients.get((k, j))).sum::<f64>() + intercept.get((k, 0))
+    };
+
+    (0..n_rows)
+        .map(|i| {
+            let mut probabilities = vec![0.0; N_CLASSES];
+            if classes.len() == 2 {
+                let p = 1.0 / (1.0 + (-linear(i, 0)).exp());
+                probabilities[classes[0] as usize] = 1.0 - p;
+                probabilities[classes[1] as usize] = p;
+            } else {
+                let logits: Vec<f64> = (0..classes.len()).map(|k| linear(i, k)).collect();
+                let max = logits.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+                let total: f64 = logits.iter().map(|l| (l - max).exp()).sum();
+                for (k, logit) in logits.iter().enumerate() {
+                    probabilities[classes[k] as usize] = (logit - max).exp() / total;
+                }
+            }
+            probabilities
+        })
+        .collect()
+}
+
+/// Soft-voting ensemble of a random forest, a decision tree and a logistic
+/// regression trained on the same rows: the final class is the argmax of the
+/// members' averaged class probabilities.
+pub fn soft_voting(
+    config: &ModelConfig,
+    train: &Dataset,
+    test: &Dataset,
+) -> Result<EnsemblePrediction, Box<dyn Error>> {
+    let x_test = test.to_matrix();
+    let mut members = Vec::new();
+    let mut member_scores = Vec::new();
+
+    for kind in [ModelKind::RandomForest, ModelKind::DecisionTree] {
+        let member_config = ModelConfig { kind, ..config.clone() };
+        let model = FittedModel::fit(&member_config, train)?;
+        members.push(MemberPrediction {
+            name: kind.label(),
+            y_pred: model.predict(&x_test)?,
+        });
+        member_scores.push(model.scores(&x_test)?);
+    }
+
+    let logistic = Logistic::fit(
+        &standardize(train, train),
+        &train.labels,
+        LogisticRegressionParameters::default(),
+    )?;
+    let x_test_scaled = standardize(train, test);
+    members.push(MemberPrediction {
+        name: "Logistic Regression",
+        y_pred: logistic.predict(&x_test_scaled)?,
+    });
+    member_scores.push(logistic_probabilities(&logistic, &x_test_scaled));
+
+    let scores: Vec<Vec<f64>> = (0..test.len())
+        .map(|i| {
+            (0..N_CLASSES)
+                .map(|k| member_scores.iter().map(|s| s[i][k]).sum::<f64>() / member_scores.len() as f64)
+                .collect()
+        })
+        .collect();
+    let y_pred = scores.iter().map(|s| argmax(s)).collect();
+
+    Ok(EnsemblePrediction { members, y_pred, scores })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::dataset::RowId;
+    use crate::model::ForestConfig;
+
+    #[test]
+    fn test_soft_voting_labels_every_test_row() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut features = Vec::new();
+        let mut labels = Vec::new();
+        for _ in 0..120 {
+            let signal: f64 = rng.gen_range(-1.0..1.0);
+            features.push(vec![signal, rng.gen_range(-1.0..1.0)]);
+            labels.push(match signal {
+                s if s < -0.3 => 0,
+                s if s < 0.3 => 1,
+                _ => 2,
+            });
+        }
+        let dataset = Dataset::from_rows(
+            vec!["signal".to_string(), "noise".to_string()],
+            &features,
+            labels,
+            vec![RowId::default(); 120],
+        );
+        let (train, test) = dataset.train_test_split(0.25, 2);
+        let config = ModelConfig {
+            kind: ModelKind::RandomForest,
+            tree_depth: 3,
+            forest: ForestConfig {
+                n_trees: 20,
+                min_samples_split: 2,
+                m: None,
+                ..Default::default()
+            },
+            seed: 2,
+        };
+
+        let prediction = soft_voting(&config, &train, &test).unwrap();
+        assert_eq!(prediction.y_pred.len(), test.len());
+        assert!(prediction.y_pred.iter().all(|&class| (class as usize) < N_CLASSES));
+        assert_eq!(prediction.members.len(), 3);
+        assert!(prediction.members.iter().all(|member| member.y_pred.len() == test.len()));
+        for scores in &prediction.scores {
+            assert!((scores.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,561 @@
+use std::error::Error;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use serde::Serialize;
+use smartcore::linalg::basic::matrix::DenseMatrix;
+use smartcore::metrics::accuracy;
+use crate::dataset::{Dataset, ForecastRows, N_CLASSES};
+use crate::metrics::{macro_f1, LearningCurvePoint, RepeatRun, RepeatSummary, Summary};
+use crate::model::{FittedModel, ForestConfig, ModelConfig};
+
+/// Runs split -> train -> evaluate `repeats` times on the already prepared
+/// dataset, with seeds `seed, seed + 1, ...` for both the split and the model.
+/// `prepare` gets each split's training and test rows before the fit, for what
+/// has to be fit on the training rows alone (`Pipeline::prepare_fold`).
+pub fn repeated_splits(
+    config: &ModelConfig,
+    dataset: &Dataset,
+    test_size: f64,
+    repeats: usize,
+    seed: u64,
+    prepare: &dyn Fn(Dataset, Dataset) -> (Dataset, Dataset),
+) -> Result<RepeatSummary, Box<dyn Error>> {
+    if repeats == 0 {
+        return Err("repeated splits need at least one repeat".into());
+    }
+    let mut runs = Vec::with_capacity(repeats);
+    for i in 0..repeats {
+        let run_seed = seed.wrapping_add(i as u64);
+        let (train, test) = dataset.train_test_split(test_size, run_seed);
+        let (train, test) = prepare(train, test);
+        let run_config = ModelConfig {
+            seed: run_seed,
+            ..config.clone()
+        };
+        let model = FittedModel::fit(&run_config, &train)?;
+        let y_pred = model.predict(&test.to_matrix())?;
+        runs.push(RepeatRun {
+            seed: run_seed,
+            accuracy: accuracy(&test.labels, &y_pred),
+            macro_f1: macro_f1(&test.labels, &y_pred, N_CLASSES),
+        });
+    }
+
+    let accuracies: Vec<f64> = runs.iter().map(|run| run.accuracy).collect();
+    let f1_scores: Vec<f64> = runs.iter().map(|run| run.macro_f1).collect();
+    Ok(RepeatSummary {
+        accuracy: Summary::of(&accuracies),
+        macro_f1: Summary::of(&f1_scores),
+        runs,
+    })
+}
+
+/// Row indices of each fold's test set; every row is in exactly one fold.
+pub type Folds = Vec<Vec<usize>>;
+
+// Every fold needs a test row and the rest training rows
+fn check_folds(n_rows: usize, k: usize) -> Result<(), Box<dyn Error>> {
+    if k < 2 || k > n_rows {
+        return Err(format!("cross-validation needs 2 <= k <= {} (the number of rows), got k = {}", n_rows, k).into());
+    }
+    Ok(())
+}
+
+/// Shuffles the rows and deals them into `k` folds; an error unless `2 <= k <= n_rows`.
+pub fn kfold(n_rows: usize, k: usize, seed: u64) -> Result<Folds, Box<dyn Error>> {
+    check_folds(n_rows, k)?;
+    let mut indices: Vec<usize> = (0..n_rows).collect();
+    indices.shuffle(&mut StdRng::seed_from_u64(seed));
+    let mut folds = vec![Vec::new(); k];
+    for (i, index) in indices.into_iter().enumerate() {
+        folds[i % k].push(index);
+    }
+    Ok(folds)
+}
+
+/// Like `kfold`, but deals each class's shuffled rows round-robin across the
+/// folds so every fold keeps roughly the overall class proportions.
+pub fn stratified_kfold(labels: &[u8], k: usize, seed: u64) -> Result<Folds, Box<dyn Error>> {
+    check_folds(labels.len(), k)?;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut classes: Vec<u8> = labels.to_vec();
+    classes.sort_unstable();
+    classes.dedup();
+
+    let mut folds = vec![Vec::new(); k];
+    let mut next_fold = 0;
+    for class in classes {
+        let mut indices: Vec<usize> = (0..labels.len()).filter(|&i| labels[i] == class).collect();
+        indices.shuffle(&mut rng);
+        // Continue from the fold the previous class stopped at so fold sizes stay even
+        for index in indices {
+            folds[next_fold].push(index);
+            next_fold = (next_fold + 1) % k;
+        }
+    }
+    Ok(folds)
+}
+
+/// Sorted indices of a seeded sample of `fraction` of the rows that keeps each
+/// class's share. The sample has `round(n * fraction)` rows; the rows left over
+/// after flooring every class's share go to the classes with the largest remainders.
+pub fn stratified_subsample(labels: &[u8], fraction: f64, seed: u64) -> Vec<usize> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut classes: Vec<u8> = labels.to_vec();
+    classes.sort_unstable();
+    classes.dedup();
+
+    let by_class: Vec<Vec<usize>> = classes
+        .iter()
+        .map(|&class| (0..labels.len()).filter(|&i| labels[i] == class).collect())
+        .collect();
+    let quotas: Vec<f64> = by_class.iter().map(|rows| rows.len() as f64 * fraction).collect();
+    let mut counts: Vec<usize> = quotas.iter().map(|quota| quota.floor() as usize).collect();
+    let target = (labels.len() as f64 * fraction).round() as usize;
+    let mut by_remainder: Vec<usize> = (0..classes.len()).collect();
+    by_remainder.sort_by(|&a, &b| (quotas[b] - quotas[b].floor()).total_cmp(&(quotas[a] - quotas[a].floor())));
+    for &class in by_remainder.iter().take(target.saturating_sub(counts.iter().sum())) {
+        counts[class] += 1;
+    }
+
+    let mut sample = Vec::with_capacity(target);
+    for (mut rows, count) in by_class.into_iter().zip(counts) {
+        rows.shuffle(&mut rng);
+        sample.extend(rows.into_iter().take(count));
+    }
+    sample.sort_unstable();
+    sample
+}
+
+/// Seed of the fit for one grid combination and fold. Every task's seed is
+/// fixed before any task runs, so results do not depend on the thread count
+/// or the order in which the tasks finish.
+pub fn task_seed(seed: u64, combination: usize, fold: usize) -> u64 {
+    seed.wrapping_add((combination as u64) << 32).wrapping_add(fold as u64)
+}
+
+// One fold's training rows and both matrices, built once and borrowed by
+// every combination's fit on that fold
+struct FoldData {
+    train: Dataset,
+    x_train: DenseMatrix<f64>,
+    x_test: DenseMatrix<f64>,
+    y_test: Vec<u8>,
+}
+
+fn fold_data(
+    dataset: &Dataset,
+    folds: &Folds,
+    prepare: &dyn Fn(Dataset, Dataset) -> (Dataset, Dataset),
+) -> Vec<FoldData> {
+    folds
+        .iter()
+        .enumerate()
+        .map(|(i, test_indices)| {
+            let train_indices: Vec<usize> = folds
+                .iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .flat_map(|(_, fold)| fold.iter().copied())
+                .collect();
+            let (train, test) = prepare(dataset.subset(&train_indices), dataset.subset(test_indices));
+            FoldData {
+                x_train: train.to_matrix(),
+                x_test: test.to_matrix(),
+                y_test: test.labels,
+                train,
+            }
+        })
+        .collect()
+}
+
+/// Trains on all but one fold and evaluates on the held-out fold, once per fold,
+/// with the folds fit in parallel and each prepared as in `repeated_splits`.
+/// Returns the accuracy of each fold.
+pub fn cross_validate(
+    config: &ModelConfig,
+    dataset: &Dataset,
+    folds: &Folds,
+    prepare: &dyn Fn(Dataset, Dataset) -> (Dataset, Dataset),
+) -> Result<Vec<f64>, Box<dyn Error>> {
+    let mut results = grid_search(config, std::slice::from_ref(&config.forest), dataset, folds, prepare)?;
+    Ok(results.remove(0).fold_accuracies)
+}
+
+/// Cross-validated accuracy of one forest configuration in a grid.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GridResult {
+    pub combination: usize, // index into the grid
+    pub forest: ForestConfig,
+    pub fold_accuracies: Vec<f64>,
+    pub accuracy: Summary,
+}
+
+/// Every forest setting of `n_trees x max_depth x m x min_samples_split`,
+/// with `base`'s value wherever a list is empty.
+pub fn forest_grid(
+    base: &ForestConfig,
+    n_trees: &[u16],
+    max_depth: &[Option<u16>],
+    m: &[Option<usize>],
+    min_samples_split: &[usize],
+) -> Vec<ForestConfig> {
+    fn or_base<T: Clone>(values: &[T], base: T) -> Vec<T> {
+        if values.is_empty() {
+            vec![base]
+        } else {
+            values.to_vec()
+        }
+    }
+    let mut grid = Vec::new();
+    for &n_trees in &or_base(n_trees, base.n_trees) {
+        for &max_depth in &or_base(max_depth, base.max_depth) {
+            for &m in &or_base(m, base.m) {
+                for &min_samples_split in &or_base(min_samples_split, base.min_samples_split) {
+                    grid.push(ForestConfig {
+                        n_trees,
+                        max_depth,
+                        m,
+                        min_samples_split,
+                        ..base.clone()
+                    });
+                }
+            }
+        }
+    }
+    grid
+}
+
+/// Cross-validates every configuration of `grid` (fit as `config.kind`) on the
+/// same folds, each prepared once as in `repeated_splits`, running the
+/// combination x fold fits on rayon's thread pool with seeds from
+/// `task_seed(config.seed, ..)`. Returned best mean accuracy first, ties in grid order.
+pub fn grid_search(
+    config: &ModelConfig,
+    grid: &[ForestConfig],
+    dataset: &Dataset,
+    folds: &Folds,
+    prepare: &dyn Fn(Dataset, Dataset) -> (Dataset, Dataset),
+) -> Result<Vec<GridResult>, Box<dyn Error>> {
+    let fold_data = fold_data(dataset, folds, prepare);
+    let tasks: Vec<(usize, usize)> =
+        (0..grid.len()).flat_map(|combination| (0..folds.len()).map(move |fold| (combination, fold))).collect();
+    // Errors cross threads as strings; `Box<dyn Error>` is not `Send`
+    let accuracies: Vec<f64> = tasks
+        .par_iter()
+        .map(|&(combination, fold)| {
+            let data = &fold_data[fold];
+            let task_config = ModelConfig {
+                forest: grid[combination].clone(),
+                seed: task_seed(config.seed, combination, fold),
+                ..config.clone()
+            };
+            let model = FittedModel::fit_matrix(&task_config, &data.train, &data.x_train).map_err(|e| e.to_string())?;
+            let y_pred = model.predict(&data.x_test).map_err(|e| e.to_string())?;
+            Ok(accuracy(&data.y_test, &y_pred))
+        })
+        .collect::<Result<_, String>>()?;
+
+    let mut results: Vec<GridResult> = grid
+        .iter()
+        .zip(accuracies.chunks(folds.len().max(1)))
+        .enumerate()
+        .map(|(combination, (forest, fold_accuracies))| GridResult {
+            combination,
+            forest: forest.clone(),
+            fold_accuracies: fold_accuracies.to_vec(),
+            accuracy: Summary::of(fold_accuracies),
+        })
+        .collect();
+    results.sort_by(|a, b| b.accuracy.mean.total_cmp(&a.accuracy.mean));
+    Ok(results)
+}
+
+/// Writes one line per test row, sorted by ticker then year:
+/// `ticker,year,true_label,predicted_label,correct,price_change`, followed by
+/// `score_<class>` columns when per-class scores are given.
+pub fn write_results(
+    path: &str,
+    test: &Dataset,
+    y_pred: &[u8],
+    scores: Option<&[Vec<f64>]>,
+) -> Result<(), csv::Error> {
+    let mut writer = csv::Writer::from_path(path)?;
+    let mut header: Vec<String> = ["ticker", "year", "true_label", "predicted_label", "correct", "price_change"]
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+    let n_scores = scores.and_then(|scores| scores.first()).map_or(0, Vec::len);
+    header.extend((0..n_scores).map(|class| format!("score_{}", class)));
+    writer.write_record(&header)?;
+
+    let mut order: Vec<usize> = (0..test.len()).collect();
+    order.sort_by(|&a, &b| (&test.rows[a].ticker, test.rows[a].year).cmp(&(&test.rows[b].ticker, test.rows[b].year)));
+    for i in order {
+        let row = &test.rows[i];
+        let mut record = vec![
+            row.ticker.clone(),
+            row.year.to_string(),
+            test.labels[i].to_string(),
+            y_pred[i].to_string(),
+            (test.labels[i] == y_pred[i]).to_string(),
+            row.price_change.to_string(),
+        ];
+        if let Some(scores) = scores {
+            record.extend(scores[i].iter().map(|score| score.to_string()));
+        }
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes `ticker,latest_year,predicted_next_class`, one line per forecast row.
+pub fn write_forecast<W: std::io::Write>(output: W, rows: &ForecastRows, predicted: &[u8]) -> Result<(), csv::Error> {
+    let mut writer = csv::Writer::from_writer(output);
+    writer.write_record(["ticker", "latest_year", "predicted_next_class"])?;
+    for (row, class) in rows.rows.iter().zip(predicted) {
+        writer.write_record(&[row.ticker.clone(), row.year.to_string(), class.to_string()])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes one line per learning-curve point: `fraction,n_train,train_accuracy_mean,
+/// train_accuracy_std,accuracy_mean,accuracy_std,macro_f1_mean,macro_f1_std`.
+pub fn write_learning_curve(path: &str, points: &[LearningCurvePoint]) -> Result<(), csv::Error> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record([
+        "fraction",
+        "n_train",
+        "train_accuracy_mean",
+        "train_accuracy_std",
+        "accuracy_mean",
+        "accuracy_std",
+        "macro_f1_mean",
+        "macro_f1_std",
+    ])?;
+    for point in points {
+        writer.write_record(&[
+            point.fraction.to_string(),
+            point.n_train.to_string(),
+            point.train_accuracy.mean.to_string(),
+            point.train_accuracy.std.to_string(),
+            point.accuracy.mean.to_string(),
+            point.accuracy.std.to_string(),
+            point.macro_f1.mean.to_string(),
+            point.macro_f1.std.to_string(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::dataset::RowId;
+    use crate::model::{ForestConfig, ModelKind};
+    use crate::test_util::fixture_path;
+
+    #[test]
+    fn test_three_repeats() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let features: Vec<Vec<f64>> = (0..90).map(|_| vec![rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)]).collect();
+        let labels = features.iter().map(|row| if row[0] + 0.3 * row[1] < 0.0 { 1 } else { 2 }).collect();
+        let dataset = Dataset::from_rows(
+            vec!["a".to_string(), "b".to_string()],
+            &features,
+            labels,
+            vec![RowId::default(); 90],
+        );
+        let config = ModelConfig {
+            kind: ModelKind::RandomForest,
+            tree_depth: 3,
+            forest: ForestConfig {
+                n_trees: 10,
+                min_samples_split: 2,
+                m: None,
+                ..Default::default()
+            },
+            seed: 0,
+        };
+
+        let unprepared = |train, test| (train, test);
+        assert!(repeated_splits(&config, &dataset, 0.3, 0, 40, &unprepared).is_err());
+        let summary = repeated_splits(&config, &dataset, 0.3, 3, 40, &unprepared).unwrap();
+        let seeds: Vec<u64> = summary.runs.iter().map(|run| run.seed).collect();
+        assert_eq!(seeds, vec![40, 41, 42]);
+
+        let accuracies: Vec<f64> = summary.runs.iter().map(|run| run.accuracy).collect();
+        let mean = accuracies.iter().sum::<f64>() / 3.0;
+        assert!((summary.accuracy.mean - mean).abs() < 1e-12);
+        assert!(accuracies.iter().all(|&a| a >= summary.accuracy.min && a <= summary.accuracy.max));
+        assert!(accuracies.contains(&summary.accuracy.min) && accuracies.contains(&summary.accuracy.max));
+        assert!(summary.macro_f1.min <= summary.macro_f1.mean && summary.macro_f1.mean <= summary.macro_f1.max);
+    }
+
+    #[test]
+    fn test_grid_search_independent_of_thread_count() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let features: Vec<Vec<f64>> =
+            (0..120).map(|_| vec![rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)]).collect();
+        let labels: Vec<u8> = features.iter().map(|row| if row[0] - 0.5 * row[1] < 0.1 { 1 } else { 2 }).collect();
+        let dataset = Dataset::from_rows(
+            vec!["a".to_string(), "b".to_string()],
+            &features,
+            labels.clone(),
+            vec![RowId::default(); 120],
+        );
+        let base = ForestConfig {
+            n_trees: 8,
+            min_samples_split: 2,
+            ..Default::default()
+        };
+        let grid = forest_grid(&base, &[4, 8], &[Some(2), None], &[Some(1), Some(2)], &[]);
+        assert_eq!(grid.len(), 8);
+        let config = ModelConfig {
+            kind: ModelKind::RandomForest,
+            tree_depth: 3,
+            forest: base,
+            seed: 21,
+        };
+        let folds = stratified_kfold(&labels, 4, 21).unwrap();
+        let run = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| grid_search(&config, &grid, &dataset, &folds, &|train, test| (train, test)).unwrap())
+        };
+
+        let sequential = run(1);
+        assert_eq!(sequential, run(4));
+        assert_eq!(sequential.len(), grid.len());
+        assert!(sequential.windows(2).all(|pair| pair[0].accuracy.mean >= pair[1].accuracy.mean));
+        assert!(sequential.iter().all(|result| result.fold_accuracies.len() == 4));
+        // Cross-validation is the one-combination grid
+        let only = sequential.iter().find(|result| result.combination == 0).unwrap();
+        let single = ModelConfig {
+            forest: grid[0].clone(),
+            ..config.clone()
+        };
+        let accuracies = cross_validate(&single, &dataset, &folds, &|train, test| (train, test)).unwrap();
+        assert_eq!(accuracies, only.fold_accuracies);
+    }
+
+    #[test]
+    fn test_write_results_covers_test_rows() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let rows: Vec<RowId> = (0..40)
+            .map(|i| RowId {
+                ticker: format!("T{:02}", i % 10),
+                year: 2018 + (i / 10) as u32,
+                price_change: rng.gen_range(-80.0..80.0),
+                ..Default::default()
+            })
+            .collect();
+        let features: Vec<Vec<f64>> = rows.iter().map(|row| vec![row.price_change / 100.0 + rng.gen_range(-0.2..0.2)]).collect();
+        let labels = rows.iter().flat_map(|row| crate::dataset::categorize_price_change(row.price_change)).collect();
+        let dataset = Dataset::from_rows(
+            vec!["signal".to_string()],
+            &features,
+            labels,
+            rows,
+        );
+        let (train, test) = dataset.train_test_split(0.25, 8);
+        let config = ModelConfig {
+            kind: ModelKind::DecisionTree,
+            tree_depth: 3,
+            forest: ForestConfig::default(),
+            seed: 8,
+        };
+        let model = FittedModel::fit(&config, &train).unwrap();
+        let x_test = test.to_matrix();
+        let y_pred = model.predict(&x_test).unwrap();
+        let scores = model.scores(&x_test).unwrap();
+
+        let path = fixture_path("results.csv");
+        write_results(path.to_str().unwrap(), &test, &y_pred, Some(&scores)).unwrap();
+
+        let mut reader = csv::Reader::from_path(&path).unwrap();
+        let header: Vec<String> = reader.headers().unwrap().iter().map(String::from).collect();
+        assert_eq!(header[..6], ["ticker", "year", "true_label", "predicted_label", "correct", "price_change"]);
+        assert_eq!(header.len(), 6 + N_CLASSES);
+
+        let records: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(records.len(), test.len());
+        let mut written: Vec<(String, u32)> = Vec::new();
+        for record in &records {
+            let correct = record[2] == record[3];
+            assert_eq!(record[4].parse::<bool>().unwrap(), correct);
+            written.push((record[0].to_string(), record[1].parse().unwrap()));
+        }
+        let mut expected: Vec<(String, u32)> = test.rows.iter().map(|row| (row.ticker.clone(), row.year)).collect();
+        expected.sort();
+        assert_eq!(written, expected);
+    }
+
+    #[test]
+    fn test_kfold_covers_every_row_once() {
+        let folds = kfold(23, 5, 1).unwrap();
+        assert_eq!(folds.len(), 5);
+        let mut all: Vec<usize> = folds.concat();
+        all.sort_unstable();
+        assert_eq!(all, (0..23).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_fold_count_bounds() {
+        assert!(kfold(10, 0, 1).is_err());
+        assert!(kfold(10, 1, 1).is_err());
+        assert!(kfold(10, 11, 1).is_err());
+        assert!(kfold(10, 10, 1).unwrap().iter().all(|fold| fold.len() == 1));
+        assert_eq!(kfold(10, 2, 1).unwrap().len(), 2);
+        let labels = [0, 1, 1, 2];
+        assert!(stratified_kfold(&labels, 0, 1).is_err());
+        assert!(stratified_kfold(&labels, 5, 1).is_err());
+        assert!(stratified_kfold(&labels, 4, 1).unwrap().iter().all(|fold| fold.len() == 1));
+    }
+
+    #[test]
+    fn test_stratified_kfold_preserves_class_proportions() {
+        let mut labels = vec![0u8; 60];
+        labels.extend(vec![1u8; 30]);
+        labels.extend(vec![3u8; 10]);
+
+        let folds = stratified_kfold(&labels, 5, 9).unwrap();
+        assert_eq!(folds.len(), 5);
+        for fold in &folds {
+            let count = |class| fold.iter().filter(|&&i| labels[i] == class).count();
+            assert_eq!(fold.len(), 20);
+            assert_eq!(count(0), 12);
+            assert_eq!(count(1), 6);
+            assert_eq!(count(3), 2);
+        }
+        let mut all: Vec<usize> = folds.concat();
+        all.sort_unstable();
+        assert_eq!(all, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_stratified_subsample_sizes() {
+        let mut labels = vec![0u8; 15];
+        labels.extend(vec![1u8; 50]);
+        labels.extend(vec![2u8; 35]);
+
+        for (fraction, expected) in [(0.1, 10), (0.25, 25), (0.5, 50), (0.75, 75), (0.33, 33)] {
+            let sample = stratified_subsample(&labels, fraction, 3);
+            assert_eq!(sample.len(), expected, "fraction {}", fraction);
+            for class in 0..3u8 {
+                let total = labels.iter().filter(|&&label| label == class).count() as f64;
+                let taken = sample.iter().filter(|&&i| labels[i] == class).count() as f64;
+                assert!((taken - total * fraction).abs() < 1.0, "class {} at {}", class, fraction);
+            }
+        }
+        assert_eq!(stratified_subsample(&labels, 1.0, 3), (0..100).collect::<Vec<_>>());
+        assert_eq!(stratified_subsample(&labels, 0.5, 3), stratified_subsample(&labels, 0.5, 3));
+        assert_ne!(stratified_subsample(&labels, 0.5, 3), stratified_subsample(&labels, 0.5, 4));
+    }
+}
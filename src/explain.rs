@@ -0,0 +1,137 @@
+//! Explanation of one prediction for `--explain TICKER:YEAR`: the row's feature
+//! values, the votes the model cast for each class, and how the votes for the
+//! predicted class move when each feature in turn is replaced by its training
+//! mean. A feature whose replacement costs the predicted class many votes is
+//! one the prediction leans on.
+use std::error::Error;
+use smartcore::linalg::basic::matrix::DenseMatrix;
+use crate::dataset::Dataset;
+use crate::forest::TieBreak;
+use crate::model::FittedModel;
+use crate::tickers::canonical_ticker;
+
+/// A `TICKER:YEAR` pair, as `--explain` takes it.
+pub fn parse_row_key(value: &str) -> Result<(String, u32), String> {
+    let (ticker, year) = value.rsplit_once(':').ok_or_else(|| format!("`{}` is not TICKER:YEAR", value))?;
+    let year = year.trim().parse().map_err(|_| format!("`{}` is not a year", year))?;
+    if ticker.trim().is_empty() {
+        return Err(format!("`{}` has no ticker", value));
+    }
+    Ok((canonical_ticker(ticker), year))
+}
+
+/// Index of the row of `ticker` (canonical) and `year` in `dataset`.
+pub fn find_row(dataset: &Dataset, ticker: &str, year: u32) -> Option<usize> {
+    dataset.rows.iter().position(|row| row.year == year && canonical_ticker(&row.ticker) == ticker)
+}
+
+/// One feature's part in a prediction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureContribution {
+    pub feature: String,
+    pub value: f64,
+    pub replacement: f64,   // the training mean the value was swapped for
+    pub vote_change: isize, // votes for the predicted class with the value minus votes with the replacement
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explanation {
+    pub ticker: String,
+    pub year: u32,
+    pub label: u8,                               // the class the row's price change falls in
+    pub predicted: u8,
+    pub votes: Vec<usize>,                       // trees voting for each class; a single tree casts its one vote
+    pub contributions: Vec<FeatureContribution>, // in column order
+}
+
+/// Explains the prediction for row `index` of `dataset`, with the replacement
+/// values taken from `train`, the rows the model was fitted on.
+pub fn explain(
+    model: &FittedModel,
+    train: &Dataset,
+    dataset: &Dataset,
+    index: usize,
+    tie_break: TieBreak,
+) -> Result<Explanation, Box<dyn Error>> {
+    let row = dataset.row(index);
+    let means: Vec<f64> = train.column_stats().iter().map(|column| column.mean).collect();
+    // The row itself, then one copy per feature with that feature at its mean
+    let mut values = row.to_vec();
+    for (j, &mean) in means.iter().enumerate() {
+        let mut perturbed = row.to_vec();
+        perturbed[j] = mean;
+        values.extend(perturbed);
+    }
+    let votes = model.votes(&DenseMatrix::new(row.len() + 1, row.len(), values, false))?;
+    let (predicted, _) = tie_break.decide(&votes[0]);
+    let contributions = dataset
+        .feature_names
+        .iter()
+        .zip(row)
+        .zip(&means)
+        .enumerate()
+        .map(|(j, ((feature, &value), &replacement))| FeatureContribution {
+            feature: feature.clone(),
+            value,
+            replacement,
+            vote_change: votes[0][predicted as usize] as isize - votes[j + 1][predicted as usize] as isize,
+        })
+        .collect();
+    let id = &dataset.rows[index];
+    Ok(Explanation {
+        ticker: id.ticker.clone(),
+        year: id.year,
+        label: dataset.labels[index],
+        predicted,
+        votes: votes[0].clone(),
+        contributions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::dataset::RowId;
+    use crate::model::{ForestConfig, ModelConfig, ModelKind};
+
+    #[test]
+    fn test_explained_row_values_and_votes() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut features = Vec::new();
+        let mut labels = Vec::new();
+        let mut rows = Vec::new();
+        for i in 0..120 {
+            let signal: f64 = rng.gen_range(-1.0..1.0);
+            features.push(vec![signal, rng.gen_range(-1.0..1.0)]);
+            labels.push(if signal < 0.0 { 0 } else { 3 });
+            rows.push(RowId { ticker: format!("T{:03}", i), year: 2020, ..Default::default() });
+        }
+        let dataset = Dataset::from_rows(vec!["signal".into(), "noise".into()], &features, labels, rows);
+        let config = ModelConfig {
+            kind: ModelKind::RandomForest,
+            tree_depth: 3,
+            forest: ForestConfig { n_trees: 25, m: None, ..Default::default() },
+            seed: 5,
+        };
+        let model = FittedModel::fit(&config, &dataset).unwrap();
+
+        let (ticker, year) = parse_row_key("t042:2020").unwrap();
+        let index = find_row(&dataset, &ticker, year).unwrap();
+        assert_eq!(index, 42);
+        let explanation = explain(&model, &dataset, &dataset, index, TieBreak::LowestClass).unwrap();
+        assert_eq!((explanation.ticker.as_str(), explanation.year), ("T042", 2020));
+        let values: Vec<f64> = explanation.contributions.iter().map(|c| c.value).collect();
+        assert_eq!(values, features[42]);
+        assert_eq!(explanation.votes.iter().sum::<usize>(), 25);
+        assert_eq!(explanation.predicted, explanation.label);
+        // The signal moves the vote at least as much as the noise
+        let signal = &explanation.contributions[0];
+        assert!(signal.vote_change.abs() >= explanation.contributions[1].vote_change.abs());
+
+        assert!(parse_row_key("AAPL").is_err());
+        assert!(parse_row_key("AAPL:twenty").is_err());
+        assert!(find_row(&dataset, "T042", 2021).is_none());
+    }
+}
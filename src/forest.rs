@@ -0,0 +1,259 @@
+use serde::Deserialize;
+use smartcore::ensemble::random_forest_classifier::RandomForestClassifier;
+use smartcore::linalg::basic::arrays::Array;
+use smartcore::linalg::basic::matrix::DenseMatrix;
+use smartcore::tree::decision_tree_classifier::DecisionTreeClassifier;
+
+pub type Forest = RandomForestClassifier<f64, u8, DenseMatrix<f64>, Vec<u8>>;
+pub type Tree = DecisionTreeClassifier<f64, u8, DenseMatrix<f64>, Vec<u8>>;
+
+// smartcore keeps the fitted trees private, but its serde representation exposes
+// the node tables, which is enough to replay every tree and count its votes.
+#[derive(Debug, Deserialize)]
+struct TreeNode {
+    output: usize,
+    split_feature: usize,
+    split_value: Option<f64>,
+    true_child: Option<usize>,
+    false_child: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TreeNodes {
+    nodes: Vec<TreeNode>,
+    classes: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SerializedForest {
+    trees: Option<Vec<TreeNodes>>,
+    classes: Option<Vec<u8>>,
+}
+
+impl TreeNodes {
+    // Mirrors smartcore's traversal: `<=` goes to the true child.
+    fn predict_row(&self, row: &[f64]) -> usize {
+        let mut node = &self.nodes[0];
+        while let (Some(true_child), Some(false_child)) = (node.true_child, node.false_child) {
+            let split_value = node.split_value.unwrap_or(f64::NAN);
+            node = if row[node.split_feature] <= split_value {
+                &self.nodes[true_child]
+            } else {
+                &self.nodes[false_child]
+            };
+        }
+        node.output
+    }
+
+    fn write_rules(&self, id: usize, depth: usize, feature_names: &[impl AsRef<str>], out: &mut String) {
+        let indent = "    ".repeat(depth);
+        let node = &self.nodes[id];
+        match (node.true_child, node.false_child) {
+            (Some(true_child), Some(false_child)) => {
+                let feature = feature_names
+                    .get(node.split_feature)
+                    .map(|name| name.as_ref().to_string())
+                    .unwrap_or_else(|| format!("feature_{}", node.split_feature));
+                let threshold = node.split_value.unwrap_or(f64::NAN);
+                out.push_str(&format!("{}if {} <= {} then\n", indent, feature, threshold));
+                self.write_rules(true_child, depth + 1, feature_names, out);
+                out.push_str(&format!("{}else\n", indent));
+                self.write_rules(false_child, depth + 1, feature_names, out);
+            }
+            _ => out.push_str(&format!("{}class {}\n", indent, self.classes[node.output])),
+        }
+    }
+}
+
+/// Prints a fitted tree as nested if/else rules. The split features and
+/// thresholds are read from the tree's serde representation, so the rules are
+/// the exact splits smartcore uses rather than an approximation.
+pub fn tree_rules(tree: &Tree, feature_names: &[impl AsRef<str>]) -> Result<String, serde_json::Error> {
+    let nodes: TreeNodes = serde_json::from_value(serde_json::to_value(tree)?)?;
+    let mut rules = String::new();
+    nodes.write_rules(0, 0, feature_names, &mut rules);
+    Ok(rules)
+}
+
+/// A forest voting with the trees of both `base` and `extra`. Both must have
+/// been fit on the same classes, since each tree's output indexes them.
+/// Through the serde representation too, as smartcore has no way to add trees.
+pub fn append_trees(base: &Forest, extra: &Forest) -> Result<Forest, Box<dyn std::error::Error>> {
+    let mut merged = serde_json::to_value(base)?;
+    let extra = serde_json::to_value(extra)?;
+    if merged["classes"] != extra["classes"] {
+        return Err(format!(
+            "the forests were fit on different classes ({} and {}); train on data with every class",
+            merged["classes"], extra["classes"]
+        )
+        .into());
+    }
+    if let (Some(trees), Some(extra_trees)) = (merged["trees"].as_array_mut(), extra["trees"].as_array()) {
+        trees.extend(extra_trees.iter().cloned());
+    }
+    // The bootstrap samples refer to different training sets, so neither side's are kept
+    merged["samples"] = serde_json::Value::Null;
+    Ok(serde_json::from_value(merged)?)
+}
+
+/// Which class a forest predicts when several classes share the most votes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TieBreak {
+    /// The lowest tied class, as smartcore's own `predict` picks
+    #[default]
+    LowestClass,
+    /// The highest tied class
+    HighestClass,
+}
+
+impl TieBreak {
+    /// The class with the most of `votes` (indexed by class) under this rule,
+    /// and whether another class had as many.
+    pub fn decide(self, votes: &[usize]) -> (u8, bool) {
+        let most = votes.iter().copied().max().unwrap_or(0);
+        let mut tied = votes.iter().enumerate().filter(|(_, &count)| count == most).map(|(class, _)| class as u8);
+        let class = match self {
+            TieBreak::LowestClass => tied.next(),
+            TieBreak::HighestClass => tied.next_back(),
+        };
+        (class.unwrap_or(0), votes.iter().filter(|&&count| count == most).count() > 1)
+    }
+}
+
+/// Majority-vote labels, and the rows whose vote was tied and settled by the rule.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VotedPredictions {
+    pub labels: Vec<u8>,
+    pub tied: Vec<usize>, // row indices, ascending
+}
+
+#[derive(Debug)]
+pub struct ForestVotes {
+    trees: Vec<TreeNodes>,
+    classes: Vec<u8>,
+}
+
+impl ForestVotes {
+    pub fn from_forest(forest: &Forest) -> Result<Self, serde_json::Error> {
+        let serialized: SerializedForest = serde_json::from_value(serde_json::to_value(forest)?)?;
+        Ok(ForestVotes {
+            trees: serialized.trees.unwrap_or_default(),
+            classes: serialized.classes.unwrap_or_default(),
+        })
+    }
+
+    pub fn n_trees(&self) -> usize {
+        self.trees.len()
+    }
+
+    /// Number of trees voting for each label value `0..n_classes`.
+    pub fn votes(&self, row: &[f64], n_classes: usize) -> Vec<usize> {
+        let mut votes = vec![0; n_classes];
+        for tree in &self.trees {
+            let class = self.classes[tree.predict_row(row)] as usize;
+            if class < n_classes {
+                votes[class] += 1;
+            }
+        }
+        votes
+    }
+
+    /// The label of every row of `x` by majority vote, ties settled by `tie_break`,
+    /// so the same forest and rows always give the same labels.
+    pub fn predict(&self, x: &DenseMatrix<f64>, n_classes: usize, tie_break: TieBreak) -> VotedPredictions {
+        let (n_rows, n_cols) = x.shape();
+        let mut predictions = VotedPredictions::default();
+        for i in 0..n_rows {
+            let row: Vec<f64> = (0..n_cols).map(|j| *x.get((i, j))).collect();
+            let (label, tied) = tie_break.decide(&self.votes(&row, n_classes));
+            predictions.labels.push(label);
+            if tied {
+                predictions.tied.push(i);
+            }
+        }
+        predictions
+    }
+
+    /// `votes` for every row of `x`.
+    pub fn votes_by_row(&self, x: &DenseMatrix<f64>, n_classes: usize) -> Vec<Vec<usize>> {
+        let (n_rows, n_cols) = x.shape();
+        (0..n_rows)
+            .map(|i| {
+                let row: Vec<f64> = (0..n_cols).map(|j| *x.get((i, j))).collect();
+                self.votes(&row, n_classes)
+            })
+            .collect()
+    }
+
+    /// Per-class vote fractions for every row of `x`.
+    pub fn scores(&self, x: &DenseMatrix<f64>, n_classes: usize) -> Vec<Vec<f64>> {
+        let n_trees = self.trees.len().max(1) as f64;
+        self.votes_by_row(x, n_classes)
+            .into_iter()
+            .map(|votes| votes.into_iter().map(|v| v as f64 / n_trees).collect())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smartcore::tree::decision_tree_classifier::DecisionTreeClassifierParameters;
+
+    #[test]
+    fn test_tree_rules_mention_informative_feature() {
+        // Only the second column separates the classes
+        let x = DenseMatrix::from_2d_vec(&vec![
+            vec![0.5, -0.20],
+            vec![0.1, -0.10],
+            vec![0.9, -0.05],
+            vec![0.4, 0.05],
+            vec![0.2, 0.10],
+            vec![0.8, 0.20],
+        ]);
+        let y: Vec<u8> = vec![1, 1, 1, 2, 2, 2];
+        let params = DecisionTreeClassifierParameters {
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        let tree = Tree::fit(&x, &y, params).unwrap();
+
+        let rules = tree_rules(&tree, &["delta_revenue", "delta_roa"]).unwrap();
+        assert!(rules.starts_with("if delta_roa <= "), "{}", rules);
+        assert!(rules.contains("class 1"));
+        assert!(rules.contains("class 2"));
+        assert!(!rules.contains("delta_revenue"));
+    }
+
+    // A tree of a single leaf, voting for `classes[output]` on every row
+    fn leaf(output: usize) -> TreeNodes {
+        TreeNodes {
+            nodes: vec![TreeNode { output, split_feature: 0, split_value: None, true_child: None, false_child: None }],
+            classes: vec![0, 1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn test_tied_votes_follow_the_tie_break() {
+        let tied = ForestVotes {
+            trees: vec![leaf(3), leaf(1), leaf(1), leaf(3), leaf(0)],
+            classes: vec![0, 1, 2, 3],
+        };
+        let x = DenseMatrix::from_2d_vec(&vec![vec![0.5, -0.2], vec![0.1, 0.3], vec![-1.0, 2.0]]);
+        assert_eq!(tied.votes(&[0.5, -0.2], 4), vec![1, 2, 0, 2]);
+        for _ in 0..5 {
+            let lowest = tied.predict(&x, 4, TieBreak::LowestClass);
+            assert_eq!(lowest, VotedPredictions { labels: vec![1, 1, 1], tied: vec![0, 1, 2] });
+            assert_eq!(tied.predict(&x, 4, TieBreak::HighestClass).labels, vec![3, 3, 3]);
+        }
+
+        let clear = ForestVotes {
+            trees: vec![leaf(3), leaf(1), leaf(3)],
+            classes: vec![0, 1, 2, 3],
+        };
+        assert_eq!(clear.predict(&x, 4, TieBreak::LowestClass), VotedPredictions { labels: vec![3; 3], tied: vec![] });
+        assert_eq!(TieBreak::HighestClass.decide(&[2, 0, 1, 0]), (0, false));
+        assert_eq!(TieBreak::LowestClass.decide(&[0, 2, 2, 2]), (1, true));
+    }
+}
@@ -0,0 +1,106 @@
+//! Classes from a `ticker,year,label` file for `--labels`, in place of the
+//! price-change buckets, for targets the price change alone does not give
+//! (outperformance of an index, hand-labelled events). The file is joined to
+//! the feature rows on ticker and year; rows it does not list are dropped.
+use std::collections::HashMap;
+use crate::dataset::Dataset;
+use crate::stock_data::{open_csv, StockDataError};
+use crate::tickers::canonical_ticker;
+
+/// Class per canonical ticker and year.
+pub type ExternalLabels = HashMap<(String, u32), u8>;
+
+/// Reads a `ticker,year,label` file. A row without a year and a class number,
+/// or a ticker-year listed twice, is an error naming it.
+pub fn read_labels(path: &str) -> Result<ExternalLabels, StockDataError> {
+    let mut reader = open_csv(path)?;
+    let mut labels = ExternalLabels::new();
+    for result in reader.records() {
+        let record = result.map_err(|source| StockDataError::Csv {
+            path: path.to_string(),
+            source,
+        })?;
+        let ticker = canonical_ticker(record.get(0).unwrap_or(""));
+        let year = record.get(1).and_then(|year| year.trim().parse().ok());
+        let label = record.get(2).and_then(|label| label.trim().parse().ok());
+        let fields: Vec<&str> = record.iter().collect();
+        let error = |message: String| StockDataError::ColumnType {
+            path: path.to_string(),
+            column: "label".to_string(),
+            message,
+        };
+        match (year, label) {
+            (Some(year), Some(label)) if !ticker.is_empty() => {
+                if labels.insert((ticker, year), label).is_some() {
+                    return Err(error(format!("`{}` repeats a ticker and year", fields.join(","))));
+                }
+            }
+            _ => return Err(error(format!("`{}` is not a ticker, year and class number", fields.join(",")))),
+        }
+    }
+    Ok(labels)
+}
+
+/// `dataset` with each row's class taken from `labels`, without the rows
+/// `labels` lacks; those are returned as ticker and year, in row order.
+pub fn relabel(dataset: &Dataset, labels: &ExternalLabels) -> (Dataset, Vec<(String, u32)>) {
+    let mut kept = Vec::new();
+    let mut classes = Vec::new();
+    let mut dropped = Vec::new();
+    for (i, row) in dataset.rows.iter().enumerate() {
+        match labels.get(&(canonical_ticker(&row.ticker), row.year)) {
+            Some(&label) => {
+                kept.push(i);
+                classes.push(label);
+            }
+            None => dropped.push((row.ticker.clone(), row.year)),
+        }
+    }
+    let mut relabelled = dataset.subset(&kept);
+    relabelled.labels = classes;
+    (relabelled, dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::Pipeline;
+    use crate::synthetic::SyntheticConfig;
+    use crate::test_util::write_fixture;
+
+    #[test]
+    fn test_external_label_overrides_computed_class() {
+        let stock_data = SyntheticConfig { n_tickers: 2, n_years: 5, seed: 2, ..Default::default() }
+            .generate()
+            .stock_data();
+        let computed = Pipeline::builder().stock_data(stock_data.clone()).build().unwrap().dataset(&stock_data);
+        let first = &computed.rows[0];
+        let flipped = 3 - computed.labels[0];
+
+        // Every row but the last, with the first one's class flipped
+        let mut contents = String::from("ticker,year,label\n");
+        let last = computed.len() - 1;
+        for (i, row) in computed.rows.iter().enumerate().take(last) {
+            let label = if i == 0 { flipped } else { computed.labels[i] };
+            contents.push_str(&format!("{},{},{}\n", row.ticker.to_lowercase(), row.year, label));
+        }
+        let labels = read_labels(&write_fixture("labels.csv", &contents)).unwrap();
+        let pipeline = Pipeline::builder().stock_data(stock_data.clone()).labels(labels.clone()).build().unwrap();
+        let (dataset, report) = pipeline.dataset_with_report(&stock_data);
+
+        assert_eq!(dataset.len(), computed.len() - 1);
+        assert_eq!((&dataset.rows[0].ticker, dataset.rows[0].year), (&first.ticker, first.year));
+        assert_eq!(dataset.labels[0], flipped);
+        assert_eq!(dataset.labels[1..], computed.labels[1..last]);
+        let (_, dropped) = relabel(&computed, &labels);
+        assert_eq!(dropped, [(computed.rows[last].ticker.clone(), computed.rows[last].year)]);
+        assert_eq!(report.without_external_label, dropped);
+
+        let repeated = write_fixture("labels_repeated.csv", "ticker,year,label\nAAA,2020,1\naaa,2020,2\n");
+        assert!(matches!(read_labels(&repeated), Err(StockDataError::ColumnType { .. })));
+        let malformed = write_fixture("labels_malformed.csv", "ticker,year,label\nAAA,2020,up\n");
+        assert!(read_labels(&malformed).is_err());
+        let out_of_range = HashMap::from([(("AAA".to_string(), 2020), 4)]);
+        assert!(Pipeline::builder().stock_data(stock_data).labels(out_of_range).build().is_err());
+    }
+}
@@ -0,0 +1,34 @@
+pub mod ablation;
+pub mod cache;
+pub mod currency;
+pub mod dataset;
+pub mod ensemble;
+pub mod evaluation;
+pub mod explain;
+pub mod forest;
+pub mod labels;
+pub mod metrics;
+pub mod model;
+pub mod nonfinite;
+pub mod outliers;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+pub mod pipeline;
+pub mod ranking;
+pub mod report;
+#[cfg(feature = "remote")]
+pub mod remote;
+pub mod sanity;
+pub mod selection;
+pub mod splits;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod standardize;
+pub mod stock_data;
+pub mod synthetic;
+#[cfg(test)]
+mod test_util;
+pub mod tickers;
+pub mod weighting;
+#[cfg(feature = "xlsx")]
+pub mod xlsx;
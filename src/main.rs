@@ -1,128 +1,1328 @@
-mod stock_data;
-use smartcore::linalg::basic::matrix::DenseMatrix;
+use clap::{Parser, Subcommand, ValueEnum};
+use final_project::ablation::{ablation, permutation_importance};
+use final_project::cache::{CacheReport, CachedRecords};
+use final_project::currency::MissingRate;
+use final_project::evaluation::{
+    cross_validate, forest_grid, grid_search, kfold, repeated_splits, stratified_kfold, write_forecast,
+    write_learning_curve, write_results,
+};
+use final_project::explain::{explain, find_row, parse_row_key};
+use final_project::forest::{self, TieBreak};
+use final_project::labels::read_labels;
+use final_project::metrics::{self, RunMetrics};
+use final_project::model::{parse_optional, FittedModel, ForestConfig, ModelKind, SavedForest};
+use final_project::nonfinite::NonFinitePolicy;
+use final_project::outliers::OutlierMode;
+use final_project::pipeline::{Model, Pipeline, Split, DEFAULT_TEST_SIZE};
+use final_project::ranking::{attractiveness, backtest, top_k_by_year, top_n_by_top_class, GoodOutcome};
+use final_project::report::RunReport;
+use final_project::sanity::SanityRules;
+use final_project::standardize::{read_sectors, Standardize};
+use final_project::stock_data::{
+    ticker_inventory, undefined_ratio_counts, GapPolicy, InputLayout, JoinPolicy, LoadOptions, LoadReport,
+    PriceConflict, PriceReference, ReturnBasis, YearRange, CASH_FLOW_METRICS, DEFAULT_RATIO_EPSILON, METRICS,
+};
+use final_project::synthetic::generate_synthetic_dataset;
+use final_project::tickers::{read_ticker_list, TickerFilter};
 use smartcore::metrics::accuracy;
-use smartcore::model_selection::train_test_split;
-use smartcore::ensemble::random_forest_classifier::{RandomForestClassifier, RandomForestClassifierParameters};
-use stock_data::{process_stock_data, StockData};
-
-fn prepare_dataset(
-    stock_data: &std::collections::HashMap<String, Vec<stock_data::StockData>>,
-) -> (DenseMatrix<f64>, Vec<u8>) {
-    let mut features = Vec::new();
-    let mut labels = Vec::new();
-
-    for (_, records) in stock_data {
-        for i in 1..records.len() {
-            let current = &records[i];
-            let previous = &records[i - 1];
-
-            if i == 1
-                || previous.change_in_revenue.is_none()
-                || previous.change_in_profit_margin.is_none()
-                || previous.change_in_roa.is_none()
-            {
-                continue;
-            }
 
-            let delta_revenue = current.change_in_revenue.unwrap();
-            let delta_profit_margin = current.change_in_profit_margin.unwrap();
-            let delta_roa = current.change_in_roa.unwrap();
+#[derive(Parser)]
+#[command(about = "Predict yearly stock price movements from financial statements")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Model to train
+    #[arg(long, value_enum, default_value_t = ModelKind::RandomForest, global = true)]
+    model: ModelKind,
+    /// Average the class probabilities of a random forest, a decision tree and a logistic regression
+    #[arg(long, global = true)]
+    ensemble: bool,
+    /// Maximum depth of the single tree used by `--model decision-tree`
+    #[arg(long, default_value_t = 3, global = true)]
+    tree_depth: u16,
+    /// Where the financial and price data come from; `sqlite` and `parquet` read the `--input` files
+    #[arg(long, value_enum, global = true)]
+    source: Option<Source>,
+    /// Database file for `--source sqlite`, comma-separated metric-group files for `--source parquet`,
+    /// or the CSV file for `--input-layout long`; a `.sqlite`/`.db` or `.parquet` path selects the source by itself
+    #[arg(long, global = true)]
+    input: Option<String>,
+    /// Layout of the CSV financial data: `wide` reads one `data_<metric>.csv` per metric, `long` reads
+    /// every metric from the `--input` file (`ticker,year,metric,value` or `ticker,year,assets,cash,...`)
+    #[arg(long, value_enum, default_value_t = InputLayout::Wide, global = true)]
+    input_layout: InputLayout,
+    /// Run on a generated universe with a learnable revenue signal instead of any input files
+    #[arg(long, global = true)]
+    synthetic: bool,
+    /// Number of tickers for `--synthetic`
+    #[arg(long, default_value_t = 200, global = true)]
+    synthetic_tickers: usize,
+    /// Number of years for `--synthetic`
+    #[arg(long, default_value_t = 8, global = true)]
+    synthetic_years: usize,
+    /// Price files (comma-separated), merged month by month when they split tickers or periods
+    #[arg(long, value_delimiter = ',', default_value = "stock_prices.csv", global = true)]
+    price_files: Vec<String>,
+    /// Prices to keep for a ticker-month found in more than one price file
+    #[arg(long, value_enum, default_value_t = PriceConflict::First, global = true)]
+    price_conflict: PriceConflict,
+    /// Price each year's change is measured from: start-of-year, prior-year-end or month:<1-12>
+    #[arg(long, default_value_t = PriceReference::StartOfYear, global = true)]
+    price_reference: PriceReference,
+    /// Price years to read, both included (`2010..2022`, `2015..`); rows of other years are skipped while parsing
+    #[arg(long, global = true)]
+    year_range: Option<YearRange>,
+    /// Download prices from this URL template (`{ticker}`, `{api_key}` from $PRICE_API_KEY) instead of price files
+    #[arg(long, global = true)]
+    price_url: Option<String>,
+    /// Keep the processed CSV records in this file and, when the files gain a year, build only the new years
+    #[arg(long, global = true)]
+    cache: Option<String>,
+    /// Directory where downloaded price responses are cached between runs
+    #[arg(long, default_value = "price_cache", global = true)]
+    price_cache: String,
+    /// Minimum milliseconds between two price requests
+    #[arg(long, default_value_t = 1000, global = true)]
+    price_throttle_ms: u64,
+    #[command(flatten)]
+    forest: ForestConfig,
+    /// Read the random forest settings from this JSON file instead of the command line
+    #[arg(long, global = true)]
+    forest_config: Option<String>,
+    /// Seed for the train/test split and the model; random when omitted
+    #[arg(long, global = true)]
+    seed: Option<u64>,
+    /// Continue without financial files that fail to load, dropping the features that need them
+    #[arg(long, global = true)]
+    skip_missing_files: bool,
+    /// Tickers to keep when the financial files list different ones
+    #[arg(long, value_enum, default_value_t = JoinPolicy::Intersection, global = true)]
+    join_policy: JoinPolicy,
+    /// Changes spanning missing years: skip them, keep them whole, or normalize them per year
+    #[arg(long, value_enum, default_value_t = GapPolicy::Skip, global = true)]
+    gap_policy: GapPolicy,
+    /// Deprecated: use --gap-policy normalize
+    #[arg(long, hide = true, conflicts_with = "gap_policy", global = true)]
+    allow_gaps: bool,
+    /// Label each row by the compounded price change over this many years, starting with its own
+    #[arg(long, default_value_t = 1, global = true)]
+    horizon: u32,
+    /// Months with prices a year needs for its price volatility; rows without one follow --non-finite
+    #[arg(long, default_value_t = 6, global = true)]
+    min_volatility_months: usize,
+    /// Label price-only or total-return changes; total uses dividends.csv and is the default when it exists
+    #[arg(long, value_enum, global = true)]
+    returns: Option<ReturnBasis>,
+    /// `ticker,currency` file; listings in other currencies are converted to USD with --fx-rates
+    /// [default: currencies.csv when it exists]
+    #[arg(long, global = true)]
+    currencies: Option<String>,
+    /// `currency,year,rate` file of USD per unit of each currency [default: fx_rates.csv when it exists]
+    #[arg(long, global = true)]
+    fx_rates: Option<String>,
+    /// `ticker,date,split_ratio` file; prices and dividends before a split are put in today's shares
+    /// [default: splits.csv when it exists]
+    #[arg(long, global = true)]
+    splits: Option<String>,
+    /// Do not pick up currencies.csv, fx_rates.csv or splits.csv from the working directory
+    #[arg(long, global = true)]
+    no_auto_files: bool,
+    /// Ticker-years whose currency in --currencies has no rate in --fx-rates: fail, or leave them out
+    #[arg(long, value_enum, default_value_t = MissingRate::Error, global = true)]
+    missing_fx_rate: MissingRate,
+    /// Print each ticker's year range and record counts after loading, then exit
+    #[arg(long, global = true)]
+    list_tickers: bool,
+    /// Exclude records with impossible fundamentals and print a rejection report
+    #[arg(long, global = true)]
+    sanity_filters: bool,
+    #[command(flatten)]
+    sanity_rules: SanityRules,
+    /// Clip or drop feature values outside the IQR fences of their column
+    #[arg(long, value_enum, default_value_t = OutlierMode::Off, global = true)]
+    outlier: OutlierMode,
+    /// Fence distance for `--outlier`, in interquartile ranges beyond the quartiles
+    #[arg(long, default_value_t = 3.0, global = true)]
+    outlier_threshold: f64,
+    /// What to do with NaN or infinite feature values: drop the row, clamp them, or use the column median
+    #[arg(long, value_enum, default_value_t = NonFinitePolicy::Drop, global = true)]
+    non_finite: NonFinitePolicy,
+    /// Denominators closer to zero than this leave a ratio (margin, ROA, ROE, asset shares) undefined
+    #[arg(long, default_value_t = DEFAULT_RATIO_EPSILON, global = true)]
+    ratio_epsilon: f64,
+    /// Class the forest predicts when classes tie for the most votes; the tied rows are listed
+    #[arg(long, value_enum, default_value_t = TieBreak::LowestClass, global = true)]
+    tie_break: TieBreak,
+    /// After training, explain the prediction for this row: its features, votes, and each feature's effect on them
+    #[arg(long, value_name = "TICKER:YEAR", value_parser = parse_row_key, global = true)]
+    explain: Option<(String, u32)>,
+    /// Stop with an error before training when fewer feature rows than this survive joining and filtering
+    #[arg(long, default_value_t = 20, global = true)]
+    min_rows: usize,
+    /// Train only on the tickers listed in this file (one per line, `#` comments)
+    #[arg(long, global = true)]
+    include_tickers: Option<String>,
+    /// Leave out the tickers listed in this file (one per line, `#` comments)
+    #[arg(long, global = true)]
+    exclude_tickers: Option<String>,
+    /// Leave these features out of the model (comma-separated names)
+    #[arg(long, value_delimiter = ',', global = true)]
+    exclude_features: Vec<String>,
+    /// Add the product of every pair of these features as a column (comma-separated names)
+    #[arg(long, value_delimiter = ',', global = true)]
+    interactions: Vec<String>,
+    /// With --interactions, also add each listed feature times itself
+    #[arg(long, requires = "interactions", global = true)]
+    interaction_squares: bool,
+    /// Drop each feature whose absolute correlation with an earlier feature on the training rows exceeds this
+    #[arg(long, global = true)]
+    select_corr: Option<f64>,
+    /// `ticker,year,label` file whose classes replace the price-change classes; rows it lacks are dropped
+    #[arg(long, global = true)]
+    labels: Option<String>,
+    /// Scale features to z-scores fitted on the training rows: over all of them, or within each row's sector
+    #[arg(long, value_enum, default_value_t = Standardize::Off, global = true)]
+    standardize: Standardize,
+    /// `ticker,sector` file for --standardize per-sector; rows of unlisted tickers use the overall statistics
+    #[arg(long, global = true)]
+    sectors: Option<String>,
+    /// Weight training rows by recency, halving every this many years (applied by weighted resampling)
+    #[arg(long, global = true)]
+    recency_halflife: Option<f64>,
+    /// Weight training rows by recency, replicating each lambda^(latest year - its year) times (0 < lambda <= 1)
+    #[arg(long, global = true, conflicts_with = "recency_halflife")]
+    recency_decay: Option<f64>,
+    /// Oversample the smaller classes of the training rows up to the largest by replication
+    #[arg(long, global = true)]
+    balance_classes: bool,
+    /// Repeat the random split, training and evaluation this many times with derived seeds
+    #[arg(long, global = true)]
+    repeats: Option<usize>,
+    /// Report k-fold cross-validated accuracy instead of a single split
+    #[arg(long, global = true)]
+    cv_folds: Option<usize>,
+    /// Keep each class's proportion in every cross-validation fold
+    #[arg(long, global = true)]
+    stratified: bool,
+    /// Worker threads for cross-validation folds and grid search; every core when omitted
+    #[arg(long, global = true)]
+    threads: Option<usize>,
+    /// Report a 95% bootstrap interval on the test accuracy from this many resamples of the test rows
+    #[arg(long, global = true)]
+    bootstrap: Option<usize>,
+    /// Seed for `--bootstrap` resampling; the run's seed when omitted
+    #[arg(long, global = true)]
+    bootstrap_seed: Option<u64>,
+    /// Write the run's metrics as JSON to this path
+    #[arg(long, global = true)]
+    metrics_json: Option<String>,
+    /// Print diagnostic tables after the run: probability calibration by confidence decile
+    #[arg(long, global = true)]
+    calibration: bool,
+    /// Write a Markdown report of the configuration, dataset, metrics and importances to this path
+    #[arg(long, global = true)]
+    report: Option<String>,
+    /// Print the test accuracy, macro F1 and class distribution of each year's rows
+    #[arg(long, global = true)]
+    by_year: bool,
+    /// Print how often each predicted class is right, and the top class's hit rate by score
+    #[arg(long, global = true)]
+    reliability: bool,
+    /// Print the N test rows with the highest probability of the top gain class, with their true classes
+    #[arg(long, global = true)]
+    top_n: Option<usize>,
+    /// Save the fitted random forest and its feature names to this JSON file
+    #[arg(long, global = true)]
+    model_out: Option<String>,
+    /// Score a forest saved with --model-out instead of fitting one
+    #[arg(long, global = true)]
+    model_in: Option<String>,
+    /// With --model-in, fit --n-trees more trees on this run's training rows (old and new data) and add them
+    #[arg(long, requires = "model_in", global = true)]
+    warm_append: bool,
+    /// With --model-in, add these --export-features files (comma-separated) to the training rows and append
+    /// --n-trees trees fit on them all, as --warm-append does
+    #[arg(long, value_delimiter = ',', requires = "model_in", global = true)]
+    append_data: Vec<String>,
+    /// Write the test rows' labels, predictions, price changes and class scores to this CSV
+    #[arg(long, global = true)]
+    results: Option<String>,
+    /// Write the engineered feature rows (ticker, year, features, label) to this CSV before splitting
+    #[arg(long, global = true)]
+    export_features: Option<String>,
+    /// Write the train and test splits in svmlight format, with .features and .meta sidecars, to these two paths
+    #[arg(long, num_args = 2, value_names = ["TRAIN", "TEST"], global = true)]
+    export_libsvm: Option<Vec<String>>,
+}
 
-            let current_cash_to_assets = if current.assets != 0.0 {
-                current.cash / current.assets
-            } else {
-                0.0
-            };
-            let previous_cash_to_assets = if previous.assets != 0.0 {
-                previous.cash / previous.assets
-            } else {
-                0.0
-            };
-            let delta_cash_to_assets = current_cash_to_assets - previous_cash_to_assets;
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Source {
+    /// The wide CSV files in the working directory
+    Csv,
+    /// A database with `fundamentals` and `prices` tables (needs the `sqlite` feature)
+    Sqlite,
+    /// Parquet fundamentals (`ticker`, `year`, metric columns) with prices from the price files
+    /// (needs the `parquet` feature)
+    Parquet,
+}
 
-            let current_equity_to_assets = if current.assets != 0.0 {
-                current.equity / current.assets
-            } else {
-                0.0
-            };
-            let previous_equity_to_assets = if previous.assets != 0.0 {
-                previous.equity / previous.assets
-            } else {
-                0.0
-            };
-            let delta_equity_to_assets = current_equity_to_assets - previous_equity_to_assets;
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SummaryFormat {
+    Text,
+    Json,
+}
 
-            features.push(vec![
-                delta_revenue,
-                delta_profit_margin,
-                delta_roa,
-                delta_cash_to_assets,
-                delta_equity_to_assets,
-                delta_revenue * delta_profit_margin, // Interaction
-            ]);
+#[derive(Subcommand)]
+enum Command {
+    /// Retrain once per feature with that column removed and report the accuracy change
+    Ablation {
+        /// Only ablate these features (comma-separated names)
+        #[arg(long, value_delimiter = ',')]
+        features: Vec<String>,
+    },
+    /// Rank the test rows by expected class and report precision@k of the top picks per year
+    Rank {
+        /// Picks per year
+        #[arg(long, default_value_t = 10)]
+        k: usize,
+        /// A pick is good when its price change beats this percent; the test-set median when omitted
+        #[arg(long)]
+        good_above: Option<f64>,
+    },
+    /// Buy every test row predicted in the top class, hold it for the year and compare to holding every test row
+    Backtest,
+    /// Train on growing stratified fractions of the training rows and score each on its own rows and the test rows
+    LearningCurve {
+        /// Fractions of the training rows to train on (comma-separated)
+        #[arg(long, value_delimiter = ',', default_value = "0.1,0.25,0.5,0.75,1")]
+        fractions: Vec<f64>,
+        /// Number of resamples to average at each fraction
+        #[arg(long, default_value_t = 1)]
+        resamples: usize,
+        /// Write the curve as CSV to this path
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Profile the prepared dataset: tickers, years, classes and per-feature statistics
+    Summary {
+        #[arg(long, value_enum, default_value_t = SummaryFormat::Text)]
+        format: SummaryFormat,
+    },
+    /// Cross-validate every combination of the listed forest settings (--cv-folds, 5 by default) and rank them
+    GridSearch {
+        /// Tree counts to try (comma-separated); --n-trees alone when omitted
+        #[arg(long, value_delimiter = ',')]
+        trees: Vec<u16>,
+        /// Maximum depths to try, `none` for unlimited (comma-separated); --max-depth alone when omitted
+        #[arg(long, value_delimiter = ',', value_parser = parse_optional::<u16>)]
+        depths: Vec<Option<u16>>,
+        /// Features per split to try, `none` for sqrt (comma-separated); --mtry alone when omitted
+        #[arg(long, value_delimiter = ',', value_parser = parse_optional::<usize>)]
+        mtry_values: Vec<Option<usize>>,
+        /// Minimum samples to split to try (comma-separated); --min-samples-split alone when omitted
+        #[arg(long, value_delimiter = ',')]
+        min_samples_splits: Vec<usize>,
+        /// Combinations to print, best first
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+    /// Train each row on its following year's class and predict next year's class from each ticker's latest record
+    Forecast {
+        /// Write `ticker,latest_year,predicted_next_class` to this path instead of stdout
+        #[arg(long)]
+        output: Option<String>,
+    },
+}
 
-            labels.push(categorize_price_change(current.price_change));
-        }
+#[cfg(feature = "sqlite")]
+fn load_sqlite(
+    path: Option<&str>,
+    options: &LoadOptions,
+) -> Result<(CachedRecords, LoadReport), Box<dyn std::error::Error>> {
+    let path = path.ok_or("--source sqlite needs an --input database path")?;
+    Ok(final_project::sqlite::process_sqlite(path, options)?)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn load_sqlite(
+    _path: Option<&str>,
+    _options: &LoadOptions,
+) -> Result<(CachedRecords, LoadReport), Box<dyn std::error::Error>> {
+    Err("SQLite input needs a build with `--features sqlite`".into())
+}
+
+#[cfg(feature = "parquet")]
+fn load_parquet(
+    paths: Option<&str>,
+    price_files: &[&str],
+    options: &LoadOptions,
+) -> Result<(CachedRecords, LoadReport), Box<dyn std::error::Error>> {
+    let paths = paths.ok_or("--source parquet needs --input Parquet paths")?;
+    let paths: Vec<&str> = paths.split(',').map(str::trim).collect();
+    Ok(final_project::parquet::process_parquet(&paths, price_files, options)?)
+}
+
+#[cfg(not(feature = "parquet"))]
+fn load_parquet(
+    _paths: Option<&str>,
+    _price_files: &[&str],
+    _options: &LoadOptions,
+) -> Result<(CachedRecords, LoadReport), Box<dyn std::error::Error>> {
+    Err("Parquet input needs a build with `--features parquet`".into())
+}
+
+#[cfg(feature = "remote")]
+fn load_remote_prices(
+    cli: &Cli,
+    url_template: &str,
+    financial_files: &[(&str, &str)],
+    options: &LoadOptions,
+) -> Result<(CachedRecords, LoadReport), Box<dyn std::error::Error>> {
+    use final_project::remote::RemotePriceSource;
+    use final_project::stock_data::{cash_flow_metrics, combine_stock_data, load_financial_files};
+
+    let (metrics, unavailable) = load_financial_files(financial_files, options)?;
+    let mut tickers: Vec<String> = metrics.iter().flat_map(|values| values.keys().cloned()).collect();
+    tickers.sort();
+    tickers.dedup();
+
+    let source = RemotePriceSource {
+        cache_dir: Some(cli.price_cache.clone().into()),
+        min_interval: std::time::Duration::from_millis(cli.price_throttle_ms),
+        year_range: options.year_range,
+        ..RemotePriceSource::new(url_template)
+    };
+    let fetched = source.fetch_price_changes(&tickers);
+    for (ticker, err) in &fetched.failed {
+        eprintln!("warning: skipping {}: {}", ticker, err);
     }
+    Ok(combine_stock_data(
+        [&metrics[0], &metrics[1], &metrics[2], &metrics[3], &metrics[4]],
+        cash_flow_metrics(&metrics),
+        &unavailable,
+        &fetched.price_changes,
+        None,
+        options,
+    )?)
+}
 
-    let feature_matrix = DenseMatrix::from_2d_vec(&features);
+#[cfg(not(feature = "remote"))]
+fn load_remote_prices(
+    _cli: &Cli,
+    _url_template: &str,
+    _financial_files: &[(&str, &str)],
+    _options: &LoadOptions,
+) -> Result<(CachedRecords, LoadReport), Box<dyn std::error::Error>> {
+    Err("--price-url needs a build with `--features remote`".into())
+}
 
-    (feature_matrix, labels)
+// `explicit`, or else `default` when it is in the working directory and --no-auto-files is off
+fn data_file(cli: &Cli, explicit: &Option<String>, default: &str) -> Option<String> {
+    if explicit.is_some() || cli.no_auto_files || !std::path::Path::new(default).exists() {
+        return explicit.clone();
+    }
+    eprintln!("note: using {} found in the working directory; --no-auto-files ignores it", default);
+    Some(default.to_string())
 }
 
-fn categorize_price_change(price_change: f64) -> u8 {
-    match price_change {
-        pc if pc < -50.0 => 0,
-        pc if pc < 0.0 => 1,
-        pc if pc < 50.0 => 2,
-        pc if pc > 50.0 => 3,
-        _ => 3,
+// `--gap-policy`, or `normalize` under the deprecated `--allow-gaps` it replaced
+fn gap_policy(cli: &Cli) -> GapPolicy {
+    if cli.allow_gaps {
+        eprintln!("warning: --allow-gaps is deprecated; use --gap-policy normalize");
+        return GapPolicy::Normalize;
     }
+    cli.gap_policy
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let financial_files = vec![
+fn main() {
+    // Print errors with Display so their hints on what to relax are readable
+    if let Err(err) = run(Cli::parse()) {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let seed = cli.seed.unwrap_or_else(rand::random);
+    if let Some(threads) = cli.threads {
+        rayon::ThreadPoolBuilder::new().num_threads(threads).build_global()?;
+    }
+
+    let mut financial_files = vec![
         ("data_assets.csv", "assets"),
         ("data_cash.csv", "cash"),
         ("data_equity.csv", "equity"),
         ("data_profit.csv", "profit"),
         ("data_revenue.csv", "revenue"),
     ];
-    let stock_data = process_stock_data(&financial_files, "stock_prices.csv")?;
+    // Cash-flow files are optional; without both the free-cash-flow features are left out
+    for (path, metric) in [("data_operating_cashflow.csv", "operating_cashflow"), ("data_capex.csv", "capex")] {
+        if std::path::Path::new(path).exists() {
+            financial_files.push((path, metric));
+        }
+    }
+    if cli.input_layout == InputLayout::Long {
+        let path = cli.input.as_deref().ok_or("--input-layout long needs an --input CSV file")?;
+        financial_files = METRICS.iter().chain(&CASH_FLOW_METRICS).map(|&metric| (path, metric)).collect();
+    }
+    let options = LoadOptions {
+        skip_missing_files: cli.skip_missing_files,
+        gap_policy: gap_policy(&cli),
+        join_policy: cli.join_policy,
+        horizon: cli.horizon,
+        min_volatility_months: cli.min_volatility_months,
+        price_conflict: cli.price_conflict,
+        price_reference: cli.price_reference,
+        year_range: cli.year_range.unwrap_or_default(),
+        dividend_file: match cli.returns {
+            Some(ReturnBasis::Price) => None,
+            Some(ReturnBasis::Total) if !std::path::Path::new("dividends.csv").exists() => {
+                return Err("--returns total needs a dividends.csv (ticker,ex_date,amount)".into())
+            }
+            _ => std::path::Path::new("dividends.csv").exists().then(|| "dividends.csv".to_string()),
+        },
+        split_file: data_file(&cli, &cli.splits, "splits.csv"),
+        currency_file: data_file(&cli, &cli.currencies, "currencies.csv"),
+        fx_rates_file: data_file(&cli, &cli.fx_rates, "fx_rates.csv"),
+        missing_fx_rate: cli.missing_fx_rate,
+        ratio_epsilon: cli.ratio_epsilon,
+        input_layout: cli.input_layout,
+        ..Default::default()
+    };
+    if options.currency_file.is_some() && options.fx_rates_file.is_none() {
+        return Err("--currencies needs --fx-rates (a currency,year,rate file in USD per unit)".into());
+    }
+    let input = cli.input.as_deref().unwrap_or("");
+    let price_files: Vec<&str> = cli.price_files.iter().map(String::as_str).collect();
+    let source = cli.source.unwrap_or(if input.ends_with(".sqlite") || input.ends_with(".db") {
+        Source::Sqlite
+    } else if input.ends_with(".parquet") {
+        Source::Parquet
+    } else {
+        Source::Csv
+    });
+    let forest = match &cli.forest_config {
+        Some(path) => ForestConfig::from_json_file(path)?,
+        None => cli.forest.clone(),
+    };
+    let model = match cli.model {
+        _ if cli.ensemble => Model::Ensemble {
+            forest: forest.clone(),
+            tree_depth: cli.tree_depth,
+        },
+        ModelKind::RandomForest => Model::RandomForest(forest.clone()),
+        ModelKind::DecisionTree => Model::DecisionTree {
+            max_depth: cli.tree_depth,
+        },
+    };
+
+    let mut builder = Pipeline::builder()
+        .load_options(options.clone())
+        .split(Split::Random { test_size: DEFAULT_TEST_SIZE })
+        .outliers(cli.outlier, cli.outlier_threshold)
+        .non_finite(cli.non_finite)
+        .min_rows(cli.min_rows)
+        .tie_break(cli.tie_break)
+        .exclude_features(&cli.exclude_features)
+        .interactions(&cli.interactions, cli.interaction_squares)
+        .model(model)
+        .seed(seed);
+    builder = match source {
+        _ if cli.synthetic => {
+            builder.stock_data(generate_synthetic_dataset(cli.synthetic_tickers, cli.synthetic_years, seed))
+        }
+        Source::Csv => match &cli.price_url {
+            Some(url_template) => {
+                let (stock_data, report) = load_remote_prices(&cli, url_template, &financial_files, &options)?;
+                builder.stock_data(stock_data).load_report(report)
+            }
+            None => {
+                let mut builder = builder.fundamentals(&financial_files);
+                if let Some(path) = &cli.cache {
+                    builder = builder.cache(path);
+                }
+                price_files.iter().fold(builder, |builder, path| builder.prices(path))
+            }
+        },
+        Source::Sqlite => {
+            let (stock_data, report) = load_sqlite(cli.input.as_deref(), &options)?;
+            builder.stock_data(stock_data).load_report(report)
+        }
+        Source::Parquet => {
+            let (stock_data, report) = load_parquet(cli.input.as_deref(), &price_files, &options)?;
+            builder.stock_data(stock_data).load_report(report)
+        }
+    };
+    let ticker_filter = TickerFilter {
+        include: cli.include_tickers.as_deref().map(read_ticker_list).transpose()?,
+        exclude: cli.exclude_tickers.as_deref().map(read_ticker_list).transpose()?.unwrap_or_default(),
+    };
+    if ticker_filter.is_active() {
+        builder = builder.ticker_filter(ticker_filter);
+    }
+    if cli.sanity_filters {
+        builder = builder.sanity_filters(cli.sanity_rules.clone());
+    }
+    if let Some(threshold) = cli.select_corr {
+        builder = builder.select_correlated(threshold);
+    }
+    if let Some(path) = &cli.labels {
+        builder = builder.labels(read_labels(path)?);
+    }
+    if let Some(path) = &cli.sectors {
+        builder = builder.sectors(read_sectors(path)?);
+    }
+    builder = builder.standardize(cli.standardize);
+    if let Some(halflife) = cli.recency_halflife {
+        builder = builder.recency_halflife(halflife);
+    }
+    if let Some(lambda) = cli.recency_decay {
+        builder = builder.recency_decay(lambda);
+    }
+    builder = builder.balance_classes(cli.balance_classes);
+    let pipeline = builder.build()?;
 
-    let (features, labels) = prepare_dataset(&stock_data);
+    let (mut stock_data, load_report, cache) = pipeline.load_cached()?;
+    match cache {
+        Some(CacheReport { rebuilt: Some(reason), .. }) => println!("Cache rebuilt from the files: {}", reason),
+        Some(report) => println!(
+            "Cache: {} cached records, {} new, {} with refreshed prices",
+            report.cached_records, report.new_records, report.refreshed_prices
+        ),
+        None if cli.cache.is_some() => eprintln!("warning: --cache only applies to CSV input files; ignoring it"),
+        None => {}
+    }
+    let join = &load_report.join;
+    if join.is_mismatched() {
+        eprintln!(
+            "warning: the financial files list different tickers ({}); {:?} join keeps {}",
+            join.file_counts(),
+            join.policy,
+            join.joined.len()
+        );
+        if !join.filled.is_empty() {
+            let filled: Vec<String> =
+                join.filled.iter().map(|(ticker, metric)| format!("{} {}", ticker, metric)).collect();
+            let value = if join.policy == JoinPolicy::Union { "NaN" } else { "zero" };
+            eprintln!("warning: {} missing metrics are {}: {}", filled.len(), value, filled.join(", "));
+        }
+    }
+    if !load_report.without_prices.is_empty() {
+        eprintln!(
+            "warning: {} tickers have financials but no prices and give no labelled rows: {}",
+            load_report.without_prices.len(),
+            load_report.without_prices.join(", ")
+        );
+    }
+    if let Some(path) = &options.split_file {
+        let mut adjusted: Vec<&str> = stock_data
+            .iter()
+            .filter(|(_, records)| records.iter().any(|record| record.split_adjusted))
+            .map(|(ticker, _)| ticker.as_str())
+            .collect();
+        adjusted.sort_unstable();
+        println!(
+            "Prices adjusted for share splits of {} tickers from {}: {}",
+            adjusted.len(),
+            path,
+            adjusted.join(", ")
+        );
+    }
+    if cli.include_tickers.is_some() || cli.exclude_tickers.is_some() {
+        let report = pipeline.filter_tickers(&mut stock_data);
+        println!(
+            "Ticker filters removed {} tickers not in the include list and {} in the exclude list; {} remain",
+            report.removed_by_include,
+            report.removed_by_exclude,
+            stock_data.len()
+        );
+        if !report.not_found.is_empty() {
+            eprintln!(
+                "warning: {} include-list tickers are not in the data: {}",
+                report.not_found.len(),
+                report.not_found.join(", ")
+            );
+        }
+    }
 
-    let (x_train, x_test, y_train, y_test) =
-        train_test_split(&features, &labels, 0.8, true, None);
+    if cli.list_tickers {
+        println!("{:<10} {:>6} {:>6} {:>8} {:>9}", "ticker", "first", "last", "records", "complete");
+        for summary in ticker_inventory(&stock_data) {
+            println!(
+                "{:<10} {:>6} {:>6} {:>8} {:>9}",
+                summary.ticker, summary.first_year, summary.last_year, summary.n_records, summary.n_complete
+            );
+        }
+        return Ok(());
+    }
 
-    let rf_params = RandomForestClassifierParameters {
-        n_trees: 500,
-        max_depth: Some(10),
-        min_samples_split: 25,
-        m: Some(3), 
-        ..Default::default()
+    if cli.sanity_filters {
+        let rejections = pipeline.filter(&mut stock_data);
+        println!("Sanity filters rejected {} records", rejections.len());
+        for rejection in &rejections {
+            println!(
+                "  {} {}: {} (value {})",
+                rejection.ticker, rejection.year, rejection.rule, rejection.value
+            );
+        }
+    }
+
+    let undefined_ratios = undefined_ratio_counts(&stock_data);
+    if undefined_ratios.iter().any(|(_, count)| *count > 0) {
+        let counts: Vec<String> = undefined_ratios
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(ratio, count)| format!("{} {}", ratio, count))
+            .collect();
+        println!(
+            "Undefined ratios (denominator within {} of zero or negative; their deltas follow --non-finite): {}",
+            cli.ratio_epsilon,
+            counts.join(", ")
+        );
+    }
+
+    let (labelled, dataset_report) = pipeline.dataset_with_report(&stock_data);
+    if dataset_report.unlabelled > 0 {
+        eprintln!(
+            "warning: dropped {} rows whose price change is NaN or infinite (or lacks prices for the whole horizon)",
+            dataset_report.unlabelled
+        );
+    }
+    if dataset_report.volatility_dropped {
+        eprintln!("warning: no year has prices in enough months for a volatility; prior_price_volatility is dropped");
+    }
+    let without_label = &dataset_report.without_external_label;
+    if !without_label.is_empty() {
+        let mut rows: Vec<String> =
+            without_label.iter().take(10).map(|(ticker, year)| format!("{} {}", ticker, year)).collect();
+        if without_label.len() > rows.len() {
+            rows.push(format!("and {} more", without_label.len() - rows.len()));
+        }
+        eprintln!(
+            "warning: dropped {} rows the labels file has no class for: {}",
+            without_label.len(),
+            rows.join(", ")
+        );
+    }
+
+    if let Some(Command::Summary { format }) = &cli.command {
+        let summary = metrics::summarize_dataset(&labelled, pipeline.n_classes());
+        if *format == SummaryFormat::Json {
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+            return Ok(());
+        }
+        println!("Rows: {} from {} tickers", summary.n_rows, summary.n_tickers);
+        if let (Some(first), Some(last)) = (summary.first_year, summary.last_year) {
+            println!("Years: {}-{}", first, last);
+        }
+        println!("Rows per year:");
+        for (year, count) in &summary.rows_per_year {
+            println!("  {}: {}", year, count);
+        }
+        println!("Class distribution:");
+        for (class, count) in summary.class_counts.iter().enumerate() {
+            let share = *count as f64 / summary.n_rows.max(1) as f64;
+            println!("  class {}: {} ({:.1}%)", class, count, share * 100.0);
+        }
+        println!(
+            "  {:<34} {:>6} {:>8} {:>12} {:>12} {:>12} {:>12}",
+            "feature", "rows", "missing", "min", "mean", "max", "std"
+        );
+        for column in &summary.features {
+            println!(
+                "  {:<34} {:>6} {:>8} {:>12.4} {:>12.4} {:>12.4} {:>12.4}",
+                column.name, column.count, column.missing, column.min, column.mean, column.max, column.std
+            );
+        }
+        return Ok(());
+    }
+
+    let (dataset, non_finite) = pipeline.sanitize(&labelled);
+    if non_finite.total() > 0 {
+        println!(
+            "Non-finite values ({:?}): {} in {} rows",
+            cli.non_finite,
+            non_finite.total(),
+            non_finite.rows_affected
+        );
+        for (feature, count) in non_finite.per_feature.iter().filter(|(_, count)| *count > 0) {
+            println!("  {}: {}", feature, count);
+        }
+    }
+    if let Some(path) = &cli.export_features {
+        dataset.export_features(path)?;
+        println!("Wrote {} feature rows to {}", dataset.len(), path);
+    }
+
+    if let Some(Command::Forecast { output }) = &cli.command {
+        // `forecast` cleans the rows itself, once they carry next year's class
+        let empty = dataset.subset(&[]);
+        let (train, _, _) = pipeline.select_features(dataset.clone(), empty);
+        let forecast = pipeline.forecast(&stock_data, &train)?;
+        match output {
+            Some(path) => {
+                write_forecast(std::fs::File::create(path)?, &forecast.rows, &forecast.predicted)?;
+                println!("Wrote {} forecasts to {}", forecast.predicted.len(), path);
+            }
+            None => write_forecast(std::io::stdout(), &forecast.rows, &forecast.predicted)?,
+        }
+        if !forecast.rows.unforecastable.is_empty() {
+            println!(
+                "Unforecastable (latest record lacks prior-year deltas or a finite feature): {}",
+                forecast.rows.unforecastable.join(", ")
+            );
+        }
+        return Ok(());
+    }
+
+    let (train, test, split) = pipeline.split(&dataset)?;
+    if cli.outlier != OutlierMode::Off {
+        println!(
+            "Outliers ({:?}, fences from the training rows): {} values in {} rows",
+            cli.outlier, split.outliers.values_flagged, split.outliers.rows_affected
+        );
+    }
+    let mut replication: Vec<String> =
+        cli.recency_decay.iter().map(|lambda| format!("recency decay {}", lambda)).collect();
+    if cli.balance_classes {
+        replication.push("class balancing".to_string());
+    }
+    if !replication.is_empty() {
+        println!(
+            "Training rows replicated ({}): {} rows to {}",
+            replication.join(", "),
+            split.unweighted_train_rows,
+            train.len()
+        );
+    }
+    let (train, test) = if cli.append_data.is_empty() {
+        (train, test)
+    } else {
+        let n_train = train.len();
+        let (train, test) = pipeline.append_rows(train, test, &cli.append_data)?;
+        println!("Added {} training rows from {}", train.len() - n_train, cli.append_data.join(", "));
+        (train, test)
     };
-    let rf_classifier = RandomForestClassifier::fit(&x_train, &y_train, rf_params)?;
+    let (train, test, dropped) = pipeline.select_features(train, test);
+    if cli.select_corr.is_some() {
+        println!("Correlation filter dropped {} features: {}", dropped.len(), dropped.join(", "));
+    }
+    let (train, test, scaling) = pipeline.standardize(train, test);
+    if cli.standardize == Standardize::PerSector {
+        let groups: Vec<String> =
+            scaling.groups.iter().map(|(sector, rows)| format!("{} ({})", sector, rows)).collect();
+        println!(
+            "Standardized per sector against {} groups: {}; {} rows without a group used the overall statistics",
+            groups.len(),
+            groups.join(", "),
+            scaling.fallback_rows
+        );
+    }
+    if let Some([train_path, test_path]) = cli.export_libsvm.as_deref() {
+        train.export_libsvm(train_path)?;
+        test.export_libsvm(test_path)?;
+        println!("Wrote {} training rows to {} and {} test rows to {}", train.len(), train_path, test.len(), test_path);
+    }
+
+    if cli.model == ModelKind::RandomForest || cli.ensemble {
+        forest.validate(train.feature_names.len())?;
+        println!("Random forest configuration: {}", forest);
+    }
+    let config = pipeline.model_config();
+    // Repeats and folds fit their own preprocessing on their training rows, as the split above did
+    let prepare = |train, test| pipeline.prepare_fold(train, test);
+    if cli.ensemble && (cli.repeats.is_some() || cli.cv_folds.is_some()) {
+        return Err("--repeats and --cv-folds score a single model; drop --ensemble".into());
+    }
+
+    if let Some(repeats) = cli.repeats {
+        let summary = repeated_splits(&config, &dataset, DEFAULT_TEST_SIZE, repeats, seed, &prepare)?;
+        for (i, run) in summary.runs.iter().enumerate() {
+            println!(
+                "Repeat {} (seed {}): accuracy {:.2}%, macro F1 {:.3}",
+                i + 1,
+                run.seed,
+                run.accuracy * 100.0,
+                run.macro_f1
+            );
+        }
+        println!(
+            "Accuracy: {:.2}% ± {:.2}% (min {:.2}%, max {:.2}%)",
+            summary.accuracy.mean * 100.0,
+            summary.accuracy.std * 100.0,
+            summary.accuracy.min * 100.0,
+            summary.accuracy.max * 100.0
+        );
+        println!(
+            "Macro F1: {:.3} ± {:.3} (min {:.3}, max {:.3})",
+            summary.macro_f1.mean, summary.macro_f1.std, summary.macro_f1.min, summary.macro_f1.max
+        );
+        let run_metrics = RunMetrics {
+            model: cli.model.label().to_string(),
+            seed,
+            horizon: cli.horizon,
+            returns: options.return_basis(),
+            n_rows: dataset.len(),
+            features: dataset.feature_names.clone(),
+            repeats: Some(summary),
+            ..Default::default()
+        };
+        if let Some(path) = &cli.metrics_json {
+            run_metrics.write_json(path)?;
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::GridSearch {
+        trees,
+        depths,
+        mtry_values,
+        min_samples_splits,
+        top,
+    }) = &cli.command
+    {
+        if cli.ensemble || cli.model != ModelKind::RandomForest {
+            return Err("grid-search tunes the random forest; drop --ensemble and --model".into());
+        }
+        let grid = forest_grid(&config.forest, trees, depths, mtry_values, min_samples_splits);
+        let k = cli.cv_folds.unwrap_or(5);
+        let folds = if cli.stratified {
+            stratified_kfold(&dataset.labels, k, seed)?
+        } else {
+            kfold(dataset.len(), k, seed)?
+        };
+        let results = grid_search(&config, &grid, &dataset, &folds, &prepare)?;
+        let optional = |value: Option<usize>| value.map_or("none".to_string(), |v| v.to_string());
+        println!("{} combinations x {} folds, best first:", grid.len(), k);
+        println!(
+            "{:>4} {:>7} {:>9} {:>5} {:>17} {:>18}",
+            "rank", "n_trees", "max_depth", "mtry", "min_samples_split", "accuracy"
+        );
+        for (rank, result) in results.iter().take(*top).enumerate() {
+            println!(
+                "{:>4} {:>7} {:>9} {:>5} {:>17} {:>9.2}% ± {:>5.2}%",
+                rank + 1,
+                result.forest.n_trees,
+                optional(result.forest.max_depth.map(usize::from)),
+                optional(result.forest.m),
+                result.forest.min_samples_split,
+                result.accuracy.mean * 100.0,
+                result.accuracy.std * 100.0
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(k) = cli.cv_folds {
+        let folds = if cli.stratified {
+            stratified_kfold(&dataset.labels, k, seed)?
+        } else {
+            kfold(dataset.len(), k, seed)?
+        };
+        let accuracies = cross_validate(&config, &dataset, &folds, &prepare)?;
+        for (i, acc) in accuracies.iter().enumerate() {
+            println!("Fold {}: accuracy {:.2}%", i + 1, acc * 100.0);
+        }
+        let summary = metrics::Summary::of(&accuracies);
+        println!(
+            "Cross-validated accuracy: {:.2}% ± {:.2}%",
+            summary.mean * 100.0,
+            summary.std * 100.0
+        );
+        return Ok(());
+    }
+
+    if let Some(Command::Ablation { features }) = &cli.command {
+        let (baseline, results) = ablation(&config, &train, &test, features)?;
+        println!("Full model accuracy: {:.2}%", baseline * 100.0);
+        println!("{:<36} {:>10} {:>10}", "removed feature", "accuracy", "delta");
+        for result in results {
+            println!(
+                "{:<36} {:>9.2}% {:>+9.2}%",
+                result.feature,
+                result.accuracy * 100.0,
+                result.delta * 100.0
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::LearningCurve {
+        fractions,
+        resamples,
+        output,
+    }) = &cli.command
+    {
+        let points = pipeline.learning_curve(&train, &test, fractions, *resamples)?;
+        println!(
+            "{:>8} {:>10} {:>18} {:>18} {:>8} {:>16}",
+            "fraction", "train rows", "train accuracy", "test accuracy", "gap", "macro F1"
+        );
+        for point in &points {
+            println!(
+                "{:>8.2} {:>10} {:>9.2}% ± {:>5.2}% {:>9.2}% ± {:>5.2}% {:>7.2}% {:>7.3} ± {:>6.3}",
+                point.fraction,
+                point.n_train,
+                point.train_accuracy.mean * 100.0,
+                point.train_accuracy.std * 100.0,
+                point.accuracy.mean * 100.0,
+                point.accuracy.std * 100.0,
+                (point.train_accuracy.mean - point.accuracy.mean) * 100.0,
+                point.macro_f1.mean,
+                point.macro_f1.std
+            );
+        }
+        if let Some(path) = output {
+            write_learning_curve(path, &points)?;
+            println!("Wrote the learning curve to {}", path);
+        }
+        return Ok(());
+    }
+
+    let result = match &cli.model_in {
+        Some(path) => {
+            if cli.model != ModelKind::RandomForest || cli.ensemble {
+                return Err("--model-in needs --model random-forest without --ensemble".into());
+            }
+            let saved = SavedForest::load(path)?;
+            if saved.feature_names != train.feature_names {
+                return Err(format!(
+                    "{} was fit on features {}, but this run has {}",
+                    path,
+                    saved.feature_names.join(", "),
+                    train.feature_names.join(", ")
+                )
+                .into());
+            }
+            let model = if cli.warm_append || !cli.append_data.is_empty() {
+                FittedModel::warm_append(&saved.forest, &config, &train)?
+            } else {
+                FittedModel::Forest(saved.forest)
+            };
+            if let FittedModel::Forest(forest) = &model {
+                println!("Forest from {}: {} trees", path, forest::ForestVotes::from_forest(forest)?.n_trees());
+            }
+            pipeline.evaluate_model(model, train, test)?
+        }
+        None => pipeline.evaluate(train, test)?,
+    };
+    if let Some(path) = &cli.model_out {
+        match &result.model {
+            Some(FittedModel::Forest(forest)) => {
+                SavedForest::save(path, &result.train.feature_names, forest)?;
+                println!("Saved the forest to {}", path);
+            }
+            _ => return Err("--model-out needs --model random-forest without --ensemble".into()),
+        }
+    }
+
+    if let Some(Command::Rank { k, good_above }) = &cli.command {
+        let outcome = good_above.map_or(GoodOutcome::AboveMedian, GoodOutcome::AboveThreshold);
+        let ranking = top_k_by_year(&result.test.rows, &attractiveness(&result.scores), *k, outcome);
+        println!("Top {} picks per year: {} rows", k, ranking.n_selected);
+        println!(
+            "Precision@{}: {:.2}% (base rate {:.2}%)",
+            k,
+            ranking.precision_at_k * 100.0,
+            ranking.base_rate * 100.0
+        );
+        println!(
+            "Mean price change: {:.2}% for the picks, {:.2}% for all test rows",
+            ranking.mean_selected_change, ranking.mean_change
+        );
+        return Ok(());
+    }
+    if let Some(Command::Backtest) = &cli.command {
+        let top_class = (pipeline.label_mode().n_classes() - 1) as u8;
+        let result = backtest(&result.test.rows, &result.y_pred, top_class);
+        println!("Backtest: buy every test row predicted in class {}, held for the year", top_class);
+        println!("  Picks: {} of {} rows", result.n_selected, result.n_rows);
+        match (result.strategy_return, result.excess_return()) {
+            (Some(strategy), Some(excess)) => {
+                println!("  Strategy return: {:.2}% ({:+.2} points against the market)", strategy, excess)
+            }
+            _ => println!("  Strategy return: undefined, nothing was predicted in class {}", top_class),
+        }
+        println!("  Market return: {:.2}% (every test row, equally weighted)", result.market_return);
+        return Ok(());
+    }
+    for member in &result.members {
+        let acc = accuracy(&result.test.labels, &member.y_pred);
+        println!("{} Accuracy: {:.2}%", member.name, acc * 100.0);
+    }
+    if let Some(FittedModel::Tree(tree)) = &result.model {
+        println!("Decision tree rules:");
+        print!("{}", forest::tree_rules(tree, &result.train.feature_names)?);
+    }
 
-    let y_pred = rf_classifier.predict(&x_test)?;
+    let mut run_metrics = result.metrics.clone();
+    println!(
+        "{} Accuracy: {:.2}%",
+        run_metrics.model,
+        run_metrics.accuracy.unwrap_or_default() * 100.0
+    );
+    if !result.tied.is_empty() {
+        let mut rows: Vec<String> = result
+            .tied
+            .iter()
+            .take(10)
+            .map(|&i| {
+                let row = &result.test.rows[i];
+                format!("{} {} (class {})", row.ticker, row.year, result.y_pred[i])
+            })
+            .collect();
+        if result.tied.len() > rows.len() {
+            rows.push(format!("and {} more", result.tied.len() - rows.len()));
+        }
+        println!(
+            "Tied votes on {} test rows, settled by --tie-break {}: {}",
+            result.tied.len(),
+            cli.tie_break.to_possible_value().map_or(String::new(), |value| value.get_name().to_string()),
+            rows.join(", ")
+        );
+    }
+    if let Some((ticker, year)) = &cli.explain {
+        let Some(model) = &result.model else {
+            return Err("explaining a prediction needs a single model; drop --ensemble".into());
+        };
+        let (split, rows, index) = match find_row(&result.test, ticker, *year) {
+            Some(index) => ("test", &result.test, index),
+            None => match find_row(&result.train, ticker, *year) {
+                Some(index) => ("training", &result.train, index),
+                None => {
+                    let reason = "it lacks a prior year or a label, or was filtered out";
+                    return Err(format!("no feature row for {} {}: {}", ticker, year, reason).into());
+                }
+            },
+        };
+        let explanation = explain(model, &result.train, rows, index, cli.tie_break)?;
+        println!(
+            "Explanation of {} {} ({} row): predicted class {}, actual class {}",
+            explanation.ticker, explanation.year, split, explanation.predicted, explanation.label
+        );
+        let votes: Vec<String> =
+            explanation.votes.iter().enumerate().map(|(class, votes)| format!("{}: {}", class, votes)).collect();
+        println!("  Votes per class: {}", votes.join(", "));
+        println!("  Votes for class {} lost when a feature is set to its training mean:", explanation.predicted);
+        println!("  {:<36} {:>12} {:>12} {:>6}", "feature", "value", "train mean", "votes");
+        for contribution in &explanation.contributions {
+            println!(
+                "  {:<36} {:>12.4} {:>12.4} {:>+6}",
+                contribution.feature, contribution.value, contribution.replacement, contribution.vote_change
+            );
+        }
+    }
+    if let Some(iterations) = cli.bootstrap {
+        let ci = metrics::bootstrap_accuracy_ci(
+            &result.test.labels,
+            &result.y_pred,
+            iterations,
+            cli.bootstrap_seed.unwrap_or(seed),
+        );
+        println!(
+            "Accuracy 95% CI (bootstrap, {} resamples): [{:.2}%, {:.2}%]",
+            ci.iterations,
+            ci.lower * 100.0,
+            ci.upper * 100.0
+        );
+        run_metrics.accuracy_ci = Some(ci);
+    }
+    println!("Macro F1: {:.3}", run_metrics.macro_f1.unwrap_or_default());
+    if let Some(report) = &run_metrics.classification {
+        println!("Per-class scores:");
+        println!("  {:<10} {:>9} {:>7} {:>6} {:>8}", "class", "precision", "recall", "F1", "support");
+        for class in &report.per_class {
+            println!(
+                "  {:<10} {:>9.3} {:>7.3} {:>6.3} {:>8}",
+                class.class, class.precision, class.recall, class.f1, class.support
+            );
+        }
+        let support = result.test.len();
+        for (name, avg) in [("macro avg", &report.macro_avg), ("micro avg", &report.micro_avg)] {
+            println!("  {:<10} {:>9.3} {:>7.3} {:>6.3} {:>8}", name, avg.precision, avg.recall, avg.f1, support);
+        }
+    }
 
-    let acc = accuracy(&y_test, &y_pred);
-    println!("Random Forest Classifier Accuracy: {:.2}%", acc * 100.0);
+    if let Some(auc) = &run_metrics.roc_auc {
+        println!("ROC AUC (one-vs-rest):");
+        for (class, class_auc) in auc.per_class.iter().enumerate() {
+            match class_auc {
+                Some(value) => println!("  class {}: {:.3}", class, value),
+                None => println!("  class {}: N/A", class),
+            }
+        }
+        match auc.macro_avg {
+            Some(value) => println!("  macro average: {:.3}", value),
+            None => println!("  macro average: N/A"),
+        }
+    }
+
+    if let Some(benchmarks) = &run_metrics.benchmarks {
+        println!(
+            "Rule benchmarks on the {} test rows with a prior-year price change ({} without one excluded):",
+            benchmarks.n_rows, benchmarks.excluded
+        );
+        println!("  {:<11} {:>9} {:>9}", "predictor", "accuracy", "macro F1");
+        for score in std::iter::once(&benchmarks.model).chain(&benchmarks.rules) {
+            println!("  {:<11} {:>8.2}% {:>9.3}", score.rule, score.accuracy * 100.0, score.macro_f1);
+        }
+    }
+
+    if cli.calibration {
+        let bins = metrics::calibration_bins(&result.test.labels, &result.scores);
+        println!("Calibration of the top-class probability:");
+        println!("  {:<12} {:>6} {:>11} {:>9}", "bin", "rows", "confidence", "accuracy");
+        for bin in &bins {
+            match (bin.mean_confidence, bin.accuracy) {
+                (Some(confidence), Some(acc)) => println!(
+                    "  [{:.1}, {:.1}) {:>6} {:>11.3} {:>9.3}",
+                    bin.lower, bin.upper, bin.count, confidence, acc
+                ),
+                _ => println!("  [{:.1}, {:.1}) {:>6} {:>11} {:>9}", bin.lower, bin.upper, 0, "-", "-"),
+            }
+        }
+        println!("  expected calibration error: {:.3}", metrics::expected_calibration_error(&bins));
+    }
+
+    if cli.by_year {
+        println!("Accuracy by year:");
+        println!("  {:<6} {:>6} {:>9} {:>9}  rows per class", "year", "rows", "accuracy", "macro F1");
+        for score in run_metrics.by_year.iter().flatten() {
+            let classes: Vec<String> = score.class_counts.iter().map(|count| count.to_string()).collect();
+            println!(
+                "  {:<6} {:>6} {:>8.2}% {:>9.3}  {}",
+                score.year,
+                score.count,
+                score.accuracy * 100.0,
+                score.macro_f1,
+                classes.join(" / ")
+            );
+        }
+    }
+
+    if let Some(n) = cli.top_n {
+        let top_class = pipeline.label_mode().n_classes() - 1;
+        let picks = top_n_by_top_class(&result.test.rows, &result.scores, &result.test.labels, top_class, n)?;
+        let hits = picks.iter().filter(|pick| pick.label as usize == top_class).count();
+        println!("Top {} test rows by probability of class {}:", picks.len(), top_class);
+        println!(
+            "  {:>4} {:<8} {:<6} {:>11} {:>10} {:>13}",
+            "rank", "ticker", "year", "probability", "true class", "price change"
+        );
+        for (rank, pick) in picks.iter().enumerate() {
+            println!(
+                "  {:>4} {:<8} {:<6} {:>11.3} {:>10} {:>12.2}%",
+                rank + 1,
+                pick.row.ticker,
+                pick.row.year,
+                pick.probability,
+                pick.label,
+                pick.row.price_change
+            );
+        }
+        if !picks.is_empty() {
+            println!(
+                "  precision@{}: {:.2}% of them are in class {}",
+                picks.len(),
+                hits as f64 / picks.len() as f64 * 100.0,
+                top_class
+            );
+        }
+    }
+
+    if cli.reliability {
+        let report = metrics::reliability_report(
+            &result.test.labels,
+            &result.y_pred,
+            Some(&result.scores),
+            pipeline.label_mode().n_classes(),
+        );
+        println!("Reliability by predicted class (true class counts):");
+        for class in &report.per_class {
+            match class.hit_rate {
+                Some(rate) => println!(
+                    "  predicted {}: {} rows, {:.2}% correct, true {:?}",
+                    class.class,
+                    class.count,
+                    rate * 100.0,
+                    class.true_counts
+                ),
+                None => println!("  predicted {}: never predicted", class.class),
+            }
+        }
+        println!("Top-class hit rate by score:");
+        for bucket in report.top_class_buckets.iter().filter(|bucket| bucket.count > 0) {
+            println!(
+                "  [{:.1}, {:.1}): {} rows, {:.2}% correct",
+                bucket.lower,
+                bucket.upper,
+                bucket.count,
+                bucket.hit_rate.unwrap_or_default() * 100.0
+            );
+        }
+    }
+
+    if let Some(path) = &cli.results {
+        write_results(path, &result.test, &result.y_pred, Some(&result.scores))?;
+        println!("Wrote {} test predictions to {}", result.test.len(), path);
+    }
+    if let Some(path) = &cli.report {
+        if let Some(model) = &result.model {
+            run_metrics.importances = Some(permutation_importance(model, &result.test, seed)?);
+        }
+        let report = RunReport {
+            settings: pipeline.settings(),
+            dataset: metrics::summarize_dataset(&dataset, pipeline.n_classes()),
+            undefined_ratios,
+            metrics: run_metrics.clone(),
+        };
+        report.write(path)?;
+        println!("Wrote the run report to {}", path);
+    }
+    if let Some(path) = &cli.metrics_json {
+        run_metrics.write_json(path)?;
+    }
 
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-    use stock_data::{read_csv, calculate_price_changes};
+    use clap::Parser;
+    use final_project::dataset::categorize_price_change;
+    use final_project::stock_data::{read_csv, calculate_price_changes, GapPolicy};
+    use super::{data_file, gap_policy, Cli};
+
+    #[test]
+    fn test_allow_gaps_is_an_alias_for_normalize() {
+        let parse = |args: &[&str]| Cli::try_parse_from(std::iter::once("final_project").chain(args.iter().copied()));
+        assert_eq!(gap_policy(&parse(&[]).unwrap()), GapPolicy::Skip);
+        assert_eq!(gap_policy(&parse(&["--allow-gaps"]).unwrap()), GapPolicy::Normalize);
+        assert_eq!(gap_policy(&parse(&["--gap-policy", "annotate"]).unwrap()), GapPolicy::Annotate);
+        assert!(parse(&["--allow-gaps", "--gap-policy", "skip"]).is_err());
+    }
+
+    #[test]
+    fn test_data_files_explicit_or_detected() {
+        let parse = |args: &[&str]| Cli::try_parse_from(std::iter::once("final_project").chain(args.iter().copied()));
+        // assets_mock.csv stands in for a splits.csv in the working directory
+        let cli = parse(&["--splits", "my_splits.csv"]).unwrap();
+        assert_eq!(data_file(&cli, &cli.splits, "assets_mock.csv").as_deref(), Some("my_splits.csv"));
+        let cli = parse(&[]).unwrap();
+        assert_eq!(data_file(&cli, &cli.splits, "assets_mock.csv").as_deref(), Some("assets_mock.csv"));
+        assert_eq!(data_file(&cli, &cli.splits, "no_such_file.csv"), None);
+        let cli = parse(&["--no-auto-files"]).unwrap();
+        assert_eq!(data_file(&cli, &cli.splits, "assets_mock.csv"), None);
+        let cli = parse(&["--no-auto-files", "--fx-rates", "rates.csv"]).unwrap();
+        assert_eq!(data_file(&cli, &cli.fx_rates, "assets_mock.csv").as_deref(), Some("rates.csv"));
+    }
 
     #[test]
     fn test_categorize_price_change() {
-        assert_eq!(categorize_price_change(-60.0), 0); 
-        assert_eq!(categorize_price_change(-30.0), 1);
-        assert_eq!(categorize_price_change(10.0), 2);  
-        assert_eq!(categorize_price_change(70.0), 3); 
+        assert_eq!(categorize_price_change(-60.0), Some(0));
+        assert_eq!(categorize_price_change(-30.0), Some(1));
+        assert_eq!(categorize_price_change(10.0), Some(2));
+        assert_eq!(categorize_price_change(70.0), Some(3));
     }
 
     #[test]
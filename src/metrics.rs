@@ -0,0 +1,936 @@
+use std::collections::{BTreeMap, HashSet};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use crate::dataset::Dataset;
+use crate::stock_data::ReturnBasis;
+
+/// One-vs-rest ROC AUC for every class plus their macro average.
+#[derive(Debug, Clone, Serialize)]
+pub struct RocAuc {
+    pub per_class: Vec<Option<f64>>, // None when the class is absent from (or is all of) y_true
+    pub macro_avg: Option<f64>,
+}
+
+/// ROC AUC of `scores` for separating `positive` from every other label.
+/// Rows are sorted by descending score and tied scores are moved through
+/// together, so ties contribute a diagonal segment (half credit).
+pub fn roc_auc(y_true: &[u8], scores: &[f64], positive: u8) -> Option<f64> {
+    let n_pos = y_true.iter().filter(|&&y| y == positive).count();
+    let n_neg = y_true.len() - n_pos;
+    if n_pos == 0 || n_neg == 0 {
+        return None;
+    }
+
+    let mut order: Vec<usize> = (0..scores.len()).collect();
+    order.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+
+    let mut auc = 0.0;
+    let (mut tp, mut fp) = (0usize, 0usize);
+    let (mut prev_tpr, mut prev_fpr) = (0.0, 0.0);
+    let mut i = 0;
+    while i < order.len() {
+        let score = scores[order[i]];
+        while i < order.len() && scores[order[i]] == score {
+            if y_true[order[i]] == positive {
+                tp += 1;
+            } else {
+                fp += 1;
+            }
+            i += 1;
+        }
+        let tpr = tp as f64 / n_pos as f64;
+        let fpr = fp as f64 / n_neg as f64;
+        auc += (fpr - prev_fpr) * (tpr + prev_tpr) / 2.0;
+        prev_tpr = tpr;
+        prev_fpr = fpr;
+    }
+    Some(auc)
+}
+
+/// `scores[i][k]` is the score of row `i` for class `k`.
+pub fn multiclass_roc_auc(y_true: &[u8], scores: &[Vec<f64>], n_classes: usize) -> RocAuc {
+    let per_class: Vec<Option<f64>> = (0..n_classes)
+        .map(|k| {
+            let class_scores: Vec<f64> = scores.iter().map(|s| s[k]).collect();
+            roc_auc(y_true, &class_scores, k as u8)
+        })
+        .collect();
+
+    let defined: Vec<f64> = per_class.iter().flatten().copied().collect();
+    let macro_avg = if defined.is_empty() {
+        None
+    } else {
+        Some(defined.iter().sum::<f64>() / defined.len() as f64)
+    };
+
+    RocAuc { per_class, macro_avg }
+}
+
+/// F1 score for each class that appears in `y_true` or `y_pred`, averaged
+/// without weighting. A class that is never predicted scores 0.
+pub fn macro_f1(y_true: &[u8], y_pred: &[u8], n_classes: usize) -> f64 {
+    let mut f1_scores = Vec::new();
+    for class in 0..n_classes as u8 {
+        let tp = y_true.iter().zip(y_pred).filter(|&(&t, &p)| t == class && p == class).count();
+        let actual = y_true.iter().filter(|&&t| t == class).count();
+        let predicted = y_pred.iter().filter(|&&p| p == class).count();
+        if actual == 0 && predicted == 0 {
+            continue;
+        }
+        // 2 * precision * recall / (precision + recall), without the 0/0 cases
+        f1_scores.push(2.0 * tp as f64 / (actual + predicted) as f64);
+    }
+    if f1_scores.is_empty() {
+        0.0
+    } else {
+        f1_scores.iter().sum::<f64>() / f1_scores.len() as f64
+    }
+}
+
+/// Precision, recall and F1 of one class, and its number of true rows.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClassScores {
+    pub class: u8,
+    pub precision: f64, // 0 when the class is never predicted
+    pub recall: f64,    // 0 when the class never occurs
+    pub f1: f64,
+    pub support: usize,
+}
+
+/// Precision, recall and F1 averaged one way over the classes.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AveragedScores {
+    pub precision: f64,
+    pub recall: f64,
+    pub f1: f64,
+}
+
+/// Per-class scores with their macro average (every class that occurs or is
+/// predicted counts equally, as in `macro_f1`) and micro average (every row
+/// counts equally). With one label per row the micro averages all equal the
+/// accuracy, so a gap between the two shows how much the rare classes drag.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClassificationReport {
+    pub per_class: Vec<ClassScores>,
+    pub macro_avg: AveragedScores,
+    pub micro_avg: AveragedScores,
+}
+
+pub fn classification_report(y_true: &[u8], y_pred: &[u8], n_classes: usize) -> ClassificationReport {
+    let ratio = |num: usize, den: usize| if den == 0 { 0.0 } else { num as f64 / den as f64 };
+    let mut per_class = Vec::new();
+    let mut counted = Vec::new();
+    let (mut total_tp, mut total_predicted, mut total_actual) = (0, 0, 0);
+    for class in 0..n_classes as u8 {
+        let tp = y_true.iter().zip(y_pred).filter(|&(&t, &p)| t == class && p == class).count();
+        let actual = y_true.iter().filter(|&&t| t == class).count();
+        let predicted = y_pred.iter().filter(|&&p| p == class).count();
+        let scores = ClassScores {
+            class,
+            precision: ratio(tp, predicted),
+            recall: ratio(tp, actual),
+            f1: ratio(2 * tp, actual + predicted),
+            support: actual,
+        };
+        if actual > 0 || predicted > 0 {
+            counted.push(scores.clone());
+        }
+        per_class.push(scores);
+        total_tp += tp;
+        total_predicted += predicted;
+        total_actual += actual;
+    }
+    let mean = |score: fn(&ClassScores) -> f64| {
+        if counted.is_empty() {
+            0.0
+        } else {
+            counted.iter().map(score).sum::<f64>() / counted.len() as f64
+        }
+    };
+    let macro_avg = AveragedScores {
+        precision: mean(|scores| scores.precision),
+        recall: mean(|scores| scores.recall),
+        f1: mean(|scores| scores.f1),
+    };
+    let micro_avg = AveragedScores {
+        precision: ratio(total_tp, total_predicted),
+        recall: ratio(total_tp, total_actual),
+        f1: ratio(2 * total_tp, total_predicted + total_actual),
+    };
+    ClassificationReport {
+        per_class,
+        macro_avg,
+        micro_avg,
+    }
+}
+
+/// How the test rows given one predicted class were actually labelled.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PredictedClassReliability {
+    pub class: u8,
+    pub count: usize,
+    pub true_counts: Vec<usize>, // indexed by true class
+    pub hit_rate: Option<f64>,   // None when the class is never predicted
+}
+
+/// Rows predicted as the top class whose top-class score is in `[lower, upper)`
+/// (the last bucket includes 1.0).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScoreBucket {
+    pub lower: f64,
+    pub upper: f64,
+    pub count: usize,
+    pub hit_rate: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReliabilityReport {
+    pub per_class: Vec<PredictedClassReliability>,
+    /// Ten score deciles of the highest class's predictions; empty without scores
+    pub top_class_buckets: Vec<ScoreBucket>,
+}
+
+fn hit_rate(hits: usize, count: usize) -> Option<f64> {
+    (count > 0).then(|| hits as f64 / count as f64)
+}
+
+/// Groups the rows by predicted class to show how often each prediction is right.
+/// With per-class `scores`, predictions of the highest class (the biggest movers)
+/// are also bucketed by that class's score in steps of 0.1.
+pub fn reliability_report(
+    y_true: &[u8],
+    y_pred: &[u8],
+    scores: Option<&[Vec<f64>]>,
+    n_classes: usize,
+) -> ReliabilityReport {
+    let per_class = (0..n_classes as u8)
+        .map(|class| {
+            let mut true_counts = vec![0; n_classes];
+            for (&t, _) in y_true.iter().zip(y_pred).filter(|&(_, &p)| p == class) {
+                true_counts[t as usize] += 1;
+            }
+            let count = true_counts.iter().sum();
+            PredictedClassReliability {
+                class,
+                count,
+                hit_rate: hit_rate(true_counts[class as usize], count),
+                true_counts,
+            }
+        })
+        .collect();
+
+    let top = n_classes.saturating_sub(1);
+    let top_class_buckets = match scores {
+        Some(scores) => (0..10)
+            .map(|decile| {
+                let (lower, upper) = (decile as f64 / 10.0, (decile + 1) as f64 / 10.0);
+                let rows: Vec<usize> = (0..y_pred.len())
+                    .filter(|&i| y_pred[i] as usize == top)
+                    .filter(|&i| {
+                        let score = scores[i][top];
+                        score >= lower && (score < upper || decile == 9)
+                    })
+                    .collect();
+                let hits = rows.iter().filter(|&&i| y_true[i] as usize == top).count();
+                ScoreBucket {
+                    lower,
+                    upper,
+                    count: rows.len(),
+                    hit_rate: hit_rate(hits, rows.len()),
+                }
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    ReliabilityReport {
+        per_class,
+        top_class_buckets,
+    }
+}
+
+/// Rows whose top-class probability is in `[lower, upper)` (the last bin includes 1.0).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CalibrationBin {
+    pub lower: f64,
+    pub upper: f64,
+    pub count: usize,
+    pub mean_confidence: Option<f64>, // None for an empty bin
+    pub accuracy: Option<f64>,
+}
+
+/// Reliability-diagram data: rows are binned into deciles by the probability of
+/// their highest-scoring class, and each bin compares that mean probability with
+/// how often the highest-scoring class was the true one.
+pub fn calibration_bins(y_true: &[u8], scores: &[Vec<f64>]) -> Vec<CalibrationBin> {
+    let mut sums = [(0usize, 0.0, 0usize); 10]; // (count, confidence sum, correct)
+    for (&label, row) in y_true.iter().zip(scores) {
+        let Some((class, &confidence)) = row.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1).then(b.0.cmp(&a.0)))
+        else {
+            continue;
+        };
+        let bin = ((confidence * 10.0).floor() as usize).min(9);
+        sums[bin].0 += 1;
+        sums[bin].1 += confidence;
+        sums[bin].2 += usize::from(class == label as usize);
+    }
+    sums.iter()
+        .enumerate()
+        .map(|(bin, &(count, confidence, correct))| CalibrationBin {
+            lower: bin as f64 / 10.0,
+            upper: (bin + 1) as f64 / 10.0,
+            count,
+            mean_confidence: (count > 0).then(|| confidence / count as f64),
+            accuracy: hit_rate(correct, count),
+        })
+        .collect()
+}
+
+/// Count-weighted mean gap between confidence and accuracy over the non-empty bins.
+pub fn expected_calibration_error(bins: &[CalibrationBin]) -> f64 {
+    let total: usize = bins.iter().map(|bin| bin.count).sum();
+    if total == 0 {
+        return 0.0;
+    }
+    bins.iter()
+        .filter_map(|bin| Some(bin.count as f64 * (bin.accuracy? - bin.mean_confidence?).abs()))
+        .sum::<f64>()
+        / total as f64
+}
+
+/// Counts of test rows by true class (rows) and predicted class (columns).
+pub fn confusion_matrix(y_true: &[u8], y_pred: &[u8], n_classes: usize) -> Vec<Vec<usize>> {
+    let mut matrix = vec![vec![0; n_classes]; n_classes];
+    for (&label, &predicted) in y_true.iter().zip(y_pred) {
+        matrix[label as usize][predicted as usize] += 1;
+    }
+    matrix
+}
+
+/// Multi-class Matthews correlation coefficient (Gorodkin's R_K) from the
+/// confusion matrix: 1 for perfect predictions, 0 for chance level. Defined as 0
+/// when every prediction or every true label is one class, where it is 0/0.
+pub fn mcc(y_true: &[u8], y_pred: &[u8], n_classes: usize) -> f64 {
+    let matrix = confusion_matrix(y_true, y_pred, n_classes);
+    let total = y_true.len() as f64;
+    let correct = (0..n_classes).map(|k| matrix[k][k]).sum::<usize>() as f64;
+    let true_counts: Vec<f64> = matrix.iter().map(|row| row.iter().sum::<usize>() as f64).collect();
+    let predicted_counts: Vec<f64> =
+        (0..n_classes).map(|k| matrix.iter().map(|row| row[k]).sum::<usize>() as f64).collect();
+    let covariance = correct * total - true_counts.iter().zip(&predicted_counts).map(|(t, p)| t * p).sum::<f64>();
+    let spread = |counts: &[f64]| total * total - counts.iter().map(|count| count * count).sum::<f64>();
+    let denominator = (spread(&predicted_counts) * spread(&true_counts)).sqrt();
+    if denominator == 0.0 {
+        0.0
+    } else {
+        covariance / denominator
+    }
+}
+
+/// Test accuracy of predictors that ignore the features, for comparison with the model.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Baselines {
+    pub majority_class: u8,       // the most common training class, the lowest on ties
+    pub majority_accuracy: f64,   // always predicting `majority_class`
+    pub stratified_accuracy: f64, // expected accuracy of guessing classes at their training frequencies
+}
+
+pub fn baselines(train_labels: &[u8], test_labels: &[u8], n_classes: usize) -> Baselines {
+    let share = |labels: &[u8], class: u8| {
+        labels.iter().filter(|&&label| label == class).count() as f64 / labels.len().max(1) as f64
+    };
+    let majority_class = (0..n_classes as u8)
+        .max_by(|&a, &b| share(train_labels, a).total_cmp(&share(train_labels, b)).then(b.cmp(&a)))
+        .unwrap_or(0);
+    Baselines {
+        majority_class,
+        majority_accuracy: share(test_labels, majority_class),
+        stratified_accuracy: (0..n_classes as u8)
+            .map(|class| share(train_labels, class) * share(test_labels, class))
+            .sum(),
+    }
+}
+
+/// Accuracy and macro F1 of one predictor on the rows of `RuleBenchmarks`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RuleScore {
+    pub rule: String,
+    pub accuracy: f64,
+    pub macro_f1: f64,
+}
+
+/// Rule-based predictors that look only at the prior year's price change,
+/// scored against the model on the test rows that have one.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RuleBenchmarks {
+    pub n_rows: usize,   // test rows with a prior-year price change
+    pub excluded: usize, // test rows without one, left out of every score here
+    pub model: RuleScore,
+    pub rules: Vec<RuleScore>, // momentum, then contrarian
+}
+
+/// Scores momentum (next year's class is the prior year's) and contrarian
+/// (the opposite end of the scale, `n_classes - 1 - prior`) against the model.
+/// `prior_classes` holds the class of each test row's prior-year price change,
+/// None where it is missing; those rows are excluded from all three scores.
+pub fn rule_benchmarks(y_true: &[u8], y_pred: &[u8], prior_classes: &[Option<u8>], n_classes: usize) -> RuleBenchmarks {
+    let mut truth = Vec::new();
+    let mut model = Vec::new();
+    let mut momentum = Vec::new();
+    for ((&label, &predicted), prior) in y_true.iter().zip(y_pred).zip(prior_classes) {
+        if let Some(prior) = *prior {
+            truth.push(label);
+            model.push(predicted);
+            momentum.push(prior);
+        }
+    }
+    let contrarian: Vec<u8> = momentum.iter().map(|&prior| (n_classes as u8 - 1).saturating_sub(prior)).collect();
+    let score = |rule: &str, predicted: &[u8]| RuleScore {
+        rule: rule.to_string(),
+        accuracy: truth.iter().zip(predicted).filter(|(t, p)| t == p).count() as f64 / truth.len().max(1) as f64,
+        macro_f1: macro_f1(&truth, predicted, n_classes),
+    };
+    RuleBenchmarks {
+        n_rows: truth.len(),
+        excluded: y_true.len() - truth.len(),
+        model: score("model", &model),
+        rules: vec![score("momentum", &momentum), score("contrarian", &contrarian)],
+    }
+}
+
+/// How much the test accuracy falls when one feature's values are shuffled.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FeatureImportance {
+    pub feature: String,
+    pub importance: f64,
+}
+
+/// How the model did on the test rows of one year.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct YearScore {
+    pub year: u32,
+    pub count: usize,
+    pub accuracy: f64,
+    pub macro_f1: f64,
+    pub class_counts: Vec<usize>, // true labels of the year's rows, indexed by class
+}
+
+/// Scores per year of the test rows, in year order. `years` is aligned with the labels.
+pub fn scores_by_year(years: &[u32], y_true: &[u8], y_pred: &[u8], n_classes: usize) -> Vec<YearScore> {
+    let mut by_year: BTreeMap<u32, (Vec<u8>, Vec<u8>)> = BTreeMap::new(); // (true, predicted)
+    for ((&year, &label), &predicted) in years.iter().zip(y_true).zip(y_pred) {
+        let entry = by_year.entry(year).or_default();
+        entry.0.push(label);
+        entry.1.push(predicted);
+    }
+    by_year
+        .into_iter()
+        .map(|(year, (labels, predicted))| {
+            let mut class_counts = vec![0; n_classes];
+            for &label in &labels {
+                class_counts[label as usize] += 1;
+            }
+            let correct = labels.iter().zip(&predicted).filter(|(label, predicted)| label == predicted).count();
+            YearScore {
+                year,
+                count: labels.len(),
+                accuracy: correct as f64 / labels.len() as f64,
+                macro_f1: macro_f1(&labels, &predicted, n_classes),
+                class_counts,
+            }
+        })
+        .collect()
+}
+
+/// Percentile interval of a metric over bootstrap resamples of the test rows.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConfidenceInterval {
+    pub lower: f64,
+    pub upper: f64,
+    pub iterations: usize,
+}
+
+/// 95% interval of the accuracy: `(y_true, y_pred)` pairs are resampled with
+/// replacement `iterations` times and the 2.5th and 97.5th percentiles of the
+/// resampled accuracies are reported. The same seed gives the same interval.
+pub fn bootstrap_accuracy_ci(y_true: &[u8], y_pred: &[u8], iterations: usize, seed: u64) -> ConfidenceInterval {
+    let n = y_true.len();
+    if n == 0 || iterations == 0 {
+        return ConfidenceInterval {
+            lower: f64::NAN,
+            upper: f64::NAN,
+            iterations,
+        };
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut accuracies: Vec<f64> = (0..iterations)
+        .map(|_| {
+            let correct = (0..n)
+                .map(|_| rng.gen_range(0..n))
+                .filter(|&i| y_true[i] == y_pred[i])
+                .count();
+            correct as f64 / n as f64
+        })
+        .collect();
+    accuracies.sort_by(f64::total_cmp);
+    let percentile = |q: f64| accuracies[((q * (iterations - 1) as f64).round() as usize).min(iterations - 1)];
+    ConfidenceInterval {
+        lower: percentile(0.025),
+        upper: percentile(0.975),
+        iterations,
+    }
+}
+
+/// Count, missing count and moments of one column. `missing` counts NaN and
+/// infinite values, which the other fields leave out; `std` is the population
+/// standard deviation. The mean and std are 0 and min/max NaN for a column with
+/// no finite values.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ColumnStats {
+    pub name: String,
+    pub count: usize,
+    pub missing: usize,
+    pub min: f64,
+    pub mean: f64,
+    pub max: f64,
+    pub std: f64,
+}
+
+impl ColumnStats {
+    pub fn of(name: &str, values: impl Iterator<Item = f64> + Clone) -> ColumnStats {
+        let (mut count, mut missing, mut sum) = (0, 0, 0.0);
+        let (mut min, mut max) = (f64::NAN, f64::NAN);
+        for value in values.clone() {
+            if !value.is_finite() {
+                missing += 1;
+                continue;
+            }
+            count += 1;
+            sum += value;
+            min = min.min(value);
+            max = max.max(value);
+        }
+        let n = count.max(1) as f64;
+        let mean = sum / n;
+        let variance = values.filter(|v| v.is_finite()).map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+        ColumnStats {
+            name: name.to_string(),
+            count,
+            missing,
+            min,
+            mean,
+            max,
+            std: variance.sqrt(),
+        }
+    }
+}
+
+/// Profile of a prepared dataset, printed by the `summary` subcommand.
+#[derive(Debug, Clone, Serialize)]
+pub struct DatasetSummary {
+    pub n_rows: usize,
+    pub n_tickers: usize,
+    pub first_year: Option<u32>,
+    pub last_year: Option<u32>,
+    pub rows_per_year: Vec<(u32, usize)>, // sorted by year
+    pub class_counts: Vec<usize>,         // indexed by class
+    pub features: Vec<ColumnStats>,
+}
+
+pub fn summarize_dataset(dataset: &Dataset, n_classes: usize) -> DatasetSummary {
+    let mut per_year: BTreeMap<u32, usize> = BTreeMap::new();
+    for row in &dataset.rows {
+        *per_year.entry(row.year).or_default() += 1;
+    }
+    let tickers: HashSet<&str> = dataset.rows.iter().map(|row| row.ticker.as_str()).collect();
+    let mut class_counts = vec![0; n_classes];
+    for &label in &dataset.labels {
+        class_counts[label as usize] += 1;
+    }
+    DatasetSummary {
+        n_rows: dataset.len(),
+        n_tickers: tickers.len(),
+        first_year: per_year.keys().next().copied(),
+        last_year: per_year.keys().next_back().copied(),
+        rows_per_year: per_year.into_iter().collect(),
+        class_counts,
+        features: dataset.column_stats(),
+    }
+}
+
+/// Mean, sample standard deviation and range of a metric across runs.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub mean: f64,
+    pub std: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Summary {
+    pub fn of(values: &[f64]) -> Summary {
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let std = if values.len() > 1 {
+            (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt()
+        } else {
+            0.0
+        };
+        Summary {
+            mean,
+            std,
+            min: values.iter().cloned().fold(f64::INFINITY, f64::min),
+            max: values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RepeatRun {
+    pub seed: u64,
+    pub accuracy: f64,
+    pub macro_f1: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RepeatSummary {
+    pub runs: Vec<RepeatRun>,
+    pub accuracy: Summary,
+    pub macro_f1: Summary,
+}
+
+/// Scores of models trained on one fraction of the training rows, summarized
+/// over the resamples of that fraction. `train_accuracy` is measured on the rows
+/// each model was fit on; the other scores on the test set.
+#[derive(Debug, Clone, Serialize)]
+pub struct LearningCurvePoint {
+    pub fraction: f64,
+    pub n_train: usize,
+    pub train_accuracy: Summary,
+    pub accuracy: Summary,
+    pub macro_f1: Summary,
+}
+
+/// Everything a run reports, written by `--metrics-json`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunMetrics {
+    pub model: String,
+    pub seed: u64,
+    pub horizon: u32, // years of price change each label covers
+    pub returns: ReturnBasis,
+    pub n_rows: usize,
+    pub features: Vec<String>, // columns the model was trained on, in order
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accuracy: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accuracy_ci: Option<ConfidenceInterval>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub macro_f1: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub classification: Option<ClassificationReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mcc: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub roc_auc: Option<RocAuc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeats: Option<RepeatSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confusion_matrix: Option<Vec<Vec<usize>>>, // true class by predicted class
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub baselines: Option<Baselines>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub benchmarks: Option<RuleBenchmarks>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub by_year: Option<Vec<YearScore>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub importances: Option<Vec<FeatureImportance>>, // permutation importance, largest first
+}
+
+impl RunMetrics {
+    pub fn write_json(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roc_auc_hand_computed() {
+        // Positive/negative pairs: (0.9,0.7) (0.9,0.2) (0.4,0.7) (0.4,0.2) -> 3 of 4 ordered
+        let y = [1, 0, 1, 0];
+        let scores = [0.9, 0.7, 0.4, 0.2];
+        assert_eq!(roc_auc(&y, &scores, 1), Some(0.75));
+    }
+
+    #[test]
+    fn test_roc_auc_with_ties() {
+        // The tied 0.8 pair counts as half: (1 + 1 + 0.5 + 1) / 4
+        let y = [1, 1, 0, 0];
+        let scores = [0.9, 0.8, 0.8, 0.3];
+        assert_eq!(roc_auc(&y, &scores, 1), Some(0.875));
+
+        // Everything tied is a coin flip
+        assert_eq!(roc_auc(&y, &[0.5; 4], 1), Some(0.5));
+    }
+
+    #[test]
+    fn test_multiclass_roc_auc_absent_class() {
+        let y = [0, 1, 2, 0];
+        let scores = vec![
+            vec![0.8, 0.1, 0.1, 0.0],
+            vec![0.2, 0.6, 0.2, 0.0],
+            vec![0.1, 0.3, 0.6, 0.0],
+            vec![0.3, 0.7, 0.0, 0.0],
+        ];
+        let auc = multiclass_roc_auc(&y, &scores, 4);
+
+        assert_eq!(auc.per_class[0], Some(1.0));
+        // Class 1: positive 0.6 beats 0.1 and 0.3 but loses to 0.7
+        assert!((auc.per_class[1].unwrap() - 2.0 / 3.0).abs() < 1e-12);
+        assert_eq!(auc.per_class[2], Some(1.0));
+        assert_eq!(auc.per_class[3], None);
+        assert!((auc.macro_avg.unwrap() - (1.0 + 2.0 / 3.0 + 1.0) / 3.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_macro_f1() {
+        let y_true = [0, 0, 1, 1, 2];
+        let y_pred = [0, 1, 1, 1, 0];
+        // class 0: 2*1/(2+2) = 0.5, class 1: 2*2/(2+3) = 0.8, class 2: 0
+        let f1 = macro_f1(&y_true, &y_pred, 4);
+        assert!((f1 - (0.5 + 0.8 + 0.0) / 3.0).abs() < 1e-12);
+        assert_eq!(macro_f1(&y_true, &y_true, 4), 1.0);
+    }
+
+    #[test]
+    fn test_classification_report_macro_and_micro() {
+        // 8 rows of class 0, one each of 1 and 2; the model always says class 0
+        let mut y_true = vec![0; 8];
+        y_true.extend([1, 2]);
+        let y_pred = vec![0; 10];
+        let report = classification_report(&y_true, &y_pred, 4);
+
+        assert_eq!(report.per_class[0].precision, 0.8);
+        assert_eq!(report.per_class[0].recall, 1.0);
+        assert_eq!(report.per_class[1].support, 1);
+        assert_eq!(report.per_class[1].f1, 0.0);
+        // Micro averages count rows, so they all equal the 80% accuracy
+        let accuracy = 0.8;
+        assert_eq!(report.micro_avg.precision, accuracy);
+        assert_eq!(report.micro_avg.recall, accuracy);
+        assert!((report.micro_avg.f1 - accuracy).abs() < 1e-12);
+        // Macro averages count classes 0-2 (class 3 neither occurs nor is predicted)
+        assert!((report.macro_avg.precision - 0.8 / 3.0).abs() < 1e-12);
+        assert!((report.macro_avg.recall - 1.0 / 3.0).abs() < 1e-12);
+        let f1_class_0 = 2.0 * 8.0 / 18.0;
+        assert!((report.macro_avg.f1 - f1_class_0 / 3.0).abs() < 1e-12);
+        assert_eq!(report.macro_avg.f1, macro_f1(&y_true, &y_pred, 4));
+        assert!(report.micro_avg.f1 > 2.0 * report.macro_avg.f1);
+    }
+
+    #[test]
+    fn test_summary() {
+        let summary = Summary::of(&[0.5, 0.7, 0.9]);
+        assert!((summary.mean - 0.7).abs() < 1e-12);
+        assert!((summary.std - 0.2).abs() < 1e-12);
+        assert_eq!(summary.min, 0.5);
+        assert_eq!(summary.max, 0.9);
+        assert_eq!(Summary::of(&[0.4]).std, 0.0);
+    }
+
+    #[test]
+    fn test_reliability_report() {
+        let y_true = [3, 3, 2, 1, 0, 2, 3];
+        let y_pred = [3, 3, 3, 1, 1, 2, 2];
+        let top_scores = [0.95, 0.62, 0.68, 0.1, 0.2, 0.3, 0.4];
+        let scores: Vec<Vec<f64>> = top_scores.iter().map(|&s| vec![0.0, 0.0, 1.0 - s, s]).collect();
+
+        let report = reliability_report(&y_true, &y_pred, Some(&scores), 4);
+        // Class 0 is never predicted
+        assert_eq!(report.per_class[0].count, 0);
+        assert_eq!(report.per_class[0].true_counts, vec![0, 0, 0, 0]);
+        assert_eq!(report.per_class[0].hit_rate, None);
+        assert_eq!(report.per_class[1].true_counts, vec![1, 1, 0, 0]);
+        assert_eq!(report.per_class[1].hit_rate, Some(0.5));
+        assert_eq!(report.per_class[2].true_counts, vec![0, 0, 1, 1]);
+        assert_eq!(report.per_class[3].count, 3);
+        assert_eq!(report.per_class[3].true_counts, vec![0, 0, 1, 2]);
+        assert!((report.per_class[3].hit_rate.unwrap() - 2.0 / 3.0).abs() < 1e-12);
+
+        let buckets = &report.top_class_buckets;
+        assert_eq!(buckets.len(), 10);
+        assert_eq!(buckets.iter().map(|b| b.count).sum::<usize>(), 3);
+        // 0.62 (hit) and 0.68 (miss) share a bucket; rows predicted as class 2 are left out
+        assert_eq!((buckets[6].count, buckets[6].hit_rate), (2, Some(0.5)));
+        assert_eq!((buckets[9].count, buckets[9].hit_rate), (1, Some(1.0)));
+        assert_eq!(buckets[3].hit_rate, None);
+
+        assert!(reliability_report(&y_true, &y_pred, None, 4).top_class_buckets.is_empty());
+    }
+
+    #[test]
+    fn test_calibration_bins() {
+        // Ten rows at 0.95 confidence with nine right, five at 0.65 with three right
+        let mut y_true = Vec::new();
+        let mut scores = Vec::new();
+        for i in 0..10 {
+            y_true.push(if i < 9 { 2 } else { 0 });
+            scores.push(vec![0.0, 0.05, 0.95, 0.0]);
+        }
+        for i in 0..5 {
+            y_true.push(if i < 3 { 1 } else { 3 });
+            scores.push(vec![0.1, 0.65, 0.0, 0.25]);
+        }
+
+        let bins = calibration_bins(&y_true, &scores);
+        assert_eq!(bins.len(), 10);
+        assert_eq!(bins[9].count, 10);
+        assert!((bins[9].mean_confidence.unwrap() - 0.95).abs() < 1e-12);
+        assert_eq!(bins[9].accuracy, Some(0.9));
+        assert_eq!(bins[6].count, 5);
+        assert!((bins[6].mean_confidence.unwrap() - 0.65).abs() < 1e-12);
+        assert_eq!(bins[6].accuracy, Some(0.6));
+        // Empty bins have no statistics rather than zeros
+        assert_eq!((bins[0].count, bins[0].mean_confidence, bins[0].accuracy), (0, None, None));
+
+        // (10 * 0.05 + 5 * 0.05) / 15
+        assert!((expected_calibration_error(&bins) - 0.05).abs() < 1e-12);
+        assert_eq!(expected_calibration_error(&calibration_bins(&[], &[])), 0.0);
+    }
+
+    #[test]
+    fn test_scores_by_year() {
+        // Every 2021 row is right and every 2022 row wrong
+        let years = [2022, 2021, 2022, 2021, 2022, 2021];
+        let y_true = [0, 1, 2, 3, 2, 1];
+        let y_pred = [1, 1, 0, 3, 1, 1];
+        assert_eq!(
+            scores_by_year(&years, &y_true, &y_pred, 4),
+            vec![
+                YearScore {
+                    year: 2021,
+                    count: 3,
+                    accuracy: 1.0,
+                    macro_f1: 1.0,
+                    class_counts: vec![0, 2, 0, 1],
+                },
+                YearScore {
+                    year: 2022,
+                    count: 3,
+                    accuracy: 0.0,
+                    macro_f1: 0.0,
+                    class_counts: vec![1, 0, 2, 0],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_confusion_matrix_and_baselines() {
+        let y_true = [0, 1, 1, 2, 2, 2];
+        let y_pred = [0, 2, 1, 2, 1, 2];
+        assert_eq!(confusion_matrix(&y_true, &y_pred, 3), vec![vec![1, 0, 0], vec![0, 1, 1], vec![0, 1, 2]]);
+
+        // Class 1 and 2 tie in training, so the lower one is the majority
+        let baselines = baselines(&[1, 1, 2, 2], &y_true, 3);
+        assert_eq!(baselines.majority_class, 1);
+        assert!((baselines.majority_accuracy - 2.0 / 6.0).abs() < 1e-12);
+        assert!((baselines.stratified_accuracy - (0.5 * 2.0 / 6.0 + 0.5 * 3.0 / 6.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_momentum_and_contrarian_benchmarks() {
+        let y_true = [3, 0, 2, 1, 3, 0];
+        let y_pred = [3, 1, 2, 0, 3, 0];
+        // Momentum is right on the first two rows and wrong on the third and fifth;
+        // the fourth and sixth have no prior-year change
+        let prior = [Some(3), Some(0), Some(1), None, Some(0), None];
+        let benchmarks = rule_benchmarks(&y_true, &y_pred, &prior, 4);
+        assert_eq!((benchmarks.n_rows, benchmarks.excluded), (4, 2));
+        assert_eq!(benchmarks.model.accuracy, 0.75);
+
+        let momentum = &benchmarks.rules[0];
+        assert_eq!(momentum.rule, "momentum");
+        assert_eq!(momentum.accuracy, 0.5);
+        // Classes 0 and 3 score 2/3 each, 1 (predicted only) and 2 (never predicted) 0
+        assert!((momentum.macro_f1 - 1.0 / 3.0).abs() < 1e-12);
+
+        // Contrarian predicts 0, 3, 2 and 3: right on the third and fifth rows
+        let contrarian = &benchmarks.rules[1];
+        assert_eq!(contrarian.rule, "contrarian");
+        assert_eq!(contrarian.accuracy, 0.5);
+        assert!((contrarian.macro_f1 - (0.0 + 1.0 + 0.5) / 3.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_mcc() {
+        // Confusion matrix [[2, 1, 0], [0, 2, 1], [1, 0, 3]]: c = 7, s = 10, t = p = (3, 3, 4)
+        // (7 * 10 - 34) / (100 - 34) = 36 / 66
+        let y_true = [0, 0, 0, 1, 1, 1, 2, 2, 2, 2];
+        let y_pred = [0, 0, 1, 1, 1, 2, 0, 2, 2, 2];
+        assert!((mcc(&y_true, &y_pred, 3) - 36.0 / 66.0).abs() < 1e-12);
+        assert_eq!(mcc(&y_true, &y_true, 3), 1.0);
+        assert_eq!(mcc(&y_true, &[1; 10], 3), 0.0);
+        assert_eq!(mcc(&[], &[], 3), 0.0);
+    }
+
+    #[test]
+    fn test_bootstrap_accuracy_ci() {
+        // 70 of 100 predictions correct
+        let y_true: Vec<u8> = (0..100).map(|i| (i % 4) as u8).collect();
+        let y_pred: Vec<u8> = y_true.iter().enumerate().map(|(i, &y)| if i < 70 { y } else { (y + 1) % 4 }).collect();
+
+        let ci = bootstrap_accuracy_ci(&y_true, &y_pred, 1000, 3);
+        assert_eq!(ci.iterations, 1000);
+        assert!(ci.lower < 0.7 && 0.7 < ci.upper, "{:?}", ci);
+        assert!(ci.upper - ci.lower > 0.05 && ci.upper - ci.lower < 0.3, "{:?}", ci);
+        assert_eq!(ci, bootstrap_accuracy_ci(&y_true, &y_pred, 1000, 3));
+    }
+
+    #[test]
+    fn test_column_stats_and_dataset_summary() {
+        use crate::dataset::RowId;
+
+        let row = |ticker: &str, year: u32| RowId { ticker: ticker.to_string(), year, ..Default::default() };
+        let dataset = Dataset::from_rows(
+            vec!["a".to_string(), "b".to_string(), "empty".to_string()],
+            &[
+                vec![1.0, 10.0, f64::NAN],
+                vec![2.0, f64::NAN, f64::NAN],
+                vec![3.0, 10.0, f64::INFINITY],
+                vec![6.0, 10.0, f64::NAN],
+            ],
+            vec![0, 2, 2, 3],
+            vec![row("AAA", 2021), row("AAA", 2022), row("BBB", 2022), row("CCC", 2022)],
+        );
+
+        let stats = dataset.column_stats();
+        assert_eq!(
+            stats[0],
+            ColumnStats {
+                name: "a".to_string(),
+                count: 4,
+                missing: 0,
+                min: 1.0,
+                mean: 3.0,
+                max: 6.0,
+                std: 3.5f64.sqrt(), // (4 + 1 + 0 + 9) / 4
+            }
+        );
+        assert_eq!((stats[1].count, stats[1].missing, stats[1].mean, stats[1].std), (3, 1, 10.0, 0.0));
+        assert_eq!((stats[2].count, stats[2].missing, stats[2].mean), (0, 4, 0.0));
+        assert!(stats[2].min.is_nan() && stats[2].max.is_nan());
+
+        let summary = summarize_dataset(&dataset, 4);
+        assert_eq!((summary.n_rows, summary.n_tickers), (4, 3));
+        assert_eq!((summary.first_year, summary.last_year), (Some(2021), Some(2022)));
+        assert_eq!(summary.rows_per_year, vec![(2021, 1), (2022, 3)]);
+        assert_eq!(summary.class_counts, vec![1, 0, 2, 1]);
+        assert_eq!(summary.features[..2], stats[..2]);
+    }
+}
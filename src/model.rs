@@ -0,0 +1,440 @@
+use std::error::Error;
+use std::fmt;
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use smartcore::ensemble::random_forest_classifier::{RandomForestClassifier, RandomForestClassifierParameters};
+use smartcore::linalg::basic::matrix::DenseMatrix;
+use smartcore::tree::decision_tree_classifier::{
+    DecisionTreeClassifier, DecisionTreeClassifierParameters, SplitCriterion,
+};
+use crate::dataset::{Dataset, N_CLASSES};
+use crate::forest::{append_trees, Forest, ForestVotes, TieBreak, Tree, VotedPredictions};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ModelKind {
+    RandomForest,
+    /// A single shallow tree whose rules are printed after training
+    DecisionTree,
+}
+
+impl ModelKind {
+    pub fn label(self) -> &'static str {
+        match self {
+            ModelKind::RandomForest => "Random Forest Classifier",
+            ModelKind::DecisionTree => "Decision Tree Classifier",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Criterion {
+    Gini,
+    Entropy,
+    ClassificationError,
+}
+
+impl From<Criterion> for SplitCriterion {
+    fn from(criterion: Criterion) -> Self {
+        match criterion {
+            Criterion::Gini => SplitCriterion::Gini,
+            Criterion::Entropy => SplitCriterion::Entropy,
+            Criterion::ClassificationError => SplitCriterion::ClassificationError,
+        }
+    }
+}
+
+// Accepts `none` for the optional limits, e.g. `--max-depth none`
+pub fn parse_optional<T: std::str::FromStr>(value: &str) -> Result<Option<T>, T::Err> {
+    if value.eq_ignore_ascii_case("none") {
+        Ok(None)
+    } else {
+        value.parse().map(Some)
+    }
+}
+
+/// Random forest settings, settable from the command line or a JSON file.
+///
+/// smartcore 0.3.2 always draws bootstrap samples of the training set's size
+/// (per class), so there is no sample-size setting to expose. Defaults are this
+/// project's tuned values; each option notes smartcore's own default.
+#[derive(Debug, Clone, PartialEq, Args, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ForestConfig {
+    /// Number of trees in the forest (smartcore default: 100)
+    #[arg(long, default_value_t = 500)]
+    pub n_trees: u16,
+    /// Maximum tree depth, or `none` for unlimited (smartcore default: none)
+    // Spelled out so clap parses `Option` values itself instead of treating the flag as optional
+    #[arg(long, default_value = "10", value_parser = parse_optional::<u16>)]
+    pub max_depth: std::option::Option<u16>,
+    /// Minimum number of samples required to split a node (smartcore default: 2)
+    #[arg(long, default_value_t = 25)]
+    pub min_samples_split: usize,
+    /// Minimum number of samples in a leaf (smartcore default: 1)
+    #[arg(long, default_value_t = 1)]
+    pub min_samples_leaf: usize,
+    /// Features considered per split, or `none` for sqrt(number of features) (smartcore default: none)
+    #[arg(long = "mtry", default_value = "3", value_parser = parse_optional::<usize>)]
+    pub m: std::option::Option<usize>,
+    /// Split quality criterion (smartcore default: gini)
+    #[arg(long, value_enum, default_value_t = Criterion::Gini)]
+    pub criterion: Criterion,
+    /// Keep each tree's bootstrap sample, needed for out-of-bag predictions (smartcore default: off)
+    #[arg(long)]
+    pub keep_samples: bool,
+}
+
+impl Default for ForestConfig {
+    fn default() -> Self {
+        ForestConfig {
+            n_trees: 500,
+            max_depth: Some(10),
+            min_samples_split: 25,
+            min_samples_leaf: 1,
+            m: Some(3),
+            criterion: Criterion::Gini,
+            keep_samples: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {}: {}", self.field, self.message)
+    }
+}
+
+impl Error for ConfigError {}
+
+impl ForestConfig {
+    pub fn from_json_file(path: &str) -> Result<ForestConfig, Box<dyn Error>> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    /// Rejects settings smartcore would panic on or silently misuse, given the dataset's column count.
+    pub fn validate(&self, n_features: usize) -> Result<(), ConfigError> {
+        let invalid = |field, message: String| Err(ConfigError { field, message });
+        if self.n_trees == 0 {
+            return invalid("n_trees", "the forest needs at least one tree".to_string());
+        }
+        if self.max_depth == Some(0) {
+            return invalid("max_depth", "must be at least 1".to_string());
+        }
+        if self.min_samples_split < 2 {
+            return invalid("min_samples_split", "a split needs at least 2 samples".to_string());
+        }
+        if self.min_samples_leaf == 0 {
+            return invalid("min_samples_leaf", "must be at least 1".to_string());
+        }
+        if let Some(m) = self.m {
+            if m == 0 || m > n_features {
+                return invalid("m", format!("must be between 1 and the {} available features, got {}", n_features, m));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_params(&self, seed: u64) -> RandomForestClassifierParameters {
+        RandomForestClassifierParameters {
+            criterion: self.criterion.into(),
+            max_depth: self.max_depth,
+            min_samples_leaf: self.min_samples_leaf,
+            min_samples_split: self.min_samples_split,
+            n_trees: self.n_trees,
+            m: self.m,
+            keep_samples: self.keep_samples,
+            seed,
+        }
+    }
+}
+
+impl fmt::Display for ForestConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let or_none = |value: Option<usize>| value.map_or("none".to_string(), |v| v.to_string());
+        write!(
+            f,
+            "n_trees={} max_depth={} min_samples_split={} min_samples_leaf={} m={} criterion={:?} keep_samples={}",
+            self.n_trees,
+            or_none(self.max_depth.map(usize::from)),
+            self.min_samples_split,
+            self.min_samples_leaf,
+            or_none(self.m),
+            self.criterion,
+            self.keep_samples
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelConfig {
+    pub kind: ModelKind,
+    pub tree_depth: u16,
+    pub forest: ForestConfig,
+    pub seed: u64,
+}
+
+/// A fitted forest as `--model-out` writes it, with the columns it was fit on.
+#[derive(Serialize, Deserialize)]
+pub struct SavedForest {
+    pub feature_names: Vec<String>,
+    pub forest: Forest,
+}
+
+impl SavedForest {
+    pub fn save(path: &str, feature_names: &[String], forest: &Forest) -> Result<(), Box<dyn Error>> {
+        let saved = serde_json::json!({ "feature_names": feature_names, "forest": forest });
+        std::fs::write(path, saved.to_string())?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<SavedForest, Box<dyn Error>> {
+        let contents = std::fs::read_to_string(path).map_err(|err| format!("could not read {}: {}", path, err))?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+}
+
+pub enum FittedModel {
+    Forest(Forest),
+    Tree(Tree),
+}
+
+impl FittedModel {
+    pub fn fit(config: &ModelConfig, train: &Dataset) -> Result<FittedModel, Box<dyn Error>> {
+        FittedModel::fit_matrix(config, train, &train.to_matrix())
+    }
+
+    /// `fit` with `train`'s matrix built by the caller, so that several fits on
+    /// the same rows can share one copy.
+    pub fn fit_matrix(
+        config: &ModelConfig,
+        train: &Dataset,
+        x_train: &DenseMatrix<f64>,
+    ) -> Result<FittedModel, Box<dyn Error>> {
+        match config.kind {
+            ModelKind::RandomForest => train.check_trainable(config.forest.min_samples_split)?,
+            // smartcore's default for a single tree
+            ModelKind::DecisionTree => train.check_trainable(2)?,
+        }
+        match config.kind {
+            ModelKind::RandomForest => {
+                config.forest.validate(train.feature_names.len())?;
+                let rf_params = config.forest.to_params(config.seed);
+                Ok(FittedModel::Forest(RandomForestClassifier::fit(x_train, &train.labels, rf_params)?))
+            }
+            ModelKind::DecisionTree => {
+                let tree_params = DecisionTreeClassifierParameters {
+                    max_depth: Some(config.tree_depth),
+                    seed: Some(config.seed),
+                    ..Default::default()
+                };
+                Ok(FittedModel::Tree(DecisionTreeClassifier::fit(x_train, &train.labels, tree_params)?))
+            }
+        }
+    }
+
+    /// `base` with `config.forest.n_trees` more trees, fit on `train` (the old
+    /// rows plus the new ones), instead of refitting the whole forest. The new
+    /// trees get a seed offset by the base's tree count so they differ from its
+    /// trees even on the same rows.
+    pub fn warm_append(base: &Forest, config: &ModelConfig, train: &Dataset) -> Result<FittedModel, Box<dyn Error>> {
+        train.check_trainable(config.forest.min_samples_split)?;
+        config.forest.validate(train.feature_names.len())?;
+        let base_trees = ForestVotes::from_forest(base)?.n_trees() as u64;
+        let rf_params = config.forest.to_params(config.seed.wrapping_add(base_trees));
+        let extra = RandomForestClassifier::fit(&train.to_matrix(), &train.labels, rf_params)?;
+        Ok(FittedModel::Forest(append_trees(base, &extra)?))
+    }
+
+    pub fn predict(&self, x: &DenseMatrix<f64>) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self {
+            FittedModel::Forest(forest) => Ok(forest.predict(x)?),
+            FittedModel::Tree(tree) => Ok(tree.predict(x)?),
+        }
+    }
+
+    /// `predict` with the forest's tied votes settled by `tie_break` and listed;
+    /// a single tree has no votes to tie.
+    pub fn predict_voted(&self, x: &DenseMatrix<f64>, tie_break: TieBreak) -> Result<VotedPredictions, Box<dyn Error>> {
+        match self {
+            FittedModel::Forest(forest) => Ok(ForestVotes::from_forest(forest)?.predict(x, N_CLASSES, tie_break)),
+            FittedModel::Tree(tree) => Ok(VotedPredictions {
+                labels: tree.predict(x)?,
+                tied: Vec::new(),
+            }),
+        }
+    }
+
+    /// Per-class scores: vote fractions for the forest, one-hot predictions for a single tree.
+    pub fn scores(&self, x: &DenseMatrix<f64>) -> Result<Vec<Vec<f64>>, Box<dyn Error>> {
+        match self {
+            FittedModel::Forest(forest) => Ok(ForestVotes::from_forest(forest)?.scores(x, N_CLASSES)),
+            FittedModel::Tree(tree) => Ok(one_hot_scores(&tree.predict(x)?)),
+        }
+    }
+
+    /// Trees voting for each class, per row of `x`; a single tree casts one vote.
+    pub fn votes(&self, x: &DenseMatrix<f64>) -> Result<Vec<Vec<usize>>, Box<dyn Error>> {
+        match self {
+            FittedModel::Forest(forest) => Ok(ForestVotes::from_forest(forest)?.votes_by_row(x, N_CLASSES)),
+            FittedModel::Tree(tree) => Ok(tree
+                .predict(x)?
+                .into_iter()
+                .map(|class| {
+                    let mut votes = vec![0; N_CLASSES];
+                    votes[class as usize] = 1;
+                    votes
+                })
+                .collect()),
+        }
+    }
+}
+
+fn one_hot_scores(y_pred: &[u8]) -> Vec<Vec<f64>> {
+    y_pred
+        .iter()
+        .map(|&class| {
+            let mut scores = vec![0.0; N_CLASSES];
+            scores[class as usize] = 1.0;
+            scores
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use crate::dataset::RowId;
+    use crate::test_util::fixture_path;
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        forest: ForestConfig,
+    }
+
+    #[test]
+    fn test_forest_config_validation() {
+        let config = ForestConfig::default();
+        assert!(config.validate(6).is_ok());
+        assert_eq!(config.validate(2).unwrap_err().field, "m");
+
+        let unlimited = ForestConfig { m: None, ..Default::default() };
+        assert!(unlimited.validate(2).is_ok());
+
+        for (config, field) in [
+            (ForestConfig { n_trees: 0, ..Default::default() }, "n_trees"),
+            (ForestConfig { max_depth: Some(0), ..Default::default() }, "max_depth"),
+            (ForestConfig { min_samples_split: 1, ..Default::default() }, "min_samples_split"),
+            (ForestConfig { min_samples_leaf: 0, ..Default::default() }, "min_samples_leaf"),
+            (ForestConfig { m: Some(0), ..Default::default() }, "m"),
+        ] {
+            assert_eq!(config.validate(6).unwrap_err().field, field);
+        }
+    }
+
+    #[test]
+    fn test_forest_config_maps_onto_smartcore_parameters() {
+        let cli = TestCli::try_parse_from([
+            "test",
+            "--n-trees", "50",
+            "--max-depth", "none",
+            "--min-samples-split", "4",
+            "--min-samples-leaf", "2",
+            "--mtry", "5",
+            "--criterion", "entropy",
+            "--keep-samples",
+        ])
+        .unwrap();
+        let params = cli.forest.to_params(9);
+
+        assert_eq!(params.n_trees, 50);
+        assert_eq!(params.max_depth, None);
+        assert_eq!(params.min_samples_split, 4);
+        assert_eq!(params.min_samples_leaf, 2);
+        assert_eq!(params.m, Some(5));
+        assert!(matches!(params.criterion, SplitCriterion::Entropy));
+        assert!(params.keep_samples);
+        assert_eq!(params.seed, 9);
+
+        let defaults = TestCli::try_parse_from(["test"]).unwrap();
+        assert_eq!(defaults.forest, ForestConfig::default());
+
+        let from_json: ForestConfig = serde_json::from_str(r#"{"n_trees": 10, "criterion": "classification-error"}"#).unwrap();
+        assert_eq!(from_json.n_trees, 10);
+        assert_eq!(from_json.criterion, Criterion::ClassificationError);
+        assert_eq!(from_json.min_samples_split, 25);
+    }
+
+    #[test]
+    fn test_warm_append_adds_trees() {
+        // The class is the quartile of the first column; the second is noise
+        let features: Vec<Vec<f64>> =
+            (0..400).map(|i| vec![(i * 37 % 400) as f64 / 400.0, (i * 11 % 7) as f64]).collect();
+        let labels: Vec<u8> = features.iter().map(|row| (row[0] * 4.0) as u8).collect();
+        let dataset = Dataset::from_rows(
+            vec!["signal".to_string(), "noise".to_string()],
+            &features,
+            labels,
+            vec![RowId::default(); features.len()],
+        );
+        let rows = |range: std::ops::Range<usize>| range.collect::<Vec<usize>>();
+        let (train, test) = (dataset.subset(&rows(0..300)), dataset.subset(&rows(300..400)));
+        // The base forest only saw half the rows; the appended trees see them all
+        let older: Vec<usize> = (0..train.len()).step_by(2).collect();
+        let config = ModelConfig {
+            kind: ModelKind::RandomForest,
+            tree_depth: 3,
+            forest: ForestConfig { n_trees: 20, min_samples_split: 2, m: Some(1), ..Default::default() },
+            seed: 2,
+        };
+        let FittedModel::Forest(base) = FittedModel::fit(&config, &train.subset(&older)).unwrap() else {
+            panic!("a forest config fits a forest")
+        };
+
+        let path = fixture_path("saved_forest.json");
+        let path = path.to_str().unwrap();
+        SavedForest::save(path, &train.feature_names, &base).unwrap();
+        let saved = SavedForest::load(path).unwrap();
+        assert_eq!(saved.feature_names, train.feature_names);
+
+        let config = ModelConfig { forest: ForestConfig { n_trees: 10, ..config.forest.clone() }, ..config };
+        let appended = FittedModel::warm_append(&saved.forest, &config, &train).unwrap();
+        let FittedModel::Forest(forest) = &appended else { panic!("warm append gives a forest") };
+        assert_eq!(ForestVotes::from_forest(&base).unwrap().n_trees(), 20);
+        assert_eq!(ForestVotes::from_forest(forest).unwrap().n_trees(), 30);
+
+        let x_test = test.to_matrix();
+        let y_pred = appended.predict(&x_test).unwrap();
+        let correct = y_pred.iter().zip(&test.labels).filter(|(p, t)| p == t).count();
+        let majority = (0..N_CLASSES as u8).map(|c| test.labels.iter().filter(|&&t| t == c).count()).max().unwrap();
+        assert!(correct > majority, "{} correct, majority class has {}", correct, majority);
+        let scores = appended.scores(&x_test).unwrap();
+        assert!(scores.iter().all(|row| (row.iter().sum::<f64>() - 1.0).abs() < 1e-9));
+    }
+
+    #[test]
+    fn test_documented_smartcore_defaults() {
+        // The `smartcore default` notes in the option docs
+        let smartcore = RandomForestClassifierParameters::default();
+        let documented = ForestConfig {
+            n_trees: 100,
+            max_depth: None,
+            min_samples_split: 2,
+            min_samples_leaf: 1,
+            m: None,
+            criterion: Criterion::Gini,
+            keep_samples: false,
+        }
+        .to_params(smartcore.seed);
+        assert_eq!(format!("{:?}", documented), format!("{:?}", smartcore));
+    }
+}
@@ -0,0 +1,198 @@
+use clap::ValueEnum;
+use crate::dataset::Dataset;
+
+// Where `Clamp` puts infinite values; far beyond any ratio or change the features produce
+pub const CLAMP_BOUND: f64 = 1e12;
+
+/// What to do with NaN or infinite feature values before training.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum NonFinitePolicy {
+    /// Remove the whole row
+    #[default]
+    Drop,
+    /// Replace infinities with ±CLAMP_BOUND and NaN with 0
+    Clamp,
+    /// Replace the value with the median of the column's finite training values
+    Median,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NonFiniteReport {
+    pub per_feature: Vec<(String, usize)>, // non-finite values found in each column
+    pub rows_affected: usize,
+}
+
+impl NonFiniteReport {
+    pub fn total(&self) -> usize {
+        self.per_feature.iter().map(|(_, count)| count).sum()
+    }
+}
+
+fn median_of_finite(dataset: &Dataset, column: usize) -> f64 {
+    let mut values: Vec<f64> = dataset.column(column).filter(|v| v.is_finite()).collect();
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+/// Median of each column's finite values in `train`, for `impute_medians`.
+/// A column without any is filled with 0.
+pub fn finite_medians(train: &Dataset) -> Vec<f64> {
+    (0..train.feature_names.len()).map(|column| median_of_finite(train, column)).collect()
+}
+
+/// Replaces every NaN or infinite value with its column's entry of `medians`.
+pub fn impute_medians(dataset: &mut Dataset, medians: &[f64]) {
+    let n_features = medians.len().max(1);
+    for row in dataset.values.chunks_mut(n_features) {
+        for (value, &median) in row.iter_mut().zip(medians) {
+            if !value.is_finite() {
+                *value = median;
+            }
+        }
+    }
+}
+
+/// Applies `policy` to every NaN or infinite feature value, so that the matrix
+/// handed to smartcore is finite, and counts what was found per column.
+/// `Median` only counts: its medians must come from the training rows alone,
+/// so `impute_medians` fills the values once the rows are split.
+pub fn sanitize_features(dataset: &Dataset, policy: NonFinitePolicy) -> (Dataset, NonFiniteReport) {
+    let n_columns = dataset.feature_names.len();
+    let mut counts = vec![0; n_columns];
+    let mut result = dataset.clone();
+    let mut keep = Vec::with_capacity(dataset.len());
+    let mut rows_affected = 0;
+
+    for i in 0..result.len() {
+        let mut affected = false;
+        for (column, value) in result.row_mut(i).iter_mut().enumerate() {
+            if value.is_finite() {
+                continue;
+            }
+            affected = true;
+            counts[column] += 1;
+            *value = match policy {
+                NonFinitePolicy::Drop | NonFinitePolicy::Median => *value,
+                NonFinitePolicy::Clamp if value.is_nan() => 0.0,
+                NonFinitePolicy::Clamp => value.clamp(-CLAMP_BOUND, CLAMP_BOUND),
+            };
+        }
+        if affected {
+            rows_affected += 1;
+        }
+        if !affected || policy != NonFinitePolicy::Drop {
+            keep.push(i);
+        }
+    }
+
+    if policy == NonFinitePolicy::Drop {
+        result = result.subset(&keep);
+    }
+    let per_feature = dataset.feature_names.iter().cloned().zip(counts).collect();
+    (result, NonFiniteReport { per_feature, rows_affected })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smartcore::linalg::basic::arrays::Array;
+    use crate::dataset::prepare_dataset;
+    use crate::outliers::{handle_outliers, iqr_fences, OutlierMode};
+    use crate::synthetic::SyntheticConfig;
+
+    #[test]
+    fn test_non_finite_policies() {
+        let mut dataset = prepare_dataset(&SyntheticConfig::default().generate().stock_data());
+        dataset.row_mut(0)[0] = f64::NAN;
+        dataset.row_mut(1)[0] = f64::INFINITY;
+        dataset.row_mut(1)[2] = f64::NEG_INFINITY;
+        dataset.row_mut(5)[3] = f64::NAN;
+        let n = dataset.len();
+
+        let all_finite = |dataset: &Dataset| {
+            let matrix = dataset.to_matrix();
+            let (rows, columns) = matrix.shape();
+            (0..rows).all(|i| (0..columns).all(|j| matrix.get((i, j)).is_finite()))
+        };
+
+        let (dropped, report) = sanitize_features(&dataset, NonFinitePolicy::Drop);
+        assert_eq!(dropped.len(), n - 3);
+        assert!(all_finite(&dropped));
+        assert_eq!(report.rows_affected, 3);
+        assert_eq!(report.total(), 4);
+        assert_eq!(report.per_feature[0], (dataset.feature_names[0].clone(), 2));
+        assert_eq!(report.per_feature[1].1, 0);
+
+        let (clamped, report) = sanitize_features(&dataset, NonFinitePolicy::Clamp);
+        assert_eq!(clamped.len(), n);
+        assert!(all_finite(&clamped));
+        assert_eq!((clamped.row(0)[0], clamped.row(1)[0]), (0.0, CLAMP_BOUND));
+        assert_eq!(clamped.row(1)[2], -CLAMP_BOUND);
+        assert_eq!(report.total(), 4);
+
+        let (counted, report) = sanitize_features(&dataset, NonFinitePolicy::Median);
+        assert_eq!((counted.len(), report.total()), (n, 4));
+        assert!(counted.row(0)[0].is_nan());
+        let mut filled = counted;
+        impute_medians(&mut filled, &finite_medians(&dataset));
+        assert!(all_finite(&filled));
+        assert_eq!(filled.row(0)[0], median_of_finite(&dataset, 0));
+        assert_eq!(filled.row(0)[0], filled.row(1)[0]);
+        assert_eq!(filled.row(2), dataset.row(2));
+
+        let (unchanged, report) = sanitize_features(&filled, NonFinitePolicy::Drop);
+        assert_eq!(unchanged.len(), n);
+        assert_eq!(report.total(), 0);
+    }
+
+    #[test]
+    fn test_medians_come_from_the_training_rows() {
+        let mut dataset = prepare_dataset(&SyntheticConfig::default().generate().stock_data());
+        dataset.row_mut(0)[0] = f64::NAN;
+        let test = dataset.subset(&[0, 1]);
+        let mut train = dataset.subset(&(2..dataset.len()).collect::<Vec<_>>());
+        // A training column shifted far from the test row's neighbours
+        for i in 0..train.len() {
+            train.row_mut(i)[0] += 1000.0;
+        }
+        let medians = finite_medians(&train);
+        let mut filled = test.clone();
+        impute_medians(&mut filled, &medians);
+        assert_eq!(filled.row(0)[0], median_of_finite(&train, 0));
+        assert!(filled.row(0)[0] > 500.0);
+        assert_eq!(filled.row(1), test.row(1));
+    }
+
+    #[test]
+    fn test_overflowing_feature_is_dropped_not_clipped() {
+        let mut stock_data = SyntheticConfig::default().generate().stock_data();
+        let mut tickers: Vec<&String> = stock_data.keys().collect();
+        tickers.sort();
+        let ticker = tickers[0].clone();
+        // A data-error magnitude whose revenue-margin interaction overflows to infinity
+        let record = &mut stock_data.get_mut(&ticker).unwrap()[3];
+        record.change_in_revenue = Some(f64::MAX);
+        record.change_in_profit_margin = Some(f64::MAX);
+        let year = record.year;
+
+        let dataset = prepare_dataset(&stock_data);
+        let is_target = |dataset: &Dataset, i: usize| dataset.rows[i].ticker == ticker && dataset.rows[i].year == year;
+        let i = (0..dataset.len()).find(|&i| is_target(&dataset, i)).unwrap();
+        assert!(dataset.row(i).iter().any(|value| value.is_infinite()));
+
+        let (clipped, _) = handle_outliers(&dataset, OutlierMode::Clip, &iqr_fences(&dataset, 3.0));
+        assert!(clipped.row(i).iter().any(|value| value.is_infinite()));
+        let (sanitized, report) = sanitize_features(&clipped, NonFinitePolicy::Drop);
+        assert!(report.rows_affected >= 1);
+        assert!(!(0..sanitized.len()).any(|i| is_target(&sanitized, i)));
+        assert_eq!(sanitized.len(), dataset.len() - report.rows_affected);
+    }
+}
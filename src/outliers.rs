@@ -0,0 +1,146 @@
+use clap::ValueEnum;
+use crate::dataset::Dataset;
+
+/// What to do with feature values outside a column's IQR fences.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutlierMode {
+    #[default]
+    Off,
+    /// Move the value to the nearest fence
+    Clip,
+    /// Remove the whole row
+    Drop,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutlierReport {
+    pub rows_affected: usize, // rows with at least one value outside the fences
+    pub values_flagged: usize,
+}
+
+// Linear interpolation between the closest ranks of sorted values
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    let position = q * (sorted.len() - 1) as f64;
+    let (lower, upper) = (position.floor() as usize, position.ceil() as usize);
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (position - lower as f64)
+}
+
+/// `(low, high)` fences per column: `Q1 - threshold * IQR` and `Q3 + threshold * IQR`.
+/// A column whose IQR is zero (mostly one repeated value) gets no fences.
+pub fn iqr_fences(dataset: &Dataset, threshold: f64) -> Vec<Option<(f64, f64)>> {
+    (0..dataset.feature_names.len())
+        .map(|column| {
+            let mut values: Vec<f64> = dataset.column(column).filter(|v| v.is_finite()).collect();
+            if values.is_empty() {
+                return None;
+            }
+            values.sort_by(f64::total_cmp);
+            let (q1, q3) = (quantile(&values, 0.25), quantile(&values, 0.75));
+            let iqr = q3 - q1;
+            (iqr > 0.0).then_some((q1 - threshold * iqr, q3 + threshold * iqr))
+        })
+        .collect()
+}
+
+/// Applies `mode` to every value outside its column's `fences` and reports how many were found.
+/// The fences come from `iqr_fences` of the training rows, so test rows are judged the way any
+/// new row would be. NaN and infinite values are left alone for the non-finite policy to count
+/// and handle, rather than being clipped to a fence as if they were merely large.
+pub fn handle_outliers(
+    dataset: &Dataset,
+    mode: OutlierMode,
+    fences: &[Option<(f64, f64)>],
+) -> (Dataset, OutlierReport) {
+    if mode == OutlierMode::Off {
+        return (dataset.clone(), OutlierReport::default());
+    }
+    let mut report = OutlierReport::default();
+    let mut result = dataset.clone();
+    let mut keep = Vec::with_capacity(dataset.len());
+
+    for i in 0..result.len() {
+        let mut flagged = 0;
+        for (value, fence) in result.row_mut(i).iter_mut().zip(fences) {
+            let Some((low, high)) = *fence else { continue };
+            if !value.is_finite() {
+                continue;
+            }
+            if *value < low || *value > high {
+                flagged += 1;
+                if mode == OutlierMode::Clip {
+                    *value = value.clamp(low, high);
+                }
+            }
+        }
+        report.values_flagged += flagged;
+        if flagged > 0 {
+            report.rows_affected += 1;
+        }
+        if flagged == 0 || mode == OutlierMode::Clip {
+            keep.push(i);
+        }
+    }
+
+    if mode == OutlierMode::Drop {
+        result = result.subset(&keep);
+    }
+    (result, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::RowId;
+
+    fn dataset_with_outlier() -> Dataset {
+        let mut features: Vec<Vec<f64>> = (0..20).map(|i| vec![i as f64 * 0.01, 1.0 - i as f64 * 0.02]).collect();
+        features[7][0] = 100.0; // a 10000% revenue change
+        Dataset::from_rows(
+            vec!["delta_revenue".to_string(), "delta_roa".to_string()],
+            &features,
+            vec![1; features.len()],
+            vec![RowId::default(); features.len()],
+        )
+    }
+
+    #[test]
+    fn test_outlier_modes() {
+        let dataset = dataset_with_outlier();
+        let fences = iqr_fences(&dataset, 3.0);
+
+        let (unchanged, report) = handle_outliers(&dataset, OutlierMode::Off, &fences);
+        assert_eq!(unchanged.values, dataset.values);
+        assert_eq!(report, OutlierReport::default());
+
+        let (dropped, report) = handle_outliers(&dataset, OutlierMode::Drop, &fences);
+        assert_eq!(dropped.len(), 19);
+        assert!(dropped.column(0).all(|value| value < 1.0));
+        assert_eq!(report.rows_affected, 1);
+        assert_eq!(report.values_flagged, 1);
+
+        let (clipped, report) = handle_outliers(&dataset, OutlierMode::Clip, &fences);
+        let (_, high) = fences[0].unwrap();
+        assert_eq!(clipped.len(), 20);
+        assert_eq!(clipped.row(7)[0], high);
+        assert!(high < 1.0);
+        assert_eq!(clipped.row(8), dataset.row(8));
+        assert_eq!(report.rows_affected, 1);
+    }
+
+    #[test]
+    fn test_fences_from_training_rows_judge_the_test_rows() {
+        let dataset = dataset_with_outlier();
+        let train = dataset.subset(&(0..10).collect::<Vec<_>>());
+        let test = dataset.subset(&(10..20).collect::<Vec<_>>());
+        // Training values of delta_revenue only reach 0.09 (row 7 aside), so the test rows' 0.10-0.19
+        // are far outside the training fences though well inside those of all twenty rows
+        let fences = iqr_fences(&train, 0.5);
+        let (_, high) = fences[0].unwrap();
+        assert!(high < 0.2);
+        let (clipped, report) = handle_outliers(&test, OutlierMode::Clip, &fences);
+        assert!(report.values_flagged > 0);
+        assert!(clipped.column(0).all(|value| value <= high));
+        let (_, report) = handle_outliers(&test, OutlierMode::Clip, &iqr_fences(&dataset, 0.5));
+        assert!(report.values_flagged < 10);
+    }
+}
@@ -0,0 +1,246 @@
+//! Input backend for fundamentals exported as Parquet: one row per ticker-year
+//! with columns `ticker`, `year` and one numeric column per metric. Metrics may
+//! be spread over several files (metric groups) that together cover `METRICS`.
+use std::collections::HashMap;
+use std::fs::File;
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Float64Type, UInt32Type};
+use arrow_array::{Array, ArrayRef};
+use arrow_cast::{cast_with_options, CastOptions};
+use arrow_schema::DataType;
+use ::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use ::parquet::errors::ParquetError;
+use crate::stock_data::{
+    combine_stock_data, load_price_files, mark_split_adjusted, LoadOptions, LoadReport, StockData, StockDataError,
+    YearlyValues, CASH_FLOW_METRICS, METRICS,
+};
+
+fn parquet_error(path: &str) -> impl Fn(ParquetError) -> StockDataError + '_ {
+    move |source| StockDataError::Parquet {
+        path: path.to_string(),
+        source,
+    }
+}
+
+// Converts a column to the type the loader needs. Numbers stored as strings are
+// accepted; anything that does not convert exactly is rejected, not nulled.
+fn cast_column(array: &ArrayRef, to: &DataType, path: &str, column: &str) -> Result<ArrayRef, StockDataError> {
+    let options = CastOptions {
+        safe: false,
+        ..Default::default()
+    };
+    cast_with_options(array, to, &options).map_err(|err| StockDataError::ColumnType {
+        path: path.to_string(),
+        column: column.to_string(),
+        message: format!("cannot read {} as {}: {}", array.data_type(), to, err),
+    })
+}
+
+/// Metric -> ticker -> year -> value for every metric column in the file.
+pub fn read_parquet(path: &str) -> Result<HashMap<String, YearlyValues>, StockDataError> {
+    let file = File::open(path).map_err(|source| StockDataError::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    let builder = ParquetRecordBatchReaderBuilder::try_new(file).map_err(parquet_error(path))?;
+    let schema = builder.schema().clone();
+    for column in ["ticker", "year"] {
+        if schema.column_with_name(column).is_none() {
+            return Err(StockDataError::MissingColumn {
+                path: path.to_string(),
+                column: column.to_string(),
+            });
+        }
+    }
+    let metric_columns: Vec<String> = schema
+        .fields()
+        .iter()
+        .map(|field| field.name().clone())
+        .filter(|name| name != "ticker" && name != "year")
+        .collect();
+
+    let mut data: HashMap<String, YearlyValues> = HashMap::new();
+    for batch in builder.build().map_err(parquet_error(path))? {
+        let batch = batch.map_err(|err| parquet_error(path)(err.into()))?;
+        let column = |name: &str| batch.column_by_name(name).expect("column is in the schema");
+        let tickers = cast_column(column("ticker"), &DataType::Utf8, path, "ticker")?;
+        let tickers = tickers.as_string::<i32>();
+        let years = cast_column(column("year"), &DataType::UInt32, path, "year")?;
+        let years = years.as_primitive::<UInt32Type>();
+
+        for metric in &metric_columns {
+            let values = cast_column(column(metric), &DataType::Float64, path, metric)?;
+            let values = values.as_primitive::<Float64Type>();
+            let by_ticker = data.entry(metric.clone()).or_default();
+            for row in 0..batch.num_rows() {
+                if tickers.is_null(row) || years.is_null(row) || values.is_null(row) {
+                    continue;
+                }
+                by_ticker
+                    .entry(tickers.value(row).to_string())
+                    .or_default()
+                    .insert(years.value(row), values.value(row));
+            }
+        }
+    }
+    if data.values().all(|by_ticker| by_ticker.is_empty()) {
+        return Err(StockDataError::EmptyDataset { path: path.to_string() });
+    }
+    Ok(data)
+}
+
+/// Loads fundamentals from the Parquet metric groups and prices from the usual price CSVs.
+pub fn process_parquet(
+    parquet_files: &[&str],
+    price_files: &[&str],
+    options: &LoadOptions,
+) -> Result<(HashMap<String, Vec<StockData>>, LoadReport), StockDataError> {
+    let (price_changes, volatilities, split_adjusted) = load_price_files(price_files, options)?;
+
+    // A metric column in two groups would silently replace the first one's values
+    let mut fundamentals: HashMap<String, YearlyValues> = HashMap::new();
+    let mut sources: HashMap<String, &str> = HashMap::new();
+    for path in parquet_files {
+        let data = match read_parquet(path) {
+            Ok(data) => data,
+            Err(err) if options.skip_missing_files => {
+                eprintln!("warning: skipping {}: {}", path, err);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        for (metric, values) in data {
+            if let Some(first) = sources.insert(metric.clone(), path) {
+                return Err(StockDataError::ColumnType {
+                    path: path.to_string(),
+                    column: metric,
+                    message: format!("the metric is also in {}", first),
+                });
+            }
+            fundamentals.insert(metric, values);
+        }
+    }
+
+    let mut unavailable = Vec::new();
+    let mut metrics = Vec::new();
+    for metric in METRICS {
+        match fundamentals.remove(metric) {
+            Some(values) => metrics.push(values),
+            None if options.skip_missing_files => {
+                eprintln!("warning: no Parquet file has a `{}` column; features using it are dropped", metric);
+                unavailable.push(metric.to_string());
+                metrics.push(HashMap::new());
+            }
+            None => {
+                return Err(StockDataError::MissingColumn {
+                    path: parquet_files.join(", "),
+                    column: metric.to_string(),
+                })
+            }
+        }
+    }
+
+    let [ocf, capex] = CASH_FLOW_METRICS.map(|metric| fundamentals.remove(metric));
+    let (mut stock_data, report) = combine_stock_data(
+        [&metrics[0], &metrics[1], &metrics[2], &metrics[3], &metrics[4]],
+        ocf.as_ref().zip(capex.as_ref()).map(|(ocf, capex)| [ocf, capex]),
+        &unavailable,
+        &price_changes,
+        Some(&volatilities),
+        options,
+    )?;
+    mark_split_adjusted(&mut stock_data, &split_adjusted);
+    Ok((stock_data, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use arrow_array::{Float64Array, Int64Array, RecordBatch, StringArray};
+    use ::parquet::arrow::ArrowWriter;
+    use crate::test_util::{assert_same_as_csv, fixture_path, write_fixture};
+
+    fn write_parquet(name: &str, columns: Vec<(&str, ArrayRef)>) -> String {
+        let path = fixture_path(name);
+        let batch = RecordBatch::try_from_iter(columns).unwrap();
+        let mut writer = ArrowWriter::try_new(File::create(&path).unwrap(), batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn tickers() -> ArrayRef {
+        Arc::new(StringArray::from(vec!["AAA", "AAA", "AAA"]))
+    }
+
+    #[test]
+    fn test_parquet_matches_csv() {
+        // Years stored as strings in one group and as integers in the other
+        let balance = write_parquet(
+            "parquet_balance.parquet",
+            vec![
+                ("ticker", tickers()),
+                ("year", Arc::new(StringArray::from(vec!["2022", "2021", "2020"]))),
+                ("assets", Arc::new(Int64Array::from(vec![400, 300, 200]))),
+                ("cash", Arc::new(Float64Array::from(vec![40.0, 45.0, 20.0]))),
+                ("equity", Arc::new(Float64Array::from(vec![200.0, 120.0, 90.0]))),
+            ],
+        );
+        let income = write_parquet(
+            "parquet_income.parquet",
+            vec![
+                ("ticker", tickers()),
+                ("year", Arc::new(Int64Array::from(vec![2022, 2021, 2020]))),
+                ("profit", Arc::new(Float64Array::from(vec![30.0, 20.0, 5.0]))),
+                ("revenue", Arc::new(Float64Array::from(vec![100.0, 80.0, 50.0]))),
+            ],
+        );
+
+        let prices = write_fixture(
+            "parquet_prices.csv",
+            ",Date,AAA\n0,2021-01-04,10\n1,2021-12-30,15\n2,2022-02-01,15\n3,2022-11-30,12\n",
+        );
+        let (from_parquet, _) = process_parquet(&[&balance, &income], &[&prices], &LoadOptions::default()).unwrap();
+        assert_same_as_csv("parquet", &from_parquet);
+    }
+
+    #[test]
+    fn test_parquet_rejects_unusable_columns() {
+        let path = write_parquet(
+            "parquet_bad_year.parquet",
+            vec![
+                ("ticker", tickers()),
+                ("year", Arc::new(StringArray::from(vec!["2022", "FY21", "2020"]))),
+                ("assets", Arc::new(Float64Array::from(vec![400.0, 300.0, 200.0]))),
+            ],
+        );
+        let err = read_parquet(&path).unwrap_err();
+        assert!(matches!(&err, StockDataError::ColumnType { column, .. } if column == "year"), "{:?}", err);
+
+        let path = write_parquet(
+            "parquet_no_year.parquet",
+            vec![("ticker", tickers()), ("assets", Arc::new(Float64Array::from(vec![1.0, 2.0, 3.0])))],
+        );
+        let err = read_parquet(&path).unwrap_err();
+        assert!(matches!(&err, StockDataError::MissingColumn { column, .. } if column == "year"), "{:?}", err);
+    }
+
+    #[test]
+    fn test_metric_in_two_groups_is_an_error() {
+        let assets = |name: &str, values: Vec<f64>| {
+            let years: ArrayRef = Arc::new(Int64Array::from(vec![2022, 2021, 2020]));
+            let values: ArrayRef = Arc::new(Float64Array::from(values));
+            write_parquet(name, vec![("ticker", tickers()), ("year", years), ("assets", values)])
+        };
+        let first = assets("parquet_assets_first.parquet", vec![4.0, 3.0, 2.0]);
+        let second = assets("parquet_assets_second.parquet", vec![9.0, 9.0, 9.0]);
+        let prices = write_fixture("parquet_duplicate_prices.csv", ",Date,AAA\n0,2021-01-04,10\n1,2021-12-30,15\n");
+        let err = process_parquet(&[&first, &second], &[&prices], &LoadOptions::default()).unwrap_err();
+        assert!(
+            matches!(&err, StockDataError::ColumnType { path, column, .. } if path == &second && column == "assets"),
+            "{:?}",
+            err
+        );
+    }
+}
@@ -0,0 +1,1499 @@
+//! Builder-style entry point that runs load -> filter -> features -> split ->
+//! fit -> evaluate in one call, with each stage also available on its own for
+//! callers (like `main`) that report between stages.
+use std::collections::HashMap;
+use std::error::Error;
+use smartcore::linalg::basic::matrix::DenseMatrix;
+use smartcore::metrics::accuracy;
+use crate::cache::{load_or_update, CacheReport, CachedRecords};
+use crate::dataset::{
+    builtin_extractors, extractor_by_name, interaction_extractors, prepare_dataset_with, prepare_forecast_rows,
+    Dataset, DatasetError, DatasetReport, ForecastRows, FEATURE_NAMES, GAP_YEARS, N_CLASSES,
+};
+use crate::ensemble::{soft_voting, MemberPrediction};
+use crate::forest::TieBreak;
+use crate::labels::{relabel, ExternalLabels};
+use crate::evaluation::stratified_subsample;
+use crate::metrics::{
+    baselines, classification_report, confusion_matrix, macro_f1, mcc, multiclass_roc_auc, rule_benchmarks,
+    scores_by_year, LearningCurvePoint, RunMetrics, Summary,
+};
+use crate::model::{ConfigError, FittedModel, ForestConfig, ModelConfig, ModelKind};
+use crate::nonfinite::{finite_medians, impute_medians, sanitize_features, NonFinitePolicy, NonFiniteReport};
+use crate::outliers::{handle_outliers, iqr_fences, OutlierMode, OutlierReport};
+use crate::sanity::{apply_sanity_filters, Rejection, SanityRules};
+use crate::selection::uncorrelated_columns;
+use crate::standardize::{Scaler, Standardize, StandardizeReport};
+use crate::stock_data::{process_stock_data_with_report, GapPolicy, LoadOptions, LoadReport, StockData, StockDataError};
+use crate::tickers::{canonical_ticker, TickerFilter, TickerFilterReport};
+use crate::weighting::{balance_classes, recency_decay_factors, recency_weights, replicate, weighted_resample, Halflife};
+
+// Fraction of rows held out by the default random split
+pub const DEFAULT_TEST_SIZE: f64 = 0.2;
+
+/// How a record's price change becomes a class label.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LabelMode {
+    /// Class `k` is a price change at or above `k` of the increasing thresholds,
+    /// so class `k` covers `[thresholds[k - 1], thresholds[k])`
+    Thresholds(Vec<f64>),
+}
+
+impl Default for LabelMode {
+    /// The buckets of `categorize_price_change`: below -50%, below 0%, below 50%, the rest.
+    fn default() -> Self {
+        LabelMode::Thresholds(vec![-50.0, 0.0, 50.0])
+    }
+}
+
+impl LabelMode {
+    pub fn n_classes(&self) -> usize {
+        match self {
+            LabelMode::Thresholds(thresholds) => thresholds.len() + 1,
+        }
+    }
+
+    /// `None` for a NaN or infinite price change.
+    pub fn label(&self, price_change: f64) -> Option<u8> {
+        if !price_change.is_finite() {
+            return None;
+        }
+        match self {
+            LabelMode::Thresholds(thresholds) => {
+                Some(thresholds.iter().filter(|&&t| price_change >= t).count() as u8)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Split {
+    /// Shuffle with the pipeline seed and hold out `test_size` of the rows
+    Random { test_size: f64 },
+    /// Train on years up to and including `cutoff`, test on the later years
+    ByYear { cutoff: u32 },
+}
+
+impl Default for Split {
+    fn default() -> Self {
+        Split::Random {
+            test_size: DEFAULT_TEST_SIZE,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Model {
+    RandomForest(ForestConfig),
+    DecisionTree { max_depth: u16 },
+    /// Soft voting over a random forest, a decision tree and a logistic regression
+    Ensemble { forest: ForestConfig, tree_depth: u16 },
+}
+
+impl Default for Model {
+    fn default() -> Self {
+        Model::RandomForest(ForestConfig::default())
+    }
+}
+
+impl Model {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Model::RandomForest(_) => ModelKind::RandomForest.label(),
+            Model::DecisionTree { .. } => ModelKind::DecisionTree.label(),
+            Model::Ensemble { .. } => "Soft Voting Ensemble",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum DataSource {
+    Files {
+        financial_files: Vec<(String, String)>,
+        price_files: Vec<String>,
+        cache: Option<String>,
+    },
+    Loaded(HashMap<String, Vec<StockData>>, LoadReport),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PipelineBuilder {
+    financial_files: Vec<(String, String)>,
+    price_files: Vec<String>,
+    cache: Option<String>,
+    stock_data: Option<HashMap<String, Vec<StockData>>>,
+    load_report: LoadReport,
+    load_options: LoadOptions,
+    tickers: TickerFilter,
+    sanity: Option<SanityRules>,
+    outliers: (OutlierMode, f64),
+    non_finite: NonFinitePolicy,
+    exclude_features: Vec<String>,
+    interactions: (Vec<String>, bool),
+    select_corr: Option<f64>,
+    label: LabelMode,
+    labels: Option<ExternalLabels>,
+    split: Split,
+    model: Model,
+    recency_halflife: Option<f64>,
+    recency_decay: Option<f64>,
+    balance_classes: bool,
+    min_rows: usize,
+    tie_break: TieBreak,
+    standardize: Standardize,
+    sectors: HashMap<String, String>,
+    seed: u64,
+}
+
+impl PipelineBuilder {
+    /// `(path, metric)` pairs in `METRICS` order, as `process_stock_data` takes them.
+    pub fn fundamentals(mut self, files: &[(&str, &str)]) -> Self {
+        self.financial_files = files.iter().map(|(path, metric)| (path.to_string(), metric.to_string())).collect();
+        self
+    }
+
+    /// Adds a price file; ticker-months in several files follow `LoadOptions::price_conflict`.
+    pub fn prices(mut self, path: &str) -> Self {
+        self.price_files.push(path.to_string());
+        self
+    }
+
+    /// Keep the processed file records at `path` and, on later runs with the
+    /// same files and options, build only the years the cache lacks.
+    pub fn cache(mut self, path: &str) -> Self {
+        self.cache = Some(path.to_string());
+        self
+    }
+
+    /// Use records loaded elsewhere (another backend, generated data) instead of files.
+    pub fn stock_data(mut self, stock_data: HashMap<String, Vec<StockData>>) -> Self {
+        self.stock_data = Some(stock_data);
+        self
+    }
+
+    /// What the backend that loaded the `stock_data` records found, handed back by `load_cached`.
+    pub fn load_report(mut self, report: LoadReport) -> Self {
+        self.load_report = report;
+        self
+    }
+
+    pub fn load_options(mut self, options: LoadOptions) -> Self {
+        self.load_options = options;
+        self
+    }
+
+    /// Keep only the include list's tickers, when given, and drop the exclude list's.
+    pub fn ticker_filter(mut self, filter: TickerFilter) -> Self {
+        self.tickers = filter;
+        self
+    }
+
+    pub fn sanity_filters(mut self, rules: SanityRules) -> Self {
+        self.sanity = Some(rules);
+        self
+    }
+
+    /// Clip or drop feature values more than `threshold` IQRs outside their column's quartiles.
+    pub fn outliers(mut self, mode: OutlierMode, threshold: f64) -> Self {
+        self.outliers = (mode, threshold);
+        self
+    }
+
+    /// How NaN and infinite feature values are removed before training.
+    pub fn non_finite(mut self, policy: NonFinitePolicy) -> Self {
+        self.non_finite = policy;
+        self
+    }
+
+    /// Leave these `FEATURE_NAMES` columns out of the dataset.
+    pub fn exclude_features(mut self, names: &[String]) -> Self {
+        self.exclude_features = names.to_vec();
+        self
+    }
+
+    /// Append the pairwise products of these `FEATURE_NAMES` columns, each
+    /// feature times itself too when `squares` is set.
+    pub fn interactions(mut self, names: &[String], squares: bool) -> Self {
+        self.interactions = (names.to_vec(), squares);
+        self
+    }
+
+    /// Drop features whose absolute correlation with an earlier feature exceeds `threshold`.
+    pub fn select_correlated(mut self, threshold: f64) -> Self {
+        self.select_corr = Some(threshold);
+        self
+    }
+
+    pub fn label(mut self, label: LabelMode) -> Self {
+        self.label = label;
+        self
+    }
+
+    /// Classes per ticker-year in place of the label mode's; rows without one are dropped.
+    pub fn labels(mut self, labels: ExternalLabels) -> Self {
+        self.labels = Some(labels);
+        self
+    }
+
+    pub fn split(mut self, split: Split) -> Self {
+        self.split = split;
+        self
+    }
+
+    pub fn model(mut self, model: Model) -> Self {
+        self.model = model;
+        self
+    }
+
+    pub fn recency_halflife(mut self, halflife: f64) -> Self {
+        self.recency_halflife = Some(halflife);
+        self
+    }
+
+    /// Replicate each training row `lambda^(latest year - its year)` times in expectation.
+    pub fn recency_decay(mut self, lambda: f64) -> Self {
+        self.recency_decay = Some(lambda);
+        self
+    }
+
+    /// Oversample the smaller classes of the training rows up to the largest.
+    pub fn balance_classes(mut self, balance: bool) -> Self {
+        self.balance_classes = balance;
+        self
+    }
+
+    /// Refuse to train on fewer prepared rows than this.
+    pub fn min_rows(mut self, min_rows: usize) -> Self {
+        self.min_rows = min_rows;
+        self
+    }
+
+    /// The class a forest predicts when classes tie for the most votes.
+    pub fn tie_break(mut self, tie_break: TieBreak) -> Self {
+        self.tie_break = tie_break;
+        self
+    }
+
+    /// Z-scores of the feature columns, fitted on the training rows.
+    pub fn standardize(mut self, mode: Standardize) -> Self {
+        self.standardize = mode;
+        self
+    }
+
+    /// Sector per canonical ticker, for `Standardize::PerSector`.
+    pub fn sectors(mut self, sectors: HashMap<String, String>) -> Self {
+        self.sectors = sectors;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Checks the settings against each other before any data is read.
+    pub fn build(self) -> Result<Pipeline, ConfigError> {
+        let invalid = |field, message: &str| {
+            Err(ConfigError {
+                field,
+                message: message.to_string(),
+            })
+        };
+
+        let source = match (self.stock_data, self.financial_files.is_empty(), self.price_files.is_empty()) {
+            (Some(_), false, _) | (Some(_), _, false) => {
+                return invalid("fundamentals", "give either files or loaded stock data, not both")
+            }
+            (Some(_), true, true) if self.cache.is_some() => {
+                return invalid("cache", "only records read from files are cached")
+            }
+            (Some(stock_data), true, true) => DataSource::Loaded(stock_data, self.load_report),
+            (None, false, false) => DataSource::Files {
+                financial_files: self.financial_files,
+                price_files: self.price_files,
+                cache: self.cache,
+            },
+            (None, true, _) => return invalid("fundamentals", "no financial files or stock data given"),
+            (None, false, true) => return invalid("prices", "financial files need a price file"),
+        };
+
+        let LabelMode::Thresholds(thresholds) = &self.label;
+        if thresholds.is_empty() || thresholds.iter().any(|t| !t.is_finite()) {
+            return invalid("label", "thresholds must be finite and at least one");
+        }
+        if thresholds.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return invalid("label", "thresholds must be strictly increasing");
+        }
+        if self.label.n_classes() > N_CLASSES {
+            return Err(ConfigError {
+                field: "label",
+                message: format!(
+                    "{} thresholds make {} classes, but the models score at most {}",
+                    thresholds.len(),
+                    self.label.n_classes(),
+                    N_CLASSES
+                ),
+            });
+        }
+
+        if self.load_options.horizon == 0 {
+            return invalid("horizon", "must be at least one year");
+        }
+        if let Split::Random { test_size } = self.split {
+            if !(test_size > 0.0 && test_size < 1.0) {
+                return invalid("split", "test_size must be between 0 and 1");
+            }
+        }
+        let (outlier_mode, outlier_threshold) = self.outliers;
+        if outlier_mode != OutlierMode::Off && (outlier_threshold.is_nan() || outlier_threshold <= 0.0) {
+            return invalid("outlier_threshold", "must be positive");
+        }
+        if let Some(unknown) = self.exclude_features.iter().find(|name| !FEATURE_NAMES.contains(&name.as_str())) {
+            return Err(ConfigError {
+                field: "exclude_features",
+                message: format!("unknown feature `{}`; the features are {}", unknown, FEATURE_NAMES.join(", ")),
+            });
+        }
+        if self.exclude_features.len() >= FEATURE_NAMES.len() {
+            return invalid("exclude_features", "at least one feature must remain");
+        }
+        if let Some(unknown) = self.interactions.0.iter().find(|name| !FEATURE_NAMES.contains(&name.as_str())) {
+            return Err(ConfigError {
+                field: "interactions",
+                message: format!("unknown feature `{}`; the features are {}", unknown, FEATURE_NAMES.join(", ")),
+            });
+        }
+        if self.select_corr.is_some_and(|threshold| !(threshold > 0.0 && threshold <= 1.0)) {
+            return invalid("select_corr", "must be in (0, 1]");
+        }
+        let recency_halflife = match self.recency_halflife.map(Halflife::new) {
+            Some(None) => return invalid("recency_halflife", "must be positive"),
+            Some(halflife) => halflife,
+            None => None,
+        };
+        if self.recency_decay.is_some_and(|lambda| !(lambda > 0.0 && lambda <= 1.0)) {
+            return invalid("recency_decay", "must be in (0, 1]");
+        }
+        if self.recency_halflife.is_some() && self.recency_decay.is_some() {
+            return invalid("recency_decay", "give either a recency half-life or a recency decay, not both");
+        }
+        let n_classes = self.label.n_classes();
+        if let Some(((ticker, year), label)) =
+            self.labels.iter().flatten().find(|(_, &label)| label as usize >= n_classes)
+        {
+            return Err(ConfigError {
+                field: "labels",
+                message: format!("class {} of {} {} is not below the {} classes", label, ticker, year, n_classes),
+            });
+        }
+        if self.standardize == Standardize::PerSector && self.sectors.is_empty() {
+            return invalid("sectors", "standardizing per sector needs a ticker,sector file");
+        }
+        match &self.model {
+            Model::RandomForest(forest) => forest.validate(FEATURE_NAMES.len())?,
+            Model::Ensemble { forest, tree_depth } => {
+                forest.validate(FEATURE_NAMES.len())?;
+                if *tree_depth == 0 {
+                    return invalid("tree_depth", "must be at least 1");
+                }
+            }
+            Model::DecisionTree { max_depth } => {
+                if *max_depth == 0 {
+                    return invalid("tree_depth", "must be at least 1");
+                }
+            }
+        }
+
+        Ok(Pipeline {
+            source,
+            load_options: self.load_options,
+            tickers: self.tickers,
+            sanity: self.sanity,
+            outliers: self.outliers,
+            non_finite: self.non_finite,
+            exclude_features: self.exclude_features,
+            interactions: self.interactions,
+            select_corr: self.select_corr,
+            label: self.label,
+            labels: self.labels,
+            split: self.split,
+            model: self.model,
+            recency_halflife,
+            recency_decay: self.recency_decay,
+            balance_classes: self.balance_classes,
+            min_rows: self.min_rows,
+            tie_break: self.tie_break,
+            standardize: self.standardize,
+            sectors: self.sectors,
+            seed: self.seed,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    source: DataSource,
+    load_options: LoadOptions,
+    tickers: TickerFilter,
+    sanity: Option<SanityRules>,
+    outliers: (OutlierMode, f64),
+    non_finite: NonFinitePolicy,
+    exclude_features: Vec<String>,
+    interactions: (Vec<String>, bool),
+    select_corr: Option<f64>,
+    label: LabelMode,
+    labels: Option<ExternalLabels>,
+    split: Split,
+    model: Model,
+    recency_halflife: Option<Halflife>,
+    recency_decay: Option<f64>,
+    balance_classes: bool,
+    min_rows: usize,
+    tie_break: TieBreak,
+    standardize: Standardize,
+    sectors: HashMap<String, String>,
+    seed: u64,
+}
+
+/// Everything a run produced. `y_pred` and `scores` are aligned with `test`'s rows.
+pub struct RunResult {
+    pub metrics: RunMetrics,
+    pub model: Option<FittedModel>,     // None for the ensemble, which has no single model
+    pub members: Vec<MemberPrediction>, // the ensemble's members; empty otherwise
+    pub train: Dataset,
+    pub test: Dataset,
+    pub y_pred: Vec<u8>,
+    pub scores: Vec<Vec<f64>>,
+    pub tied: Vec<usize>, // test rows whose forest vote was tied and settled by the tie-break rule
+}
+
+/// What `Pipeline::split` did to the rows besides splitting them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SplitReport {
+    pub outliers: OutlierReport,
+    pub unweighted_train_rows: usize, // training rows after cleaning, before recency resampling or replication
+}
+
+/// Next-year classes predicted from each ticker's latest record.
+#[derive(Debug, Clone)]
+pub struct Forecast {
+    pub rows: ForecastRows,
+    pub predicted: Vec<u8>, // aligned with `rows.rows`
+    pub train_rows: usize,  // rows the model was fitted on, after the one cleaning pass
+}
+
+impl Pipeline {
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder::default()
+    }
+
+    pub fn model(&self) -> &Model {
+        &self.model
+    }
+
+    pub fn label_mode(&self) -> &LabelMode {
+        &self.label
+    }
+
+    /// The effective settings as `(name, value)` pairs in a fixed order, for reports.
+    pub fn settings(&self) -> Vec<(String, String)> {
+        let LabelMode::Thresholds(thresholds) = &self.label;
+        let model = match &self.model {
+            Model::RandomForest(forest) => forest.to_string(),
+            Model::DecisionTree { max_depth } => format!("max_depth={}", max_depth),
+            Model::Ensemble { forest, tree_depth } => format!("{} tree_depth={}", forest, tree_depth),
+        };
+        let split = match self.split {
+            Split::Random { test_size } => format!("random, test_size={}", test_size),
+            Split::ByYear { cutoff } => format!("by year, train through {}", cutoff),
+        };
+        let optional = |value: Option<f64>| value.map_or("off".to_string(), |v| v.to_string());
+        let excluded = if self.exclude_features.is_empty() {
+            "none".to_string()
+        } else {
+            self.exclude_features.join(", ")
+        };
+        let (interactions, squares) = &self.interactions;
+        let interactions = match (interactions.is_empty(), squares) {
+            (true, _) => "none".to_string(),
+            (false, false) => interactions.join(", "),
+            (false, true) => format!("{}, with squares", interactions.join(", ")),
+        };
+        let listed = |tickers: &[String]| format!("{} tickers", tickers.len());
+        let included = self.tickers.include.as_deref().map_or("all".to_string(), listed);
+        let excluded_tickers = match self.tickers.exclude.as_slice() {
+            [] => "none".to_string(),
+            tickers => listed(tickers),
+        };
+        let options = &self.load_options;
+        let tie_break = match self.tie_break {
+            TieBreak::LowestClass => "lowest-class",
+            TieBreak::HighestClass => "highest-class",
+        };
+        let labels = match &self.labels {
+            Some(labels) => format!("external file, {} ticker-years", labels.len()),
+            None => "price change classes".to_string(),
+        };
+        let standardize = match self.standardize {
+            Standardize::Off => "off".to_string(),
+            Standardize::Global => "global".to_string(),
+            Standardize::PerSector => format!("per sector, {} tickers tagged", self.sectors.len()),
+        };
+        let year_range = if options.year_range.is_unbounded() {
+            "all".to_string()
+        } else {
+            options.year_range.to_string()
+        };
+        let currency = match &options.currency_file {
+            Some(_) => format!("converted to USD, missing rates: {:?}", options.missing_fx_rate).to_lowercase(),
+            None => "as reported".to_string(),
+        };
+        vec![
+            ("model".to_string(), self.model.label().to_string()),
+            ("model_settings".to_string(), model),
+            ("seed".to_string(), self.seed.to_string()),
+            ("split".to_string(), split),
+            ("label_thresholds".to_string(), format!("{:?}", thresholds)),
+            ("labels".to_string(), labels),
+            ("horizon".to_string(), options.horizon.to_string()),
+            ("returns".to_string(), format!("{:?}", options.return_basis()).to_lowercase()),
+            ("splits".to_string(), options.split_file.as_ref().map_or("as traded", |_| "adjusted").to_string()),
+            ("price_reference".to_string(), options.price_reference.to_string()),
+            ("year_range".to_string(), year_range),
+            ("currency".to_string(), currency),
+            ("input_layout".to_string(), format!("{:?}", options.input_layout).to_lowercase()),
+            ("ratio_epsilon".to_string(), options.ratio_epsilon.to_string()),
+            ("join_policy".to_string(), format!("{:?}", options.join_policy)),
+            ("gap_policy".to_string(), format!("{:?}", options.gap_policy).to_lowercase()),
+            ("include_tickers".to_string(), included),
+            ("exclude_tickers".to_string(), excluded_tickers),
+            ("sanity_filters".to_string(), self.sanity.is_some().to_string()),
+            ("outliers".to_string(), format!("{:?}, threshold={}", self.outliers.0, self.outliers.1).to_lowercase()),
+            ("non_finite".to_string(), format!("{:?}", self.non_finite).to_lowercase()),
+            ("exclude_features".to_string(), excluded),
+            ("interactions".to_string(), interactions),
+            ("select_corr".to_string(), optional(self.select_corr)),
+            ("standardize".to_string(), standardize),
+            ("recency_halflife".to_string(), optional(self.recency_halflife.map(Halflife::years))),
+            ("recency_decay".to_string(), optional(self.recency_decay)),
+            ("balance_classes".to_string(), self.balance_classes.to_string()),
+            ("min_rows".to_string(), self.min_rows.to_string()),
+            ("tie_break".to_string(), tie_break.to_string()),
+        ]
+    }
+
+    pub fn load(&self) -> Result<HashMap<String, Vec<StockData>>, StockDataError> {
+        self.load_cached().map(|(stock_data, _, _)| stock_data)
+    }
+
+    /// Like `load`, with what the loader found and what happened to the cache
+    /// when one is configured.
+    pub fn load_cached(&self) -> Result<(CachedRecords, LoadReport, Option<CacheReport>), StockDataError> {
+        match &self.source {
+            DataSource::Files {
+                financial_files,
+                price_files,
+                cache,
+            } => {
+                let files: Vec<(&str, &str)> =
+                    financial_files.iter().map(|(path, metric)| (path.as_str(), metric.as_str())).collect();
+                let price_files: Vec<&str> = price_files.iter().map(String::as_str).collect();
+                match cache {
+                    Some(path) => {
+                        let (stock_data, load_report, report) =
+                            load_or_update(path, &files, &price_files, &self.load_options)?;
+                        Ok((stock_data, load_report, Some(report)))
+                    }
+                    None => {
+                        let (stock_data, load_report) =
+                            process_stock_data_with_report(&files, &price_files, &self.load_options)?;
+                        Ok((stock_data, load_report, None))
+                    }
+                }
+            }
+            DataSource::Loaded(stock_data, load_report) => Ok((stock_data.clone(), load_report.clone(), None)),
+        }
+    }
+
+    /// Removes the tickers the include and exclude lists rule out.
+    pub fn filter_tickers(&self, stock_data: &mut HashMap<String, Vec<StockData>>) -> TickerFilterReport {
+        self.tickers.apply(stock_data)
+    }
+
+    /// Applies the sanity filters, if configured, and returns what they rejected.
+    pub fn filter(&self, stock_data: &mut HashMap<String, Vec<StockData>>) -> Vec<Rejection> {
+        match &self.sanity {
+            Some(rules) => apply_sanity_filters(stock_data, rules),
+            None => Vec::new(),
+        }
+    }
+
+    /// Feature rows labelled by the pipeline's label mode, or by the external
+    /// labels when given, with the interaction columns (and under
+    /// `GapPolicy::Annotate` the gap column) appended and without the excluded
+    /// features.
+    pub fn dataset(&self, stock_data: &HashMap<String, Vec<StockData>>) -> Dataset {
+        self.dataset_with_report(stock_data).0
+    }
+
+    /// Like `dataset`, with the rows and features it left out.
+    pub fn dataset_with_report(&self, stock_data: &HashMap<String, Vec<StockData>>) -> (Dataset, DatasetReport) {
+        let (interactions, squares) = &self.interactions;
+        let mut extractors = builtin_extractors();
+        extractors.extend(interaction_extractors(interactions, *squares));
+        if self.load_options.gap_policy == GapPolicy::Annotate {
+            extractors.extend(extractor_by_name(GAP_YEARS));
+        }
+        let (dataset, mut report) = prepare_dataset_with(stock_data, extractors);
+        let mut dataset = dataset.without_features(&self.exclude_features);
+        dataset.labels = dataset
+            .rows
+            .iter()
+            .map(|row| self.label.label(row.price_change).expect("prepare_dataset drops non-finite price changes"))
+            .collect();
+        let Some(labels) = &self.labels else {
+            return (dataset, report);
+        };
+        let (dataset, dropped) = relabel(&dataset, labels);
+        report.without_external_label = dropped;
+        (dataset, report)
+    }
+
+    /// Applies the outlier handling to both splits with the fences of `train`; a
+    /// no-op when it is off. The report counts the values of both.
+    pub fn remove_outliers(&self, train: Dataset, test: Dataset) -> (Dataset, Dataset, OutlierReport) {
+        let (mode, threshold) = self.outliers;
+        if mode == OutlierMode::Off {
+            return (train, test, OutlierReport::default());
+        }
+        let fences = iqr_fences(&train, threshold);
+        let (train, train_report) = handle_outliers(&train, mode, &fences);
+        let (test, test_report) = handle_outliers(&test, mode, &fences);
+        let report = OutlierReport {
+            rows_affected: train_report.rows_affected + test_report.rows_affected,
+            values_flagged: train_report.values_flagged + test_report.values_flagged,
+        };
+        (train, test, report)
+    }
+
+    /// Final pass before splitting: applies the non-finite policy so the matrix is all finite,
+    /// except under `NonFinitePolicy::Median`, which `split` imputes from the training rows.
+    pub fn sanitize(&self, dataset: &Dataset) -> (Dataset, NonFiniteReport) {
+        sanitize_features(dataset, self.non_finite)
+    }
+
+    /// Fails when there are no prepared rows or fewer than `min_rows`, before
+    /// anything is trained on them.
+    pub fn check_rows(&self, dataset: &Dataset) -> Result<(), DatasetError> {
+        if dataset.is_empty() {
+            return Err(DatasetError::EmptyDataset);
+        }
+        if dataset.len() < self.min_rows {
+            return Err(DatasetError::BelowMinRows {
+                got: dataset.len(),
+                min_rows: self.min_rows,
+            });
+        }
+        Ok(())
+    }
+
+    /// Under `NonFinitePolicy::Median`, fills the values `sanitize` left with the
+    /// medians of `train`'s columns, in both splits; otherwise returns them as they are.
+    pub fn impute(&self, mut train: Dataset, mut test: Dataset) -> (Dataset, Dataset) {
+        if self.non_finite == NonFinitePolicy::Median {
+            let medians = finite_medians(&train);
+            impute_medians(&mut train, &medians);
+            impute_medians(&mut test, &medians);
+        }
+        (train, test)
+    }
+
+    /// `remove_outliers` then `impute`: the cleaning fit on the training rows, for
+    /// `split` and `prepare_fold` alike.
+    pub fn clean(&self, train: Dataset, test: Dataset) -> (Dataset, Dataset, OutlierReport) {
+        let (train, test, outliers) = self.remove_outliers(train, test);
+        let (train, test) = self.impute(train, test);
+        (train, test, outliers)
+    }
+
+    /// `(train, test)`, cleaned with what the training rows give and with those
+    /// resampled or replicated by recency when configured, and what was done.
+    pub fn split(&self, dataset: &Dataset) -> Result<(Dataset, Dataset, SplitReport), Box<dyn Error>> {
+        self.check_rows(dataset)?;
+        let (train, test) = match self.split {
+            Split::Random { test_size } => dataset.train_test_split(test_size, self.seed),
+            Split::ByYear { cutoff } => {
+                let (train_rows, test_rows): (Vec<usize>, Vec<usize>) =
+                    (0..dataset.len()).partition(|&i| dataset.rows[i].year <= cutoff);
+                (dataset.subset(&train_rows), dataset.subset(&test_rows))
+            }
+        };
+        let (train, test, outliers) = self.clean(train, test);
+        if train.is_empty() || test.is_empty() {
+            return Err(format!("the split leaves {} training and {} test rows", train.len(), test.len()).into());
+        }
+        let report = SplitReport {
+            outliers,
+            unweighted_train_rows: train.len(),
+        };
+        Ok((self.weight_training_rows(train), test, report))
+    }
+
+    /// `train` with the rows of each `--export-features` file in `paths` after
+    /// its own, for `FittedModel::warm_append` to grow its new trees on the old
+    /// and the new data. The files need this dataset's columns; their rows go
+    /// through `sanitize` and `impute` like the rows of this run.
+    pub fn append_rows(
+        &self,
+        mut train: Dataset,
+        test: Dataset,
+        paths: &[String],
+    ) -> Result<(Dataset, Dataset), Box<dyn Error>> {
+        for path in paths {
+            let (rows, _) = self.sanitize(&Dataset::read_features(path)?);
+            if rows.feature_names != train.feature_names {
+                return Err(format!(
+                    "{} has features {}, but this run has {}",
+                    path,
+                    rows.feature_names.join(", "),
+                    train.feature_names.join(", ")
+                )
+                .into());
+            }
+            train.append(rows);
+        }
+        Ok(self.impute(train, test))
+    }
+
+    /// The training rows resampled or replicated by recency, then oversampled to
+    /// balanced classes, as configured.
+    pub fn weight_training_rows(&self, mut train: Dataset) -> Dataset {
+        if let Some(halflife) = self.recency_halflife {
+            train = weighted_resample(&train, &recency_weights(&train, halflife), self.seed);
+        }
+        if let Some(lambda) = self.recency_decay {
+            train = replicate(&train, &recency_decay_factors(&train, lambda), self.seed);
+        }
+        if self.balance_classes {
+            train = balance_classes(&train, self.seed);
+        }
+        train
+    }
+
+    /// Everything `split`, `select_features` and `standardize` fit on the training
+    /// rows, applied to one repeated split or cross-validation fold, so those are
+    /// scored on the rows the configured run would see.
+    pub fn prepare_fold(&self, train: Dataset, test: Dataset) -> (Dataset, Dataset) {
+        let (train, test, _) = self.clean(train, test);
+        let train = self.weight_training_rows(train);
+        let (train, test, _) = self.select_features(train, test);
+        let (train, test, _) = self.standardize(train, test);
+        (train, test)
+    }
+
+    /// Applies the correlation filter, if configured, with the columns chosen on
+    /// `train` alone. Returns both splits and the names of the dropped features.
+    pub fn select_features(&self, train: Dataset, test: Dataset) -> (Dataset, Dataset, Vec<String>) {
+        let Some(threshold) = self.select_corr else {
+            return (train, test, Vec::new());
+        };
+        let kept = uncorrelated_columns(&train.values, train.n_features(), threshold);
+        let dropped = (0..train.feature_names.len())
+            .filter(|j| !kept.contains(j))
+            .map(|j| train.feature_names[j].clone())
+            .collect();
+        (train.select_columns(&kept), test.select_columns(&kept), dropped)
+    }
+
+    /// Scales both splits to z-scores with statistics of `train`, if configured.
+    pub fn standardize(&self, mut train: Dataset, mut test: Dataset) -> (Dataset, Dataset, StandardizeReport) {
+        if self.standardize == Standardize::Off {
+            return (train, test, StandardizeReport::default());
+        }
+        let scaler = Scaler::fit(&train, self.standardize, &self.sectors);
+        let fallback_rows = scaler.apply(&mut train.values, &train.rows) + scaler.apply(&mut test.values, &test.rows);
+        (train, test, StandardizeReport { groups: scaler.groups(), fallback_rows })
+    }
+
+    /// Number of classes the labelling produces.
+    pub fn n_classes(&self) -> usize {
+        self.label.n_classes()
+    }
+
+    /// The settings the model-level helpers (`FittedModel::fit`, cross-validation, ablation) take.
+    pub fn model_config(&self) -> ModelConfig {
+        let (kind, tree_depth, forest) = match &self.model {
+            Model::RandomForest(forest) => (ModelKind::RandomForest, 3, forest.clone()),
+            Model::DecisionTree { max_depth } => (ModelKind::DecisionTree, *max_depth, ForestConfig::default()),
+            Model::Ensemble { forest, tree_depth } => (ModelKind::RandomForest, *tree_depth, forest.clone()),
+        };
+        ModelConfig {
+            kind,
+            tree_depth,
+            forest,
+            seed: self.seed,
+        }
+    }
+
+    /// Fits on `train` and scores `test`.
+    pub fn evaluate(&self, train: Dataset, test: Dataset) -> Result<RunResult, Box<dyn Error>> {
+        let config = self.model_config();
+        match &self.model {
+            Model::Ensemble { .. } => {
+                let prediction = soft_voting(&config, &train, &test)?;
+                Ok(self.result(None, prediction.members, prediction.y_pred, prediction.scores, train, test))
+            }
+            _ => self.evaluate_model(FittedModel::fit(&config, &train)?, train, test),
+        }
+    }
+
+    /// Like `evaluate`, with a model fitted elsewhere (loaded or warm-appended)
+    /// in place of the configured one; `train` is only used for the metrics.
+    pub fn evaluate_model(
+        &self,
+        model: FittedModel,
+        train: Dataset,
+        test: Dataset,
+    ) -> Result<RunResult, Box<dyn Error>> {
+        let x_test = test.to_matrix();
+        let predictions = model.predict_voted(&x_test, self.tie_break)?;
+        let scores = model.scores(&x_test)?;
+        let mut result = self.result(Some(model), Vec::new(), predictions.labels, scores, train, test);
+        result.tied = predictions.tied;
+        Ok(result)
+    }
+
+    fn result(
+        &self,
+        model: Option<FittedModel>,
+        members: Vec<MemberPrediction>,
+        y_pred: Vec<u8>,
+        scores: Vec<Vec<f64>>,
+        train: Dataset,
+        test: Dataset,
+    ) -> RunResult {
+        let n_classes = self.label.n_classes();
+        let years: Vec<u32> = test.rows.iter().map(|row| row.year).collect();
+        // Past one year the prior record's price change overlaps the row's own, so the rules would see the
+        // future; and the rules predict price change classes, not external labels
+        let prior_classes: Vec<Option<u8>> =
+            test.rows.iter().map(|row| row.prior_price_change.and_then(|change| self.label.label(change))).collect();
+        let benchmarks = (self.load_options.horizon <= 1 && self.labels.is_none())
+            .then(|| rule_benchmarks(&test.labels, &y_pred, &prior_classes, n_classes));
+        let metrics = RunMetrics {
+            model: self.model.label().to_string(),
+            seed: self.seed,
+            horizon: self.load_options.horizon,
+            returns: self.load_options.return_basis(),
+            n_rows: train.len() + test.len(),
+            features: train.feature_names.clone(),
+            accuracy: Some(accuracy(&test.labels, &y_pred)),
+            macro_f1: Some(macro_f1(&test.labels, &y_pred, n_classes)),
+            classification: Some(classification_report(&test.labels, &y_pred, n_classes)),
+            mcc: Some(mcc(&test.labels, &y_pred, n_classes)),
+            roc_auc: Some(multiclass_roc_auc(&test.labels, &scores, n_classes)),
+            confusion_matrix: Some(confusion_matrix(&test.labels, &y_pred, n_classes)),
+            baselines: Some(baselines(&train.labels, &test.labels, n_classes)),
+            benchmarks,
+            by_year: Some(scores_by_year(&years, &test.labels, &y_pred, n_classes)),
+            ..Default::default()
+        };
+        RunResult {
+            metrics,
+            model,
+            members,
+            train,
+            test,
+            y_pred,
+            scores,
+            tied: Vec::new(),
+        }
+    }
+
+    /// Evaluates models trained on each `fraction` of `train` against the whole of
+    /// `test` and against the rows they were trained on, smallest fraction first.
+    /// Each fraction is sampled `resamples` times, stratified by label, with seeds
+    /// `seed, seed + 1, ...`; the model itself always uses the pipeline seed, so a
+    /// fraction of 1.0 scores the same as `evaluate(train, test)`.
+    pub fn learning_curve(
+        &self,
+        train: &Dataset,
+        test: &Dataset,
+        fractions: &[f64],
+        resamples: usize,
+    ) -> Result<Vec<LearningCurvePoint>, Box<dyn Error>> {
+        if resamples == 0 {
+            return Err("learning curve needs at least one resample per fraction".into());
+        }
+        let mut fractions = fractions.to_vec();
+        fractions.sort_by(f64::total_cmp);
+        let mut points = Vec::with_capacity(fractions.len());
+        for fraction in fractions {
+            if !(fraction > 0.0 && fraction <= 1.0) {
+                return Err(format!("learning-curve fraction {} is not in (0, 1]", fraction).into());
+            }
+            let mut train_accuracies = Vec::with_capacity(resamples);
+            let mut accuracies = Vec::with_capacity(resamples);
+            let mut f1_scores = Vec::with_capacity(resamples);
+            let mut n_train = 0;
+            for i in 0..resamples {
+                let rows = stratified_subsample(&train.labels, fraction, self.seed.wrapping_add(i as u64));
+                n_train = rows.len();
+                let result = self.evaluate(train.subset(&rows), test.clone())?;
+                let train_pred = match &result.model {
+                    Some(model) => model.predict(&result.train.to_matrix())?,
+                    None => soft_voting(&self.model_config(), &result.train, &result.train)?.y_pred,
+                };
+                train_accuracies.push(accuracy(&result.train.labels, &train_pred));
+                accuracies.push(result.metrics.accuracy.unwrap_or_default());
+                f1_scores.push(result.metrics.macro_f1.unwrap_or_default());
+            }
+            points.push(LearningCurvePoint {
+                fraction,
+                n_train,
+                train_accuracy: Summary::of(&train_accuracies),
+                accuracy: Summary::of(&accuracies),
+                macro_f1: Summary::of(&f1_scores),
+            });
+        }
+        Ok(points)
+    }
+
+    /// `train` with each row's class replaced by that of its ticker's following
+    /// year in `stock_data` (or the labels file), without the rows whose following
+    /// year has none, such as every ticker's latest one. A model fit on these
+    /// predicts the next year's class from this year's features.
+    pub fn next_year_labels(&self, stock_data: &HashMap<String, Vec<StockData>>, train: &Dataset) -> Dataset {
+        let changes: HashMap<(&str, u32), f64> = stock_data
+            .iter()
+            .flat_map(|(ticker, records)| records.iter().map(move |r| ((ticker.as_str(), r.year), r.price_change)))
+            .collect();
+        let next_class = |ticker: &str, year: u32| match &self.labels {
+            Some(labels) => labels.get(&(canonical_ticker(ticker), year + 1)).copied(),
+            None => changes.get(&(ticker, year + 1)).and_then(|&change| self.label.label(change)),
+        };
+        let (kept, classes): (Vec<usize>, Vec<u8>) = train
+            .rows
+            .iter()
+            .enumerate()
+            .filter_map(|(i, row)| next_class(&row.ticker, row.year).map(|class| (i, class)))
+            .unzip();
+        let mut shifted = train.subset(&kept);
+        shifted.labels = classes;
+        shifted
+    }
+
+    /// Fits on `train` (every labelled row, typically), cleaned as a training split
+    /// and relabelled by `next_year_labels`, and predicts the class of the year
+    /// after each ticker's latest record in `stock_data`, built with the same
+    /// features `train` has.
+    pub fn forecast(
+        &self,
+        stock_data: &HashMap<String, Vec<StockData>>,
+        train: &Dataset,
+    ) -> Result<Forecast, Box<dyn Error>> {
+        if let Model::Ensemble { .. } = self.model {
+            return Err("forecasting needs a single model; drop --ensemble".into());
+        }
+        let train = self.next_year_labels(stock_data, train);
+        self.check_rows(&train)?;
+        let rows = prepare_forecast_rows(stock_data, &train.feature_names);
+        // The rows keep their computed values for the output; the model sees them scaled like `train`
+        let mut values = rows.values.clone();
+        let empty = train.subset(&[]);
+        let (mut train, _, _) = self.clean(train, empty);
+        if self.standardize != Standardize::Off {
+            let scaler = Scaler::fit(&train, self.standardize, &self.sectors);
+            scaler.apply(&mut train.values, &train.rows);
+            scaler.apply(&mut values, &rows.rows);
+        }
+        let model = FittedModel::fit(&self.model_config(), &train)?;
+        let train_rows = train.len();
+        let predicted = if rows.rows.is_empty() {
+            Vec::new()
+        } else {
+            let x = DenseMatrix::new(rows.rows.len(), rows.feature_names.len(), values, false);
+            model.predict_voted(&x, self.tie_break)?.labels
+        };
+        Ok(Forecast { rows, predicted, train_rows })
+    }
+
+    pub fn run(&self) -> Result<RunResult, Box<dyn Error>> {
+        let mut stock_data = self.load()?;
+        self.filter_tickers(&mut stock_data);
+        self.filter(&mut stock_data);
+        let (dataset, _) = self.sanitize(&self.dataset(&stock_data));
+        let (train, test, _) = self.split(&dataset)?;
+        let (train, test, _) = self.select_features(train, test);
+        let (train, test, _) = self.standardize(train, test);
+        self.evaluate(train, test)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::categorize_price_change;
+    use crate::synthetic::{SignalSource, SyntheticConfig};
+    use crate::test_util::fixture_path;
+
+    fn synthetic(seed: u64) -> crate::synthetic::SyntheticData {
+        SyntheticConfig {
+            n_tickers: 40,
+            n_years: 6,
+            signal: Some(SignalSource::Revenue),
+            price_noise: 5.0,
+            seed,
+            ..Default::default()
+        }
+        .generate()
+    }
+
+    #[test]
+    fn test_default_labels_match_categorize() {
+        let mode = LabelMode::default();
+        for change in [-80.0, -50.0, -10.0, 0.0, 25.0, 50.0, 90.0, f64::NAN, f64::INFINITY] {
+            assert_eq!(mode.label(change), categorize_price_change(change));
+        }
+    }
+
+    #[test]
+    fn test_default_split_trains_on_most_rows() {
+        let stock_data = synthetic(2).stock_data();
+        let pipeline = Pipeline::builder().stock_data(stock_data.clone()).build().unwrap();
+        let dataset = pipeline.dataset(&stock_data);
+        let (train, test, _) = pipeline.split(&dataset).unwrap();
+        let n_test = (dataset.len() as f64 * DEFAULT_TEST_SIZE) as usize;
+        assert_eq!((train.len(), test.len()), (dataset.len() - n_test, n_test));
+        assert!(train.len() > 3 * test.len());
+    }
+
+    #[test]
+    fn test_median_imputation_uses_training_rows_only() {
+        let stock_data = synthetic(3).stock_data();
+        let pipeline = Pipeline::builder()
+            .stock_data(stock_data.clone())
+            .non_finite(NonFinitePolicy::Median)
+            .split(Split::ByYear { cutoff: 2020 })
+            .build()
+            .unwrap();
+        let mut dataset = pipeline.dataset(&stock_data);
+        let last_year = dataset.rows.iter().map(|row| row.year).max().unwrap();
+        let missing = dataset.rows.iter().position(|row| row.year == last_year).unwrap();
+        dataset.row_mut(missing)[0] = f64::NAN;
+        // Test rows far above the training rows would pull a median over all rows up
+        for i in 0..dataset.len() {
+            if dataset.rows[i].year > 2020 && i != missing {
+                dataset.row_mut(i)[0] += 1000.0;
+            }
+        }
+        let (dataset, report) = pipeline.sanitize(&dataset);
+        assert_eq!(report.total(), 1);
+
+        let (train, test, _) = pipeline.split(&dataset).unwrap();
+        let i = test.rows.iter().position(|row| row == &dataset.rows[missing]).unwrap();
+        assert_eq!(test.row(i)[0], finite_medians(&train)[0]);
+        assert!(test.row(i)[0] < 500.0);
+    }
+
+    #[test]
+    fn test_outlier_fences_come_from_training_rows() {
+        let stock_data = synthetic(4).stock_data();
+        let pipeline = Pipeline::builder()
+            .stock_data(stock_data.clone())
+            .outliers(OutlierMode::Clip, 1.5)
+            .split(Split::ByYear { cutoff: 2020 })
+            .build()
+            .unwrap();
+        let mut dataset = pipeline.dataset(&stock_data);
+        // Every test row far above the training rows: fences over all rows would let most of them through
+        for i in 0..dataset.len() {
+            if dataset.rows[i].year > 2020 {
+                dataset.row_mut(i)[0] += 1000.0;
+            }
+        }
+        let (train, test, report) = pipeline.split(&dataset).unwrap();
+        let (_, high) = iqr_fences(&train, 1.5)[0].unwrap();
+        assert!(test.column(0).all(|value| value <= high));
+        assert!(high < 500.0);
+        assert!(report.outliers.values_flagged >= test.len());
+    }
+
+    #[test]
+    fn test_split_reports_training_rows_before_replication() {
+        let stock_data = synthetic(40).stock_data();
+        let builder = Pipeline::builder().stock_data(stock_data.clone()).outliers(OutlierMode::Drop, 1.0);
+        let unweighted = builder.clone().build().unwrap();
+        let decayed = builder.recency_decay(0.5).build().unwrap();
+        let dataset = decayed.dataset(&stock_data);
+
+        let (cleaned, _, _) = unweighted.split(&dataset).unwrap();
+        let (train, test, report) = decayed.split(&dataset).unwrap();
+        // The dropped outliers shrink both sides, so the rows before replication are not `dataset - test`
+        assert!(report.outliers.rows_affected > 0);
+        assert_eq!(report.unweighted_train_rows, cleaned.len());
+        assert!(report.unweighted_train_rows < dataset.len() - test.len());
+        assert!(train.len() < report.unweighted_train_rows);
+    }
+
+    #[test]
+    fn test_split_balances_the_training_classes() {
+        let stock_data = synthetic(40).stock_data();
+        let pipeline = Pipeline::builder().stock_data(stock_data.clone()).balance_classes(true).build().unwrap();
+        let dataset = pipeline.dataset(&stock_data);
+
+        let (train, test, report) = pipeline.split(&dataset).unwrap();
+        let count = |label: u8| train.labels.iter().filter(|&&l| l == label).count();
+        let counts: Vec<usize> = (0..=*train.labels.iter().max().unwrap()).map(count).collect();
+        let largest = *counts.iter().max().unwrap();
+        // The smaller classes reach the largest in expectation, the largest is kept as it is
+        assert!(counts.iter().all(|&count| count * 10 >= largest * 7), "{:?}", counts);
+        assert!(train.len() > report.unweighted_train_rows);
+        assert_eq!(report.unweighted_train_rows, dataset.len() - test.len());
+    }
+
+    #[test]
+    fn test_prepared_fold_matches_the_configured_split() {
+        let stock_data = synthetic(5).stock_data();
+        let pipeline = Pipeline::builder()
+            .stock_data(stock_data.clone())
+            .select_correlated(0.5)
+            .standardize(Standardize::Global)
+            .recency_decay(0.5)
+            .build()
+            .unwrap();
+        let dataset = pipeline.dataset(&stock_data);
+        let (train, test) = dataset.train_test_split(0.25, 5);
+        let (prepared_train, prepared_test) = pipeline.prepare_fold(train.clone(), test.clone());
+
+        let (expected_train, expected_test, _) = pipeline.select_features(pipeline.weight_training_rows(train), test);
+        let (expected_train, expected_test, _) = pipeline.standardize(expected_train, expected_test);
+        assert!(prepared_train.n_features() < dataset.n_features());
+        assert_eq!(prepared_train.feature_names, expected_train.feature_names);
+        assert_eq!((prepared_train.values, prepared_test.values), (expected_train.values, expected_test.values));
+    }
+
+    #[test]
+    fn test_appended_data_grows_a_reloaded_forest() {
+        use crate::forest::ForestVotes;
+        use crate::model::SavedForest;
+        let stock_data = synthetic(7).stock_data();
+        let forest = ForestConfig { n_trees: 12, min_samples_split: 2, ..Default::default() };
+        let pipeline = Pipeline::builder()
+            .stock_data(stock_data.clone())
+            .model(Model::RandomForest(forest.clone()))
+            .build()
+            .unwrap();
+        let dataset = pipeline.dataset(&stock_data);
+        let (train, test, _) = pipeline.split(&dataset).unwrap();
+        // The saved forest saw the older half of the training rows; the newer half arrives in a file
+        let (older, newer): (Vec<usize>, Vec<usize>) = (0..train.len()).partition(|&i| i % 2 == 0);
+        let Ok(FittedModel::Forest(base)) = FittedModel::fit(&pipeline.model_config(), &train.subset(&older)) else {
+            panic!("a forest config fits a forest")
+        };
+        let saved = fixture_path("append_forest.json");
+        let saved = saved.to_str().unwrap();
+        SavedForest::save(saved, &train.feature_names, &base).unwrap();
+        let new_rows = fixture_path("append_rows.csv");
+        let new_rows = new_rows.to_str().unwrap().to_string();
+        train.subset(&newer).export_features(&new_rows).unwrap();
+
+        let (combined, test) = pipeline.append_rows(train.subset(&older), test, &[new_rows]).unwrap();
+        assert_eq!(combined.len(), train.len());
+        let reloaded = SavedForest::load(saved).unwrap();
+        let extra = ModelConfig { forest: ForestConfig { n_trees: 5, ..forest }, ..pipeline.model_config() };
+        let appended = FittedModel::warm_append(&reloaded.forest, &extra, &combined).unwrap();
+        let FittedModel::Forest(grown) = &appended else { panic!("warm append gives a forest") };
+        assert_eq!(ForestVotes::from_forest(grown).unwrap().n_trees(), 12 + 5);
+        let result = pipeline.evaluate_model(appended, combined, test).unwrap();
+        assert!(result.metrics.accuracy.unwrap() > 0.5);
+
+        let other = fixture_path("append_other.csv");
+        train.without_feature(0).export_features(other.to_str().unwrap()).unwrap();
+        let paths = [other.to_str().unwrap().to_string()];
+        assert!(pipeline.append_rows(train.clone(), train.subset(&[]), &paths).is_err());
+    }
+
+    #[test]
+    fn test_forest_from_files_with_year_split() {
+        let dir = fixture_path("pipeline_files");
+        let files = synthetic(1).write_csvs(&dir).unwrap();
+        let result = Pipeline::builder()
+            .fundamentals(&files.financial_file_pairs())
+            .prices(&files.price_file)
+            .label(LabelMode::Thresholds(vec![-20.0, 0.0, 20.0]))
+            .split(Split::ByYear { cutoff: 2020 })
+            .model(Model::RandomForest(ForestConfig {
+                n_trees: 20,
+                min_samples_split: 2,
+                m: None,
+                ..Default::default()
+            }))
+            .seed(42)
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+
+        assert!(result.train.rows.iter().all(|row| row.year <= 2020));
+        assert!(result.test.rows.iter().all(|row| row.year > 2020));
+        assert_eq!(result.y_pred.len(), result.test.len());
+        for (label, row) in result.test.labels.iter().zip(&result.test.rows) {
+            let expected = [-20.0, 0.0, 20.0].iter().filter(|&&t| row.price_change >= t).count() as u8;
+            assert_eq!(*label, expected);
+        }
+        assert!(matches!(result.model, Some(FittedModel::Forest(_))));
+        // The planted revenue signal is learnable
+        assert!(result.metrics.accuracy.unwrap() > 0.5, "{:?}", result.metrics.accuracy);
+    }
+
+    #[test]
+    fn test_ensemble_on_loaded_data_with_random_split() {
+        let result = Pipeline::builder()
+            .stock_data(synthetic(2).stock_data())
+            .split(Split::Random { test_size: 0.3 })
+            .model(Model::Ensemble {
+                forest: ForestConfig {
+                    n_trees: 10,
+                    m: None,
+                    ..Default::default()
+                },
+                tree_depth: 3,
+            })
+            .recency_halflife(2.0)
+            .seed(7)
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+
+        assert_eq!(result.members.len(), 3);
+        assert!(result.model.is_none());
+        assert_eq!(result.metrics.model, "Soft Voting Ensemble");
+        assert_eq!(result.scores.len(), result.test.len());
+        assert_eq!(result.metrics.n_rows, result.train.len() + result.test.len());
+    }
+
+    #[test]
+    fn test_full_learning_curve_matches_single_run() {
+        let pipeline = Pipeline::builder()
+            .stock_data(synthetic(3).stock_data())
+            .split(Split::Random { test_size: 0.3 })
+            .model(Model::RandomForest(ForestConfig {
+                n_trees: 10,
+                m: None,
+                ..Default::default()
+            }))
+            .seed(11)
+            .build()
+            .unwrap();
+        let dataset = pipeline.dataset(&pipeline.load().unwrap());
+        let (train, test, _) = pipeline.split(&dataset).unwrap();
+
+        let points = pipeline.learning_curve(&train, &test, &[0.25, 0.5, 1.0], 2).unwrap();
+        let sizes: Vec<usize> = points.iter().map(|point| point.n_train).collect();
+        let n = train.len() as f64;
+        assert_eq!(sizes, vec![(n * 0.25).round() as usize, (n * 0.5).round() as usize, train.len()]);
+
+        let single = pipeline.evaluate(train.clone(), test.clone()).unwrap().metrics;
+        let full = &points[2];
+        assert_eq!(full.accuracy.mean, single.accuracy.unwrap());
+        assert_eq!(full.macro_f1.mean, single.macro_f1.unwrap());
+        assert_eq!(full.accuracy.std, 0.0);
+
+        assert!(pipeline.learning_curve(&train, &test, &[1.5], 1).is_err());
+    }
+
+    #[test]
+    fn test_learning_curve_orders_points_by_training_size() {
+        let pipeline = Pipeline::builder()
+            .stock_data(synthetic(4).stock_data())
+            .split(Split::Random { test_size: 0.3 })
+            .model(Model::DecisionTree { max_depth: 4 })
+            .seed(5)
+            .build()
+            .unwrap();
+        let dataset = pipeline.dataset(&pipeline.load().unwrap());
+        let (train, test, _) = pipeline.split(&dataset).unwrap();
+
+        let points = pipeline.learning_curve(&train, &test, &[1.0, 0.1, 0.5, 0.25], 1).unwrap();
+        let fractions: Vec<f64> = points.iter().map(|point| point.fraction).collect();
+        assert_eq!(fractions, vec![0.1, 0.25, 0.5, 1.0]);
+        assert!(points.windows(2).all(|pair| pair[0].n_train < pair[1].n_train));
+        for point in &points {
+            assert!((0.0..=1.0).contains(&point.train_accuracy.mean));
+            assert!((0.0..=1.0).contains(&point.accuracy.mean));
+        }
+    }
+
+    #[test]
+    fn test_forecast_needs_prior_year_deltas() {
+        let mut stock_data = synthetic(6).stock_data();
+        // Two years give the latest record its own deltas but none for the year before
+        stock_data.insert(
+            "NEW".to_string(),
+            crate::synthetic::ticker_records(
+                "NEW",
+                &[(2021, [100.0, 10.0, 50.0, 5.0, 80.0], 0.0), (2022, [110.0, 12.0, 55.0, 6.0, 90.0], 0.0)],
+            ),
+        );
+        let pipeline = Pipeline::builder()
+            .stock_data(stock_data.clone())
+            .model(Model::DecisionTree { max_depth: 3 })
+            .seed(2)
+            .build()
+            .unwrap();
+        let dataset = pipeline.dataset(&pipeline.load().unwrap());
+        let forecast = pipeline.forecast(&stock_data, &dataset).unwrap();
+
+        assert_eq!(forecast.rows.unforecastable, vec!["NEW"]);
+        assert_eq!(forecast.rows.rows.len(), stock_data.len() - 1);
+        assert_eq!(forecast.predicted.len(), forecast.rows.rows.len());
+        assert_eq!(forecast.rows.feature_names, dataset.feature_names);
+        let first = &forecast.rows.rows[0];
+        assert_eq!(first.year, stock_data[&first.ticker].last().unwrap().year);
+        assert!(forecast.predicted.iter().all(|&class| (class as usize) < N_CLASSES));
+
+        // The latest row is built exactly like the training row of the same record
+        let training_row = dataset
+            .rows
+            .iter()
+            .position(|row| row.ticker == first.ticker && row.year == first.year)
+            .unwrap();
+        assert_eq!(&forecast.rows.values[..dataset.n_features()], dataset.row(training_row));
+
+        // The model learns each row's following year's class; latest years have none to learn
+        let shifted = pipeline.next_year_labels(&stock_data, &dataset);
+        let latest = dataset.rows.iter().filter(|row| row.year == stock_data[&row.ticker].last().unwrap().year);
+        assert_eq!(shifted.len(), dataset.len() - latest.count());
+        for (row, &class) in shifted.rows.iter().zip(&shifted.labels) {
+            let next = stock_data[&row.ticker].iter().find(|record| record.year == row.year + 1).unwrap();
+            assert_eq!(Some(class), pipeline.label_mode().label(next.price_change));
+        }
+    }
+
+    #[test]
+    fn test_forecast_cleans_the_training_rows_once() {
+        let stock_data = synthetic(40).stock_data();
+        let pipeline = Pipeline::builder()
+            .stock_data(stock_data.clone())
+            .model(Model::DecisionTree { max_depth: 3 })
+            .outliers(OutlierMode::Drop, 1.0)
+            .seed(2)
+            .build()
+            .unwrap();
+        let (dataset, _) = pipeline.sanitize(&pipeline.dataset(&stock_data));
+        let shifted = pipeline.next_year_labels(&stock_data, &dataset);
+        let (once, _, report) = pipeline.clean(shifted.clone(), shifted.subset(&[]));
+        assert!(report.rows_affected > 0 && once.len() < shifted.len());
+
+        let forecast = pipeline.forecast(&stock_data, &dataset).unwrap();
+        assert_eq!(forecast.train_rows, once.len());
+    }
+
+    #[test]
+    fn test_degenerate_datasets_are_typed_errors() {
+        let run = |config: SyntheticConfig, label: LabelMode| {
+            let err = Pipeline::builder()
+                .stock_data(config.generate().stock_data())
+                .label(label)
+                .split(Split::Random { test_size: 0.3 })
+                .seed(1)
+                .build()
+                .unwrap()
+                .run()
+                .err()
+                .expect("the run should fail");
+            err.downcast::<DatasetError>().map(|err| *err).unwrap()
+        };
+
+        // A single year has no year-over-year changes to build rows from
+        let one_year = SyntheticConfig {
+            n_years: 1,
+            ..Default::default()
+        };
+        assert_eq!(run(one_year, LabelMode::default()), DatasetError::EmptyDataset);
+
+        // Three tickers over three years leave a handful of rows
+        let tiny = SyntheticConfig {
+            n_tickers: 3,
+            n_years: 3,
+            ..Default::default()
+        };
+        let err = Pipeline::builder()
+            .stock_data(tiny.generate().stock_data())
+            .min_rows(20)
+            .build()
+            .unwrap()
+            .run()
+            .err()
+            .expect("the run should fail");
+        let err = err.downcast::<DatasetError>().map(|err| *err).unwrap();
+        assert_eq!(err, DatasetError::BelowMinRows { got: 3, min_rows: 20 });
+        assert!(err.to_string().starts_with("only 3 usable rows after joining"));
+
+        // Every price change is below the first threshold
+        let everything_class_0 = LabelMode::Thresholds(vec![1e9]);
+        assert_eq!(
+            run(SyntheticConfig::default(), everything_class_0),
+            DatasetError::SingleClassTraining { class: 0 }
+        );
+
+        // Default min_samples_split is larger than the 70% of 9 rows left to train on
+        let tiny = SyntheticConfig {
+            n_tickers: 3,
+            n_years: 4,
+            ..Default::default()
+        };
+        assert!(matches!(
+            run(tiny, LabelMode::default()),
+            DatasetError::TooFewRows { needed, .. } if needed == ForestConfig::default().min_samples_split
+        ));
+    }
+
+    #[test]
+    fn test_excluded_feature_leaves_the_matrix() {
+        use smartcore::linalg::basic::arrays::Array;
+        let stock_data = synthetic(4).stock_data();
+        let pipeline = |exclude: &[String]| {
+            Pipeline::builder()
+                .stock_data(stock_data.clone())
+                .exclude_features(exclude)
+                .build()
+        };
+        let full = pipeline(&[]).unwrap().dataset(&stock_data);
+        let interaction = "delta_revenue*delta_profit_margin".to_string();
+        let reduced = pipeline(std::slice::from_ref(&interaction)).unwrap().dataset(&stock_data);
+
+        assert_eq!(reduced.feature_names.len(), full.feature_names.len() - 1);
+        assert!(!reduced.feature_names.contains(&interaction));
+        assert_eq!(reduced.to_matrix().shape(), (full.len(), full.feature_names.len() - 1));
+
+        let err = pipeline(&["delta_typo".to_string()]).unwrap_err();
+        assert_eq!(err.field, "exclude_features");
+        assert!(err.message.contains("delta_typo"));
+    }
+
+    #[test]
+    fn test_build_rejects_incompatible_settings() {
+        let base = || Pipeline::builder().stock_data(HashMap::new());
+        let field = |builder: PipelineBuilder| builder.build().err().map(|err| err.field);
+
+        assert_eq!(field(Pipeline::builder()), Some("fundamentals"));
+        assert_eq!(field(Pipeline::builder().fundamentals(&[("a.csv", "assets")])), Some("prices"));
+        assert_eq!(field(base().prices("p.csv")), Some("fundamentals"));
+        assert_eq!(field(base().label(LabelMode::Thresholds(vec![10.0, 0.0]))), Some("label"));
+        assert_eq!(field(base().label(LabelMode::Thresholds(vec![-30.0, -10.0, 0.0, 10.0]))), Some("label"));
+        assert_eq!(field(base().split(Split::Random { test_size: 1.0 })), Some("split"));
+        assert_eq!(field(base().model(Model::DecisionTree { max_depth: 0 })), Some("tree_depth"));
+        assert_eq!(field(base().recency_halflife(0.0)), Some("recency_halflife"));
+        assert_eq!(field(base().outliers(OutlierMode::Clip, -1.0)), Some("outlier_threshold"));
+        assert_eq!(field(base().select_correlated(1.5)), Some("select_corr"));
+        let no_horizon = LoadOptions {
+            horizon: 0,
+            ..Default::default()
+        };
+        assert_eq!(field(base().load_options(no_horizon)), Some("horizon"));
+        assert!(base().build().is_ok());
+    }
+}
@@ -0,0 +1,263 @@
+//! Stock-picking evaluation: rank the test rows by how attractive the model
+//! finds them and check how many of the top picks actually did well, or buy
+//! every row predicted in the top class and compare its return to the market.
+use std::collections::BTreeMap;
+use std::error::Error;
+use crate::dataset::RowId;
+
+/// What counts as a good pick.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GoodOutcome {
+    /// Realized price change above the median of all ranked rows
+    AboveMedian,
+    /// Realized price change above this percent
+    AboveThreshold(f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RankingResult {
+    pub n_selected: usize,
+    pub precision_at_k: f64,
+    pub base_rate: f64, // share of all ranked rows with a good outcome
+    pub mean_selected_change: f64,
+    pub mean_change: f64,
+}
+
+/// Expected class index under the per-class scores, so probability on the
+/// higher (better) classes ranks a row higher.
+pub fn attractiveness(scores: &[Vec<f64>]) -> Vec<f64> {
+    scores
+        .iter()
+        .map(|row| row.iter().enumerate().map(|(class, p)| class as f64 * p).sum())
+        .collect()
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> f64 {
+    let (sum, n) = values.fold((0.0, 0usize), |(sum, n), v| (sum + v, n + 1));
+    if n == 0 {
+        0.0
+    } else {
+        sum / n as f64
+    }
+}
+
+/// A test row ranked by its probability of the top class.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopPick {
+    pub row: RowId,
+    pub probability: f64, // score of the top (biggest gain) class
+    pub label: u8,        // the true class
+}
+
+/// The `n` rows with the highest score for `top_class`, most probable first
+/// (ties broken by row order). `scores` and `labels` are aligned with `rows`;
+/// score rows are as wide as the model's classes, which may be more than the
+/// label mode uses, so the class is indexed rather than taken as the last. A
+/// score row without `top_class` is an error.
+pub fn top_n_by_top_class(
+    rows: &[RowId],
+    scores: &[Vec<f64>],
+    labels: &[u8],
+    top_class: usize,
+    n: usize,
+) -> Result<Vec<TopPick>, Box<dyn Error>> {
+    if let Some((i, row)) = scores.iter().enumerate().find(|(_, row)| row.len() <= top_class) {
+        return Err(format!("scores of row {} cover {} classes, not class {}", i, row.len(), top_class).into());
+    }
+    let probability = |i: usize| scores[i][top_class];
+    let mut order: Vec<usize> = (0..rows.len()).collect();
+    order.sort_by(|&a, &b| probability(b).total_cmp(&probability(a)));
+    Ok(order
+        .into_iter()
+        .take(n)
+        .map(|i| TopPick {
+            row: rows[i].clone(),
+            probability: probability(i),
+            label: labels[i],
+        })
+        .collect())
+}
+
+/// Takes the `k` most attractive rows of every year (ties broken by row order)
+/// and scores them against `outcome`.
+pub fn top_k_by_year(rows: &[RowId], attractiveness: &[f64], k: usize, outcome: GoodOutcome) -> RankingResult {
+    let cutoff = match outcome {
+        GoodOutcome::AboveThreshold(threshold) => threshold,
+        GoodOutcome::AboveMedian => {
+            let mut changes: Vec<f64> = rows.iter().map(|row| row.price_change).collect();
+            changes.sort_by(f64::total_cmp);
+            match changes.len() {
+                0 => 0.0,
+                n if n % 2 == 0 => (changes[n / 2 - 1] + changes[n / 2]) / 2.0,
+                n => changes[n / 2],
+            }
+        }
+    };
+    let good = |i: usize| rows[i].price_change > cutoff;
+
+    let mut by_year: BTreeMap<u32, Vec<usize>> = BTreeMap::new();
+    for (i, row) in rows.iter().enumerate() {
+        by_year.entry(row.year).or_default().push(i);
+    }
+    let mut selected = Vec::new();
+    for mut indices in by_year.into_values() {
+        indices.sort_by(|&a, &b| attractiveness[b].total_cmp(&attractiveness[a]));
+        selected.extend(indices.into_iter().take(k));
+    }
+
+    let share_good = |indices: &[usize]| {
+        if indices.is_empty() {
+            0.0
+        } else {
+            indices.iter().filter(|&&i| good(i)).count() as f64 / indices.len() as f64
+        }
+    };
+    let all: Vec<usize> = (0..rows.len()).collect();
+    RankingResult {
+        n_selected: selected.len(),
+        precision_at_k: share_good(&selected),
+        base_rate: share_good(&all),
+        mean_selected_change: mean(selected.iter().map(|&i| rows[i].price_change)),
+        mean_change: mean(rows.iter().map(|row| row.price_change)),
+    }
+}
+
+/// Buying every row predicted in one class and holding it for the year,
+/// against holding every row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BacktestResult {
+    pub n_rows: usize,
+    pub n_selected: usize,
+    pub strategy_return: Option<f64>, // mean realized price change of the picks; None without picks
+    pub market_return: f64,           // mean realized price change of every row
+}
+
+impl BacktestResult {
+    /// Percentage points the picks beat the market by.
+    pub fn excess_return(&self) -> Option<f64> {
+        self.strategy_return.map(|strategy| strategy - self.market_return)
+    }
+}
+
+/// Picks the rows predicted in `class` and averages their realized price
+/// changes, equally weighted. `y_pred` is aligned with `rows`.
+pub fn backtest(rows: &[RowId], y_pred: &[u8], class: u8) -> BacktestResult {
+    let picks: Vec<f64> =
+        rows.iter().zip(y_pred).filter(|(_, &predicted)| predicted == class).map(|(row, _)| row.price_change).collect();
+    BacktestResult {
+        n_rows: rows.len(),
+        n_selected: picks.len(),
+        strategy_return: (!picks.is_empty()).then(|| mean(picks.iter().copied())),
+        market_return: mean(rows.iter().map(|row| row.price_change)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    use crate::dataset::N_CLASSES;
+
+    fn test_rows() -> Vec<RowId> {
+        let mut rng = StdRng::seed_from_u64(12);
+        (0..1000)
+            .map(|i| RowId {
+                ticker: format!("T{:03}", i % 100),
+                year: 2013 + (i / 100) as u32,
+                price_change: rng.gen_range(-60.0..60.0),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_perfect_and_shuffled_ranking() {
+        let rows = test_rows();
+        let perfect: Vec<f64> = rows.iter().map(|row| row.price_change).collect();
+
+        let result = top_k_by_year(&rows, &perfect, 20, GoodOutcome::AboveMedian);
+        assert_eq!(result.n_selected, 200);
+        assert_eq!(result.precision_at_k, 1.0);
+        assert!((result.base_rate - 0.5).abs() < 1e-12);
+        assert!(result.mean_selected_change > result.mean_change + 30.0);
+
+        let strict = top_k_by_year(&rows, &perfect, 5, GoodOutcome::AboveThreshold(40.0));
+        assert_eq!(strict.precision_at_k, 1.0);
+        assert!(strict.base_rate < 0.25);
+
+        let mut shuffled = perfect.clone();
+        shuffled.shuffle(&mut StdRng::seed_from_u64(3));
+        let random = top_k_by_year(&rows, &shuffled, 20, GoodOutcome::AboveMedian);
+        assert!((random.precision_at_k - random.base_rate).abs() < 0.1, "{:?}", random);
+    }
+
+    #[test]
+    fn test_top_n_by_top_class() {
+        let rows: Vec<RowId> = test_rows().into_iter().take(6).collect();
+        let scores = vec![
+            vec![0.5, 0.3, 0.1, 0.1],
+            vec![0.1, 0.1, 0.2, 0.6],
+            vec![0.4, 0.1, 0.2, 0.3],
+            vec![0.0, 0.1, 0.1, 0.8],
+            vec![0.2, 0.2, 0.3, 0.3],
+            vec![0.7, 0.3, 0.0, 0.0],
+        ];
+        let labels = [0, 3, 1, 3, 2, 0];
+        let picks = top_n_by_top_class(&rows, &scores, &labels, N_CLASSES - 1, 4).unwrap();
+        assert_eq!(picks.len(), 4);
+        assert!(picks.windows(2).all(|pair| pair[0].probability >= pair[1].probability));
+        assert_eq!(picks.iter().map(|pick| pick.probability).collect::<Vec<f64>>(), vec![0.8, 0.6, 0.3, 0.3]);
+        // The tie keeps row order
+        assert_eq!(picks[2].row, rows[2]);
+        assert_eq!(picks[3].row, rows[4]);
+        assert_eq!(picks.iter().map(|pick| pick.label).collect::<Vec<u8>>(), vec![3, 3, 1, 2]);
+        assert_eq!(top_n_by_top_class(&rows, &scores, &labels, N_CLASSES - 1, 10).unwrap().len(), 6);
+
+        // Three classes under four-wide scores: the top class is 2, not the unused last column
+        let three = top_n_by_top_class(&rows, &scores, &labels, 2, 1).unwrap();
+        assert_eq!((three[0].probability, &three[0].row), (0.3, &rows[4]));
+        let narrow = vec![vec![0.5, 0.5]; 6];
+        assert!(top_n_by_top_class(&rows, &narrow, &labels, N_CLASSES - 1, 1).is_err());
+    }
+
+    #[test]
+    fn test_attractiveness_prefers_higher_classes() {
+        let scores = vec![vec![0.7, 0.3, 0.0, 0.0], vec![0.0, 0.1, 0.2, 0.7], vec![0.25; 4]];
+        let values = attractiveness(&scores);
+        assert!(values[1] > values[2] && values[2] > values[0]);
+        assert!((values[2] - 1.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_backtest_of_top_class_picks() {
+        let row = |ticker: &str, year: u32, price_change: f64| RowId {
+            ticker: ticker.to_string(),
+            year,
+            price_change,
+            ..Default::default()
+        };
+        let rows = vec![
+            row("AAA", 2021, 30.0),
+            row("BBB", 2021, -10.0),
+            row("CCC", 2021, 5.0),
+            row("AAA", 2022, -20.0),
+            row("BBB", 2022, 12.0),
+            row("CCC", 2022, 1.0),
+        ];
+        // Two right calls and one wrong one in the top class
+        let y_pred = [3, 0, 2, 3, 3, 1];
+        let result = backtest(&rows, &y_pred, 3);
+        assert_eq!(result.n_rows, 6);
+        assert_eq!(result.n_selected, 3);
+        assert_eq!(result.strategy_return, Some((30.0 - 20.0 + 12.0) / 3.0));
+        assert_eq!(result.market_return, 3.0);
+        assert!((result.excess_return().unwrap() - (22.0 / 3.0 - 3.0)).abs() < 1e-12);
+
+        let none = backtest(&rows, &[0; 6], 3);
+        assert_eq!(none.n_selected, 0);
+        assert_eq!(none.strategy_return, None);
+        assert_eq!(none.excess_return(), None);
+    }
+}
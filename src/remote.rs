@@ -0,0 +1,328 @@
+//! Price source that downloads close prices per ticker from an HTTP endpoint
+//! instead of reading `stock_prices.csv`. Responses may be Stooq-style CSV
+//! (`Date,...,Close,...`) or Alpha Vantage-style JSON (a `... Time Series ...`
+//! object of date -> `{"4. close": ...}`); both are reduced to the same
+//! `(month, price)` observations `calculate_price_changes` aggregates.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_json::Value;
+use crate::stock_data::{aggregate_price_changes, MonthlyPrices, StockDataError, YearRange};
+
+// Environment variable the API key is read from
+pub const API_KEY_VAR: &str = "PRICE_API_KEY";
+
+#[derive(Debug, Clone)]
+pub struct RemotePriceSource {
+    /// Request URL with `{ticker}` and `{api_key}` placeholders
+    pub url_template: String,
+    pub api_key: Option<String>,
+    /// Successful responses are stored here, one file per ticker, and reused on
+    /// reruns that make the same request (see `cache_key`)
+    pub cache_dir: Option<PathBuf>,
+    /// Minimum time between two requests to the endpoint
+    pub min_interval: Duration,
+    /// Years whose prices are kept from each response
+    pub year_range: YearRange,
+}
+
+/// Price changes for the tickers that could be fetched, and the error for each one that could not.
+#[derive(Debug, Default)]
+pub struct FetchedPrices {
+    pub price_changes: HashMap<String, HashMap<u32, f64>>,
+    pub failed: Vec<(String, StockDataError)>,
+}
+
+impl RemotePriceSource {
+    /// Source with the API key taken from `PRICE_API_KEY`, no cache and one request per second.
+    pub fn new(url_template: &str) -> Self {
+        RemotePriceSource {
+            url_template: url_template.to_string(),
+            api_key: std::env::var(API_KEY_VAR).ok(),
+            cache_dir: None,
+            min_interval: Duration::from_secs(1),
+            year_range: YearRange::default(),
+        }
+    }
+
+    // The request a cached response answers: the endpoint with the ticker filled
+    // in (the API key left out, so it is never written to disk) and the years kept.
+    // A cached response under another key is fetched again.
+    fn cache_key(&self, ticker: &str) -> String {
+        format!("url={} year_range={}", self.url_template.replace("{ticker}", ticker), self.year_range)
+    }
+
+    fn url(&self, ticker: &str) -> String {
+        self.url_template
+            .replace("{ticker}", ticker)
+            .replace("{api_key}", self.api_key.as_deref().unwrap_or(""))
+    }
+
+    fn cache_path(&self, ticker: &str) -> Option<PathBuf> {
+        let file_name: String = ticker
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+            .collect();
+        self.cache_dir.as_ref().map(|dir| dir.join(format!("{}.txt", file_name)))
+    }
+
+    fn download(&self, url: &str, last_request: &mut Option<Instant>) -> Result<String, StockDataError> {
+        if let Some(last) = *last_request {
+            let elapsed = last.elapsed();
+            if elapsed < self.min_interval {
+                thread::sleep(self.min_interval - elapsed);
+            }
+        }
+        *last_request = Some(Instant::now());
+
+        let http_error = |message: String| StockDataError::Http {
+            url: url.to_string(),
+            message,
+        };
+        let response = ureq::get(url).call().map_err(|err| http_error(err.to_string()))?;
+        response.into_string().map_err(|err| http_error(err.to_string()))
+    }
+
+    /// `(year, month, close)` observations for one ticker in `year_range`, from
+    /// the cache when it holds a response to the same request.
+    fn fetch_ticker(
+        &self,
+        ticker: &str,
+        last_request: &mut Option<Instant>,
+    ) -> Result<Vec<(u32, u32, f64)>, StockDataError> {
+        let in_range = |prices: Vec<(u32, u32, f64)>| -> Vec<(u32, u32, f64)> {
+            prices.into_iter().filter(|&(year, _, _)| self.year_range.contains(year)).collect()
+        };
+        let url = self.url(ticker);
+        let key = format!("# {}\n", self.cache_key(ticker));
+        let cache_path = self.cache_path(ticker);
+        if let Some(cached) = cache_path.as_ref().and_then(|path| std::fs::read_to_string(path).ok()) {
+            if let Some(Ok(prices)) = cached.strip_prefix(&key).map(parse_price_response) {
+                return Ok(in_range(prices));
+            }
+        }
+
+        let body = self.download(&url, last_request)?;
+        let prices = parse_price_response(&body).map_err(|message| StockDataError::Http {
+            url: url.clone(),
+            message,
+        })?;
+        if let Some(path) = cache_path {
+            let written = path
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| std::fs::write(&path, key + &body));
+            if let Err(err) = written {
+                eprintln!("warning: could not cache {}: {}", path.display(), err);
+            }
+        }
+        Ok(in_range(prices))
+    }
+
+    /// Fetches every ticker in turn. A ticker whose request or response fails is
+    /// recorded in `failed` and left out rather than aborting the others.
+    pub fn fetch_price_changes(&self, tickers: &[String]) -> FetchedPrices {
+        let mut data: MonthlyPrices = HashMap::new();
+        let mut failed = Vec::new();
+        let mut last_request = None;
+        for ticker in tickers {
+            match self.fetch_ticker(ticker, &mut last_request) {
+                Ok(prices) => {
+                    let years = data.entry(ticker.clone()).or_default();
+                    for (year, month, close) in prices {
+                        years.entry(year).or_default().push((month, close));
+                    }
+                }
+                Err(err) => failed.push((ticker.clone(), err)),
+            }
+        }
+        FetchedPrices {
+            price_changes: aggregate_price_changes(&data),
+            failed,
+        }
+    }
+}
+
+// `YYYY-MM...` -> (year, month)
+fn year_month(date: &str) -> Option<(u32, u32)> {
+    if date.len() < 7 {
+        return None;
+    }
+    Some((date[..4].parse().ok()?, date[5..7].parse().ok()?))
+}
+
+/// `(year, month, close)` observations from a CSV or JSON price response.
+pub fn parse_price_response(body: &str) -> Result<Vec<(u32, u32, f64)>, String> {
+    if body.trim_start().starts_with('{') {
+        parse_json_prices(body)
+    } else {
+        parse_csv_prices(body)
+    }
+}
+
+fn parse_csv_prices(body: &str) -> Result<Vec<(u32, u32, f64)>, String> {
+    let mut reader = csv::Reader::from_reader(body.as_bytes());
+    let headers = reader.headers().map_err(|err| err.to_string())?.clone();
+    let column = |name: &str| headers.iter().position(|h| h.trim().eq_ignore_ascii_case(name));
+    let (Some(date_column), Some(close_column)) = (column("date"), column("close")) else {
+        return Err(format!("expected Date and Close columns, got `{}`", body.lines().next().unwrap_or("")));
+    };
+
+    let mut prices = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|err| err.to_string())?;
+        let date = record.get(date_column).and_then(year_month);
+        let close = record.get(close_column).and_then(|value| value.trim().parse().ok());
+        if let (Some((year, month)), Some(close)) = (date, close) {
+            prices.push((year, month, close));
+        }
+    }
+    Ok(prices)
+}
+
+fn parse_json_prices(body: &str) -> Result<Vec<(u32, u32, f64)>, String> {
+    let json: Value = serde_json::from_str(body).map_err(|err| err.to_string())?;
+    let object = json.as_object().ok_or("expected a JSON object")?;
+    let Some(series) = object
+        .iter()
+        .find(|(key, _)| key.contains("Time Series"))
+        .and_then(|(_, value)| value.as_object())
+    else {
+        // The API explains rejected requests (bad key, rate limit) in one of these fields
+        let message = ["Error Message", "Note", "Information"]
+            .iter()
+            .find_map(|key| object.get(*key).and_then(Value::as_str))
+            .unwrap_or("no time series in response");
+        return Err(message.to_string());
+    };
+
+    let mut prices = Vec::new();
+    for (date, fields) in series {
+        let close = fields.as_object().and_then(|fields| {
+            fields
+                .iter()
+                .find(|(name, _)| name.as_str() == "close" || name.ends_with(". close"))
+                .and_then(|(_, value)| match value {
+                    Value::String(text) => text.trim().parse().ok(),
+                    other => other.as_f64(),
+                })
+        });
+        if let (Some((year, month)), Some(close)) = (year_month(date), close) {
+            prices.push((year, month, close));
+        }
+    }
+    Ok(prices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use crate::test_util::fixture_path;
+
+    // Serves `/AAA` as CSV and `/BBB` as JSON, and answers 404 for anything else.
+    // Returns the base URL and a counter of requests served.
+    fn mock_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let path = request_line.split_whitespace().nth(1).unwrap_or("");
+                let (status, body) = if path.starts_with("/AAA") {
+                    (
+                        "200 OK",
+                        "Date,Open,High,Low,Close,Volume\n\
+                         2022-01-31,9,11,9,10,100\n2022-02-28,10,11,9,10,100\n\
+                         2022-11-30,14,15,14,15,100\n2022-12-30,15,16,14,15,100\n"
+                            .to_string(),
+                    )
+                } else if path.starts_with("/BBB") {
+                    (
+                        "200 OK",
+                        r#"{"Meta Data": {"2. Symbol": "BBB"}, "Monthly Time Series": {
+                            "2022-01-31": {"1. open": "20", "4. close": "20"},
+                            "2022-12-30": {"1. open": "18", "4. close": "15"}}}"#
+                            .to_string(),
+                    )
+                } else {
+                    ("404 Not Found", "unknown ticker".to_string())
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        (format!("http://{}", address), requests)
+    }
+
+    #[test]
+    fn test_fetch_price_changes_from_mock_server() {
+        let (base_url, requests) = mock_server();
+        let cache_dir = fixture_path("price_cache");
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let source = RemotePriceSource {
+            url_template: format!("{}/{{ticker}}?apikey={{api_key}}", base_url),
+            api_key: Some("demo".to_string()),
+            cache_dir: Some(cache_dir),
+            min_interval: Duration::ZERO,
+            year_range: YearRange::default(),
+        };
+        let tickers = vec!["AAA".to_string(), "BBB".to_string(), "CCC".to_string()];
+
+        let fetched = source.fetch_price_changes(&tickers);
+        assert_eq!(fetched.price_changes["AAA"][&2022], 50.0);
+        assert_eq!(fetched.price_changes["BBB"][&2022], -25.0);
+        assert!(!fetched.price_changes.contains_key("CCC"));
+        assert_eq!(fetched.failed.len(), 1);
+        assert_eq!(fetched.failed[0].0, "CCC");
+        assert!(matches!(fetched.failed[0].1, StockDataError::Http { .. }));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // Cached tickers are not requested again; the failed one is retried
+        let refetched = source.fetch_price_changes(&tickers);
+        assert_eq!(refetched.price_changes["AAA"][&2022], 50.0);
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+        // Another year range or endpoint is another request, not a cache hit
+        let later = RemotePriceSource { year_range: YearRange { first: Some(2023), last: None }, ..source.clone() };
+        let fetched = later.fetch_price_changes(&tickers[..1]);
+        assert!(fetched.price_changes.get("AAA").is_none_or(|years| years.is_empty()));
+        assert_eq!(requests.load(Ordering::SeqCst), 5);
+        let other_endpoint = RemotePriceSource {
+            url_template: format!("{}/{{ticker}}?f=csv", base_url),
+            ..source.clone()
+        };
+        other_endpoint.fetch_price_changes(&tickers[..1]);
+        assert_eq!(requests.load(Ordering::SeqCst), 6);
+        source.fetch_price_changes(&tickers[..1]);
+        assert_eq!(requests.load(Ordering::SeqCst), 7);
+    }
+
+    #[test]
+    fn test_rejected_json_reports_message() {
+        let err = parse_price_response(r#"{"Note": "API call frequency exceeded"}"#).unwrap_err();
+        assert_eq!(err, "API call frequency exceeded");
+        assert!(parse_price_response("No data").is_err());
+    }
+}
@@ -0,0 +1,286 @@
+//! Markdown report of one run for `--report`, rendered from the structs that
+//! `--metrics-json` and the `summary` subcommand serialize, so nothing is
+//! recomputed. Numbers have fixed precision and every table a fixed order, so
+//! reports of identical runs are byte-identical and can be diffed.
+use std::fmt::Write;
+use serde::Serialize;
+use crate::metrics::{DatasetSummary, RunMetrics};
+
+/// Everything the report shows.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunReport {
+    pub settings: Vec<(String, String)>,        // the effective configuration, as `Pipeline::settings` lists it
+    pub dataset: DatasetSummary,                // the prepared rows before the split
+    pub undefined_ratios: Vec<(String, usize)>, // records per ratio, as `undefined_ratio_counts` counts them
+    pub metrics: RunMetrics,
+}
+
+fn percent(value: f64) -> String {
+    format!("{:.2}%", value * 100.0)
+}
+
+// Markdown table from a header and rows of cells
+fn table(out: &mut String, header: &[String], rows: &[Vec<String>]) {
+    let line = |cells: &[String]| format!("| {} |\n", cells.join(" | "));
+    out.push_str(&line(header));
+    out.push_str(&line(&vec!["---".to_string(); header.len()]));
+    for row in rows {
+        out.push_str(&line(row));
+    }
+    out.push('\n');
+}
+
+fn strings(cells: &[&str]) -> Vec<String> {
+    cells.iter().map(|cell| cell.to_string()).collect()
+}
+
+impl RunReport {
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::new();
+        let metrics = &self.metrics;
+        let dataset = &self.dataset;
+        // Writing to a String cannot fail
+        let _ = writeln!(out, "# Run report: {}\n", metrics.model);
+
+        out.push_str("## Configuration\n\n");
+        let settings: Vec<Vec<String>> = self
+            .settings
+            .iter()
+            .map(|(name, value)| vec![format!("`{}`", name), value.clone()])
+            .collect();
+        table(&mut out, &strings(&["setting", "value"]), &settings);
+        let _ = writeln!(out, "Features ({}): {}\n", metrics.features.len(), metrics.features.join(", "));
+
+        out.push_str("## Dataset\n\n");
+        let _ = write!(out, "{} rows from {} tickers", dataset.n_rows, dataset.n_tickers);
+        if let (Some(first), Some(last)) = (dataset.first_year, dataset.last_year) {
+            let _ = write!(out, ", years {}-{}", first, last);
+        }
+        out.push_str("\n\n");
+        let years: Vec<Vec<String>> =
+            dataset.rows_per_year.iter().map(|(year, count)| vec![year.to_string(), count.to_string()]).collect();
+        table(&mut out, &strings(&["year", "rows"]), &years);
+
+        if self.undefined_ratios.iter().any(|(_, count)| *count > 0) {
+            out.push_str("Records with an undefined ratio (denominator near zero or negative), ");
+            out.push_str("whose deltas were left to the non-finite policy:\n\n");
+            let rows: Vec<Vec<String>> = self
+                .undefined_ratios
+                .iter()
+                .map(|(ratio, count)| vec![ratio.clone(), count.to_string()])
+                .collect();
+            table(&mut out, &strings(&["ratio", "records"]), &rows);
+        }
+
+        out.push_str("## Class distribution\n\n");
+        let classes: Vec<Vec<String>> = dataset
+            .class_counts
+            .iter()
+            .enumerate()
+            .map(|(class, &count)| {
+                let share = count as f64 / dataset.n_rows.max(1) as f64;
+                vec![class.to_string(), count.to_string(), percent(share)]
+            })
+            .collect();
+        table(&mut out, &strings(&["class", "rows", "share"]), &classes);
+
+        out.push_str("## Model metrics\n\n");
+        let mut scores = Vec::new();
+        if let Some(accuracy) = metrics.accuracy {
+            scores.push(vec!["accuracy".to_string(), percent(accuracy)]);
+        }
+        if let Some(ci) = &metrics.accuracy_ci {
+            scores.push(vec![
+                format!("accuracy 95% CI ({} resamples)", ci.iterations),
+                format!("{} - {}", percent(ci.lower), percent(ci.upper)),
+            ]);
+        }
+        if let Some(macro_f1) = metrics.macro_f1 {
+            scores.push(vec!["macro F1".to_string(), format!("{:.3}", macro_f1)]);
+        }
+        if let Some(mcc) = metrics.mcc {
+            scores.push(vec!["Matthews correlation".to_string(), format!("{:.3}", mcc)]);
+        }
+        if let Some(auc) = metrics.roc_auc.as_ref().and_then(|auc| auc.macro_avg) {
+            scores.push(vec!["ROC AUC (macro, one-vs-rest)".to_string(), format!("{:.3}", auc)]);
+        }
+        if let Some(baselines) = &metrics.baselines {
+            scores.push(vec![
+                format!("baseline: always class {}", baselines.majority_class),
+                percent(baselines.majority_accuracy),
+            ]);
+            scores.push(vec![
+                "baseline: guess at training frequencies".to_string(),
+                percent(baselines.stratified_accuracy),
+            ]);
+        }
+        table(&mut out, &strings(&["metric", "value"]), &scores);
+
+        if let Some(benchmarks) = &metrics.benchmarks {
+            out.push_str("### Rule benchmarks\n\n");
+            let _ = writeln!(
+                out,
+                "Momentum predicts the class of the prior year's price change, contrarian the opposite end of the \
+                 scale. Scored with the model on the {} test rows with a prior-year price change; {} without one \
+                 are excluded.\n",
+                benchmarks.n_rows, benchmarks.excluded
+            );
+            let rows: Vec<Vec<String>> = std::iter::once(&benchmarks.model)
+                .chain(&benchmarks.rules)
+                .map(|score| vec![score.rule.clone(), percent(score.accuracy), format!("{:.3}", score.macro_f1)])
+                .collect();
+            table(&mut out, &strings(&["predictor", "accuracy", "macro F1"]), &rows);
+        }
+
+        if let Some(report) = &metrics.classification {
+            out.push_str("### Per-class scores\n\n");
+            out.push_str("Macro averages weigh every class equally; micro averages weigh every row, ");
+            out.push_str("so they equal the accuracy.\n\n");
+            let score = |precision: f64, recall: f64, f1: f64| {
+                vec![format!("{:.3}", precision), format!("{:.3}", recall), format!("{:.3}", f1)]
+            };
+            let mut rows: Vec<Vec<String>> = report
+                .per_class
+                .iter()
+                .map(|class| {
+                    let mut row = vec![class.class.to_string()];
+                    row.extend(score(class.precision, class.recall, class.f1));
+                    row.push(class.support.to_string());
+                    row
+                })
+                .collect();
+            for (name, avg) in [("macro avg", &report.macro_avg), ("micro avg", &report.micro_avg)] {
+                let mut row = vec![name.to_string()];
+                row.extend(score(avg.precision, avg.recall, avg.f1));
+                row.push(report.per_class.iter().map(|class| class.support).sum::<usize>().to_string());
+                rows.push(row);
+            }
+            table(&mut out, &strings(&["class", "precision", "recall", "F1", "support"]), &rows);
+        }
+
+        if let Some(matrix) = &metrics.confusion_matrix {
+            out.push_str("### Confusion matrix\n\nRows are true classes, columns predicted classes.\n\n");
+            let mut header = vec!["true \\ predicted".to_string()];
+            header.extend((0..matrix.len()).map(|class| class.to_string()));
+            let rows: Vec<Vec<String>> = matrix
+                .iter()
+                .enumerate()
+                .map(|(class, counts)| {
+                    let mut row = vec![class.to_string()];
+                    row.extend(counts.iter().map(|count| count.to_string()));
+                    row
+                })
+                .collect();
+            table(&mut out, &header, &rows);
+        }
+
+        out.push_str("## Feature importances\n\n");
+        match &metrics.importances {
+            Some(importances) => {
+                out.push_str("Test accuracy lost when the feature's values are shuffled.\n\n");
+                let rows: Vec<Vec<String>> = importances
+                    .iter()
+                    .map(|item| vec![item.feature.clone(), format!("{:+.2}%", item.importance * 100.0)])
+                    .collect();
+                table(&mut out, &strings(&["feature", "importance"]), &rows);
+            }
+            None => out.push_str("Not available for this model.\n\n"),
+        }
+
+        if let Some(by_year) = &metrics.by_year {
+            out.push_str("## Accuracy by year\n\n");
+            let rows: Vec<Vec<String>> = by_year
+                .iter()
+                .map(|score| {
+                    let classes: Vec<String> = score.class_counts.iter().map(|count| count.to_string()).collect();
+                    vec![
+                        score.year.to_string(),
+                        score.count.to_string(),
+                        percent(score.accuracy),
+                        format!("{:.3}", score.macro_f1),
+                        classes.join(" / "),
+                    ]
+                })
+                .collect();
+            table(&mut out, &strings(&["year", "rows", "accuracy", "macro F1", "rows per class"]), &rows);
+        }
+        out
+    }
+
+    pub fn write(&self, path: &str) -> std::io::Result<()> {
+        std::fs::write(path, self.to_markdown())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ablation::permutation_importance;
+    use crate::metrics::summarize_dataset;
+    use crate::pipeline::{Pipeline, Split};
+    use crate::stock_data::undefined_ratio_counts;
+    use crate::synthetic::SyntheticConfig;
+
+    #[test]
+    fn test_report_sections_and_numbers() {
+        let stock_data = SyntheticConfig {
+            n_tickers: 30,
+            n_years: 5,
+            seed: 4,
+            ..Default::default()
+        }
+        .generate()
+        .stock_data();
+        let pipeline = Pipeline::builder()
+            .stock_data(stock_data.clone())
+            .split(Split::ByYear { cutoff: 2020 })
+            .seed(4)
+            .build()
+            .unwrap();
+        let dataset = pipeline.dataset(&stock_data);
+        let (train, test, _) = pipeline.split(&dataset).unwrap();
+        let mut result = pipeline.evaluate(train, test).unwrap();
+        let model = result.model.as_ref().unwrap();
+        result.metrics.importances = Some(permutation_importance(model, &result.test, 4).unwrap());
+        let report = RunReport {
+            settings: pipeline.settings(),
+            dataset: summarize_dataset(&dataset, pipeline.n_classes()),
+            undefined_ratios: undefined_ratio_counts(&stock_data),
+            metrics: result.metrics.clone(),
+        };
+        let markdown = report.to_markdown();
+
+        for section in [
+            "## Configuration",
+            "## Dataset",
+            "## Class distribution",
+            "## Model metrics",
+            "### Rule benchmarks",
+            "### Per-class scores",
+            "### Confusion matrix",
+            "## Feature importances",
+            "## Accuracy by year",
+        ] {
+            assert!(markdown.contains(section), "missing {}", section);
+        }
+        assert!(markdown.contains("| `seed` | 4 |"));
+        assert!(markdown.contains("| `split` | by year, train through 2020 |"));
+        let accuracy = percent(result.metrics.accuracy.unwrap());
+        assert!(markdown.contains(&format!("| accuracy | {} |", accuracy)));
+        let mcc = result.metrics.mcc.unwrap();
+        assert!(markdown.contains(&format!("| Matthews correlation | {:.3} |", mcc)));
+        let by_year = result.metrics.by_year.as_ref().unwrap();
+        assert!(by_year.iter().all(|score| score.year > 2020));
+        for score in by_year {
+            let accuracy = percent(score.accuracy);
+            let row = format!("| {} | {} | {} | {:.3} |", score.year, score.count, accuracy, score.macro_f1);
+            assert!(markdown.contains(&row));
+        }
+        let classification = result.metrics.classification.as_ref().unwrap();
+        let micro = &classification.micro_avg;
+        assert!(markdown.contains(&format!("| micro avg | {:.3} | {:.3} |", micro.precision, micro.recall)));
+        let matrix = result.metrics.confusion_matrix.as_ref().unwrap();
+        assert_eq!(matrix.iter().flatten().sum::<usize>(), result.test.len());
+        assert_eq!(markdown, report.to_markdown());
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use clap::Args;
+use crate::stock_data::StockData;
+
+/// Thresholds for fundamentals that are almost certainly data errors.
+#[derive(Debug, Clone, Args)]
+pub struct SanityRules {
+    /// Records with assets at or below this are rejected
+    #[arg(long, default_value_t = 0.0)]
+    pub min_assets: f64,
+    /// Records with revenue below this are rejected
+    #[arg(long, default_value_t = 0.0)]
+    pub min_revenue: f64,
+    /// Records with |cash| above this multiple of assets are rejected
+    #[arg(long, default_value_t = 10.0)]
+    pub max_cash_to_assets: f64,
+    /// Records with |equity| above this multiple of assets are rejected
+    #[arg(long, default_value_t = 10.0)]
+    pub max_equity_to_assets: f64,
+}
+
+impl Default for SanityRules {
+    fn default() -> Self {
+        SanityRules {
+            min_assets: 0.0,
+            min_revenue: 0.0,
+            max_cash_to_assets: 10.0,
+            max_equity_to_assets: 10.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    pub ticker: String,
+    pub year: u32,
+    pub rule: &'static str,
+    pub value: f64,
+}
+
+impl SanityRules {
+    /// First rule the record violates, with the offending value.
+    pub fn check(&self, record: &StockData) -> Option<(&'static str, f64)> {
+        if record.assets <= self.min_assets {
+            Some(("assets > min_assets", record.assets))
+        } else if record.revenue < self.min_revenue {
+            Some(("revenue >= min_revenue", record.revenue))
+        } else if record.cash.abs() > record.assets * self.max_cash_to_assets {
+            Some(("|cash| <= assets * max_cash_to_assets", record.cash))
+        } else if record.equity.abs() > record.assets * self.max_equity_to_assets {
+            Some(("|equity| <= assets * max_equity_to_assets", record.equity))
+        } else {
+            None
+        }
+    }
+}
+
+/// Marks every record violating a rule as excluded, so `prepare_dataset` never
+/// builds a feature row from it (or from the year after it, whose deltas it
+/// feeds). Returns the rejections sorted by ticker and year.
+pub fn apply_sanity_filters(
+    stock_data: &mut HashMap<String, Vec<StockData>>,
+    rules: &SanityRules,
+) -> Vec<Rejection> {
+    let mut rejections = Vec::new();
+    for records in stock_data.values_mut() {
+        for record in records.iter_mut() {
+            if let Some((rule, value)) = rules.check(record) {
+                record.excluded = true;
+                rejections.push(Rejection {
+                    ticker: record.ticker.clone(),
+                    year: record.year,
+                    rule,
+                    value,
+                });
+            }
+        }
+    }
+    rejections.sort_by(|a, b| a.ticker.cmp(&b.ticker).then(a.year.cmp(&b.year)));
+    rejections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::prepare_dataset;
+    use crate::synthetic::ticker_records;
+
+    fn record(year: u32, assets: f64, cash: f64, equity: f64, revenue: f64) -> StockData {
+        ticker_records("AAA", &[(year, [assets, cash, equity, 1.0, revenue], 0.0)]).remove(0)
+    }
+
+    #[test]
+    fn test_each_rule() {
+        let rules = SanityRules::default();
+        assert_eq!(rules.check(&record(2020, 100.0, 10.0, 50.0, 20.0)), None);
+        assert_eq!(rules.check(&record(2020, -5.0, 10.0, 50.0, 20.0)).unwrap().0, "assets > min_assets");
+        assert_eq!(rules.check(&record(2020, 100.0, 10.0, 50.0, -1.0)).unwrap().0, "revenue >= min_revenue");
+        assert_eq!(
+            rules.check(&record(2020, 100.0, -1001.0, 50.0, 20.0)),
+            Some(("|cash| <= assets * max_cash_to_assets", -1001.0))
+        );
+        assert_eq!(
+            rules.check(&record(2020, 100.0, 10.0, 1001.0, 20.0)),
+            Some(("|equity| <= assets * max_equity_to_assets", 1001.0))
+        );
+
+        let loose = SanityRules {
+            max_equity_to_assets: 20.0,
+            ..Default::default()
+        };
+        assert_eq!(loose.check(&record(2020, 100.0, 10.0, 1001.0, 20.0)), None);
+    }
+
+    #[test]
+    fn test_flagged_rows_never_reach_feature_matrix() {
+        let mut stock_data = HashMap::new();
+        stock_data.insert(
+            "AAA".to_string(),
+            ticker_records(
+                "AAA",
+                &[
+                    (2018, [100.0, 10.0, 50.0, 1.0, 20.0], 0.0),
+                    (2019, [110.0, 10.0, 50.0, 1.0, 22.0], 0.0),
+                    (2020, [120.0, 10.0, 50.0, 1.0, 24.0], 0.0),
+                    (2021, [0.0, 10.0, 50.0, 1.0, 26.0], 0.0),
+                    (2022, [140.0, 10.0, 50.0, 1.0, 28.0], 0.0),
+                ],
+            ),
+        );
+        assert_eq!(prepare_dataset(&stock_data).len(), 3);
+
+        let rejections = apply_sanity_filters(&mut stock_data, &SanityRules::default());
+        assert_eq!(
+            rejections,
+            vec![Rejection {
+                ticker: "AAA".to_string(),
+                year: 2021,
+                rule: "assets > min_assets",
+                value: 0.0,
+            }]
+        );
+        // 2021 is rejected and 2022's deltas are computed from it; only 2020 survives
+        let dataset = prepare_dataset(&stock_data);
+        assert_eq!(dataset.len(), 1);
+    }
+}
@@ -0,0 +1,69 @@
+/// Pearson correlation of two columns; `None` when either is constant.
+pub fn pearson(a: &[f64], b: &[f64]) -> Option<f64> {
+    let n = a.len() as f64;
+    let (mean_a, mean_b) = (a.iter().sum::<f64>() / n, b.iter().sum::<f64>() / n);
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a).powi(2);
+        var_b += (y - mean_b).powi(2);
+    }
+    (var_a > 0.0 && var_b > 0.0).then(|| cov / (var_a * var_b).sqrt())
+}
+
+/// Indices of the columns kept when, walking the columns in order, each one is
+/// dropped if its absolute correlation with an already kept column exceeds `threshold`.
+/// `values` holds the rows one after another, `n_columns` values each.
+pub fn uncorrelated_columns(values: &[f64], n_columns: usize, threshold: f64) -> Vec<usize> {
+    let columns: Vec<Vec<f64>> =
+        (0..n_columns).map(|j| values.iter().skip(j).step_by(n_columns).copied().collect()).collect();
+    let mut kept: Vec<usize> = Vec::new();
+    for j in 0..n_columns {
+        let redundant = kept
+            .iter()
+            .any(|&k| pearson(&columns[k], &columns[j]).is_some_and(|r| r.abs() > threshold));
+        if !redundant {
+            kept.push(j);
+        }
+    }
+    kept
+}
+
+/// Drops one of each pair of features whose absolute Pearson correlation exceeds
+/// `threshold`, keeping the earlier column. `values` is row-major with one column
+/// per name; returns the reduced values, still row-major, and the surviving names.
+/// Without names there are no columns, and nothing is kept.
+pub fn select_features(values: &[f64], names: &[String], threshold: f64) -> (Vec<f64>, Vec<String>) {
+    if names.is_empty() {
+        return (Vec::new(), Vec::new());
+    }
+    let kept = uncorrelated_columns(values, names.len(), threshold);
+    let reduced = values.chunks_exact(names.len()).flat_map(|row| kept.iter().map(|&j| row[j])).collect();
+    (reduced, kept.iter().map(|&j| names[j].clone()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_near_duplicate_column_is_dropped() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let values: Vec<f64> = (0..100)
+            .flat_map(|_| {
+                let a: f64 = rng.gen_range(-1.0..1.0);
+                [a, rng.gen_range(-1.0..1.0), -2.0 * a + rng.gen_range(-0.01..0.01), 5.0]
+            })
+            .collect();
+        let names: Vec<String> = ["a", "b", "minus_a", "constant"].iter().map(|n| n.to_string()).collect();
+
+        let (reduced, kept) = select_features(&values, &names, 0.95);
+        assert_eq!(kept, vec!["a", "b", "constant"]);
+        assert_eq!(reduced.len(), 300);
+        assert_eq!(reduced[..3], [values[0], values[1], 5.0]);
+        assert_eq!(select_features(&values, &names, 1.0).1, names);
+        assert_eq!(select_features(&[], &[], 0.95), (Vec::new(), Vec::new()));
+    }
+}
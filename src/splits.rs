@@ -0,0 +1,130 @@
+//! Share-split adjustment of the price files. A 2:1 split halves the quoted
+//! price overnight, which the yearly change would read as a 50% fall; every
+//! price dated before a split is divided by its ratio instead, so the series is
+//! in today's shares throughout. Dividends are per share as well, so those paid
+//! before a split are divided the same way before total returns add them.
+use std::collections::HashMap;
+use crate::stock_data::{open_csv, StockDataError};
+use crate::tickers::canonical_ticker;
+
+/// Split dates (`YYYY-MM-DD`) and ratios per canonical ticker, each ticker's in date order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Splits {
+    by_ticker: HashMap<String, Vec<(String, f64)>>,
+}
+
+// `2`, `2:1` or `3/2`: new shares per old share
+fn parse_ratio(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let ratio = match value.split_once([':', '/']) {
+        Some((new, old)) => new.trim().parse::<f64>().ok()? / old.trim().parse::<f64>().ok()?,
+        None => value.parse().ok()?,
+    };
+    (ratio.is_finite() && ratio > 0.0).then_some(ratio)
+}
+
+/// Reads a `ticker,date,split_ratio` file. The ratio is new shares per old
+/// share, as `2`, `2:1` or `3/2`; a reverse split is below one.
+pub fn read_splits(path: &str) -> Result<Splits, StockDataError> {
+    let mut reader = open_csv(path)?;
+    let mut splits = Splits::default();
+    for result in reader.records() {
+        let record = result.map_err(|source| StockDataError::Csv {
+            path: path.to_string(),
+            source,
+        })?;
+        let ticker = canonical_ticker(record.get(0).unwrap_or(""));
+        let date = record.get(1).and_then(|date| date.trim().get(..10)).map(str::to_string);
+        let ratio = record.get(2).and_then(parse_ratio);
+        match (date, ratio) {
+            (Some(date), Some(ratio)) if !ticker.is_empty() => {
+                splits.by_ticker.entry(ticker).or_default().push((date, ratio));
+            }
+            _ => {
+                let fields: Vec<&str> = record.iter().collect();
+                return Err(StockDataError::ColumnType {
+                    path: path.to_string(),
+                    column: "split_ratio".to_string(),
+                    message: format!("`{}` is not a ticker, date and positive split ratio", fields.join(",")),
+                });
+            }
+        }
+    }
+    for dates in splits.by_ticker.values_mut() {
+        dates.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+    Ok(splits)
+}
+
+impl Splits {
+    pub fn is_empty(&self) -> bool {
+        self.by_ticker.is_empty()
+    }
+
+    /// What a price or per-share amount of `ticker` dated `date` is divided by:
+    /// the product of the ratios of the ticker's splits after that day. The
+    /// ticker is matched in canonical form, so `brk.b` finds the splits of `BRK-B`.
+    pub fn factor(&self, ticker: &str, date: &str) -> f64 {
+        let day = date.trim().get(..10).unwrap_or(date);
+        let Some(splits) = self.by_ticker.get(&canonical_ticker(ticker)) else {
+            return 1.0;
+        };
+        splits.iter().filter(|(split, _)| day < split.as_str()).map(|(_, ratio)| ratio).product()
+    }
+
+    /// Every split as ticker, date and ratio, sorted.
+    pub fn listed(&self) -> Vec<(String, String, f64)> {
+        let mut listed: Vec<(String, String, f64)> = self
+            .by_ticker
+            .iter()
+            .flat_map(|(ticker, splits)| {
+                splits.iter().map(move |(date, ratio)| (ticker.clone(), date.clone(), *ratio))
+            })
+            .collect();
+        listed.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        listed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stock_data::{load_price_files, LoadOptions};
+    use crate::test_util::write_fixture;
+
+    #[test]
+    fn test_two_for_one_split_keeps_the_real_change() {
+        // AAA splits 2:1 on 2021-07-01: 100 before is 50 in today's shares, so
+        // the 55 after is a 10% rise, not a 45% fall. BBB never splits.
+        let prices = write_fixture(
+            "split_prices.csv",
+            ",Date,AAA,BBB\n0,2020-01-15,80,80\n1,2020-12-15,100,100\n2,2021-01-15,100,100\n\
+             3,2021-06-30,100,100\n4,2021-07-01,50,100\n5,2021-12-15,55,110\n",
+        );
+        // The file writes the ticker as `aaa`, which joins to the prices' `AAA` in canonical form
+        let splits = write_fixture("splits.csv", "ticker,date,split_ratio\naaa,2021-07-01,2:1\n");
+        let (raw, _, unadjusted) = load_price_files(&[&prices], &LoadOptions::default()).unwrap();
+        assert_eq!(raw["AAA"][&2021], -45.0);
+        assert!(unadjusted.is_empty());
+
+        let options = LoadOptions { split_file: Some(splits.clone()), ..Default::default() };
+        let (adjusted, _, split_adjusted) = load_price_files(&[&prices], &options).unwrap();
+        assert_eq!(split_adjusted.into_iter().collect::<Vec<_>>(), ["AAA"]);
+        assert!((adjusted["AAA"][&2021] - 10.0).abs() < 1e-9);
+        assert_eq!(adjusted["AAA"][&2020], 25.0);
+        assert_eq!(adjusted["BBB"], raw["BBB"]);
+
+        // A dividend of 2 before the split is 1 per share today: (55 + 1 - 50) / 50
+        let dividends = write_fixture("split_dividends.csv", "ticker,ex_date,amount\nAAA,2021-03-10,2\n");
+        let total = LoadOptions { dividend_file: Some(dividends), ..options };
+        let (returns, _, _) = load_price_files(&[&prices], &total).unwrap();
+        assert!((returns["AAA"][&2021] - 12.0).abs() < 1e-9);
+
+        let splits = read_splits(&splits).unwrap();
+        assert_eq!(splits.listed(), [("AAA".to_string(), "2021-07-01".to_string(), 2.0)]);
+        assert_eq!(splits.factor("AAA", "2021-06-30 16:00"), 2.0);
+        assert_eq!(splits.factor("AAA", "2021-07-01"), 1.0);
+        let bad = write_fixture("splits_bad.csv", "ticker,date,split_ratio\nAAA,2021-07-01,0\n");
+        assert!(matches!(read_splits(&bad), Err(StockDataError::ColumnType { .. })));
+    }
+}
@@ -0,0 +1,207 @@
+//! Input backend for a SQLite database holding the same data as the CSV files,
+//! in tables `fundamentals(ticker, year, metric, value)` and `prices(ticker, date, close)`.
+use std::collections::HashMap;
+use rusqlite::{Connection, OpenFlags};
+use crate::stock_data::{
+    aggregate_price_changes, combine_stock_data, LoadOptions, LoadReport, MonthlyPrices, StockData, StockDataError,
+    YearlyValues, CASH_FLOW_METRICS, METRICS,
+};
+
+fn sqlite_error(path: &str) -> impl Fn(rusqlite::Error) -> StockDataError + '_ {
+    move |source| StockDataError::Sqlite {
+        path: path.to_string(),
+        source,
+    }
+}
+
+fn require_columns(conn: &Connection, table: &str, columns: &[&str]) -> Result<(), StockDataError> {
+    let mut statement = conn
+        .prepare(&format!("PRAGMA table_info({})", table))
+        .map_err(sqlite_error(table))?;
+    let names: Vec<String> = statement
+        .query_map([], |row| row.get(1))
+        .and_then(|rows| rows.collect())
+        .map_err(sqlite_error(table))?;
+    if names.is_empty() {
+        return Err(StockDataError::MissingTable { table: table.to_string() });
+    }
+    for column in columns {
+        if !names.iter().any(|name| name == column) {
+            return Err(StockDataError::MissingColumn {
+                path: format!("table {}", table),
+                column: column.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Metric -> ticker -> year -> value, the shape `read_csv` produces per file.
+pub fn read_fundamentals(
+    conn: &Connection,
+) -> Result<HashMap<String, YearlyValues>, StockDataError> {
+    require_columns(conn, "fundamentals", &["ticker", "year", "metric", "value"])?;
+    let mut statement = conn
+        .prepare("SELECT ticker, year, metric, value FROM fundamentals WHERE value IS NOT NULL")
+        .map_err(sqlite_error("fundamentals"))?;
+    let rows = statement
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?, row.get::<_, String>(2)?, row.get::<_, f64>(3)?))
+        })
+        .map_err(sqlite_error("fundamentals"))?;
+
+    let mut data: HashMap<String, YearlyValues> = HashMap::new();
+    for row in rows {
+        let (ticker, year, metric, value) = row.map_err(sqlite_error("fundamentals"))?;
+        data.entry(metric).or_default().entry(ticker).or_default().insert(year, value);
+    }
+    Ok(data)
+}
+
+/// Same aggregation as `calculate_price_changes`, from the `prices` table.
+pub fn read_price_changes(conn: &Connection) -> Result<HashMap<String, HashMap<u32, f64>>, StockDataError> {
+    require_columns(conn, "prices", &["ticker", "date", "close"])?;
+    let mut statement = conn
+        .prepare("SELECT ticker, date, close FROM prices WHERE close IS NOT NULL")
+        .map_err(sqlite_error("prices"))?;
+    let rows = statement
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, f64>(2)?)))
+        .map_err(sqlite_error("prices"))?;
+
+    let mut data: MonthlyPrices = HashMap::new();
+    for row in rows {
+        let (ticker, date, close) = row.map_err(sqlite_error("prices"))?;
+        if date.len() < 7 {
+            continue;
+        }
+        let year: u32 = date[..4].parse().unwrap_or(0);
+        let month: u32 = date[5..7].parse().unwrap_or(0);
+        data.entry(ticker).or_default().entry(year).or_default().push((month, close));
+    }
+    Ok(aggregate_price_changes(&data))
+}
+
+pub fn process_sqlite_connection(
+    conn: &Connection,
+    options: &LoadOptions,
+) -> Result<(HashMap<String, Vec<StockData>>, LoadReport), StockDataError> {
+    let price_changes = read_price_changes(conn)?;
+    let mut fundamentals = read_fundamentals(conn)?;
+
+    let mut unavailable = Vec::new();
+    let mut metrics = Vec::new();
+    for metric in METRICS {
+        match fundamentals.remove(metric) {
+            Some(values) => metrics.push(values),
+            None if options.skip_missing_files => {
+                eprintln!("warning: no `{}` rows in the fundamentals table; features using it are dropped", metric);
+                unavailable.push(metric.to_string());
+                metrics.push(HashMap::new());
+            }
+            None => {
+                return Err(StockDataError::EmptyDataset {
+                    path: format!("fundamentals table (metric `{}`)", metric),
+                })
+            }
+        }
+    }
+
+    let [ocf, capex] = CASH_FLOW_METRICS.map(|metric| fundamentals.remove(metric));
+    combine_stock_data(
+        [&metrics[0], &metrics[1], &metrics[2], &metrics[3], &metrics[4]],
+        ocf.as_ref().zip(capex.as_ref()).map(|(ocf, capex)| [ocf, capex]),
+        &unavailable,
+        &price_changes,
+        None,
+        options,
+    )
+}
+
+pub fn process_sqlite(
+    path: &str,
+    options: &LoadOptions,
+) -> Result<(HashMap<String, Vec<StockData>>, LoadReport), StockDataError> {
+    // Read-only, so a mistyped path is reported rather than created as an empty database
+    std::fs::metadata(path).map_err(|source| StockDataError::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(sqlite_error(path))?;
+    process_sqlite_connection(&conn, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{assert_same_as_csv, fixture_path};
+
+    fn fixture_database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE fundamentals (ticker TEXT, year INTEGER, metric TEXT, value REAL);
+             CREATE TABLE prices (ticker TEXT, date TEXT, close REAL);",
+        )
+        .unwrap();
+        let values = [
+            ("assets", [400.0, 300.0, 200.0]),
+            ("cash", [40.0, 45.0, 20.0]),
+            ("equity", [200.0, 120.0, 90.0]),
+            ("profit", [30.0, 20.0, 5.0]),
+            ("revenue", [100.0, 80.0, 50.0]),
+        ];
+        for (metric, by_year) in values {
+            for (year, value) in [2022, 2021, 2020].iter().zip(by_year) {
+                conn.execute(
+                    "INSERT INTO fundamentals VALUES ('AAA', ?1, ?2, ?3)",
+                    rusqlite::params![year, metric, value],
+                )
+                .unwrap();
+            }
+        }
+        for (date, close) in [("2021-01-04", 10.0), ("2021-12-30", 15.0), ("2022-02-01", 15.0), ("2022-11-30", 12.0)] {
+            conn.execute("INSERT INTO prices VALUES ('AAA', ?1, ?2)", rusqlite::params![date, close])
+                .unwrap();
+        }
+        conn
+    }
+
+    #[test]
+    fn test_sqlite_matches_csv() {
+        let (from_sqlite, _) = process_sqlite_connection(&fixture_database(), &LoadOptions::default()).unwrap();
+        assert_same_as_csv("sqlite", &from_sqlite);
+    }
+
+    #[test]
+    fn test_missing_table_and_column_errors() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE prices (ticker TEXT, day TEXT, close REAL);").unwrap();
+
+        let err = read_fundamentals(&conn).unwrap_err();
+        assert!(matches!(&err, StockDataError::MissingTable { table } if table == "fundamentals"), "{:?}", err);
+
+        let err = read_price_changes(&conn).unwrap_err();
+        assert!(
+            matches!(&err, StockDataError::MissingColumn { path, column } if path == "table prices" && column == "date"),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_database_file_is_opened_read_only() {
+        let missing = fixture_path("missing.sqlite");
+        let _ = std::fs::remove_file(&missing);
+        let missing = missing.to_str().unwrap();
+        let err = process_sqlite(missing, &LoadOptions::default()).unwrap_err();
+        assert!(matches!(err, StockDataError::Io { .. }), "{:?}", err);
+        assert!(!std::path::Path::new(missing).exists());
+
+        let path = fixture_path("read_only.sqlite");
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+        fixture_database().execute("VACUUM INTO ?1", [path]).unwrap();
+        let (from_file, _) = process_sqlite(path, &LoadOptions::default()).unwrap();
+        let (from_memory, _) = process_sqlite_connection(&fixture_database(), &LoadOptions::default()).unwrap();
+        assert_eq!(from_file["AAA"].len(), from_memory["AAA"].len());
+    }
+}
@@ -0,0 +1,160 @@
+//! Z-scores of the feature columns for `--standardize`. The means and standard
+//! deviations always come from the training rows, so test and forecast rows are
+//! scaled the way any new row would be. Per sector, a row is placed against its
+//! own sector's training rows, since a margin that is high in retail is low in
+//! software; rows of tickers without a sector, or of a sector with too few
+//! training rows, fall back to the statistics of all training rows.
+use std::collections::HashMap;
+use crate::dataset::{Dataset, RowId};
+use crate::metrics::ColumnStats;
+use crate::stock_data::{open_csv, StockDataError};
+use crate::tickers::canonical_ticker;
+
+/// Training rows a sector needs for statistics of its own.
+pub const MIN_GROUP_ROWS: usize = 2;
+
+/// How the feature columns are scaled before training.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Standardize {
+    /// Leave the values as computed
+    #[default]
+    Off,
+    /// Z-scores against all training rows
+    Global,
+    /// Z-scores against the training rows of the row's sector
+    PerSector,
+}
+
+/// Sector per ticker from a `ticker,sector` file, keyed by canonical ticker.
+pub fn read_sectors(path: &str) -> Result<HashMap<String, String>, StockDataError> {
+    let mut reader = open_csv(path)?;
+    let mut sectors = HashMap::new();
+    for result in reader.records() {
+        let record = result.map_err(|source| StockDataError::Csv {
+            path: path.to_string(),
+            source,
+        })?;
+        let ticker = canonical_ticker(record.get(0).unwrap_or(""));
+        let sector = record.get(1).unwrap_or("").trim();
+        if !ticker.is_empty() && !sector.is_empty() {
+            sectors.insert(ticker, sector.to_string());
+        }
+    }
+    Ok(sectors)
+}
+
+/// What `Scaler::fit` and `Scaler::apply` did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StandardizeReport {
+    pub groups: Vec<(String, usize)>, // sectors with statistics of their own and their training rows, by name
+    pub fallback_rows: usize,         // rows scaled with the statistics of all training rows instead
+}
+
+/// Column statistics of the training rows, overall and per sector.
+#[derive(Debug, Clone)]
+pub struct Scaler {
+    global: Vec<ColumnStats>,
+    groups: HashMap<String, (usize, Vec<ColumnStats>)>, // training rows and statistics per sector
+    sectors: HashMap<String, String>,
+}
+
+impl Scaler {
+    /// Statistics of `train`; per sector only under `Standardize::PerSector`.
+    pub fn fit(train: &Dataset, mode: Standardize, sectors: &HashMap<String, String>) -> Scaler {
+        let mut scaler = Scaler {
+            global: train.column_stats(),
+            groups: HashMap::new(),
+            sectors: HashMap::new(),
+        };
+        if mode != Standardize::PerSector {
+            return scaler;
+        }
+        scaler.sectors = sectors.clone();
+        let mut members: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (i, row) in train.rows.iter().enumerate() {
+            if let Some(sector) = scaler.sectors.get(&canonical_ticker(&row.ticker)) {
+                members.entry(sector.as_str()).or_default().push(i);
+            }
+        }
+        scaler.groups = members
+            .into_iter()
+            .filter(|(_, indices)| indices.len() >= MIN_GROUP_ROWS)
+            .map(|(sector, indices)| (sector.to_string(), (indices.len(), train.subset(&indices).column_stats())))
+            .collect();
+        scaler
+    }
+
+    /// Sectors with statistics of their own and their training rows, by name.
+    pub fn groups(&self) -> Vec<(String, usize)> {
+        let mut groups: Vec<(String, usize)> =
+            self.groups.iter().map(|(sector, (rows, _))| (sector.clone(), *rows)).collect();
+        groups.sort();
+        groups
+    }
+
+    /// Replaces `values` (laid out like `Dataset::values`, one row per entry of
+    /// `rows`) with their z-scores, and returns how many rows were scaled with
+    /// the overall statistics. A column without spread scales to 0.
+    pub fn apply(&self, values: &mut [f64], rows: &[RowId]) -> usize {
+        let n_features = self.global.len();
+        let mut fallback = 0;
+        for (row, id) in values.chunks_mut(n_features.max(1)).zip(rows) {
+            let group = self.sectors.get(&canonical_ticker(&id.ticker)).and_then(|sector| self.groups.get(sector));
+            fallback += usize::from(group.is_none());
+            let stats = group.map_or(&self.global, |(_, stats)| stats);
+            for (value, column) in row.iter_mut().zip(stats) {
+                *value = if column.std > 0.0 { (*value - column.mean) / column.std } else { 0.0 };
+            }
+        }
+        fallback
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_each_sector_scaled_against_its_own_group() {
+        // Retail margins around 0.03, software margins around 0.30, and one unlisted ticker
+        let row = |ticker: &str| RowId { ticker: ticker.to_string(), year: 2021, ..Default::default() };
+        let train = Dataset::from_rows(
+            vec!["profit_margin".to_string()],
+            &[vec![0.02], vec![0.04], vec![0.20], vec![0.40], vec![0.10]],
+            vec![0, 1, 0, 1, 0],
+            vec![row("WMT"), row("tgt"), row("MSFT"), row("ADBE"), row("XYZ")],
+        );
+        let sectors = HashMap::from([
+            ("WMT".to_string(), "Retail".to_string()),
+            ("TGT".to_string(), "Retail".to_string()),
+            ("MSFT".to_string(), "Software".to_string()),
+            ("ADBE".to_string(), "Software".to_string()),
+        ]);
+        let scaler = Scaler::fit(&train, Standardize::PerSector, &sectors);
+        assert_eq!(scaler.groups(), [("Retail".to_string(), 2), ("Software".to_string(), 2)]);
+
+        let mut values = train.values.clone();
+        let fallback = scaler.apply(&mut values, &train.rows);
+        // Retail: mean 0.03, std 0.01; software: mean 0.30, std 0.10
+        let expected = [-1.0, 1.0, -1.0, 1.0];
+        for (value, expected) in values.iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-9, "{:?}", values);
+        }
+        // XYZ has no sector: against all five rows, mean 0.152
+        let mean = 0.76 / 5.0;
+        let std = (train.values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / 5.0).sqrt();
+        assert!((values[4] - (0.10 - mean) / std).abs() < 1e-9);
+        assert_eq!(fallback, 1);
+
+        // A 0.05 margin is high for a retailer and low for a software company
+        let mut test = vec![0.05, 0.05];
+        scaler.apply(&mut test, &[row("WMT"), row("MSFT")]);
+        assert!((test[0] - 2.0).abs() < 1e-9 && (test[1] + 2.5).abs() < 1e-9, "{:?}", test);
+
+        let global = Scaler::fit(&train, Standardize::Global, &sectors);
+        let mut test = vec![0.05, 0.05];
+        assert_eq!(global.apply(&mut test, &[row("WMT"), row("MSFT")]), 2);
+        assert_eq!(test[0], test[1]);
+        assert!(global.groups().is_empty());
+    }
+}
@@ -1,8 +1,136 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::error::Error;
-use csv::ReaderBuilder;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use csv::{Reader, ReaderBuilder};
+use crate::currency::{convert_to_usd, read_currencies, read_fx_rates, MissingRate};
+use crate::splits::{read_splits, Splits};
 
 #[derive(Debug)]
+pub enum StockDataError {
+    Io { path: String, source: std::io::Error },
+    Csv { path: String, source: csv::Error },
+    MissingColumn { path: String, column: String },
+    EmptyDataset { path: String },
+    JoinFailure { reason: String },
+    MissingTable { table: String },
+    ColumnType { path: String, column: String, message: String },
+    MissingFxRate { ticker: String, currency: String, year: u32 },
+    #[cfg(feature = "sqlite")]
+    Sqlite { path: String, source: rusqlite::Error },
+    #[cfg(feature = "remote")]
+    Http { url: String, message: String },
+    #[cfg(feature = "parquet")]
+    Parquet { path: String, source: parquet::errors::ParquetError },
+    #[cfg(feature = "xlsx")]
+    Xlsx { path: String, source: calamine::XlsxError },
+}
+
+impl fmt::Display for StockDataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StockDataError::Io { path, source } => write!(f, "could not read {}: {}", path, source),
+            StockDataError::Csv { path, source } => write!(f, "malformed CSV in {}: {}", path, source),
+            StockDataError::MissingColumn { path, column } => {
+                write!(f, "{} has no `{}` column", path, column)
+            }
+            StockDataError::EmptyDataset { path } => write!(f, "{} contains no data rows", path),
+            StockDataError::JoinFailure { reason } => write!(f, "could not join data files: {}", reason),
+            StockDataError::MissingTable { table } => write!(f, "database has no `{}` table", table),
+            StockDataError::ColumnType { path, column, message } => {
+                write!(f, "unusable `{}` column in {}: {}", column, path, message)
+            }
+            StockDataError::MissingFxRate { ticker, currency, year } => {
+                write!(f, "no {} rate for {}, needed to convert {} to USD", currency, year, ticker)
+            }
+            #[cfg(feature = "sqlite")]
+            StockDataError::Sqlite { path, source } => write!(f, "SQLite error in {}: {}", path, source),
+            #[cfg(feature = "remote")]
+            StockDataError::Http { url, message } => write!(f, "request to {} failed: {}", url, message),
+            #[cfg(feature = "parquet")]
+            StockDataError::Parquet { path, source } => write!(f, "Parquet error in {}: {}", path, source),
+            #[cfg(feature = "xlsx")]
+            StockDataError::Xlsx { path, source } => write!(f, "Excel error in {}: {}", path, source),
+        }
+    }
+}
+
+impl Error for StockDataError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StockDataError::Io { source, .. } => Some(source),
+            StockDataError::Csv { source, .. } => Some(source),
+            #[cfg(feature = "sqlite")]
+            StockDataError::Sqlite { source, .. } => Some(source),
+            #[cfg(feature = "parquet")]
+            StockDataError::Parquet { source, .. } => Some(source),
+            #[cfg(feature = "xlsx")]
+            StockDataError::Xlsx { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+// Excel writes a byte-order mark before the header; skip it here rather than
+// relying on the csv crate so it can never end up inside a field.
+pub(crate) fn open_csv(file_path: &str) -> Result<Reader<BufReader<File>>, StockDataError> {
+    let io_error = |source| StockDataError::Io {
+        path: file_path.to_string(),
+        source,
+    };
+    let mut file = BufReader::new(File::open(file_path).map_err(io_error)?);
+    if file.fill_buf().map_err(io_error)?.starts_with(UTF8_BOM) {
+        file.consume(UTF8_BOM.len());
+    }
+    // Quoted fields may contain commas, doubled quotes and line breaks
+    Ok(ReaderBuilder::new().quoting(true).double_quote(true).from_reader(file))
+}
+
+/// Ticker as it is joined across files: surrounding whitespace and any stray
+/// byte-order marks (e.g. from files concatenated after export) removed.
+pub fn normalize_ticker(raw: &str) -> String {
+    raw.trim_matches(|c: char| c == '\u{feff}' || c.is_whitespace()).to_string()
+}
+
+// Whether `digits` is an integer part grouped in thousands, as in `1,234,567`
+fn thousands_grouped(digits: &str) -> bool {
+    let mut groups = digits.split(',');
+    let first = groups.next().unwrap_or("");
+    let all_digits = |group: &str| group.bytes().all(|b| b.is_ascii_digit());
+    (1..=3).contains(&first.len()) && all_digits(first) && groups.all(|group| group.len() == 3 && all_digits(group))
+}
+
+// Numbers exported with thousands separators arrive as quoted "1,234.5"; those
+// commas are dropped. Any other comma, such as the decimal comma of "1,5", is an
+// error naming the value rather than a number a thousand times too large.
+fn parse_number(value: &str, path: &str, column: &str) -> Result<f64, StockDataError> {
+    let value = value.trim();
+    if !value.contains(',') {
+        return Ok(value.parse().unwrap_or(0.0));
+    }
+    let unsigned = value.strip_prefix('-').unwrap_or(value);
+    let (whole, fraction) = unsigned.split_once('.').unwrap_or((unsigned, "0"));
+    if !thousands_grouped(whole) || fraction.is_empty() || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(StockDataError::ColumnType {
+            path: path.to_string(),
+            column: column.to_string(),
+            message: format!("`{}` is not a number; only thousands may be separated by commas", value),
+        });
+    }
+    Ok(value.replace(',', "").parse().unwrap_or(0.0))
+}
+
+fn csv_error(file_path: &str) -> impl Fn(csv::Error) -> StockDataError + '_ {
+    move |source| StockDataError::Csv {
+        path: file_path.to_string(),
+        source,
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
 pub struct StockData {
     pub ticker: String,
     pub year: u32,
@@ -12,64 +140,893 @@ pub struct StockData {
     pub profit: f64,
     pub revenue: f64,
     pub price_change: f64, // Yearly price change
-    pub profit_margin: f64, // Profit margin
-    pub roa: f64,           // Return on assets
+    pub price_volatility: Option<f64>, // Coefficient of variation of the year's monthly average prices
+    pub profit_margin: Option<f64>,    // Profit over revenue; None when undefined (see `Ratio`)
+    pub roa: Option<f64>,              // Return on assets
+    pub roe: Option<f64>,              // Return on equity
+    pub cash_to_assets: Option<f64>,   // Cash over assets
+    pub equity_to_assets: Option<f64>, // Equity over assets
+    pub cash_to_revenue: Option<f64>,  // Cash over revenue, a cash-burn proxy
     pub change_in_revenue: Option<f64>, // Change in revenue over the previous year
-    pub change_in_profit_margin: Option<f64>, // Change in profit margin over the previous year
-    pub change_in_roa: Option<f64>,           // Change in ROA over the previous year
+    pub change_in_profit_margin: Option<f64>, // Change in profit margin over the previous year; None if undefined
+    pub change_in_roa: Option<f64>,           // Change in ROA over the previous year; None likewise
+    pub change_in_roe: Option<f64>,           // Change in ROE over the previous year; None likewise
+    pub free_cash_flow: Option<f64>,          // Operating cash flow minus capex; None without cash-flow data
+    pub fcf_margin: Option<f64>,              // Free cash flow over revenue; None when undefined
+    pub change_in_fcf_margin: Option<f64>,    // Change in FCF margin over the previous year
+    pub gap_years: u32,           // Years since the previous record; above 1 when years are missing
+    pub changes_normalized: bool, // The changes above were divided by `gap_years` (`GapPolicy::Normalize`)
+    #[serde(with = "metric_list")]
+    pub unavailable: Vec<String>, // Metrics whose file could not be loaded
+    pub excluded: bool,           // Failed a sanity filter; kept for reporting but never used as a feature row
+    #[serde(default)]
+    pub split_adjusted: bool, // The ticker's prices were put in today's shares for a share split
+}
+
+// `unavailable` as one `;`-separated field, so records fit a CSV row
+mod metric_list {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(metrics: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&metrics.join(";"))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+        let joined = String::deserialize(deserializer)?;
+        Ok(joined.split(';').filter(|metric| !metric.is_empty()).map(String::from).collect())
+    }
+}
+
+// Ticker -> year -> value, as read from one metric file
+pub type YearlyValues = HashMap<String, HashMap<u32, f64>>;
+
+// Ticker -> year -> (month, price) observations
+pub type MonthlyPrices = HashMap<String, HashMap<u32, Vec<(u32, f64)>>>;
+
+// Order of the metric maps passed to `combine_stock_data`
+pub const METRICS: [&str; 5] = ["assets", "cash", "equity", "profit", "revenue"];
+
+// Optional metrics behind the free-cash-flow features, in the order `combine_stock_data` takes them
+pub const CASH_FLOW_METRICS: [&str; 2] = ["operating_cashflow", "capex"];
+
+// Marks records whose backend has no intra-year prices to compute a volatility from
+pub const PRICE_VOLATILITY: &str = "price_volatility";
+
+// Year of the first value column in files whose headers are not years
+pub const DEFAULT_BASE_YEAR: u32 = 2022;
+
+// Denominators closer to zero than this give no ratio, unless configured otherwise
+pub const DEFAULT_RATIO_EPSILON: f64 = 1e-6;
+
+/// Guarded division for the ratio fields. A ratio over a (near) zero or
+/// negative denominator is `None` instead of a made-up 0: ROE over negative
+/// equity, for one, turns a loss into a positive return.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ratio {
+    pub epsilon: f64,
+}
+
+impl Default for Ratio {
+    fn default() -> Self {
+        Ratio {
+            epsilon: DEFAULT_RATIO_EPSILON,
+        }
+    }
+}
+
+impl Ratio {
+    /// `numerator / denominator`, or `None` when the denominator is within
+    /// `epsilon` of zero or not a number.
+    pub fn of(self, numerator: f64, denominator: f64) -> Option<f64> {
+        (denominator.abs() >= self.epsilon).then(|| numerator / denominator)
+    }
+
+    /// Like `of`, and also `None` for a negative denominator. Every ratio the
+    /// records keep divides by revenue, assets or equity, which are
+    /// uninterpretable as denominators when negative.
+    pub fn of_positive(self, numerator: f64, denominator: f64) -> Option<f64> {
+        self.of(numerator, denominator).filter(|_| denominator > 0.0)
+    }
+}
+
+/// The ratio fields of `records`, as named in the run output.
+pub const RATIOS: [&str; 7] =
+    ["profit_margin", "roa", "roe", "cash_to_assets", "equity_to_assets", "cash_to_revenue", "fcf_margin"];
+
+/// Per `RATIOS` entry, the records whose ratio is undefined. Records without
+/// free cash flow are not counted for `fcf_margin`.
+pub fn undefined_ratio_counts(stock_data: &HashMap<String, Vec<StockData>>) -> Vec<(String, usize)> {
+    let mut counts = [0; RATIOS.len()];
+    for record in stock_data.values().flatten().filter(|record| !record.excluded) {
+        let undefined = [
+            record.profit_margin.is_none(),
+            record.roa.is_none(),
+            record.roe.is_none(),
+            record.cash_to_assets.is_none(),
+            record.equity_to_assets.is_none(),
+            record.cash_to_revenue.is_none(),
+            record.free_cash_flow.is_some() && record.fcf_margin.is_none(),
+        ];
+        for (count, undefined) in counts.iter_mut().zip(undefined) {
+            *count += usize::from(undefined);
+        }
+    }
+    RATIOS.iter().map(|name| name.to_string()).zip(counts).collect()
+}
+
+/// How the financial CSV files are laid out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputLayout {
+    /// One file per metric, `ticker,<year>,<year>...`
+    #[default]
+    Wide,
+    /// One row per ticker-year, either `ticker,year,metric,value` or
+    /// `ticker,year,<metric>,<metric>...`; one file can hold every metric
+    Long,
+}
+
+/// How year-over-year changes are computed when a ticker is missing the years in between.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum GapPolicy {
+    /// Leave the changes empty, so the record is not used as a feature row
+    #[default]
+    Skip,
+    /// Compute the change over the whole span and add the span as a `gap_years` feature
+    Annotate,
+    /// Divide the change over the span by the number of years elapsed
+    Normalize,
+}
+
+/// Which tickers to build records for when the financial files list different ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JoinPolicy {
+    /// Only tickers present in every loaded financial file
+    #[default]
+    Intersection,
+    /// Tickers of the assets file; metrics missing for them count as zero
+    AssetsDriven,
+    /// Tickers present in any file; metrics missing for them are NaN
+    Union,
+}
+
+/// Which prices to keep when several price files have the same ticker-month.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PriceConflict {
+    /// Keep the prices of the file listed first
+    #[default]
+    First,
+    /// Average the prices of every file
+    Average,
+}
+
+/// The price a year's change is measured from; it is always measured to the
+/// year's November-December average.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PriceReference {
+    /// The year's January-February average
+    #[default]
+    StartOfYear,
+    /// The previous year's November-December average, so the change runs across the year boundary
+    PriorYearEnd,
+    /// The year's average in this month (1-12), the same month for every ticker
+    Month(u32),
+}
+
+impl std::str::FromStr for PriceReference {
+    type Err = String;
+
+    /// `start-of-year`, `prior-year-end` or `month:<1-12>`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "start-of-year" => Ok(PriceReference::StartOfYear),
+            "prior-year-end" => Ok(PriceReference::PriorYearEnd),
+            _ => match value.strip_prefix("month:").and_then(|month| month.parse().ok()) {
+                Some(month @ 1..=12) => Ok(PriceReference::Month(month)),
+                _ => Err(format!(
+                    "`{}` is not a reference; use start-of-year, prior-year-end or month:<1-12>",
+                    value
+                )),
+            },
+        }
+    }
 }
 
-pub fn read_csv(file_path: &str) -> Result<HashMap<String, HashMap<u32, f64>>, Box<dyn Error>> {
-    let mut reader = ReaderBuilder::new().from_path(file_path)?;
+impl fmt::Display for PriceReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PriceReference::StartOfYear => write!(f, "start-of-year"),
+            PriceReference::PriorYearEnd => write!(f, "prior-year-end"),
+            PriceReference::Month(month) => write!(f, "month:{}", month),
+        }
+    }
+}
+
+/// Inclusive range of price years for `--year-range`; either end may be open.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct YearRange {
+    pub first: Option<u32>,
+    pub last: Option<u32>,
+}
+
+impl YearRange {
+    pub fn contains(&self, year: u32) -> bool {
+        self.first.is_none_or(|first| year >= first) && self.last.is_none_or(|last| year <= last)
+    }
+
+    pub fn is_unbounded(&self) -> bool {
+        self.first.is_none() && self.last.is_none()
+    }
+}
+
+impl std::str::FromStr for YearRange {
+    type Err = String;
+
+    /// `start..end`, both included; `2015..` and `..2022` leave one end open.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("`{}` is not a year range; use start..end, start.. or ..end", value);
+        let (first, last) = value.split_once("..").ok_or_else(invalid)?;
+        let year = |year: &str| match year.trim() {
+            "" => Ok(None),
+            year => year.parse().map(Some).map_err(|_| invalid()),
+        };
+        let range = YearRange { first: year(first)?, last: year(last)? };
+        match (range.first, range.last) {
+            (Some(first), Some(last)) if first > last => Err(format!("`{}` ends before it starts", value)),
+            _ => Ok(range),
+        }
+    }
+}
+
+impl fmt::Display for YearRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let year = |year: Option<u32>| year.map_or(String::new(), |year| year.to_string());
+        write!(f, "{}..{}", year(self.first), year(self.last))
+    }
+}
+
+/// What the price-change label measures.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReturnBasis {
+    /// The change in price alone
+    #[default]
+    Price,
+    /// The change in price plus the dividends paid during the year
+    Total,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// Warn and continue without a financial file that fails to load, instead of aborting
+    pub skip_missing_files: bool,
+    /// Per-metric year of the first value column, for files without year headers
+    pub base_years: HashMap<String, u32>,
+    /// What to do with changes that span missing years
+    pub gap_policy: GapPolicy,
+    /// Worksheet holding the data in `.xlsx` financial files; the first sheet when unset
+    pub sheet: Option<String>,
+    /// Years of price change a record's `price_change` covers, starting with its own year
+    pub horizon: u32,
+    /// Months with prices a year needs before its price volatility is computed
+    pub min_volatility_months: usize,
+    /// `ticker,ex_date,amount` file whose dividends turn the CSV and Parquet
+    /// backends' price changes into total returns; price-only when unset
+    pub dividend_file: Option<String>,
+    /// `ticker,date,split_ratio` file; the CSV and Parquet backends divide the
+    /// prices and dividends dated before each split by its ratio
+    pub split_file: Option<String>,
+    /// Which prices win when several price files cover the same ticker-month
+    pub price_conflict: PriceConflict,
+    /// Which tickers to keep when the financial files list different ones
+    pub join_policy: JoinPolicy,
+    /// The price the CSV and Parquet backends measure each year's change from
+    pub price_reference: PriceReference,
+    /// Price years the CSV and Parquet backends read; rows of other years are skipped while parsing
+    pub year_range: YearRange,
+    /// `ticker,currency` file; the financial files' values of tickers listed in
+    /// another currency than USD are converted with `fx_rates_file`
+    pub currency_file: Option<String>,
+    /// `currency,year,rate` file of USD per unit of each currency
+    pub fx_rates_file: Option<String>,
+    /// What to do with a ticker-year whose currency has no rate
+    pub missing_fx_rate: MissingRate,
+    /// Denominators closer to zero than this leave a ratio undefined
+    pub ratio_epsilon: f64,
+    /// Whether the CSV financial files are wide or long; long files are read
+    /// once however many metrics they hold
+    pub input_layout: InputLayout,
+}
+
+impl LoadOptions {
+    /// What the loaded price changes measure.
+    pub fn return_basis(&self) -> ReturnBasis {
+        match self.dividend_file {
+            Some(_) => ReturnBasis::Total,
+            None => ReturnBasis::Price,
+        }
+    }
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            skip_missing_files: false,
+            base_years: HashMap::new(),
+            gap_policy: GapPolicy::default(),
+            sheet: None,
+            horizon: 1,
+            min_volatility_months: 6,
+            dividend_file: None,
+            split_file: None,
+            price_conflict: PriceConflict::default(),
+            join_policy: JoinPolicy::default(),
+            price_reference: PriceReference::default(),
+            year_range: YearRange::default(),
+            currency_file: None,
+            fx_rates_file: None,
+            missing_fx_rate: MissingRate::default(),
+            ratio_epsilon: DEFAULT_RATIO_EPSILON,
+            input_layout: InputLayout::default(),
+        }
+    }
+}
+
+/// Percent change over the `horizon` years starting at `year`, compounding the
+/// yearly changes: +10% then +20% is +32%. `None` unless every year has a change.
+pub fn compound_price_change(changes: &HashMap<u32, f64>, year: u32, horizon: u32) -> Option<f64> {
+    (year..year + horizon)
+        .map(|y| changes.get(&y).map(|change| 1.0 + change / 100.0))
+        .product::<Option<f64>>()
+        .map(|growth| (growth - 1.0) * 100.0)
+}
+
+// `None` when the ticker is in the file but not for this year, so the year is
+// not joined. A ticker the file lacks gets `missing`; a file that was not
+// loaded gives zeros, as its features are dropped anyway.
+fn metric_value(metric: &YearlyValues, ticker: &str, year: u32, missing: f64) -> Option<f64> {
+    match metric.get(ticker) {
+        Some(years) => years.get(&year).copied(),
+        None if metric.is_empty() => Some(0.0),
+        None => Some(missing),
+    }
+}
+
+/// How the financial files' ticker sets were joined.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct JoinReport {
+    pub policy: JoinPolicy,
+    pub tickers_per_file: Vec<(String, usize)>, // per loaded metric, in `METRICS` order
+    pub joined: Vec<String>,                     // tickers records are built for, sorted
+    pub filled: Vec<(String, String)>,           // (ticker, metric) pairs a joined ticker lacks
+}
+
+impl JoinReport {
+    /// Whether the files listed different tickers, so the join left some out or filled some in.
+    pub fn is_mismatched(&self) -> bool {
+        self.tickers_per_file.iter().any(|(_, count)| *count != self.joined.len()) || !self.filled.is_empty()
+    }
+
+    /// The ticker count of each loaded file, as "assets 3, cash 2".
+    pub fn file_counts(&self) -> String {
+        let counts: Vec<String> =
+            self.tickers_per_file.iter().map(|(metric, count)| format!("{} {}", metric, count)).collect();
+        counts.join(", ")
+    }
+}
+
+/// Picks the tickers to build records for from the five `METRICS` maps under
+/// `policy`. Maps that are empty (files skipped under `skip_missing_files`) do
+/// not take part.
+pub fn join_tickers(metrics: [&YearlyValues; 5], policy: JoinPolicy) -> JoinReport {
+    let loaded: Vec<(&str, &YearlyValues)> =
+        METRICS.into_iter().zip(metrics).filter(|(_, values)| !values.is_empty()).collect();
+    let mut candidates: Vec<&String> = loaded.iter().flat_map(|(_, values)| values.keys()).collect();
+    candidates.sort();
+    candidates.dedup();
+    let in_all = |ticker: &String| loaded.iter().all(|(_, values)| values.contains_key(ticker));
+    let joined: Vec<String> = match policy {
+        JoinPolicy::Intersection => candidates.into_iter().filter(|ticker| in_all(ticker)).cloned().collect(),
+        JoinPolicy::AssetsDriven => match loaded.first() {
+            Some((_, driver)) => {
+                candidates.into_iter().filter(|ticker| driver.contains_key(*ticker)).cloned().collect()
+            }
+            None => Vec::new(),
+        },
+        JoinPolicy::Union => candidates.into_iter().cloned().collect(),
+    };
+    let filled = joined
+        .iter()
+        .flat_map(|ticker| {
+            loaded
+                .iter()
+                .filter(|(_, values)| !values.contains_key(ticker))
+                .map(|(metric, _)| (ticker.clone(), metric.to_string()))
+        })
+        .collect();
+    JoinReport {
+        policy,
+        tickers_per_file: loaded.iter().map(|(metric, values)| (metric.to_string(), values.len())).collect(),
+        joined,
+        filled,
+    }
+}
+
+pub fn read_csv(file_path: &str) -> Result<HashMap<String, HashMap<u32, f64>>, StockDataError> {
+    read_csv_with_base_year(file_path, DEFAULT_BASE_YEAR)
+}
+
+/// Year of the `i`th value column (0 for the one after the ticker) of a wide
+/// file whose header gives no year: `base_year` counted back by `i`. Counting
+/// back past year 0 is an error naming the column.
+pub fn counted_year(path: &str, header: &str, base_year: u32, i: usize) -> Result<u32, StockDataError> {
+    u32::try_from(i).ok().and_then(|i| base_year.checked_sub(i)).ok_or_else(|| StockDataError::ColumnType {
+        path: path.to_string(),
+        column: header.to_string(),
+        message: format!("column {} counts back from base year {} past year 0", i + 2, base_year),
+    })
+}
+
+/// Reads a wide `ticker,<year>,<year>...` file. Each column's year is taken from
+/// its header; columns whose header is not a year count back from `base_year`.
+pub fn read_csv_with_base_year(
+    file_path: &str,
+    base_year: u32,
+) -> Result<HashMap<String, HashMap<u32, f64>>, StockDataError> {
+    let mut reader = open_csv(file_path)?;
+    let headers = reader.headers().map_err(csv_error(file_path))?.clone();
+    let column_years: Vec<u32> = headers
+        .iter()
+        .skip(1)
+        .enumerate()
+        .map(|(i, header)| match header.trim().parse() {
+            Ok(year) => Ok(year),
+            Err(_) => counted_year(file_path, header, base_year, i),
+        })
+        .collect::<Result<_, _>>()?;
     let mut data: HashMap<String, HashMap<u32, f64>> = HashMap::new();
 
     for result in reader.records() {
-        let record = result?;
-        let ticker = record.get(0).unwrap_or("").to_string();
+        let record = result.map_err(csv_error(file_path))?;
+        let ticker = normalize_ticker(record.get(0).unwrap_or(""));
         if ticker.is_empty() {
             continue;
         }
         let mut years = HashMap::new();
         for (i, value) in record.iter().skip(1).enumerate() {
-            let year = 2022 - i as u32;
-            let value: f64 = value.parse().unwrap_or(0.0);
-            years.insert(year, value);
+            let year = match column_years.get(i) {
+                Some(&year) => year,
+                None => counted_year(file_path, "", base_year, i)?,
+            };
+            years.insert(year, parse_number(value, file_path, headers.get(i + 1).unwrap_or(""))?);
         }
         data.insert(ticker, years);
     }
+    if data.is_empty() {
+        return Err(StockDataError::EmptyDataset { path: file_path.to_string() });
+    }
+    Ok(data)
+}
+
+/// Reads a long (tidy) file into one `ticker -> year -> value` map per metric,
+/// keyed by the lower-cased metric name. A file with `metric` and `value`
+/// columns has one row per ticker, year and metric; otherwise every column
+/// besides `ticker` and `year` is a metric. Years written as dates such as
+/// `2021-12-31` count as their calendar year.
+pub fn read_csv_long(file_path: &str) -> Result<HashMap<String, YearlyValues>, StockDataError> {
+    let mut reader = open_csv(file_path)?;
+    let headers = reader.headers().map_err(csv_error(file_path))?;
+    let headers: Vec<String> = headers.iter().map(|header| header.trim().to_ascii_lowercase()).collect();
+    let column = |name: &str| headers.iter().position(|header| header == name);
+    let missing = |name: &str| StockDataError::MissingColumn {
+        path: file_path.to_string(),
+        column: name.to_string(),
+    };
+    let ticker_column = column("ticker").ok_or_else(|| missing("ticker"))?;
+    let year_column = column("year").ok_or_else(|| missing("year"))?;
+    // (column of the metric name or None, column of the value, metric name when fixed by the header)
+    let value_columns: Vec<(Option<usize>, usize, &str)> = match (column("metric"), column("value")) {
+        (Some(metric), Some(value)) => vec![(Some(metric), value, "")],
+        _ => headers
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != ticker_column && *i != year_column)
+            .map(|(i, header)| (None, i, header.as_str()))
+            .collect(),
+    };
+    let mut data: HashMap<String, YearlyValues> = HashMap::new();
+
+    for result in reader.records() {
+        let record = result.map_err(csv_error(file_path))?;
+        let ticker = normalize_ticker(record.get(ticker_column).unwrap_or(""));
+        if ticker.is_empty() {
+            continue;
+        }
+        let raw_year = record.get(year_column).unwrap_or("").trim();
+        let year: u32 = raw_year.get(..4).unwrap_or(raw_year).parse().map_err(|_| StockDataError::ColumnType {
+            path: file_path.to_string(),
+            column: "year".to_string(),
+            message: format!("`{}` of {} is not a year", raw_year, ticker),
+        })?;
+        for &(metric_column, value_column, header) in &value_columns {
+            let metric = match metric_column {
+                Some(i) => record.get(i).unwrap_or("").trim().to_ascii_lowercase(),
+                None => header.to_string(),
+            };
+            let value = parse_number(record.get(value_column).unwrap_or(""), file_path, &metric)?;
+            let years = data.entry(metric.clone()).or_default().entry(ticker.clone()).or_default();
+            if years.insert(year, value).is_some() {
+                return Err(StockDataError::ColumnType {
+                    path: file_path.to_string(),
+                    column: metric,
+                    message: format!("{} has more than one value for {}", ticker, year),
+                });
+            }
+        }
+    }
+    if data.is_empty() {
+        return Err(StockDataError::EmptyDataset { path: file_path.to_string() });
+    }
     Ok(data)
 }
 
-pub fn calculate_price_changes(file_path: &str) -> Result<HashMap<String, HashMap<u32, f64>>, Box<dyn Error>> {
-    let mut reader = ReaderBuilder::new().from_path(file_path)?;
-    let headers = reader.headers()?.clone();
-    let mut data: HashMap<String, HashMap<u32, Vec<(u32, f64)>>> = HashMap::new();
+pub fn calculate_price_changes(file_path: &str) -> Result<HashMap<String, HashMap<u32, f64>>, StockDataError> {
+    Ok(read_price_windows(file_path)?.price_changes())
+}
 
+/// Dividends per ticker and calendar year from a long `ticker,ex_date,amount`
+/// file, summed over the ex-dates falling in each year, each in today's shares
+/// after `splits`.
+pub fn read_dividends(file_path: &str, splits: &Splits) -> Result<YearlyValues, StockDataError> {
+    let mut reader = open_csv(file_path)?;
+    let mut dividends: YearlyValues = HashMap::new();
     for result in reader.records() {
-        let record = result?;
+        let record = result.map_err(csv_error(file_path))?;
+        let ticker = normalize_ticker(record.get(0).unwrap_or(""));
+        let date = record.get(1).unwrap_or("");
+        let year = date.trim().get(..4).and_then(|year| year.parse().ok());
+        let (false, Some(year)) = (ticker.is_empty(), year) else {
+            continue;
+        };
+        let amount = parse_number(record.get(2).unwrap_or(""), file_path, "amount")? / splits.factor(&ticker, date);
+        *dividends.entry(ticker).or_default().entry(year).or_default() += amount;
+    }
+    Ok(dividends)
+}
+
+/// Price changes, or total returns when `options` asks for them, and the
+/// intra-year volatilities from one pass over each price file, with the tickers
+/// whose prices were adjusted for a share split.
+/// Years before `year_range` are read only as far as the first year's
+/// reference price needs, and years lacking either window get no change.
+pub fn load_price_files(
+    price_files: &[&str],
+    options: &LoadOptions,
+) -> Result<(YearlyValues, YearlyValues, BTreeSet<String>), StockDataError> {
+    let mut years = options.year_range;
+    if options.price_reference == PriceReference::PriorYearEnd {
+        years.first = years.first.map(|first| first.saturating_sub(1));
+    }
+    let splits = options.split_file.as_deref().map(read_splits).transpose()?.unwrap_or_default();
+    let prices = read_price_files(price_files, options.price_conflict, years, &splits)?;
+    let mut changes = match &options.dividend_file {
+        Some(path) => prices.total_returns(&read_dividends(path, &splits)?, options.price_reference),
+        None => prices.total_returns(&HashMap::new(), options.price_reference),
+    };
+    let mut volatilities = prices.price_volatilities(options.min_volatility_months);
+    for values in [&mut changes, &mut volatilities] {
+        for years in values.values_mut() {
+            years.retain(|&year, _| options.year_range.contains(year));
+        }
+    }
+    let partial = prices.partial_years();
+    for (ticker, year) in &partial {
+        if let Some(years) = changes.get_mut(ticker) {
+            years.remove(year);
+        }
+    }
+    let partial: Vec<&(String, u32)> = partial.iter().filter(|(_, year)| options.year_range.contains(*year)).collect();
+    if !partial.is_empty() {
+        let mut per_year: BTreeMap<u32, usize> = BTreeMap::new();
+        for (_, year) in &partial {
+            *per_year.entry(*year).or_default() += 1;
+        }
+        let listed: Vec<String> =
+            per_year.iter().map(|(year, count)| format!("{} ({} tickers)", year, count)).collect();
+        eprintln!(
+            "warning: {} ticker-years lack January-February or November-December prices and get no price change: {}",
+            partial.len(),
+            listed.join(", ")
+        );
+    }
+    Ok((changes, volatilities, prices.split_adjusted))
+}
+
+/// Flags every record of the `split_adjusted` tickers from `load_price_files`.
+pub fn mark_split_adjusted(stock_data: &mut HashMap<String, Vec<StockData>>, split_adjusted: &BTreeSet<String>) {
+    for ticker in split_adjusted {
+        for record in stock_data.get_mut(ticker).into_iter().flatten() {
+            record.split_adjusted = true;
+        }
+    }
+}
+
+/// Reads the `years` of every price file and merges their monthly observations,
+/// so a year whose January is in one file and December in another still gets a
+/// change. Months that several files have are resolved by `conflict` and listed
+/// in a warning. Prices are adjusted for `splits` as they are read.
+pub fn read_price_files(
+    price_files: &[&str],
+    conflict: PriceConflict,
+    years: YearRange,
+    splits: &Splits,
+) -> Result<PriceWindows, StockDataError> {
+    let mut merged = PriceWindows::default();
+    let mut conflicts = Vec::new();
+    for path in price_files {
+        conflicts.extend(merged.merge(read_price_windows_in(path, years, splits)?, conflict));
+    }
+    if !conflicts.is_empty() {
+        conflicts.sort();
+        conflicts.dedup();
+        let listed: Vec<String> = conflicts.iter().map(|(ticker, year)| format!("{} {}", ticker, year)).collect();
+        eprintln!(
+            "warning: {} ticker-years have prices in more than one file ({:?} kept): {}",
+            conflicts.len(),
+            conflict,
+            listed.join(", ")
+        );
+    }
+    Ok(merged)
+}
+
+/// Streams the price file into per-ticker-year windows, from which both the
+/// price changes and the intra-year volatilities are computed.
+pub fn read_price_windows(file_path: &str) -> Result<PriceWindows, StockDataError> {
+    read_price_windows_in(file_path, YearRange::default(), &Splits::default())
+}
+
+/// `read_price_windows` of the rows dated within `years`, with each price
+/// divided by the ratios of its ticker's later `splits`; the prices of other
+/// rows are not parsed.
+pub fn read_price_windows_in(
+    file_path: &str,
+    years: YearRange,
+    splits: &Splits,
+) -> Result<PriceWindows, StockDataError> {
+    let mut reader = open_csv(file_path)?;
+    let headers = reader.headers().map_err(csv_error(file_path))?.clone();
+    // Layout is `<index>,Date,<ticker>,<ticker>...`; a file without this header row
+    // would otherwise have its first price row silently consumed as ticker names.
+    if !headers.get(1).is_some_and(|h| h.trim().eq_ignore_ascii_case("date")) {
+        return Err(StockDataError::MissingColumn {
+            path: file_path.to_string(),
+            column: "Date".to_string(),
+        });
+    }
+    let tickers: Vec<String> = headers.iter().map(normalize_ticker).collect();
+    let mut windows = PriceWindows::default();
+
+    for result in reader.records() {
+        let record = result.map_err(csv_error(file_path))?;
         let date = record.get(1).unwrap_or("");
         if date.len() < 7 {
             continue;
         }
 
         let year: u32 = date[..4].parse().unwrap_or(0);
+        if !years.contains(year) {
+            continue;
+        }
         let month: u32 = date[5..7].parse().unwrap_or(0);
 
-        for (i, header) in headers.iter().enumerate().skip(2) {
-            let ticker = header.to_string();
-            let price: f64 = record.get(i).unwrap_or("0").parse().unwrap_or(0.0);
+        for (i, ticker) in tickers.iter().enumerate().skip(2) {
+            let mut price = parse_number(record.get(i).unwrap_or("0"), file_path, ticker)?;
+            if !splits.is_empty() {
+                let factor = splits.factor(ticker, date);
+                if factor != 1.0 && !windows.split_adjusted.contains(ticker) {
+                    windows.split_adjusted.insert(ticker.clone());
+                }
+                price /= factor;
+            }
+            windows.add(ticker, year, month, price);
+        }
+    }
+
+    Ok(windows)
+}
+
+// Running sums of the January-February and November-December prices of one
+// ticker-year, and of every month's prices for the volatility
+#[derive(Debug, Clone, Copy, Default)]
+struct PriceWindow {
+    first_sum: f64,
+    first_count: usize,
+    last_sum: f64,
+    last_count: usize,
+    monthly: [(f64, usize); 12], // (sum, count) for January..December
+}
+
+impl PriceWindow {
+    fn first_average(&self) -> Option<f64> {
+        (self.first_count > 0).then(|| self.first_sum / self.first_count as f64)
+    }
+
+    fn last_average(&self) -> Option<f64> {
+        (self.last_count > 0).then(|| self.last_sum / self.last_count as f64)
+    }
+
+    fn month_average(&self, month: u32) -> Option<f64> {
+        let (sum, count) = *self.monthly.get(month.checked_sub(1)? as usize)?;
+        (count > 0).then(|| sum / count as f64)
+    }
+
+    fn add_month(&mut self, index: usize, sum: f64, count: usize) {
+        self.monthly[index].0 += sum;
+        self.monthly[index].1 += count;
+        if index < 2 {
+            self.first_sum += sum;
+            self.first_count += count;
+        } else if index >= 10 {
+            self.last_sum += sum;
+            self.last_count += count;
+        }
+    }
+}
+
+/// Incremental form of `aggregate_price_changes`: each observation only updates
+/// the running sums of its ticker-year, so memory grows with the number of
+/// ticker-years rather than with the number of price rows.
+#[derive(Debug, Clone, Default)]
+pub struct PriceWindows {
+    windows: HashMap<String, HashMap<u32, PriceWindow>>,
+    split_adjusted: BTreeSet<String>, // tickers with a price divided by a split ratio
+}
+
+impl PriceWindows {
+    pub fn add(&mut self, ticker: &str, year: u32, month: u32, price: f64) {
+        let years = match self.windows.get_mut(ticker) {
+            Some(years) => years,
+            None => self.windows.entry(ticker.to_string()).or_default(),
+        };
+        let window = years.entry(year).or_default();
+        if let Some((sum, count)) = month.checked_sub(1).and_then(|i| window.monthly.get_mut(i as usize)) {
+            *sum += price;
+            *count += 1;
+        }
+        if month <= 2 {
+            window.first_sum += price;
+            window.first_count += 1;
+        } else if month >= 11 {
+            window.last_sum += price;
+            window.last_count += 1;
+        }
+    }
 
-            data.entry(ticker.clone())
-                .or_insert_with(HashMap::new)
-                .entry(year)
-                .or_insert_with(Vec::new)
-                .push((month, price));
+    /// Adds `other`'s observations month by month and returns the ticker-years
+    /// with a month both have: `First` keeps this side's prices for that month,
+    /// `Average` pools the two.
+    pub fn merge(&mut self, other: PriceWindows, conflict: PriceConflict) -> Vec<(String, u32)> {
+        let mut conflicts = Vec::new();
+        self.split_adjusted.extend(other.split_adjusted);
+        for (ticker, years) in other.windows {
+            let merged_years = self.windows.entry(ticker.clone()).or_default();
+            for (year, window) in years {
+                let merged = merged_years.entry(year).or_default();
+                let mut conflicted = false;
+                for (index, &(sum, count)) in window.monthly.iter().enumerate().filter(|(_, (_, count))| *count > 0) {
+                    if merged.monthly[index].1 > 0 {
+                        conflicted = true;
+                        if conflict == PriceConflict::First {
+                            continue;
+                        }
+                    }
+                    merged.add_month(index, sum, count);
+                }
+                if conflicted {
+                    conflicts.push((ticker.clone(), year));
+                }
+            }
         }
+        conflicts
+    }
+
+    /// Ticker-years with prices but without a January-February or a
+    /// November-December price, such as a current year only a few months in,
+    /// sorted. Their first and last windows do not span the year.
+    pub fn partial_years(&self) -> Vec<(String, u32)> {
+        let mut partial: Vec<(String, u32)> = self
+            .windows
+            .iter()
+            .flat_map(|(ticker, years)| {
+                years
+                    .iter()
+                    .filter(|(_, window)| window.first_count == 0 || window.last_count == 0)
+                    .map(move |(&year, _)| (ticker.clone(), year))
+            })
+            .collect();
+        partial.sort();
+        partial
+    }
+
+    /// Same result as `aggregate_price_changes` over the same observations.
+    pub fn price_changes(&self) -> HashMap<String, HashMap<u32, f64>> {
+        self.total_returns(&HashMap::new(), PriceReference::StartOfYear)
+    }
+
+    /// Percent change from the `reference` price to the November-December
+    /// average, with the dividends paid during the year added to the latter.
+    /// Tickers or years without dividends get the plain price change; years
+    /// without a reference price or a year-end price get none.
+    pub fn total_returns(&self, dividends: &YearlyValues, reference: PriceReference) -> YearlyValues {
+        self.windows
+            .iter()
+            .map(|(ticker, years)| {
+                let reference_price = |year: u32, window: &PriceWindow| match reference {
+                    PriceReference::StartOfYear => window.first_average(),
+                    PriceReference::PriorYearEnd => years.get(&year.checked_sub(1)?)?.last_average(),
+                    PriceReference::Month(month) => window.month_average(month),
+                };
+                let changes = years
+                    .iter()
+                    .filter_map(|(&year, window)| {
+                        let (start, end) = (reference_price(year, window)?, window.last_average()?);
+                        let paid = dividends.get(ticker).and_then(|years| years.get(&year)).copied().unwrap_or(0.0);
+                        Some((year, ((end + paid - start) / start) * 100.0))
+                    })
+                    .collect();
+                (ticker.clone(), changes)
+            })
+            .collect()
+    }
+
+    /// Coefficient of variation of the monthly average prices per ticker-year.
+    /// Years with prices in fewer than `min_months` months are left out.
+    pub fn price_volatilities(&self, min_months: usize) -> HashMap<String, HashMap<u32, f64>> {
+        self.windows
+            .iter()
+            .map(|(ticker, years)| {
+                let volatilities = years
+                    .iter()
+                    .filter_map(|(year, window)| {
+                        let monthly_means: Vec<f64> = window
+                            .monthly
+                            .iter()
+                            .filter(|(_, count)| *count > 0)
+                            .map(|(sum, count)| sum / *count as f64)
+                            .collect();
+                        if monthly_means.len() < min_months.max(1) {
+                            return None;
+                        }
+                        coefficient_of_variation(&monthly_means).map(|volatility| (*year, volatility))
+                    })
+                    .collect();
+                (ticker.clone(), volatilities)
+            })
+            .collect()
     }
+}
+
+/// Population standard deviation over the mean; `None` for a zero mean.
+pub fn coefficient_of_variation(values: &[f64]) -> Option<f64> {
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    if values.is_empty() || mean == 0.0 {
+        return None;
+    }
+    let variance = values.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / n;
+    Some(variance.sqrt() / mean)
+}
 
+/// Percent change from the average price in January-February to the average in
+/// November-December, per ticker and year, from `(month, price)` observations.
+pub fn aggregate_price_changes(
+    data: &MonthlyPrices,
+) -> HashMap<String, HashMap<u32, f64>> {
     let mut price_changes: HashMap<String, HashMap<u32, f64>> = HashMap::new();
 
-    for (ticker, years) in &data {
+    for (ticker, years) in data {
         let mut changes = HashMap::new();
         for (year, prices) in years {
             let mut first_month_prices = Vec::new();
@@ -92,47 +1049,273 @@ pub fn calculate_price_changes(file_path: &str) -> Result<HashMap<String, HashMa
         }
         price_changes.insert(ticker.clone(), changes);
     }
-    Ok(price_changes)
+    price_changes
 }
 
+/// Loads the financial files and joins them with the price changes of the
+/// price files, which may split tickers or months between them.
 pub fn process_stock_data(
     financial_files: &[(&str, &str)],
-    price_file: &str,
-) -> Result<HashMap<String, Vec<StockData>>, Box<dyn Error>> {
-    let price_changes = calculate_price_changes(price_file)?;
-    let assets = read_csv(financial_files[0].0)?;
-    let cash = read_csv(financial_files[1].0)?;
-    let equity = read_csv(financial_files[2].0)?;
-    let profit = read_csv(financial_files[3].0)?;
-    let revenue = read_csv(financial_files[4].0)?;
+    price_files: &[&str],
+    options: &LoadOptions,
+) -> Result<HashMap<String, Vec<StockData>>, StockDataError> {
+    process_stock_data_with_report(financial_files, price_files, options).map(|(stock_data, _)| stock_data)
+}
+
+/// Like `process_stock_data`, with what the join found.
+pub fn process_stock_data_with_report(
+    financial_files: &[(&str, &str)],
+    price_files: &[&str],
+    options: &LoadOptions,
+) -> Result<(HashMap<String, Vec<StockData>>, LoadReport), StockDataError> {
+    let (price_changes, volatilities, split_adjusted) = load_price_files(price_files, options)?;
+    let (metrics, unavailable) = load_financial_files(financial_files, options)?;
+    let (mut stock_data, report) = combine_stock_data(
+        [&metrics[0], &metrics[1], &metrics[2], &metrics[3], &metrics[4]],
+        cash_flow_metrics(&metrics),
+        &unavailable,
+        &price_changes,
+        Some(&volatilities),
+        options,
+    )?;
+    mark_split_adjusted(&mut stock_data, &split_adjusted);
+    Ok((stock_data, report))
+}
+
+/// The cash-flow maps from `load_financial_files`' output, when both were loaded.
+pub fn cash_flow_metrics(metrics: &[YearlyValues]) -> Option<[&YearlyValues; 2]> {
+    match metrics.get(METRICS.len()..) {
+        Some([ocf, capex]) if !ocf.is_empty() && !capex.is_empty() => Some([ocf, capex]),
+        _ => None,
+    }
+}
+
+// `.xlsx` workbooks go through calamine when the `xlsx` feature is on; anything else is CSV
+#[cfg_attr(not(feature = "xlsx"), allow(unused_variables))]
+fn read_financial_file(path: &str, base_year: u32, options: &LoadOptions) -> Result<YearlyValues, StockDataError> {
+    #[cfg(feature = "xlsx")]
+    if path.to_ascii_lowercase().ends_with(".xlsx") {
+        return crate::xlsx::read_xlsx(path, options.sheet.as_deref(), base_year);
+    }
+    read_csv_with_base_year(path, base_year)
+}
+
+// `metric` from the long file at `path`, read on first use. A cash-flow
+// metric the file lacks is left out as if its file were not supplied.
+fn long_metric(
+    long_files: &mut HashMap<String, HashMap<String, YearlyValues>>,
+    path: &str,
+    metric: &str,
+) -> Result<YearlyValues, StockDataError> {
+    if !long_files.contains_key(path) {
+        long_files.insert(path.to_string(), read_csv_long(path)?);
+    }
+    match long_files[path].get(metric) {
+        Some(values) => Ok(values.clone()),
+        None if CASH_FLOW_METRICS.contains(&metric) => Ok(HashMap::new()),
+        None => Err(StockDataError::MissingColumn {
+            path: path.to_string(),
+            column: metric.to_string(),
+        }),
+    }
+}
+
+/// Reads the five financial files (in `METRICS` order), then the cash-flow files
+/// found among the pairs by metric name (in `CASH_FLOW_METRICS` order, empty when
+/// not supplied), and lists the metrics that were skipped under `skip_missing_files`.
+/// Under `InputLayout::Long` several pairs can name the same file. Values are
+/// converted to USD when `options` has a currency file.
+pub fn load_financial_files(
+    financial_files: &[(&str, &str)],
+    options: &LoadOptions,
+) -> Result<(Vec<YearlyValues>, Vec<String>), StockDataError> {
+    let mut unavailable = Vec::new();
+    let mut long_files = HashMap::new();
+    let mut load = |(path, metric): (&str, &str)| {
+        let base_year = options.base_years.get(metric).copied().unwrap_or(DEFAULT_BASE_YEAR);
+        let data = match options.input_layout {
+            InputLayout::Wide => read_financial_file(path, base_year, options),
+            InputLayout::Long => long_metric(&mut long_files, path, metric),
+        };
+        match data {
+            Ok(data) => Ok(data),
+            Err(err) if options.skip_missing_files => {
+                eprintln!("warning: skipping {}: {}; features using `{}` are dropped", path, err, metric);
+                unavailable.push(metric.to_string());
+                Ok(HashMap::new())
+            }
+            Err(err) => Err(err),
+        }
+    };
+    let mut metrics: Vec<YearlyValues> =
+        financial_files.iter().take(METRICS.len()).map(|&file| load(file)).collect::<Result<_, _>>()?;
+    for metric in CASH_FLOW_METRICS {
+        let supplied = financial_files.iter().skip(METRICS.len()).find(|(_, name)| *name == metric);
+        metrics.push(match supplied {
+            Some(&file) => load(file)?,
+            None => HashMap::new(),
+        });
+    }
+    if let Some(path) = &options.currency_file {
+        let currencies = read_currencies(path)?;
+        let rates = match &options.fx_rates_file {
+            Some(path) => read_fx_rates(path)?,
+            None => HashMap::new(),
+        };
+        let excluded = convert_to_usd(&mut metrics, &currencies, &rates, options.missing_fx_rate)?;
+        if !excluded.is_empty() {
+            let listed: Vec<String> = excluded.iter().map(|(ticker, year)| format!("{} {}", ticker, year)).collect();
+            eprintln!(
+                "warning: {} ticker-years have no exchange rate and are left out: {}",
+                excluded.len(),
+                listed.join(", ")
+            );
+        }
+    }
+    Ok((metrics, unavailable))
+}
+
+/// The label's price change of `ticker` in `year`: the year's own change, or
+/// the compounded changes of the `horizon` years starting with it. NaN marks
+/// records without prices for the whole horizon; prepare_dataset drops them
+/// rather than reading a missing price as a 0% change.
+pub fn horizon_price_change(price_changes: &YearlyValues, ticker: &str, year: u32, horizon: u32) -> f64 {
+    let changes = price_changes.get(ticker);
+    match horizon {
+        0 | 1 => changes.and_then(|years| years.get(&year)).copied().unwrap_or(f64::NAN),
+        horizon => changes.and_then(|changes| compound_price_change(changes, year, horizon)).unwrap_or(f64::NAN),
+    }
+}
+
+/// Recomputes the year-over-year changes of `records[from..]` (sorted by year)
+/// against the record before each; earlier records are left as they are.
+pub fn fill_changes(records: &mut [StockData], from: usize, gap_policy: GapPolicy) {
+    for i in from.max(1)..records.len() {
+        let (prev, current) = records.split_at_mut(i);
+        let prev = &prev[i - 1];
+        let current = &mut current[0];
+
+        // A change spanning a missing year is not a year-over-year change
+        current.gap_years = current.year - prev.year;
+        current.changes_normalized = false;
+        let divisor = match gap_policy {
+            GapPolicy::Skip if current.gap_years > 1 => {
+                current.change_in_revenue = None;
+                current.change_in_profit_margin = None;
+                current.change_in_roa = None;
+                current.change_in_roe = None;
+                current.change_in_fcf_margin = None;
+                continue;
+            }
+            GapPolicy::Skip | GapPolicy::Annotate => 1.0,
+            GapPolicy::Normalize => {
+                current.changes_normalized = true;
+                current.gap_years as f64
+            }
+        };
+
+        // A change from or to an undefined ratio is undefined too; the feature
+        // rows turn it into NaN for the non-finite policy
+        let ratio_change = |current: Option<f64>, previous: Option<f64>| {
+            current.zip(previous).map(|(current, previous)| (current - previous) / divisor)
+        };
+        current.change_in_revenue = Some((current.revenue - prev.revenue) / divisor);
+        current.change_in_profit_margin = ratio_change(current.profit_margin, prev.profit_margin);
+        current.change_in_roa = ratio_change(current.roa, prev.roa);
+        current.change_in_roe = ratio_change(current.roe, prev.roe);
+        current.change_in_fcf_margin = ratio_change(current.fcf_margin, prev.fcf_margin);
+    }
+}
+
+/// What `combine_stock_data` found while joining the loaded data, for the caller to report.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    pub join: JoinReport,
+    pub without_prices: Vec<String>, // tickers with financials but no prices, so no labelled rows; sorted
+}
+
+/// Joins per-metric values (in `METRICS` order) with price changes into
+/// year-sorted records per ticker and fills in the year-over-year deltas.
+/// `cash_flow` holds the optional `CASH_FLOW_METRICS` and `price_volatility`
+/// the backend's intra-year volatilities; without them they are marked
+/// unavailable. Every input backend funnels through here.
+pub fn combine_stock_data(
+    metrics: [&YearlyValues; 5],
+    cash_flow: Option<[&YearlyValues; 2]>,
+    unavailable: &[String],
+    price_changes: &HashMap<String, HashMap<u32, f64>>,
+    price_volatility: Option<&HashMap<String, HashMap<u32, f64>>>,
+    options: &LoadOptions,
+) -> Result<(HashMap<String, Vec<StockData>>, LoadReport), StockDataError> {
+    let [assets, cash, equity, profit, revenue] = metrics;
+    let mut unavailable = unavailable.to_vec();
+    let mut mark_unavailable = |metric: &str| {
+        if !unavailable.iter().any(|name| name == metric) {
+            unavailable.push(metric.to_string());
+        }
+    };
+    if cash_flow.is_none() {
+        CASH_FLOW_METRICS.into_iter().for_each(&mut mark_unavailable);
+    }
+    if price_volatility.is_none() {
+        mark_unavailable(PRICE_VOLATILITY);
+    }
+
+    if metrics.iter().all(|metric| metric.is_empty()) {
+        return Err(StockDataError::JoinFailure {
+            reason: "none of the financial files could be loaded".to_string(),
+        });
+    }
+    let join = join_tickers(metrics, options.join_policy);
+    if join.joined.is_empty() {
+        return Err(StockDataError::JoinFailure {
+            reason: format!(
+                "the {:?} join of the financial files keeps no ticker ({})",
+                join.policy,
+                join.file_counts()
+            ),
+        });
+    }
+    let missing = if join.policy == JoinPolicy::Union { f64::NAN } else { 0.0 };
+    let ratio = Ratio {
+        epsilon: options.ratio_epsilon,
+    };
 
     let mut combined_data: HashMap<String, Vec<StockData>> = HashMap::new();
 
-    for (ticker, years) in &assets {
+    for ticker in &join.joined {
         let mut stock_data = Vec::new();
+        // Years come from the first file listing the ticker, in `METRICS` order
+        let Some(years) = metrics.iter().find_map(|metric| metric.get(ticker)) else {
+            continue;
+        };
 
-        for (&year, &asset_value) in years {
-            let cash_value = cash.get(ticker).and_then(|y| y.get(&year)).cloned().unwrap_or(0.0);
-            let equity_value = equity.get(ticker).and_then(|y| y.get(&year)).cloned().unwrap_or(0.0);
-            let profit_value = profit.get(ticker).and_then(|y| y.get(&year)).cloned().unwrap_or(0.0);
-            let revenue_value = revenue.get(ticker).and_then(|y| y.get(&year)).cloned().unwrap_or(0.0);
-            let price_change = price_changes
-                .get(ticker)
-                .and_then(|y| y.get(&year))
-                .cloned()
-                .unwrap_or(0.0);
-
-            let profit_margin = if revenue_value != 0.0 {
-                profit_value / revenue_value
-            } else {
-                0.0
+        for &year in years.keys() {
+            let values = (
+                metric_value(assets, ticker, year, missing),
+                metric_value(cash, ticker, year, missing),
+                metric_value(equity, ticker, year, missing),
+                metric_value(profit, ticker, year, missing),
+                metric_value(revenue, ticker, year, missing),
+            );
+            let (Some(asset_value), Some(cash_value), Some(equity_value), Some(profit_value), Some(revenue_value)) =
+                values
+            else {
+                continue;
             };
+            let price_change = horizon_price_change(price_changes, ticker, year, options.horizon);
 
-            let roa = if asset_value != 0.0 {
-                (profit_margin * revenue_value) / asset_value
-            } else {
-                0.0
-            };
+            let profit_margin = ratio.of_positive(profit_value, revenue_value);
+            let roa = ratio.of_positive(profit_value, asset_value);
+            let roe = ratio.of_positive(profit_value, equity_value);
+
+            // Cash flow is optional, so a year missing from its files leaves the
+            // FCF fields empty instead of dropping the year
+            let free_cash_flow = cash_flow.and_then(|[ocf, capex]| {
+                let value = |metric: &YearlyValues| metric.get(ticker).and_then(|years| years.get(&year)).copied();
+                Some(value(ocf)? - value(capex)?)
+            });
+            let fcf_margin = free_cash_flow.and_then(|fcf| ratio.of_positive(fcf, revenue_value));
 
             stock_data.push(StockData {
                 ticker: ticker.clone(),
@@ -143,26 +1326,851 @@ pub fn process_stock_data(
                 profit: profit_value,
                 revenue: revenue_value,
                 price_change,
+                price_volatility: price_volatility.and_then(|by_ticker| by_ticker.get(ticker)?.get(&year).copied()),
                 profit_margin,
                 roa,
+                roe,
+                cash_to_assets: ratio.of_positive(cash_value, asset_value),
+                equity_to_assets: ratio.of_positive(equity_value, asset_value),
+                cash_to_revenue: ratio.of_positive(cash_value, revenue_value),
                 change_in_revenue: None,
                 change_in_profit_margin: None,
                 change_in_roa: None,
+                change_in_roe: None,
+                free_cash_flow,
+                fcf_margin,
+                change_in_fcf_margin: None,
+                gap_years: 0,
+                changes_normalized: false,
+                unavailable: unavailable.clone(),
+                excluded: false,
+                split_adjusted: false,
             });
         }
 
-        for i in 1..stock_data.len() {
-            let (prev, current) = stock_data.split_at_mut(i);
-            let prev = &prev[i - 1];
-            let current = &mut current[0];
+        stock_data.sort_by_key(|record| record.year);
+        fill_changes(&mut stock_data, 1, options.gap_policy);
+        combined_data.insert(ticker.clone(), stock_data);
+    }
+
+    let mut without_prices: Vec<String> =
+        combined_data.keys().filter(|ticker| !price_changes.contains_key(*ticker)).cloned().collect();
+    if without_prices.len() == combined_data.len() {
+        return Err(StockDataError::JoinFailure {
+            reason: "no ticker in the financial data appears in the price data".to_string(),
+        });
+    }
+    without_prices.sort();
+
+    Ok((combined_data, LoadReport { join, without_prices }))
+}
+
+/// What the loader produced for one ticker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TickerSummary {
+    pub ticker: String,
+    pub first_year: u32,
+    pub last_year: u32,
+    pub n_records: usize,
+    pub n_complete: usize, // records with year-over-year changes, i.e. a usable previous year
+}
+
+/// One summary per ticker with at least one record, sorted by ticker.
+pub fn ticker_inventory(stock_data: &HashMap<String, Vec<StockData>>) -> Vec<TickerSummary> {
+    let mut inventory: Vec<TickerSummary> = stock_data
+        .iter()
+        .filter(|(_, records)| !records.is_empty())
+        .map(|(ticker, records)| TickerSummary {
+            ticker: ticker.clone(),
+            first_year: records.iter().map(|r| r.year).min().unwrap_or(0),
+            last_year: records.iter().map(|r| r.year).max().unwrap_or(0),
+            n_records: records.len(),
+            n_complete: records
+                .iter()
+                .filter(|r| r.change_in_revenue.is_some())
+                .count(),
+        })
+        .collect();
+    inventory.sort_by(|a, b| a.ticker.cmp(&b.ticker));
+    inventory
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::{builtin_extractors, prepare_dataset_with};
+    use crate::test_util::write_fixture;
+
+    #[test]
+    fn test_missing_file_is_io_error() {
+        let err = read_csv("no_such_file.csv").unwrap_err();
+        assert!(matches!(err, StockDataError::Io { .. }), "{:?}", err);
+
+        let err = calculate_price_changes("no_such_file.csv").unwrap_err();
+        assert!(matches!(err, StockDataError::Io { .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn test_year_ranges_align_per_file() {
+        let assets = write_fixture("years_assets.csv", "Ticker,2021,2020,2019\nAAA,300,200,100\n");
+        let cash = write_fixture("years_cash.csv", "Ticker,2021,2020,2019\nAAA,30,20,10\n");
+        let equity = write_fixture("years_equity.csv", "Ticker,2021,2020,2019\nAAA,150,100,50\n");
+        let profit = write_fixture("years_profit.csv", "Ticker,2023,2022,2021,2020\nAAA,9,8,7,6\n");
+        let revenue = write_fixture("years_revenue.csv", "Ticker,2023,2022,2021\nAAA,90,80,70\n");
+        let prices = write_fixture("years_prices.csv", ",Date,AAA\n0,2021-01-04,10\n1,2021-12-30,12\n");
+        let files = [
+            (assets.as_str(), "assets"),
+            (cash.as_str(), "cash"),
+            (equity.as_str(), "equity"),
+            (profit.as_str(), "profit"),
+            (revenue.as_str(), "revenue"),
+        ];
+
+        let revenue_by_year = read_csv(&revenue).unwrap();
+        assert_eq!(revenue_by_year["AAA"][&2023], 90.0);
+        assert_eq!(revenue_by_year["AAA"][&2021], 70.0);
+
+        let stock_data = process_stock_data(&files, &[&prices], &LoadOptions::default()).unwrap();
+        let records = &stock_data["AAA"];
+        // Only 2021 is present in every file
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].year, 2021);
+        assert_eq!(records[0].assets, 300.0);
+        assert_eq!(records[0].profit, 7.0);
+        assert_eq!(records[0].revenue, 70.0);
+    }
+
+    // AAA is missing 2019, so its 2020 changes span two years; prices for 2020 and 2021
+    fn load_with_gap(gap_policy: GapPolicy) -> HashMap<String, Vec<StockData>> {
+        let years = "Ticker,2021,2020,2018,2017\n";
+        let metric = |name: &str, values: &str| {
+            (write_fixture(&format!("gap_{}.csv", name), &format!("{}AAA,{}\n", years, values)), name.to_string())
+        };
+        let files = [
+            metric("assets", "100,100,100,100"),
+            metric("cash", "10,10,10,10"),
+            metric("equity", "50,50,50,50"),
+            metric("profit", "10,10,10,10"),
+            metric("revenue", "70,60,40,30"),
+        ];
+        let files: Vec<(&str, &str)> = files.iter().map(|(path, name)| (path.as_str(), name.as_str())).collect();
+        let prices = write_fixture(
+            "gap_prices.csv",
+            ",Date,AAA\n0,2020-01-06,10\n1,2020-12-30,11\n2,2021-01-04,11\n3,2021-12-30,12\n",
+        );
+        let options = LoadOptions {
+            gap_policy,
+            ..Default::default()
+        };
+        process_stock_data(&files, &[&prices], &options).unwrap()
+    }
+
+    fn gap_dataset(gap_policy: GapPolicy, stock_data: &HashMap<String, Vec<StockData>>) -> crate::dataset::Dataset {
+        let options = LoadOptions {
+            gap_policy,
+            ..Default::default()
+        };
+        let pipeline = crate::pipeline::Pipeline::builder().stock_data(stock_data.clone()).load_options(options);
+        pipeline.build().unwrap().dataset(stock_data)
+    }
+
+    #[test]
+    fn test_skip_gap_policy_leaves_the_changes_out() {
+        let stock_data = load_with_gap(GapPolicy::Skip);
+        let records = &stock_data["AAA"];
+        let years: Vec<u32> = records.iter().map(|r| r.year).collect();
+        assert_eq!(years, vec![2017, 2018, 2020, 2021]);
+        assert_eq!((records[2].change_in_revenue, records[2].change_in_roa), (None, None));
+        assert_eq!(records[2].gap_years, 2);
+        assert_eq!(records[3].change_in_revenue, Some(10.0));
+        assert_eq!(records[3].gap_years, 1);
+        // 2020 has no changes, and 2021 needs 2020's
+        assert!(gap_dataset(GapPolicy::Skip, &stock_data).is_empty());
+    }
+
+    #[test]
+    fn test_annotate_gap_policy_adds_the_span_as_a_feature() {
+        let stock_data = load_with_gap(GapPolicy::Annotate);
+        let records = &stock_data["AAA"];
+        // 2018 -> 2020 is a two-year change of 20, kept whole with its span
+        assert_eq!(records[2].change_in_revenue, Some(20.0));
+        assert_eq!(records[2].gap_years, 2);
+        assert!(!records[2].changes_normalized);
+
+        let dataset = gap_dataset(GapPolicy::Annotate, &stock_data);
+        let gap = dataset.feature_index(crate::dataset::GAP_YEARS).unwrap();
+        let spans: Vec<(u32, f64)> = (0..dataset.len()).map(|i| (dataset.rows[i].year, dataset.row(i)[gap])).collect();
+        assert_eq!(spans, [(2020, 2.0), (2021, 1.0)]);
+        assert_eq!(dataset.row(0)[dataset.feature_index("delta_revenue").unwrap()], 20.0);
+    }
+
+    #[test]
+    fn test_normalize_gap_policy_reports_changes_per_year() {
+        let stock_data = load_with_gap(GapPolicy::Normalize);
+        let records = &stock_data["AAA"];
+        assert_eq!(records[2].change_in_revenue, Some(10.0));
+        assert!(records[2].changes_normalized);
+        assert_eq!((records[3].change_in_revenue, records[3].gap_years), (Some(10.0), 1));
+
+        let dataset = gap_dataset(GapPolicy::Normalize, &stock_data);
+        assert_eq!(dataset.len(), 2);
+        assert!(dataset.feature_index(crate::dataset::GAP_YEARS).is_none());
+        assert_eq!(dataset.row(0)[dataset.feature_index("delta_revenue").unwrap()], 10.0);
+    }
+
+    #[test]
+    fn test_base_year_for_headers_without_years() {
+        let path = write_fixture("base_year_assets.csv", "Ticker,latest,previous\nAAA,2,1\n");
+
+        let data = read_csv_with_base_year(&path, 2019).unwrap();
+        assert_eq!(data["AAA"][&2019], 2.0);
+        assert_eq!(data["AAA"][&2018], 1.0);
+
+        // Three columns back from year 1 is before year 0
+        let path = write_fixture("base_year_underflow.csv", "Ticker,a,b,c\nAAA,3,2,1\n");
+        let err = read_csv_with_base_year(&path, 1).unwrap_err();
+        assert!(matches!(&err, StockDataError::ColumnType { column, .. } if column == "c"), "{:?}", err);
+    }
 
-            current.change_in_revenue = Some(current.revenue - prev.revenue);
-            current.change_in_profit_margin = Some(current.profit_margin - prev.profit_margin);
-            current.change_in_roa = Some(current.roa - prev.roa);
+    #[test]
+    fn test_streaming_price_changes_match_two_pass() {
+        // The two-pass version keeps every (month, price) pair of the file in
+        // memory before aggregating; `calculate_price_changes` now keeps four
+        // running numbers per ticker-year, whatever the number of rows.
+        let mut contents = String::from(",Date,AAA,BBB\n");
+        let mut monthly: MonthlyPrices = HashMap::new();
+        let mut row = 0;
+        for year in 2019..2023u32 {
+            for month in 1..=12u32 {
+                for day in [3, 17] {
+                    let aaa = 10.0 + year as f64 * 0.5 + month as f64 * 0.37 + day as f64 * 0.01;
+                    let bbb = 200.0 - month as f64 * 3.1 + (year % 3) as f64;
+                    contents.push_str(&format!("{},{}-{:02}-{:02},{},{}\n", row, year, month, day, aaa, bbb));
+                    row += 1;
+                    for (ticker, price) in [("AAA", aaa), ("BBB", bbb)] {
+                        monthly.entry(ticker.to_string()).or_default().entry(year).or_default().push((month, price));
+                    }
+                }
+            }
         }
+        let path = write_fixture("streaming_prices.csv", &contents);
 
-        combined_data.insert(ticker.clone(), stock_data);
+        let streamed = calculate_price_changes(&path).unwrap();
+        assert_eq!(streamed, aggregate_price_changes(&monthly));
+        assert_eq!(streamed["AAA"].len(), 4);
+    }
+
+    #[test]
+    fn test_ticker_inventory() {
+        let files: Vec<String> = METRICS
+            .iter()
+            .map(|metric| {
+                // The profit file stops at 2020, so 2019 cannot be joined
+                let contents = if *metric == "profit" {
+                    "Ticker,2022,2021,2020\nBBB,9,8,7\nAAA,4,3,2\n"
+                } else {
+                    "Ticker,2022,2021,2020,2019\nBBB,9,8,7,6\nAAA,4,3,2,1\n"
+                };
+                write_fixture(&format!("inventory_{}.csv", metric), contents)
+            })
+            .collect();
+        let files: Vec<(&str, &str)> = files.iter().map(String::as_str).zip(METRICS).collect();
+        let prices = write_fixture("inventory_prices.csv", ",Date,AAA,BBB\n0,2022-01-03,10,5\n1,2022-12-30,12,4\n");
+
+        let stock_data = process_stock_data(&files, &[&prices], &LoadOptions::default()).unwrap();
+        let inventory = ticker_inventory(&stock_data);
+
+        let tickers: Vec<&str> = inventory.iter().map(|t| t.ticker.as_str()).collect();
+        assert_eq!(tickers, vec!["AAA", "BBB"]);
+        assert_eq!((inventory[0].first_year, inventory[0].last_year), (2020, 2022));
+        assert_eq!(inventory[0].n_records, 3);
+        // The first year has no previous year to change from
+        assert_eq!(inventory[0].n_complete, 2);
+    }
+
+    #[test]
+    fn test_headerless_price_file_is_missing_column() {
+        let path = write_fixture("headerless_prices.csv", "0,2022-01-03,100.0\n1,2022-12-30,120.0\n");
+
+        let err = calculate_price_changes(&path).unwrap_err();
+        assert!(
+            matches!(&err, StockDataError::MissingColumn { column, .. } if column == "Date"),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_only_thousands_separators_are_dropped() {
+        let parse = |value: &str| parse_number(value, "file.csv", "2022").ok();
+        assert_eq!(parse("1,234"), Some(1234.0));
+        assert_eq!(parse(" -1,234,567.25 "), Some(-1234567.25));
+        assert_eq!(parse("12.5"), Some(12.5));
+        for decimal_comma in ["1,5", "1,234,5", "1234,567", ",123", "1,234.", "1.234,5"] {
+            assert_eq!(parse(decimal_comma), None, "{}", decimal_comma);
+        }
+
+        let path = write_fixture("decimal_comma_assets.csv", "Ticker,2022,2021\nAAA,\"1,5\",2\n");
+        let err = read_csv(&path).unwrap_err();
+        assert!(matches!(&err, StockDataError::ColumnType { column, .. } if column == "2022"), "{:?}", err);
+    }
+
+    #[test]
+    fn test_bom_and_quoted_fields_still_join() {
+        // Excel export: BOM before the header, a header cell spanning two lines,
+        // a stray BOM on a ticker and quoted numbers with thousands separators
+        let assets = write_fixture(
+            "bom_assets.csv",
+            "\u{feff}\"Ticker\n(symbol)\",2022,2021\n\u{feff}AAA,\"1,200\",\"1,000\"\n\"BBB \",50,40\n",
+        );
+        let others = write_fixture("bom_others.csv", "Ticker,2022,2021\nAAA,100,80\nBBB,10,8\n");
+        let prices = write_fixture(
+            "bom_prices.csv",
+            "\u{feff},Date,\u{feff}AAA,BBB\n0,2022-01-03,10,5\n1,2022-12-30,\"1,010\",6\n",
+        );
+
+        let data = read_csv(&assets).unwrap();
+        let mut tickers: Vec<&String> = data.keys().collect();
+        tickers.sort();
+        assert_eq!(tickers, ["AAA", "BBB"]);
+        assert_eq!(data["AAA"][&2022], 1200.0);
+        assert_eq!(data["AAA"][&2021], 1000.0);
+
+        let price_changes = calculate_price_changes(&prices).unwrap();
+        assert_eq!(price_changes["AAA"][&2022], 10000.0);
+
+        let files = [
+            (assets.as_str(), "assets"),
+            (others.as_str(), "cash"),
+            (others.as_str(), "equity"),
+            (others.as_str(), "profit"),
+            (others.as_str(), "revenue"),
+        ];
+        let stock_data = process_stock_data(&files, &[&prices], &LoadOptions::default()).unwrap();
+        let aaa = stock_data["AAA"].iter().find(|r| r.year == 2022).unwrap();
+        assert_eq!(aaa.assets, 1200.0);
+        assert_eq!(aaa.price_change, 10000.0);
+        assert!(stock_data.contains_key("BBB"));
+    }
+
+    #[test]
+    fn test_undefined_ratios_never_become_zero() {
+        use crate::dataset::prepare_dataset;
+        use crate::nonfinite::{sanitize_features, NonFinitePolicy};
+        use crate::synthetic::ticker_records;
+
+        let ratio = Ratio::default();
+        assert_eq!(ratio.of(-3.0, 2.0), Some(-1.5));
+        assert_eq!(ratio.of(1.0, -4.0), Some(-0.25));
+        assert_eq!(ratio.of_positive(1.0, -4.0), None);
+        assert_eq!(ratio.of(1.0, 1e-9), None);
+        assert_eq!(ratio.of(1.0, f64::NAN), None);
+        assert_eq!(Ratio { epsilon: 1e-12 }.of(1e-9, 1e-9), Some(1.0));
+
+        // [assets, cash, equity, profit, revenue]; each ticker breaks one
+        // denominator in 2022, the year its first feature row comes from
+        let years = |broken: [f64; 5]| {
+            vec![
+                (2020, [100.0, 10.0, 50.0, 5.0, 80.0], 10.0),
+                (2021, [110.0, 12.0, 55.0, 6.0, 90.0], -10.0),
+                (2022, broken, 30.0),
+                (2023, [130.0, 15.0, 60.0, 9.0, 110.0], 5.0),
+            ]
+        };
+        let mut stock_data: HashMap<String, Vec<StockData>> = HashMap::new();
+        for (ticker, broken) in [
+            ("ZERO_ASSETS", [0.0, 14.0, 58.0, 7.0, 100.0]),
+            ("NEG_EQUITY", [120.0, 14.0, -20.0, -7.0, 100.0]),
+            ("TINY_REVENUE", [120.0, 14.0, 58.0, 7.0, 1e-9]),
+            ("HEALTHY", [120.0, 14.0, 58.0, 7.0, 100.0]),
+        ] {
+            stock_data.insert(ticker.to_string(), ticker_records(ticker, &years(broken)));
+        }
+
+        let zero_assets = &stock_data["ZERO_ASSETS"][2];
+        assert_eq!((zero_assets.roa, zero_assets.cash_to_assets, zero_assets.equity_to_assets), (None, None, None));
+        assert!(zero_assets.roe.is_some());
+        let negative_equity = &stock_data["NEG_EQUITY"][2];
+        assert_eq!(negative_equity.roe, None);
+        assert_eq!(negative_equity.equity_to_assets, Some(-20.0 / 120.0));
+        assert_eq!(negative_equity.change_in_roe, None);
+        // The undefined year spoils the changes into and out of it
+        assert_eq!(stock_data["NEG_EQUITY"][3].change_in_roe, None);
+        assert_eq!(stock_data["TINY_REVENUE"][2].profit_margin, None);
+
+        let counts: HashMap<String, usize> = undefined_ratio_counts(&stock_data).into_iter().collect();
+        assert_eq!(counts["roa"], 1);
+        assert_eq!(counts["roe"], 1);
+        assert_eq!(counts["profit_margin"], 1);
+        assert_eq!(counts["cash_to_assets"], 1);
+        assert_eq!(counts["fcf_margin"], 0);
+
+        // Under the default policy only the healthy ticker's rows reach the matrix
+        let dataset = prepare_dataset(&stock_data);
+        let (clean, report) = sanitize_features(&dataset, NonFinitePolicy::Drop);
+        assert!(report.per_feature.iter().any(|(name, count)| name == "delta_roe" && *count > 0));
+        assert!(clean.rows.iter().all(|row| row.ticker == "HEALTHY"));
+        assert_eq!(clean.len(), 2);
+        let ratio_columns = ["delta_profit_margin", "delta_roa", "delta_roe", "cash_to_assets", "equity_to_assets"];
+        for column in ratio_columns.map(|name| clean.feature_index(name).unwrap()) {
+            assert!(clean.column(column).all(|value| value.is_finite() && value != 0.0));
+        }
+    }
+
+    #[test]
+    fn test_roa_and_roe_are_direct_ratios() {
+        let records = crate::synthetic::ticker_records(
+            "AAA",
+            &[
+                (2021, [0.7, 0.1, 0.3, 0.1, 0.3], 0.0),
+                (2022, [1.9, 0.2, 0.9, 0.7, 2.3], 0.0),
+            ],
+        );
+        for record in &records {
+            assert_eq!(record.roa, Some(record.profit / record.assets));
+            assert_eq!(record.roe, Some(record.profit / record.equity));
+        }
+        assert_eq!(records[1].change_in_roe, Some(0.7 / 0.9 - 0.1 / 0.3));
+        assert_eq!(records[0].change_in_roe, None);
+
+        let no_equity = crate::synthetic::ticker_records("BBB", &[(2022, [1.0, 0.1, 0.0, 0.5, 2.0], 0.0)]);
+        assert_eq!(no_equity[0].roe, None);
+    }
+
+    #[test]
+    fn test_two_year_horizon_compounds_changes() {
+        let header = "Ticker,2022,2021,2020\n";
+        let fundamentals = write_fixture("horizon_fundamentals.csv", &format!("{}AAA,100,90,80\n", header));
+        // 2021: 10 -> 12 (+20%), 2022: 12 -> 9 (-25%), no 2020 or 2023 prices
+        let prices = write_fixture(
+            "horizon_prices.csv",
+            ",Date,AAA\n0,2021-01-04,10\n1,2021-12-30,12\n2,2022-01-03,12\n3,2022-12-30,9\n",
+        );
+        let files: Vec<(&str, &str)> = METRICS.iter().map(|metric| (fundamentals.as_str(), *metric)).collect();
+        let options = LoadOptions {
+            horizon: 2,
+            ..Default::default()
+        };
+        let records = process_stock_data(&files, &[&prices], &options).unwrap().remove("AAA").unwrap();
+
+        // 1.2 * 0.75 = 0.9: ten percent down over 2021-2022
+        let change_2021 = records.iter().find(|r| r.year == 2021).unwrap().price_change;
+        assert!((change_2021 - -10.0).abs() < 1e-9, "{}", change_2021);
+        assert_eq!(crate::dataset::categorize_price_change(change_2021), Some(1));
+        // 2020 has no prices and 2022 has no 2023 to compound with
+        assert!(records.iter().filter(|r| r.year != 2021).all(|r| r.price_change.is_nan()));
+
+        let one_year = process_stock_data(&files, &[&prices], &LoadOptions::default()).unwrap().remove("AAA").unwrap();
+        let changes: Vec<f64> = one_year.iter().map(|r| r.price_change).collect();
+        assert!(changes[0].is_nan(), "2020 has no prices");
+        assert_eq!(changes[1..], [20.0, -25.0]);
+    }
+
+    #[test]
+    fn test_ticker_without_prices_gives_no_labelled_rows() {
+        let header = "Ticker,2023,2022,2021,2020\n";
+        let fundamentals = write_fixture(
+            "unpriced_fundamentals.csv",
+            &format!("{}AAA,100,90,80,70\nBBB,100,90,80,70\n", header),
+        );
+        let revenue = write_fixture("unpriced_revenue.csv", &format!("{}AAA,50,40,30,20\nBBB,50,40,30,20\n", header));
+        let prices = write_fixture(
+            "unpriced_prices.csv",
+            ",Date,AAA\n0,2022-01-03,10\n1,2022-12-30,12\n2,2023-01-03,12\n3,2023-12-29,9\n",
+        );
+        let mut files: Vec<(&str, &str)> = METRICS.iter().map(|metric| (fundamentals.as_str(), *metric)).collect();
+        files[4] = (revenue.as_str(), "revenue");
+        let (stock_data, report) = process_stock_data_with_report(&files, &[&prices], &LoadOptions::default()).unwrap();
+
+        assert_eq!(report.without_prices, ["BBB"]);
+        assert!(stock_data["BBB"].iter().all(|r| r.price_change.is_nan()));
+        let (dataset, report) = prepare_dataset_with(&stock_data, builtin_extractors());
+        assert_eq!(dataset.rows.iter().map(|row| row.ticker.as_str()).collect::<Vec<&str>>(), ["AAA", "AAA"]);
+        assert_eq!(report.unlabelled, 2);
+        // Two prices a year are too few months for a volatility
+        assert!(report.volatility_dropped);
+        assert!(dataset.feature_index("prior_price_volatility").is_none());
+    }
+
+    #[test]
+    fn test_price_files_merge_by_month() {
+        // AAA's 2022 is split between the files; BBB is only in the second
+        let early = write_fixture(
+            "merge_prices_early.csv",
+            ",Date,AAA\n0,2021-01-04,10\n1,2021-12-30,12\n2,2022-01-03,20\n3,2022-02-01,20\n",
+        );
+        let late = write_fixture(
+            "merge_prices_late.csv",
+            ",Date,AAA,BBB\n0,2022-11-30,30,5\n1,2022-12-30,30,6\n2,2021-12-30,18,4\n3,2021-01-04,9,8\n",
+        );
+        let no_splits = Splits::default();
+
+        let windows =
+            read_price_files(&[&early, &late], PriceConflict::First, YearRange::default(), &no_splits).unwrap();
+        let changes = windows.price_changes();
+        assert_eq!(changes["AAA"][&2022], 50.0);
+        assert_eq!(changes["BBB"][&2021], -50.0);
+        // January and December 2021 are in both files: the first file's prices win...
+        assert_eq!(changes["AAA"][&2021], 20.0);
+
+        // ...or both are averaged: (12 + 18) / 2 over (10 + 9) / 2
+        let averaged =
+            read_price_files(&[&early, &late], PriceConflict::Average, YearRange::default(), &no_splits).unwrap();
+        let averaged = averaged.price_changes();
+        assert!((averaged["AAA"][&2021] - (15.0 / 9.5 - 1.0) * 100.0).abs() < 1e-9);
+        assert_eq!(averaged["AAA"][&2022], 50.0);
+
+        let mut first = read_price_windows(&early).unwrap();
+        let conflicts = first.merge(read_price_windows(&late).unwrap(), PriceConflict::First);
+        assert_eq!(conflicts, vec![("AAA".to_string(), 2021)]);
+    }
+
+    #[test]
+    fn test_price_reference_points() {
+        // The price jumps over the new year: December 2020 ends at 100, 2021 opens at 120
+        let prices = write_fixture(
+            "reference_prices.csv",
+            ",Date,AAA\n0,2020-11-30,100\n1,2020-12-30,100\n2,2021-01-04,120\n3,2021-02-01,120\n\
+             4,2021-03-01,110\n5,2021-11-30,132\n6,2021-12-30,132\n",
+        );
+        let windows = read_price_windows(&prices).unwrap();
+        let changes = |reference| windows.total_returns(&HashMap::new(), reference)["AAA"].clone();
+
+        let start_of_year = changes(PriceReference::StartOfYear);
+        assert!((start_of_year[&2021] - 10.0).abs() < 1e-9);
+        let prior_year_end = changes(PriceReference::PriorYearEnd);
+        assert!((prior_year_end[&2021] - 32.0).abs() < 1e-9);
+        assert!(!prior_year_end.contains_key(&2020));
+        assert!((changes(PriceReference::Month(3))[&2021] - 20.0).abs() < 1e-9);
+        assert!(changes(PriceReference::Month(6)).is_empty());
+
+        assert_eq!("prior-year-end".parse(), Ok(PriceReference::PriorYearEnd));
+        assert_eq!("month:3".parse(), Ok(PriceReference::Month(3)));
+        assert!("month:13".parse::<PriceReference>().is_err());
+        assert_eq!(PriceReference::Month(3).to_string(), "month:3");
     }
 
-    Ok(combined_data)
+    #[test]
+    fn test_year_range_and_partial_years() {
+        let mut contents = String::from(",Date,AAA\n");
+        let mut row = 0;
+        let months: [(u32, &[u32]); 4] = [
+            (2019, &[1, 12]),
+            (2020, &[1, 2, 3, 11, 12]),
+            // No January-February; a March reference would still give it a change
+            (2021, &[3, 4, 5, 6, 7, 8, 9, 10, 11, 12]),
+            // The current year, three months in
+            (2022, &[1, 2, 3]),
+        ];
+        for (year, months) in months {
+            for &month in months {
+                contents.push_str(&format!("{},{}-{:02}-15,{}\n", row, year, month, 100 + month));
+                row += 1;
+            }
+        }
+        let prices = write_fixture("year_range_prices.csv", &contents);
+
+        let years: YearRange = "2020..2022".parse().unwrap();
+        let windows = read_price_windows_in(&prices, years, &Splits::default()).unwrap();
+        assert_eq!(windows.partial_years(), [("AAA".to_string(), 2021), ("AAA".to_string(), 2022)]);
+        assert!(!windows.price_changes()["AAA"].contains_key(&2019));
+        assert!(read_price_windows(&prices).unwrap().price_changes()["AAA"].contains_key(&2019));
+
+        let options = LoadOptions {
+            year_range: years,
+            price_reference: PriceReference::Month(3),
+            ..Default::default()
+        };
+        let (changes, _, _) = load_price_files(&[&prices], &options).unwrap();
+        let mut kept: Vec<u32> = changes["AAA"].keys().copied().collect();
+        kept.sort();
+        assert_eq!(kept, [2020]);
+        // From March's 103 to the November-December average of 111.5
+        assert!((changes["AAA"][&2020] - (111.5 - 103.0) / 103.0 * 100.0).abs() < 1e-9);
+
+        assert_eq!("2015..".parse::<YearRange>().unwrap(), YearRange { first: Some(2015), last: None });
+        assert_eq!("..2022".parse::<YearRange>().unwrap().to_string(), "..2022");
+        assert!("2022..2015".parse::<YearRange>().is_err());
+        assert!("2015-2022".parse::<YearRange>().is_err());
+    }
+
+    #[test]
+    fn test_join_policies_on_disjoint_tickers() {
+        let header = "Ticker,2022,2021,2020\n";
+        let rows =
+            |tickers: &[&str]| tickers.iter().map(|ticker| format!("{},100,90,80\n", ticker)).collect::<String>();
+        let all = write_fixture("join_all.csv", &format!("{}{}", header, rows(&["AAA", "BBB", "CCC", "DDD"])));
+        let assets = write_fixture("join_assets.csv", &format!("{}{}", header, rows(&["AAA", "BBB", "CCC"])));
+        let cash = write_fixture("join_cash.csv", &format!("{}{}", header, rows(&["AAA", "BBB", "DDD"])));
+        let prices = write_fixture(
+            "join_prices.csv",
+            ",Date,AAA,BBB,CCC,DDD\n0,2021-01-04,10,10,10,10\n1,2021-12-30,12,12,12,12\n",
+        );
+        let mut files: Vec<(&str, &str)> = METRICS.iter().map(|metric| (all.as_str(), *metric)).collect();
+        files[0] = (assets.as_str(), "assets");
+        files[1] = (cash.as_str(), "cash");
+        let load = |join_policy| {
+            let options = LoadOptions {
+                join_policy,
+                ..Default::default()
+            };
+            process_stock_data(&files, &[&prices], &options).unwrap()
+        };
+        let tickers = |stock_data: &HashMap<String, Vec<StockData>>| {
+            let mut tickers: Vec<String> = stock_data.keys().cloned().collect();
+            tickers.sort();
+            tickers
+        };
+
+        let intersection = load(JoinPolicy::Intersection);
+        assert_eq!(tickers(&intersection), ["AAA", "BBB"]);
+        assert_eq!(intersection.values().map(Vec::len).sum::<usize>(), 6);
+
+        let assets_driven = load(JoinPolicy::AssetsDriven);
+        assert_eq!(tickers(&assets_driven), ["AAA", "BBB", "CCC"]);
+        assert!(assets_driven["CCC"].iter().all(|r| r.cash == 0.0 && r.assets > 0.0));
+
+        let union = load(JoinPolicy::Union);
+        assert_eq!(tickers(&union), ["AAA", "BBB", "CCC", "DDD"]);
+        assert_eq!(union.values().map(Vec::len).sum::<usize>(), 12);
+        assert!(union["CCC"].iter().all(|r| r.cash.is_nan()));
+        assert!(union["DDD"].iter().all(|r| r.assets.is_nan() && r.roa.is_none()));
+
+        let [a, c, e, p, r] = [&assets, &cash, &all, &all, &all].map(|path| read_csv(path).unwrap());
+        let report = join_tickers([&a, &c, &e, &p, &r], JoinPolicy::AssetsDriven);
+        assert_eq!(report.tickers_per_file[..2], [("assets".to_string(), 3), ("cash".to_string(), 3)]);
+        assert_eq!(report.filled, vec![("CCC".to_string(), "cash".to_string())]);
+        assert!(report.is_mismatched());
+
+        // The loader hands the same report back for the caller to print
+        let options = LoadOptions { join_policy: JoinPolicy::AssetsDriven, ..Default::default() };
+        let (_, load_report) = process_stock_data_with_report(&files, &[&prices], &options).unwrap();
+        assert_eq!(load_report.join, report);
+        assert_eq!(load_report.join.file_counts(), "assets 3, cash 3, equity 4, profit 4, revenue 4");
+        let matched = [&a, &a, &a, &a, &a];
+        assert!(!join_tickers(matched, JoinPolicy::Intersection).is_mismatched());
+    }
+
+    #[test]
+    fn test_join_failures_name_their_cause() {
+        let header = "Ticker,2022,2021,2020\n";
+        let assets = write_fixture("join_failure_assets.csv", &format!("{}AAA,100,90,80\n", header));
+        let others = write_fixture("join_failure_others.csv", &format!("{}BBB,100,90,80\n", header));
+        let prices = write_fixture("join_failure_prices.csv", ",Date,CCC\n0,2021-01-04,10\n1,2021-12-30,12\n");
+        let mut files: Vec<(&str, &str)> = METRICS.iter().map(|metric| (others.as_str(), *metric)).collect();
+        files[0] = (assets.as_str(), "assets");
+        let reason = |files: &[(&str, &str)], join_policy| {
+            let options = LoadOptions { join_policy, ..Default::default() };
+            match process_stock_data(files, &[&prices], &options) {
+                Err(StockDataError::JoinFailure { reason }) => reason,
+                other => panic!("expected a join failure, got {:?}", other.map(|data| data.len())),
+            }
+        };
+
+        // The fundamentals share no ticker: the join is to blame, not the prices
+        let empty_join = reason(&files, JoinPolicy::Intersection);
+        assert!(empty_join.contains("Intersection join of the financial files keeps no ticker"), "{}", empty_join);
+        assert!(empty_join.contains("assets 1, cash 1"), "{}", empty_join);
+
+        // They join, but on a ticker the price file does not have
+        files[0] = (others.as_str(), "assets");
+        let no_prices = reason(&files, JoinPolicy::Intersection);
+        assert_eq!(no_prices, "no ticker in the financial data appears in the price data");
+    }
+
+    #[test]
+    fn test_free_cash_flow_features_need_both_files() {
+        let header = "Ticker,2022,2021,2020\n";
+        let fundamentals = write_fixture("fcf_fundamentals.csv", &format!("{}AAA,100,100,100\n", header));
+        let revenue = write_fixture("fcf_revenue.csv", &format!("{}AAA,100,80,50\n", header));
+        let ocf = write_fixture("fcf_ocf.csv", &format!("{}AAA,30,20,10\n", header));
+        let capex = write_fixture("fcf_capex.csv", &format!("{}AAA,10,12,5\n", header));
+        let prices = write_fixture(
+            "fcf_prices.csv",
+            ",Date,AAA\n0,2020-01-02,10\n1,2020-12-30,11\n2,2021-01-04,11\n3,2021-12-30,12\n\
+             4,2022-01-03,12\n5,2022-12-30,13\n",
+        );
+        let mut files: Vec<(&str, &str)> = METRICS[..4].iter().map(|metric| (fundamentals.as_str(), *metric)).collect();
+        files.push((revenue.as_str(), "revenue"));
+
+        // FCF margins 5/50, 8/80 and 20/100
+        let mut with_cash_flow = files.clone();
+        with_cash_flow.extend([(ocf.as_str(), "operating_cashflow"), (capex.as_str(), "capex")]);
+        let stock_data = process_stock_data(&with_cash_flow, &[&prices], &LoadOptions::default()).unwrap();
+        let records = &stock_data["AAA"];
+        assert_eq!(records[2].free_cash_flow, Some(20.0));
+        assert_eq!(records[0].change_in_fcf_margin, None);
+        assert!((records[1].change_in_fcf_margin.unwrap() - 0.0).abs() < 1e-12);
+        assert!((records[2].change_in_fcf_margin.unwrap() - 0.1).abs() < 1e-12);
+
+        let dataset = crate::dataset::prepare_dataset(&stock_data);
+        let delta = dataset.feature_index("delta_fcf_margin").unwrap();
+        assert!(dataset.feature_index("free_cash_flow").is_some());
+        assert!((dataset.row(0)[dataset.feature_index("fcf_margin").unwrap()] - 0.2).abs() < 1e-12);
+        assert!((dataset.row(0)[delta] - 0.1).abs() < 1e-12);
+
+        // Operating cash flow alone is not enough
+        let n_features = dataset.feature_names.len();
+        let mut ocf_only = files.clone();
+        ocf_only.push((ocf.as_str(), "operating_cashflow"));
+        for files in [files, ocf_only] {
+            let stock_data = process_stock_data(&files, &[&prices], &LoadOptions::default()).unwrap();
+            assert_eq!(stock_data["AAA"][2].free_cash_flow, None);
+            let dataset = crate::dataset::prepare_dataset(&stock_data);
+            assert_eq!(dataset.len(), 1);
+            assert_eq!(dataset.feature_names.len(), n_features - 3);
+            assert!(dataset.feature_names.iter().all(|name| !name.contains("fcf") && name != "free_cash_flow"));
+        }
+    }
+
+    #[test]
+    fn test_long_layouts_match_wide_files() {
+        let years = [2022, 2021, 2020];
+        let value = |metric: usize, ticker: usize, year: u32| (100 * metric + 10 * ticker) as f64 + (year % 7) as f64;
+        let metrics: Vec<&str> = METRICS.iter().chain(&CASH_FLOW_METRICS).copied().collect();
+        let wide: Vec<(String, &str)> = metrics
+            .iter()
+            .enumerate()
+            .map(|(m, metric)| {
+                let mut contents = String::from("Ticker,2022,2021,2020\n");
+                for (t, ticker) in ["AAA", "BBB"].iter().enumerate() {
+                    let values: Vec<String> = years.iter().map(|&year| value(m, t, year).to_string()).collect();
+                    contents.push_str(&format!("{},{}\n", ticker, values.join(",")));
+                }
+                (write_fixture(&format!("layout_wide_{}.csv", metric), &contents), *metric)
+            })
+            .collect();
+        // One row per ticker, year and metric, with fiscal-year-end dates and other casing
+        let mut narrow = String::from("Ticker,Year,Metric,Value\n");
+        // One row per ticker-year with a column per metric, leaving out the cash-flow metrics
+        let mut by_metric = format!("ticker,year,{}\n", METRICS.join(","));
+        for (t, ticker) in ["AAA", "BBB"].iter().enumerate() {
+            for &year in &years {
+                for (m, metric) in metrics.iter().enumerate() {
+                    let row = format!("{},{}-12-31,{},{}\n", ticker, year, metric.to_uppercase(), value(m, t, year));
+                    narrow.push_str(&row);
+                }
+                let values: Vec<String> = (0..METRICS.len()).map(|m| value(m, t, year).to_string()).collect();
+                by_metric.push_str(&format!("{},{},{}\n", ticker, year, values.join(",")));
+            }
+        }
+        let narrow = write_fixture("layout_narrow.csv", &narrow);
+        let by_metric = write_fixture("layout_by_metric.csv", &by_metric);
+        let prices = write_fixture(
+            "layout_prices.csv",
+            ",Date,AAA,BBB\n0,2020-01-02,10,20\n1,2020-12-30,11,19\n2,2021-01-04,11,19\n3,2021-12-30,12,21\n\
+             4,2022-01-03,12,21\n5,2022-12-30,13,20\n",
+        );
+        let load = |files: &[(&str, &str)], input_layout: InputLayout| {
+            let options = LoadOptions { input_layout, ..Default::default() };
+            let stock_data = process_stock_data(files, &[&prices], &options).unwrap();
+            let records = stock_data.values().flatten().map(|record| format!("{:?}", record));
+            let mut records: Vec<String> = records.collect();
+            records.sort();
+            records
+        };
+
+        let wide_files: Vec<(&str, &str)> = wide.iter().map(|(path, metric)| (path.as_str(), *metric)).collect();
+        let from_wide = load(&wide_files, InputLayout::Wide);
+        let narrow_files: Vec<(&str, &str)> = metrics.iter().map(|metric| (narrow.as_str(), *metric)).collect();
+        assert_eq!(load(&narrow_files, InputLayout::Long), from_wide);
+        assert_eq!(from_wide.len(), 6);
+        assert!(from_wide.iter().all(|record| record.contains("free_cash_flow: Some(")));
+
+        // The cash-flow metrics the file lacks are left out as if their files were not supplied
+        let by_metric_files: Vec<(&str, &str)> = metrics.iter().map(|metric| (by_metric.as_str(), *metric)).collect();
+        assert_eq!(load(&by_metric_files, InputLayout::Long), load(&wide_files[..METRICS.len()], InputLayout::Wide));
+
+        let long = read_csv_long(&by_metric).unwrap();
+        assert_eq!(long["revenue"]["BBB"][&2021], value(4, 1, 2021));
+        let duplicated = "ticker,year,metric,value\nAAA,2021,cash,1\nAAA,2021,cash,2\n";
+        let duplicated = write_fixture("layout_duplicated.csv", duplicated);
+        assert!(matches!(read_csv_long(&duplicated), Err(StockDataError::ColumnType { .. })));
+        let no_year = write_fixture("layout_no_year.csv", "ticker,metric,value\nAAA,cash,1\n");
+        let err = read_csv_long(&no_year).unwrap_err();
+        assert!(matches!(err, StockDataError::MissingColumn { ref column, .. } if column == "year"));
+    }
+
+    #[test]
+    fn test_price_volatility_of_monthly_averages() {
+        // Two prices a month averaging 10, 12, 14, 10, 12, 14 in the first half of 2021
+        let mut contents = String::from(",Date,AAA\n");
+        for (month, average) in (1..=6).zip([10.0, 12.0, 14.0, 10.0, 12.0, 14.0]) {
+            contents.push_str(&format!("0,2021-{:02}-05,{}\n", month, average - 1.0));
+            contents.push_str(&format!("0,2021-{:02}-20,{}\n", month, average + 1.0));
+        }
+        contents.push_str("0,2022-01-05,10\n0,2022-06-05,20\n0,2022-12-05,30\n");
+        let path = write_fixture("volatility_prices.csv", &contents);
+        let windows = read_price_windows(&path).unwrap();
+
+        // Mean 12, population variance 16 / 6
+        let expected = (16.0f64 / 6.0).sqrt() / 12.0;
+        let volatilities = windows.price_volatilities(6);
+        assert!((volatilities["AAA"][&2021] - expected).abs() < 1e-12);
+        assert!(!volatilities["AAA"].contains_key(&2022));
+        assert!(windows.price_volatilities(7)["AAA"].is_empty());
+        assert!((windows.price_volatilities(3)["AAA"][&2022] - (200.0f64 / 3.0).sqrt() / 20.0).abs() < 1e-12);
+        assert_eq!(coefficient_of_variation(&[0.0, 0.0]), None);
+    }
+
+    #[test]
+    fn test_volatility_feature_uses_prior_year() {
+        let header = "Ticker,2022,2021,2020\n";
+        let fundamentals = write_fixture("prior_volatility_fundamentals.csv", &format!("{}AAA,110,100,90\n", header));
+        let mut contents = String::from(",Date,AAA\n");
+        for (year, swing) in [(2020, 1.0), (2021, 4.0), (2022, 9.0)] {
+            for month in 1..=12 {
+                let price = 50.0 + if month % 2 == 0 { swing } else { -swing };
+                contents.push_str(&format!("0,{}-{:02}-10,{}\n", year, month, price));
+            }
+        }
+        let prices = write_fixture("prior_volatility_prices.csv", &contents);
+        let files: Vec<(&str, &str)> = METRICS.iter().map(|metric| (fundamentals.as_str(), *metric)).collect();
+        let stock_data = process_stock_data(&files, &[&prices], &LoadOptions::default()).unwrap();
+        let records = &stock_data["AAA"];
+        assert!((records[1].price_volatility.unwrap() - 4.0 / 50.0).abs() < 1e-12);
+
+        let dataset = crate::dataset::prepare_dataset(&stock_data);
+        let column = dataset.feature_index("prior_price_volatility").unwrap();
+        assert_eq!(dataset.rows[0].year, 2022);
+        assert_eq!(dataset.row(0)[column], records[1].price_volatility.unwrap());
+    }
+
+    #[test]
+    fn test_dividends_turn_a_loss_into_a_gain() {
+        let header = "Ticker,2022,2021,2020\n";
+        let fundamentals =
+            write_fixture("dividend_fundamentals.csv", &format!("{}AAA,110,100,90\nBBB,110,100,90\n", header));
+        // Both tickers fall from 10 to 9 in 2022; only AAA pays 2.0 during the year
+        let prices = write_fixture(
+            "dividend_prices.csv",
+            ",Date,AAA,BBB\n0,2021-01-04,10,10\n1,2021-12-30,10,10\n2,2022-01-03,10,10\n3,2022-12-30,9,9\n",
+        );
+        let dividends = write_fixture(
+            "dividends.csv",
+            "ticker,ex_date,amount\nAAA,2022-03-15,1.5\nAAA,2022-09-15,0.5\nAAA,2021-12-31,4.0\nCCC,2022-03-01,1.0\n",
+        );
+        let files: Vec<(&str, &str)> = METRICS.iter().map(|metric| (fundamentals.as_str(), *metric)).collect();
+        let year_2022 = |options: &LoadOptions| {
+            let stock_data = process_stock_data(&files, &[&prices], options).unwrap();
+            let change = |ticker: &str| stock_data[ticker].iter().find(|r| r.year == 2022).unwrap().price_change;
+            (change("AAA"), change("BBB"))
+        };
+
+        let price_only = LoadOptions::default();
+        let total = LoadOptions {
+            dividend_file: Some(dividends),
+            ..Default::default()
+        };
+        let (aaa, bbb) = year_2022(&price_only);
+        assert!((aaa + 10.0).abs() < 1e-9);
+        assert_eq!(crate::dataset::categorize_price_change(aaa), Some(1));
+
+        // (9 + 2 - 10) / 10: the 2021 ex-date is not counted
+        let (aaa_total, bbb_total) = year_2022(&total);
+        assert!((aaa_total - 10.0).abs() < 1e-9);
+        assert_eq!(crate::dataset::categorize_price_change(aaa_total), Some(2));
+        assert_eq!(bbb_total, bbb);
+        assert_eq!((price_only.return_basis(), total.return_basis()), (ReturnBasis::Price, ReturnBasis::Total));
+    }
 }
@@ -0,0 +1,363 @@
+//! Generated stock universes for tests, benchmarks and demos. Fundamentals
+//! follow noisy growth paths, and the price change can be tied to one of the
+//! year-over-year changes so that a model has a known signal to find. Records
+//! always go through `combine_stock_data`, so margins, ROA and changes are
+//! derived exactly as the loaders derive them.
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use crate::stock_data::{combine_stock_data, LoadOptions, StockData, YearlyValues, DEFAULT_BASE_YEAR, METRICS};
+
+/// Year-over-year change a planted signal ties the price change to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalSource {
+    Revenue,
+    ProfitMargin,
+    Roa,
+}
+
+impl SignalSource {
+    /// The `prepare_dataset` feature the source shows up as.
+    pub fn feature_name(self) -> &'static str {
+        match self {
+            SignalSource::Revenue => "delta_revenue",
+            SignalSource::ProfitMargin => "delta_profit_margin",
+            SignalSource::Roa => "delta_roa",
+        }
+    }
+
+    fn value(self, record: &StockData) -> Option<f64> {
+        match self {
+            SignalSource::Revenue => record.change_in_revenue,
+            SignalSource::ProfitMargin => record.change_in_profit_margin,
+            SignalSource::Roa => record.change_in_roa,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyntheticConfig {
+    pub n_tickers: usize,
+    pub n_years: usize,
+    pub last_year: u32,
+    /// Mean yearly revenue growth, as a fraction
+    pub revenue_growth: f64,
+    /// Mean yearly asset growth, as a fraction
+    pub asset_growth: f64,
+    /// Standard deviation of the yearly growth shocks
+    pub noise: f64,
+    /// Change the price change follows, if any
+    pub signal: Option<SignalSource>,
+    /// Percent price change per standard deviation of the signal source
+    pub signal_strength: f64,
+    /// Standard deviation of the price change noise, in percent
+    pub price_noise: f64,
+    pub seed: u64,
+}
+
+impl Default for SyntheticConfig {
+    fn default() -> Self {
+        SyntheticConfig {
+            n_tickers: 20,
+            n_years: 5,
+            last_year: DEFAULT_BASE_YEAR,
+            revenue_growth: 0.05,
+            asset_growth: 0.04,
+            noise: 0.1,
+            signal: None,
+            signal_strength: 40.0,
+            price_noise: 30.0,
+            seed: 0,
+        }
+    }
+}
+
+/// A generated universe in the loaders' intermediate form.
+#[derive(Debug, Clone)]
+pub struct SyntheticData {
+    pub metrics: Vec<YearlyValues>, // in `METRICS` order
+    pub price_changes: HashMap<String, HashMap<u32, f64>>,
+}
+
+/// Paths of the CSV files written by `SyntheticData::write_csvs`.
+#[derive(Debug, Clone)]
+pub struct SyntheticFiles {
+    pub financial_files: Vec<(String, &'static str)>,
+    pub price_file: String,
+}
+
+impl SyntheticFiles {
+    /// The `(path, metric)` pairs `process_stock_data` takes.
+    pub fn financial_file_pairs(&self) -> Vec<(&str, &str)> {
+        self.financial_files.iter().map(|(path, metric)| (path.as_str(), *metric)).collect()
+    }
+}
+
+// Standard normal draw (Box-Muller)
+fn normal(rng: &mut StdRng) -> f64 {
+    let u1: f64 = rng.gen_range(f64::EPSILON..1.0);
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+fn combine(metrics: &[YearlyValues], price_changes: &HashMap<String, HashMap<u32, f64>>) -> HashMap<String, Vec<StockData>> {
+    combine_stock_data(
+        [&metrics[0], &metrics[1], &metrics[2], &metrics[3], &metrics[4]],
+        None,
+        &[],
+        price_changes,
+        None,
+        &LoadOptions::default(),
+    )
+    .expect("synthetic tickers always have prices")
+    .0
+}
+
+impl SyntheticConfig {
+    pub fn tickers(&self) -> Vec<String> {
+        (0..self.n_tickers).map(|i| format!("SYN{:03}", i)).collect()
+    }
+
+    pub fn years(&self) -> Vec<u32> {
+        (0..self.n_years as u32).map(|i| self.last_year + 1 + i - self.n_years as u32).collect()
+    }
+
+    pub fn generate(&self) -> SyntheticData {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut metrics: Vec<YearlyValues> = vec![HashMap::new(); METRICS.len()];
+
+        for ticker in self.tickers() {
+            let mut assets = 10f64.powf(rng.gen_range(2.0..4.0));
+            let mut revenue = assets * rng.gen_range(0.3..1.2);
+            let mut margin = rng.gen_range(-0.05..0.2);
+            let mut cash_ratio: f64 = rng.gen_range(0.05..0.3);
+            let mut equity_ratio: f64 = rng.gen_range(0.2..0.7);
+
+            for (i, year) in self.years().into_iter().enumerate() {
+                if i > 0 {
+                    revenue *= 1.0 + self.revenue_growth + self.noise * normal(&mut rng);
+                    assets *= 1.0 + self.asset_growth + 0.5 * self.noise * normal(&mut rng);
+                    margin += 0.2 * self.noise * normal(&mut rng);
+                    cash_ratio = (cash_ratio + 0.1 * self.noise * normal(&mut rng)).clamp(0.01, 0.9);
+                    equity_ratio = (equity_ratio + 0.1 * self.noise * normal(&mut rng)).clamp(0.05, 0.95);
+                }
+                let values = [assets, assets * cash_ratio, assets * equity_ratio, revenue * margin, revenue];
+                for (metric, value) in metrics.iter_mut().zip(values) {
+                    metric.entry(ticker.clone()).or_default().insert(year, value);
+                }
+            }
+        }
+
+        // Price changes need the derived changes, so combine once without them first
+        let no_prices: HashMap<String, HashMap<u32, f64>> =
+            self.tickers().into_iter().map(|ticker| (ticker, HashMap::new())).collect();
+        let records = combine(&metrics, &no_prices);
+        let signal_values: Vec<f64> = match self.signal {
+            // In ticker order: summing in HashMap order would change the last bits between runs
+            Some(source) => self
+                .tickers()
+                .iter()
+                .flat_map(|ticker| &records[ticker])
+                .filter_map(|record| source.value(record))
+                .collect(),
+            None => Vec::new(),
+        };
+        let n = signal_values.len().max(1) as f64;
+        let mean = signal_values.iter().sum::<f64>() / n;
+        let std = (signal_values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt();
+
+        let mut price_changes: HashMap<String, HashMap<u32, f64>> = HashMap::new();
+        for ticker in self.tickers() {
+            let changes = price_changes.entry(ticker.clone()).or_default();
+            for record in &records[&ticker] {
+                let planted = match self.signal.and_then(|source| source.value(record)) {
+                    Some(value) if std > 0.0 => self.signal_strength * (value - mean) / std,
+                    _ => 0.0,
+                };
+                let change = planted + self.price_noise * normal(&mut rng);
+                changes.insert(record.year, change.max(-95.0));
+            }
+        }
+
+        SyntheticData { metrics, price_changes }
+    }
+}
+
+impl SyntheticData {
+    /// The records `process_stock_data` would build from `write_csvs` output,
+    /// up to rounding in the price aggregation.
+    pub fn stock_data(&self) -> HashMap<String, Vec<StockData>> {
+        combine(&self.metrics, &self.price_changes)
+    }
+
+    /// Writes `data_<metric>.csv` for each metric and `stock_prices.csv` into
+    /// `dir`, in the wide layouts `read_csv` and `calculate_price_changes` read.
+    /// Each year's prices are flat over January-February and November-December
+    /// at levels that reproduce the price change.
+    pub fn write_csvs(&self, dir: &Path) -> std::io::Result<SyntheticFiles> {
+        fs::create_dir_all(dir)?;
+        let mut tickers: Vec<&String> = self.price_changes.keys().collect();
+        tickers.sort();
+        let mut years: Vec<u32> = self.metrics[0].values().flat_map(|years| years.keys().copied()).collect();
+        years.sort_unstable();
+        years.dedup();
+
+        let mut financial_files = Vec::new();
+        for (metric, values) in METRICS.iter().zip(&self.metrics) {
+            let mut contents = String::from("Ticker");
+            for year in years.iter().rev() {
+                contents.push_str(&format!(",{}", year));
+            }
+            contents.push('\n');
+            for ticker in &tickers {
+                contents.push_str(ticker);
+                for year in years.iter().rev() {
+                    let value = values.get(*ticker).and_then(|by_year| by_year.get(year));
+                    contents.push_str(&format!(",{}", value.map(f64::to_string).unwrap_or_default()));
+                }
+                contents.push('\n');
+            }
+            let path = dir.join(format!("data_{}.csv", metric));
+            fs::write(&path, contents)?;
+            financial_files.push((path.to_string_lossy().into_owned(), *metric));
+        }
+
+        let mut contents = String::from(",Date");
+        for ticker in &tickers {
+            contents.push_str(&format!(",{}", ticker));
+        }
+        contents.push('\n');
+        let mut levels: Vec<f64> = vec![100.0; tickers.len()];
+        let mut row = 0;
+        for year in &years {
+            let start = levels.clone();
+            for (i, ticker) in tickers.iter().enumerate() {
+                let change = self.price_changes[*ticker].get(year).copied().unwrap_or(0.0);
+                levels[i] = start[i] * (1.0 + change / 100.0);
+            }
+            for (month, prices) in [(1, &start), (2, &start), (11, &levels), (12, &levels)] {
+                contents.push_str(&format!("{},{}-{:02}-15", row, year, month));
+                for price in prices.iter() {
+                    contents.push_str(&format!(",{}", price));
+                }
+                contents.push('\n');
+                row += 1;
+            }
+        }
+        let price_file = dir.join("stock_prices.csv");
+        fs::write(&price_file, contents)?;
+
+        Ok(SyntheticFiles {
+            financial_files,
+            price_file: price_file.to_string_lossy().into_owned(),
+        })
+    }
+}
+
+/// A demo universe of `n_tickers` tickers over the `n_years` up to `DEFAULT_BASE_YEAR`,
+/// with price changes following the revenue change closely enough for a model to learn.
+pub fn generate_synthetic_dataset(n_tickers: usize, n_years: usize, seed: u64) -> HashMap<String, Vec<StockData>> {
+    SyntheticConfig {
+        n_tickers,
+        n_years,
+        signal: Some(SignalSource::Revenue),
+        signal_strength: 40.0,
+        price_noise: 15.0,
+        seed,
+        ..Default::default()
+    }
+    .generate()
+    .stock_data()
+}
+
+/// Records for one ticker from explicit `(year, [assets, cash, equity, profit, revenue], price_change)`
+/// rows, with the derived fields filled in by `combine_stock_data`.
+pub fn ticker_records(ticker: &str, rows: &[(u32, [f64; 5], f64)]) -> Vec<StockData> {
+    let mut metrics: Vec<YearlyValues> = vec![HashMap::new(); METRICS.len()];
+    let mut price_changes: HashMap<String, HashMap<u32, f64>> = HashMap::new();
+    for (year, values, price_change) in rows {
+        for (metric, value) in metrics.iter_mut().zip(values) {
+            metric.entry(ticker.to_string()).or_default().insert(*year, *value);
+        }
+        price_changes.entry(ticker.to_string()).or_default().insert(*year, *price_change);
+    }
+    combine(&metrics, &price_changes).remove(ticker).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::prepare_dataset;
+    use crate::stock_data::process_stock_data;
+    use crate::test_util::fixture_path;
+
+    #[test]
+    fn test_written_csvs_load_back() {
+        let data = SyntheticConfig {
+            n_tickers: 4,
+            n_years: 4,
+            seed: 3,
+            ..Default::default()
+        }
+        .generate();
+        let dir = fixture_path("synthetic_csvs");
+        let files = data.write_csvs(&dir).unwrap();
+
+        let loaded =
+            process_stock_data(&files.financial_file_pairs(), &[&files.price_file], &LoadOptions::default()).unwrap();
+        let direct = data.stock_data();
+        assert_eq!(loaded.len(), 4);
+        for (ticker, records) in &direct {
+            let loaded_records = &loaded[ticker];
+            assert_eq!(loaded_records.len(), 4);
+            for (a, b) in records.iter().zip(loaded_records) {
+                assert_eq!((a.year, a.assets, a.profit, a.change_in_roa), (b.year, b.assets, b.profit, b.change_in_roa));
+                assert!((a.price_change - b.price_change).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_generated_dataset_shape_and_determinism() {
+        let stock_data = generate_synthetic_dataset(7, 6, 21);
+        assert_eq!(stock_data.len(), 7);
+        for records in stock_data.values() {
+            let years: Vec<u32> = records.iter().map(|r| r.year).collect();
+            assert_eq!(years, (DEFAULT_BASE_YEAR - 5..=DEFAULT_BASE_YEAR).collect::<Vec<_>>());
+        }
+
+        let key = |data: &HashMap<String, Vec<StockData>>| {
+            let mut rows: Vec<String> = data
+                .values()
+                .flatten()
+                .map(|r| format!("{} {} {} {} {:?}", r.ticker, r.year, r.revenue, r.price_change, r.change_in_roa))
+                .collect();
+            rows.sort();
+            rows
+        };
+        assert_eq!(key(&stock_data), key(&generate_synthetic_dataset(7, 6, 21)));
+        assert_ne!(key(&stock_data), key(&generate_synthetic_dataset(7, 6, 22)));
+    }
+
+    #[test]
+    fn test_planted_signal_orders_labels() {
+        let data = SyntheticConfig {
+            n_tickers: 50,
+            signal: Some(SignalSource::Roa),
+            signal_strength: 60.0,
+            price_noise: 1.0,
+            ..Default::default()
+        }
+        .generate();
+        let dataset = prepare_dataset(&data.stock_data());
+        let feature = dataset.feature_index(SignalSource::Roa.feature_name()).unwrap();
+
+        // With almost no noise a larger ROA change never gets a lower label
+        let mut rows: Vec<(f64, u8)> = dataset.column(feature).zip(dataset.labels.iter().copied()).collect();
+        rows.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let out_of_order = rows.windows(2).filter(|pair| pair[1].1 < pair[0].1).count();
+        assert!(out_of_order <= rows.len() / 20, "{} of {}", out_of_order, rows.len());
+    }
+}
@@ -0,0 +1,64 @@
+//! Fixtures shared by the tests: files and directories in the temp directory,
+//! and the CSV copy of the one-ticker data set the SQLite and Parquet tests
+//! load from their own formats to check that every backend reads the same.
+use std::path::PathBuf;
+#[cfg(any(feature = "sqlite", feature = "parquet"))]
+use std::collections::HashMap;
+#[cfg(any(feature = "sqlite", feature = "parquet"))]
+use crate::stock_data::{process_stock_data, LoadOptions, StockData};
+
+/// Where a fixture file or directory named `name` lives in the temp directory.
+pub(crate) fn fixture_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("final_project_{}", name))
+}
+
+/// Writes `contents` to the fixture `name` and returns its path.
+pub(crate) fn write_fixture(name: &str, contents: &str) -> String {
+    let path = fixture_path(name);
+    std::fs::write(&path, contents).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+// AAA's 2020-2022 fundamentals and prices loaded from CSV files, the files
+// named with `prefix` so tests running side by side do not share them
+#[cfg(any(feature = "sqlite", feature = "parquet"))]
+fn csv_stock_data(prefix: &str) -> HashMap<String, Vec<StockData>> {
+    let header = "Ticker,2022,2021,2020\n";
+    let metrics = [
+        ("assets", "400,300,200"),
+        ("cash", "40,45,20"),
+        ("equity", "200,120,90"),
+        ("profit", "30,20,5"),
+        ("revenue", "100,80,50"),
+    ];
+    let paths: Vec<(String, &str)> = metrics
+        .iter()
+        .map(|(metric, values)| {
+            let contents = format!("{}AAA,{}\n", header, values);
+            (write_fixture(&format!("{}_{}.csv", prefix, metric), &contents), *metric)
+        })
+        .collect();
+    let prices = write_fixture(
+        &format!("{}_prices.csv", prefix),
+        ",Date,AAA\n0,2021-01-04,10\n1,2021-12-30,15\n2,2022-02-01,15\n3,2022-11-30,12\n",
+    );
+    let files: Vec<(&str, &str)> = paths.iter().map(|(path, metric)| (path.as_str(), *metric)).collect();
+    process_stock_data(&files, &[&prices], &LoadOptions::default()).unwrap()
+}
+
+/// Asserts that `actual` holds the same AAA records as the CSV fixture,
+/// comparing the loaded values and every change computed from them.
+#[cfg(any(feature = "sqlite", feature = "parquet"))]
+pub(crate) fn assert_same_as_csv(prefix: &str, actual: &HashMap<String, Vec<StockData>>) {
+    let key = |r: &StockData| {
+        format!(
+            "{} {} {} {} {} {} {} {} {:?} {:?} {:?}",
+            r.ticker, r.year, r.assets, r.cash, r.equity, r.profit, r.revenue, r.price_change,
+            r.change_in_revenue, r.change_in_profit_margin, r.change_in_roa
+        )
+    };
+    let expected: Vec<String> = csv_stock_data(prefix)["AAA"].iter().map(key).collect();
+    let actual: Vec<String> = actual["AAA"].iter().map(key).collect();
+    assert_eq!(expected.len(), 3);
+    assert_eq!(expected, actual);
+}
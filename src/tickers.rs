@@ -0,0 +1,112 @@
+//! Ticker lists for `--include-tickers` and `--exclude-tickers`. Lists and data
+//! are compared in canonical form, so a list written as `brk.b` matches the
+//! data's `BRK-B`.
+use std::collections::{HashMap, HashSet};
+use crate::stock_data::{normalize_ticker, StockData, StockDataError};
+
+/// Upper case, with the share-class separators `.`, `/` and spaces written as `-`.
+pub fn canonical_ticker(raw: &str) -> String {
+    normalize_ticker(raw)
+        .chars()
+        .map(|c| match c {
+            '.' | '/' | ' ' => '-',
+            c => c.to_ascii_uppercase(),
+        })
+        .collect()
+}
+
+/// One symbol per line; `#` starts a comment and blank lines are skipped.
+pub fn read_ticker_list(path: &str) -> Result<Vec<String>, StockDataError> {
+    let contents = std::fs::read_to_string(path).map_err(|source| StockDataError::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    Ok(contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Tickers to keep (all when `include` is unset) and tickers to remove.
+#[derive(Debug, Clone, Default)]
+pub struct TickerFilter {
+    pub include: Option<Vec<String>>,
+    pub exclude: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TickerFilterReport {
+    pub removed_by_include: usize, // tickers in the data but not in the include list
+    pub removed_by_exclude: usize, // tickers in the data and the exclude list
+    pub not_found: Vec<String>,    // include-list entries no ticker in the data matches, in list order
+}
+
+impl TickerFilter {
+    pub fn is_active(&self) -> bool {
+        self.include.is_some() || !self.exclude.is_empty()
+    }
+
+    /// Removes the tickers the lists rule out. The include list is applied first,
+    /// so a ticker in both lists counts as removed by the exclude list.
+    pub fn apply(&self, stock_data: &mut HashMap<String, Vec<StockData>>) -> TickerFilterReport {
+        let canonical = |tickers: &[String]| tickers.iter().map(|ticker| canonical_ticker(ticker)).collect();
+        let excluded: HashSet<String> = canonical(&self.exclude);
+        let mut report = TickerFilterReport::default();
+
+        if let Some(include) = &self.include {
+            let included: HashSet<String> = canonical(include);
+            let present: HashSet<String> = stock_data.keys().map(|ticker| canonical_ticker(ticker)).collect();
+            report.not_found =
+                include.iter().filter(|ticker| !present.contains(&canonical_ticker(ticker))).cloned().collect();
+            let before = stock_data.len();
+            stock_data.retain(|ticker, _| included.contains(&canonical_ticker(ticker)));
+            report.removed_by_include = before - stock_data.len();
+        }
+        let before = stock_data.len();
+        stock_data.retain(|ticker, _| !excluded.contains(&canonical_ticker(ticker)));
+        report.removed_by_exclude = before - stock_data.len();
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::ticker_records;
+    use crate::test_util::write_fixture;
+
+    #[test]
+    fn test_include_and_exclude_lists() {
+        let mut stock_data: HashMap<String, Vec<StockData>> = ["AAPL", "BRK-B", "MSFT", "XOM", "GE"]
+            .iter()
+            .map(|ticker| (ticker.to_string(), ticker_records(ticker, &[(2021, [1.0; 5], 0.0)])))
+            .collect();
+        let include = write_fixture(
+            "include_tickers.txt",
+            "# S&P members\naapl\nbrk.b   # class B shares\n\nMSFT\nGE\nZZZZ\n",
+        );
+        let exclude = write_fixture("exclude_tickers.txt", "ge\n# data problems\nTSLA\n");
+        let filter = TickerFilter {
+            include: Some(read_ticker_list(&include).unwrap()),
+            exclude: read_ticker_list(&exclude).unwrap(),
+        };
+        assert_eq!(filter.include.as_ref().unwrap(), &["aapl", "brk.b", "MSFT", "GE", "ZZZZ"]);
+
+        let report = filter.apply(&mut stock_data);
+        let mut kept: Vec<&String> = stock_data.keys().collect();
+        kept.sort();
+        assert_eq!(kept, ["AAPL", "BRK-B", "MSFT"]);
+        assert_eq!(
+            report,
+            TickerFilterReport {
+                removed_by_include: 1,
+                removed_by_exclude: 1,
+                not_found: vec!["ZZZZ".to_string()],
+            }
+        );
+        assert_eq!(canonical_ticker(" brk/b\u{feff}"), "BRK-B");
+        assert!(read_ticker_list("no_such_list.txt").is_err());
+    }
+}
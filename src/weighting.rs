@@ -0,0 +1,141 @@
+use rand::distributions::WeightedIndex;
+use rand::prelude::Distribution;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use crate::dataset::Dataset;
+
+/// Years over which a recency weight halves; always positive, so a weight
+/// never divides by zero or turns NaN.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Halflife(f64);
+
+impl Halflife {
+    /// `years` as a half-life, or `None` unless it is positive (NaN is not).
+    pub fn new(years: f64) -> Option<Halflife> {
+        (years > 0.0).then_some(Halflife(years))
+    }
+
+    pub fn years(self) -> f64 {
+        self.0
+    }
+}
+
+/// Exponential-decay weight per row: 1.0 for the latest year in the dataset,
+/// halving every `halflife` years before it.
+pub fn recency_weights(dataset: &Dataset, halflife: Halflife) -> Vec<f64> {
+    let latest = dataset.rows.iter().map(|row| row.year).max().unwrap_or(0);
+    dataset
+        .rows
+        .iter()
+        .map(|row| 0.5f64.powf((latest - row.year) as f64 / halflife.years()))
+        .collect()
+}
+
+/// Replication factor per row: `lambda^(latest - year)`, so 1.0 for the latest
+/// year in the dataset and `lambda` for the year before.
+pub fn recency_decay_factors(dataset: &Dataset, lambda: f64) -> Vec<f64> {
+    let latest = dataset.rows.iter().map(|row| row.year).max().unwrap_or(0);
+    dataset.rows.iter().map(|row| lambda.powi((latest - row.year) as i32)).collect()
+}
+
+/// Replication factor per row that gives every class as many expected rows as the largest one.
+pub fn class_balance_factors(dataset: &Dataset) -> Vec<f64> {
+    let mut counts = vec![0usize; dataset.labels.iter().max().map_or(0, |&label| label as usize + 1)];
+    for &label in &dataset.labels {
+        counts[label as usize] += 1;
+    }
+    let largest = counts.iter().copied().max().unwrap_or(0) as f64;
+    dataset.labels.iter().map(|&label| largest / counts[label as usize] as f64).collect()
+}
+
+/// Keeps row `i` `factors[i]` times in expectation: the whole part of the factor
+/// always, plus one more copy with probability equal to the fractional part.
+/// Copies stay next to their row, so the row order is kept.
+pub fn replicate(dataset: &Dataset, factors: &[f64], seed: u64) -> Dataset {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut indices = Vec::with_capacity(dataset.len());
+    for (i, &factor) in factors.iter().enumerate() {
+        let whole = factor.floor();
+        let copies = whole as usize + usize::from(rng.gen_bool((factor - whole).clamp(0.0, 1.0)));
+        indices.extend(std::iter::repeat_n(i, copies));
+    }
+    dataset.subset(&indices)
+}
+
+/// Oversamples the smaller classes up to the size of the largest by replication.
+pub fn balance_classes(dataset: &Dataset, seed: u64) -> Dataset {
+    replicate(dataset, &class_balance_factors(dataset), seed)
+}
+
+/// smartcore's `fit` takes no sample weights, so weights are applied by drawing
+/// `dataset.len()` rows with replacement, each with probability proportional to its weight.
+pub fn weighted_resample(dataset: &Dataset, weights: &[f64], seed: u64) -> Dataset {
+    let Ok(distribution) = WeightedIndex::new(weights) else {
+        return dataset.clone();
+    };
+    let mut rng = StdRng::seed_from_u64(seed);
+    let indices: Vec<usize> = (0..dataset.len()).map(|_| distribution.sample(&mut rng)).collect();
+    dataset.subset(&indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::RowId;
+
+    fn dataset_for_years(years: &[u32]) -> Dataset {
+        Dataset {
+            feature_names: vec!["x".to_string()],
+            values: years.iter().map(|&year| year as f64).collect(),
+            labels: vec![1; years.len()],
+            rows: years
+                .iter()
+                .map(|&year| RowId { ticker: "AAA".to_string(), year, ..Default::default() })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_older_rows_get_smaller_weights() {
+        let dataset = dataset_for_years(&[2018, 2020, 2022, 2021]);
+        let weights = recency_weights(&dataset, Halflife::new(2.0).unwrap());
+
+        assert_eq!(weights, vec![0.25, 0.5, 1.0, 0.5f64.powf(0.5)]);
+        assert!(weights[0] < weights[1] && weights[1] < weights[3] && weights[3] < weights[2]);
+        assert!([0.0, -1.0, f64::NAN].iter().all(|&years| Halflife::new(years).is_none()));
+    }
+
+    #[test]
+    fn test_recency_decay_replication() {
+        let years: Vec<u32> = (0..3000).map(|i| 2020 + (i % 3) as u32).collect();
+        let dataset = dataset_for_years(&years);
+
+        let unchanged = replicate(&dataset, &recency_decay_factors(&dataset, 1.0), 5);
+        assert_eq!(unchanged.rows, dataset.rows);
+        assert_eq!(unchanged.values, dataset.values);
+
+        let decayed = replicate(&dataset, &recency_decay_factors(&dataset, 0.5), 5);
+        let count = |year: u32| decayed.rows.iter().filter(|row| row.year == year).count();
+        assert_eq!(count(2022), 1000);
+        assert!((450..550).contains(&count(2021)), "{} rows from 2021", count(2021));
+        assert!((200..300).contains(&count(2020)), "{} rows from 2020", count(2020));
+
+        let mut imbalanced = dataset_for_years(&[2022; 8]);
+        imbalanced.labels = vec![0, 0, 0, 0, 0, 0, 1, 1];
+        let balanced = balance_classes(&imbalanced, 5);
+        assert_eq!(balanced.labels.iter().filter(|&&label| label == 1).count(), 6);
+        assert_eq!(balanced.len(), 12);
+    }
+
+    #[test]
+    fn test_weighted_resample_favors_recent_rows() {
+        let years: Vec<u32> = (0..1000).map(|i| if i % 2 == 0 { 2012 } else { 2022 }).collect();
+        let dataset = dataset_for_years(&years);
+        let resampled = weighted_resample(&dataset, &recency_weights(&dataset, Halflife::new(2.0).unwrap()), 3);
+
+        assert_eq!(resampled.len(), dataset.len());
+        let old = resampled.rows.iter().filter(|row| row.year == 2012).count();
+        // 2012 rows weigh 1/32 of 2022 rows
+        assert!(old < 100, "{} old rows drawn", old);
+    }
+}
@@ -0,0 +1,146 @@
+//! Reader for financial files kept as Excel workbooks, laid out like the wide
+//! CSVs: a header row `Ticker,<year>,<year>...` followed by one row per ticker.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use calamine::{Data, Reader, Xlsx, XlsxError};
+use crate::stock_data::{counted_year, StockDataError, YearlyValues};
+
+fn xlsx_error(path: &str) -> impl Fn(XlsxError) -> StockDataError + '_ {
+    move |source| StockDataError::Xlsx {
+        path: path.to_string(),
+        source,
+    }
+}
+
+// Cell contents as the CSV reader would see them
+fn cell_text(cell: &Data) -> String {
+    match cell {
+        Data::Int(value) => value.to_string(),
+        Data::Float(value) => value.to_string(),
+        Data::String(text) => text.trim().to_string(),
+        _ => String::new(),
+    }
+}
+
+fn cell_value(cell: &Data) -> f64 {
+    match cell {
+        Data::Int(value) => *value as f64,
+        Data::Float(value) => *value,
+        Data::String(text) => text.trim().parse().unwrap_or(0.0),
+        _ => 0.0,
+    }
+}
+
+/// Same result as `read_csv_with_base_year` on the CSV export of the sheet.
+/// `sheet` picks the worksheet by name; the first sheet is used otherwise.
+pub fn read_xlsx(path: &str, sheet: Option<&str>, base_year: u32) -> Result<YearlyValues, StockDataError> {
+    let file = File::open(path).map_err(|source| StockDataError::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    let mut workbook: Xlsx<_> = Xlsx::new(BufReader::new(file)).map_err(xlsx_error(path))?;
+    let range = match sheet {
+        Some(name) => workbook.worksheet_range(name).map_err(xlsx_error(path))?,
+        None => match workbook.worksheet_range_at(0) {
+            Some(range) => range.map_err(xlsx_error(path))?,
+            None => return Err(StockDataError::EmptyDataset { path: path.to_string() }),
+        },
+    };
+
+    let mut rows = range.rows();
+    let column_years: Vec<u32> = rows
+        .next()
+        .unwrap_or_default()
+        .iter()
+        .skip(1)
+        .enumerate()
+        .map(|(i, header)| match cell_text(header).parse::<f64>() {
+            Ok(year) => Ok(year as u32),
+            Err(_) => counted_year(path, &cell_text(header), base_year, i),
+        })
+        .collect::<Result<_, _>>()?;
+    let mut data: YearlyValues = HashMap::new();
+
+    for row in rows {
+        let ticker = row.first().map(cell_text).unwrap_or_default();
+        if ticker.is_empty() {
+            continue;
+        }
+        let mut years = HashMap::new();
+        for (i, cell) in row.iter().skip(1).enumerate() {
+            let year = match column_years.get(i) {
+                Some(&year) => year,
+                None => counted_year(path, "", base_year, i)?,
+            };
+            years.insert(year, cell_value(cell));
+        }
+        data.insert(ticker, years);
+    }
+    if data.is_empty() {
+        return Err(StockDataError::EmptyDataset { path: path.to_string() });
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_xlsxwriter::Workbook;
+    use crate::stock_data::{process_stock_data, read_csv, LoadOptions, METRICS};
+    use crate::synthetic::SyntheticConfig;
+    use crate::test_util::fixture_path;
+
+    #[test]
+    fn test_xlsx_matches_csv() {
+        let data = SyntheticConfig {
+            n_tickers: 3,
+            n_years: 3,
+            seed: 11,
+            ..Default::default()
+        }
+        .generate();
+        let dir = fixture_path("xlsx");
+        let written = data.write_csvs(&dir).unwrap();
+
+        // Each CSV becomes the "Fundamentals" sheet of a workbook, after a decoy first sheet
+        let mut xlsx_files = Vec::new();
+        for (csv_path, metric) in &written.financial_files {
+            let mut workbook = Workbook::new();
+            workbook.add_worksheet().set_name("Notes").unwrap().write(0, 0, "exported").unwrap();
+            let sheet = workbook.add_worksheet().set_name("Fundamentals").unwrap();
+            let contents = std::fs::read_to_string(csv_path).unwrap();
+            for (row, line) in contents.lines().enumerate() {
+                for (col, cell) in line.split(',').enumerate() {
+                    match cell.parse::<f64>() {
+                        Ok(value) => sheet.write_number(row as u32, col as u16, value).unwrap(),
+                        Err(_) => sheet.write_string(row as u32, col as u16, cell).unwrap(),
+                    };
+                }
+            }
+            let path = dir.join(format!("data_{}.xlsx", metric));
+            workbook.save(&path).unwrap();
+            xlsx_files.push(path.to_str().unwrap().to_string());
+        }
+
+        let assets = read_xlsx(&xlsx_files[0], Some("Fundamentals"), 2022).unwrap();
+        assert_eq!(assets, read_csv(&written.financial_files[0].0).unwrap());
+        assert!(read_xlsx(&xlsx_files[0], Some("Missing"), 2022).is_err());
+
+        let options = LoadOptions {
+            sheet: Some("Fundamentals".to_string()),
+            ..Default::default()
+        };
+        let files: Vec<(&str, &str)> = xlsx_files.iter().map(String::as_str).zip(METRICS).collect();
+        let from_xlsx = process_stock_data(&files, &[&written.price_file], &options).unwrap();
+        let price_files = [written.price_file.as_str()];
+        let from_csv = process_stock_data(&written.financial_file_pairs(), &price_files, &LoadOptions::default()).unwrap();
+        for (ticker, records) in &from_csv {
+            let years: Vec<(u32, f64, Option<f64>)> =
+                records.iter().map(|r| (r.year, r.revenue, r.change_in_roa)).collect();
+            let xlsx_years: Vec<(u32, f64, Option<f64>)> =
+                from_xlsx[ticker].iter().map(|r| (r.year, r.revenue, r.change_in_roa)).collect();
+            assert_eq!(years, xlsx_years);
+        }
+    }
+}